@@ -0,0 +1,418 @@
+use ash::{vk, Device, Instance};
+use std::ptr;
+use std::sync::Mutex;
+use crate::error::VulkanDemoError;
+
+// Size of the memory blocks that small resources are carved out of, clamped to an
+// eighth of the heap for small heaps.
+const BLOCK_SIZE: vk::DeviceSize = 64 << 20;
+// Resources above this size get a block of their own instead.
+const DEDICATED_THRESHOLD: vk::DeviceSize = BLOCK_SIZE / 2;
+
+/// Where a buffer or image lives, and so how the host may access it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MemoryLocation {
+    /// Device-local memory the host never touches.
+    GpuOnly,
+    /// Host-visible memory written by the CPU and read by the GPU.
+    CpuToGpu,
+    /// Host-visible memory written by the GPU and read back by the CPU, cached where possible.
+    GpuToCpu,
+}
+
+impl MemoryLocation {
+    fn required_flags(self) -> vk::MemoryPropertyFlags {
+        match self {
+            Self::GpuOnly => vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            Self::CpuToGpu | Self::GpuToCpu => vk::MemoryPropertyFlags::HOST_VISIBLE,
+        }
+    }
+
+    /// Coherent memory is preferred, but not every device has host-visible memory
+    /// that is; the owners of non-coherent allocations flush and invalidate instead.
+    fn preferred_flags(self) -> vk::MemoryPropertyFlags {
+        match self {
+            Self::GpuOnly => vk::MemoryPropertyFlags::empty(),
+            Self::CpuToGpu => vk::MemoryPropertyFlags::HOST_COHERENT,
+            Self::GpuToCpu => vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_CACHED,
+        }
+    }
+}
+
+/// A range of a memory block bound to one buffer or image. Handed back with
+/// `Allocator::free` by the owning wrapper.
+pub struct Allocation {
+    memory: vk::DeviceMemory,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    /// Start of the range in the block's persistent mapping; null unless host-visible.
+    mapped: *mut u8,
+    /// `nonCoherentAtomSize` for host-visible memory that is not coherent, `None` otherwise.
+    atom_size: Option<vk::DeviceSize>,
+    block_size: vk::DeviceSize,
+}
+
+impl Allocation {
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    pub fn offset(&self) -> vk::DeviceSize {
+        self.offset
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    pub fn mapped_ptr(&self) -> Option<*mut u8> {
+        (!self.mapped.is_null()).then_some(self.mapped)
+    }
+
+    /// The memory range to flush after the host writes, or invalidate before it
+    /// reads, `size` bytes at `offset` into the allocation. `None` for coherent
+    /// memory, which needs neither.
+    pub fn mapped_range(&self, offset: vk::DeviceSize, size: vk::DeviceSize) -> Option<vk::MappedMemoryRange<'static>> {
+        let atom_size = self.atom_size?;
+        let (offset, size) = atom_aligned_range(self.offset + offset, size, atom_size, self.block_size);
+        Some(vk::MappedMemoryRange::default().memory(self.memory).offset(offset).size(size))
+    }
+}
+
+/// Widens `size` bytes at `offset` to whole multiples of `atom_size`, as
+/// `vkFlushMappedMemoryRanges` requires, except at the end of the memory object,
+/// which the range must not run past.
+fn atom_aligned_range(
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    atom_size: vk::DeviceSize,
+    memory_size: vk::DeviceSize,
+) -> (vk::DeviceSize, vk::DeviceSize) {
+    let start = offset / atom_size * atom_size;
+    let end = (offset + size).next_multiple_of(atom_size).min(memory_size);
+    (start, end - start)
+}
+
+/// Bytes allocated from one memory heap, for the stats overlay.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HeapUsage {
+    pub device_local: bool,
+    /// Total size of the heap.
+    pub size: vk::DeviceSize,
+    /// Memory allocated from the driver, in blocks.
+    pub reserved: vk::DeviceSize,
+    /// The part of `reserved` bound to live buffers and images.
+    pub used: vk::DeviceSize,
+    pub blocks: u32,
+    pub allocations: u32,
+    /// How much of the heap this process can use before allocations fail or
+    /// slow down, from `VK_EXT_memory_budget`; the heap size without it.
+    pub budget: vk::DeviceSize,
+    /// This process's use of the heap as the driver sees it, including memory
+    /// not allocated here; `reserved` without `VK_EXT_memory_budget`.
+    pub usage: vk::DeviceSize,
+}
+
+impl HeapUsage {
+    /// What is left of the budget.
+    pub fn available(&self) -> vk::DeviceSize {
+        self.budget.saturating_sub(self.usage)
+    }
+}
+
+/// One `vk::DeviceMemory` allocation that resources are sub-allocated from.
+struct Block {
+    memory: vk::DeviceMemory,
+    memory_type: u32,
+    /// Set for non-coherent host-visible memory, whose allocations are aligned to it
+    /// so that flushing one never touches a neighbor.
+    atom_size: Option<vk::DeviceSize>,
+    /// Buffers and optimal-tiling images never share a block, which sidesteps
+    /// `bufferImageGranularity`.
+    linear: bool,
+    /// Allocated with these, `DEVICE_ADDRESS` for buffers whose address is
+    /// taken; only resources asking for the same are placed in the block.
+    flags: vk::MemoryAllocateFlags,
+    dedicated: bool,
+    size: vk::DeviceSize,
+    /// Host-visible blocks stay mapped for their whole lifetime, since a memory
+    /// object can only be mapped once at a time.
+    mapped: *mut u8,
+    /// Free ranges as `(offset, size)`, sorted by offset and never adjacent.
+    free: Vec<(vk::DeviceSize, vk::DeviceSize)>,
+    used: vk::DeviceSize,
+    allocations: u32,
+}
+
+// The mapping is only written through the allocations' owners, and the block
+// list itself is behind the allocator's mutex.
+unsafe impl Send for Block {}
+
+impl Block {
+    /// First-fit: carves `size` bytes aligned to `alignment` out of the first free range they fit in.
+    fn take(&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize) -> Option<vk::DeviceSize> {
+        let (index, offset) = self.free.iter().enumerate().find_map(|(index, &(start, len))| {
+            let offset = start.next_multiple_of(alignment);
+            (offset + size <= start + len).then_some((index, offset))
+        })?;
+        let (start, len) = self.free[index];
+        let before = (start, offset - start);
+        let after = (offset + size, start + len - offset - size);
+        self.free.splice(index..=index, [before, after].into_iter().filter(|&(_, len)| len > 0));
+        self.used += size;
+        self.allocations += 1;
+        Some(offset)
+    }
+
+    /// Returns a range from `take`, merging it with its free neighbors.
+    fn give_back(&mut self, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        let index = self.free.partition_point(|&(start, _)| start < offset);
+        self.free.insert(index, (offset, size));
+        if index + 1 < self.free.len() && offset + size == self.free[index + 1].0 {
+            self.free[index].1 += self.free.remove(index + 1).1;
+        }
+        if index > 0 && self.free[index - 1].0 + self.free[index - 1].1 == offset {
+            let (_, len) = self.free.remove(index);
+            self.free[index - 1].1 += len;
+        }
+        self.used -= size;
+        self.allocations -= 1;
+    }
+}
+
+/// Sub-allocates buffers and images from large memory blocks, so the demo stays
+/// far below `maxMemoryAllocationCount` however many resources it creates.
+///
+/// Owned by `VulkanContext`, which destroys it after every resource and before
+/// the device. Blocks are released as soon as they are empty.
+pub struct Allocator {
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    non_coherent_atom_size: vk::DeviceSize,
+    blocks: Mutex<Vec<Block>>,
+}
+
+impl Allocator {
+    pub fn new(instance: &Instance, physical_device: vk::PhysicalDevice) -> Self {
+        let memory_properties = unsafe { instance.get_physical_device_memory_properties(physical_device) };
+        let limits = unsafe { instance.get_physical_device_properties(physical_device) }.limits;
+        Self {
+            memory_properties,
+            non_coherent_atom_size: limits.non_coherent_atom_size,
+            blocks: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn memory_properties(&self) -> &vk::PhysicalDeviceMemoryProperties {
+        &self.memory_properties
+    }
+
+    /// Allocates memory for `requirements` in `location`. `linear` is true for
+    /// buffers and false for optimal-tiling images; `flags` are what the
+    /// memory must be allocated with, such as `DEVICE_ADDRESS`.
+    pub fn allocate(
+        &self,
+        device: &Device,
+        requirements: vk::MemoryRequirements,
+        location: MemoryLocation,
+        linear: bool,
+        flags: vk::MemoryAllocateFlags,
+    ) -> Result<Allocation, VulkanDemoError> {
+        let memory_type = self
+            .find_memory_type(requirements.memory_type_bits, location.required_flags() | location.preferred_flags())
+            .or_else(|| self.find_memory_type(requirements.memory_type_bits, location.required_flags()))
+            .ok_or(VulkanDemoError::MissingMemoryType)?;
+        let atom_size = self.atom_size(memory_type);
+        let alignment = requirements.alignment.max(atom_size.unwrap_or(1));
+
+        let mut blocks = self.blocks.lock().unwrap();
+        let found = blocks.iter_mut().find_map(|block| {
+            if block.dedicated || block.memory_type != memory_type || block.linear != linear || block.flags != flags {
+                return None;
+            }
+            block.take(requirements.size, alignment).map(|offset| (block, offset))
+        });
+        if let Some((block, offset)) = found {
+            return Ok(block_allocation(block, offset, requirements.size));
+        }
+
+        let dedicated = requirements.size > DEDICATED_THRESHOLD;
+        let block_size = if dedicated { requirements.size } else { self.block_size(memory_type).max(requirements.size) };
+        let mut block = self.allocate_block(device, memory_type, atom_size, linear, flags, dedicated, block_size)?;
+        let offset = block.take(requirements.size, alignment).expect("a new block fits its first allocation");
+        let allocation = block_allocation(&block, offset, requirements.size);
+        blocks.push(block);
+        Ok(allocation)
+    }
+
+    /// Returns `allocation`'s range to its block, freeing the block once it is empty.
+    /// The buffer or image bound to it must already be destroyed.
+    pub fn free(&self, device: &Device, allocation: &Allocation) {
+        let mut blocks = self.blocks.lock().unwrap();
+        let Some(index) = blocks.iter().position(|block| block.memory == allocation.memory) else {
+            log::error!("Freeing an allocation from an unknown memory block");
+            return;
+        };
+        blocks[index].give_back(allocation.offset, allocation.size);
+        if blocks[index].allocations == 0 {
+            let block = blocks.swap_remove(index);
+            unsafe { device.free_memory(block.memory, None) };
+        }
+    }
+
+    /// Allocated bytes per memory heap, indexed like `memoryHeaps`.
+    pub fn report(&self) -> Vec<HeapUsage> {
+        let heaps = &self.memory_properties.memory_heaps[..self.memory_properties.memory_heap_count as usize];
+        let mut usage: Vec<_> = heaps
+            .iter()
+            .map(|heap| HeapUsage {
+                device_local: heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL),
+                size: heap.size,
+                ..HeapUsage::default()
+            })
+            .collect();
+        for block in self.blocks.lock().unwrap().iter() {
+            let heap = &mut usage[self.memory_properties.memory_types[block.memory_type as usize].heap_index as usize];
+            heap.reserved += block.size;
+            heap.used += block.used;
+            heap.blocks += 1;
+            heap.allocations += block.allocations;
+        }
+        for heap in &mut usage {
+            heap.budget = heap.size;
+            heap.usage = heap.reserved;
+        }
+        usage
+    }
+
+    /// The heap `allocate` would take memory in `location` from, for a resource
+    /// any memory type can hold.
+    pub fn heap_index(&self, location: MemoryLocation) -> Option<u32> {
+        self.find_memory_type(u32::MAX, location.required_flags() | location.preferred_flags())
+            .or_else(|| self.find_memory_type(u32::MAX, location.required_flags()))
+            .map(|memory_type| self.memory_properties.memory_types[memory_type as usize].heap_index)
+    }
+
+    /// Logs the final report and frees whatever blocks are left, warning about
+    /// them as leaks. Called by `VulkanContext` just before destroying the device.
+    pub(crate) fn destroy(&self, device: &Device) {
+        for (index, heap) in self.report().iter().enumerate() {
+            log::info!(
+                "Memory heap {index}: {} bytes in {} allocations, {} blocks",
+                heap.used,
+                heap.allocations,
+                heap.blocks,
+            );
+        }
+        let mut blocks = self.blocks.lock().unwrap();
+        if blocks.is_empty() {
+            log::info!("No leaked memory blocks");
+        } else {
+            log::warn!("{} memory blocks leaked", blocks.len());
+        }
+        for block in blocks.drain(..) {
+            unsafe { device.free_memory(block.memory, None) };
+        }
+    }
+
+    fn find_memory_type(&self, type_filter: u32, properties: vk::MemoryPropertyFlags) -> Option<u32> {
+        (0..self.memory_properties.memory_type_count).find(|&i| {
+            (type_filter & (1 << i)) != 0 && self.memory_properties.memory_types[i as usize].property_flags.contains(properties)
+        })
+    }
+
+    fn atom_size(&self, memory_type: u32) -> Option<vk::DeviceSize> {
+        let flags = self.memory_properties.memory_types[memory_type as usize].property_flags;
+        (flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) && !flags.contains(vk::MemoryPropertyFlags::HOST_COHERENT))
+            .then_some(self.non_coherent_atom_size)
+    }
+
+    fn block_size(&self, memory_type: u32) -> vk::DeviceSize {
+        let heap_index = self.memory_properties.memory_types[memory_type as usize].heap_index;
+        BLOCK_SIZE.min(self.memory_properties.memory_heaps[heap_index as usize].size / 8)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn allocate_block(
+        &self,
+        device: &Device,
+        memory_type: u32,
+        atom_size: Option<vk::DeviceSize>,
+        linear: bool,
+        flags: vk::MemoryAllocateFlags,
+        dedicated: bool,
+        size: vk::DeviceSize,
+    ) -> Result<Block, VulkanDemoError> {
+        let mut flags_info = vk::MemoryAllocateFlagsInfo::default().flags(flags);
+        let mut alloc_info = vk::MemoryAllocateInfo::default()
+            .allocation_size(size)
+            .memory_type_index(memory_type);
+        if !flags.is_empty() {
+            alloc_info = alloc_info.push_next(&mut flags_info);
+        }
+        let memory = unsafe { device.allocate_memory(&alloc_info, None)? };
+
+        let host_visible = self.memory_properties.memory_types[memory_type as usize]
+            .property_flags
+            .contains(vk::MemoryPropertyFlags::HOST_VISIBLE);
+        let mapped = if host_visible {
+            let mapping = unsafe { device.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty()) };
+            mapping.inspect_err(|_| unsafe { device.free_memory(memory, None) })? as *mut u8
+        } else {
+            ptr::null_mut()
+        };
+
+        Ok(Block {
+            memory,
+            memory_type,
+            atom_size,
+            linear,
+            flags,
+            dedicated,
+            size,
+            mapped,
+            free: vec![(0, size)],
+            used: 0,
+            allocations: 0,
+        })
+    }
+}
+
+fn block_allocation(block: &Block, offset: vk::DeviceSize, size: vk::DeviceSize) -> Allocation {
+    let mapped = if block.mapped.is_null() { block.mapped } else { unsafe { block.mapped.add(offset as usize) } };
+    Allocation { memory: block.memory, offset, size, mapped, atom_size: block.atom_size, block_size: block.size }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ATOM: vk::DeviceSize = 64;
+    const BLOCK: vk::DeviceSize = 4096;
+
+    #[test]
+    fn aligned_ranges_are_kept() {
+        assert_eq!(atom_aligned_range(0, 64, ATOM, BLOCK), (0, 64));
+        assert_eq!(atom_aligned_range(128, 256, ATOM, BLOCK), (128, 256));
+    }
+
+    #[test]
+    fn unaligned_ranges_widen_to_whole_atoms() {
+        // Start rounds down, end rounds up.
+        assert_eq!(atom_aligned_range(10, 20, ATOM, BLOCK), (0, 64));
+        assert_eq!(atom_aligned_range(100, 50, ATOM, BLOCK), (64, 128));
+        assert_eq!(atom_aligned_range(64, 1, ATOM, BLOCK), (64, 64));
+        assert_eq!(atom_aligned_range(63, 2, ATOM, BLOCK), (0, 128));
+        // An atom of one byte changes nothing.
+        assert_eq!(atom_aligned_range(13, 7, 1, BLOCK), (13, 7));
+    }
+
+    #[test]
+    fn ranges_stop_at_the_end_of_the_block() {
+        // A block that isn't a whole number of atoms: its tail can't round up.
+        let block = 4000;
+        assert_eq!(atom_aligned_range(3970, 30, ATOM, block), (3968, 32));
+        assert_eq!(atom_aligned_range(0, block, ATOM, block), (0, block));
+        // Running exactly to an aligned end.
+        assert_eq!(atom_aligned_range(BLOCK - 10, 10, ATOM, BLOCK), (BLOCK - ATOM, ATOM));
+    }
+}
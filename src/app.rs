@@ -0,0 +1,1906 @@
+use ash::vk;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use winit::{
+    dpi::{PhysicalPosition, PhysicalSize},
+    event::{ElementState, KeyEvent, MouseButton, MouseScrollDelta, WindowEvent},
+    keyboard::{KeyCode, PhysicalKey},
+    window::{Window, WindowId},
+};
+use crate::attractors::Attractor;
+use crate::autotune::dispatch_tuning;
+use crate::camera::{world_projection, OrbitCamera};
+use crate::config::AppConfig;
+use crate::cpu_sim::SimBackend;
+use crate::debug::{DebugUtils, COMPUTE_LABEL_COLOR, GRAPHICS_LABEL_COLOR};
+use crate::device_features::FeatureRequest;
+use crate::emitter::{EmitterConfig, EmitterPreset};
+use crate::frame_graph::{BufferUsage, FrameGraph, RecordPass, RenderFrame};
+use crate::fullscreen::toggle_fullscreen;
+use crate::error::VulkanDemoError;
+use crate::gpu_timer::GpuTimer;
+use crate::obstacles::default_obstacles;
+use crate::particles::{BoundaryMode, ColorMode, ParticleFormat, ParticleSystem, SimParams, SimPipelines, SimPushConstants, SimulationMode};
+use crate::pipeline_utils::{ShaderCompileOptions, SHADER_INCLUDES};
+use crate::renderer::{BlendMode, PointShape, PresentMode, Renderer, POINT_SIZE_SCALE_STEP};
+use crate::recorder::{FrameRecorder, RECORD_FRAME_DT};
+use crate::resources::OwnedSurface;
+use crate::screenshot::{screenshot_path, write_png};
+use crate::scene::ScenePreset;
+use crate::secondary_commands::SecondaryCommands;
+use crate::snapshot::{read_snapshot, write_snapshot};
+use crate::shader_watcher::ShaderWatcher;
+use crate::speed_histogram::SpeedHistogram;
+use crate::stats::FrameStats;
+use crate::step_clock::StepClock;
+use crate::ui::{Overlay, Settings};
+use crate::sync::{FrameSync, GraphicsSubmit, ViewSync, FRAMES_IN_FLIGHT};
+use crate::texture::Texture;
+use crate::vulkan_context::VulkanContext;
+
+pub const WINDOW_TITLE: &str = "Vulkan Particle Demo";
+pub const SECOND_WINDOW_TITLE: &str = "Vulkan Particle Demo (second view)";
+
+/// What the simulation does while the window is in the background: covered,
+/// or unfocused with `--background-fps`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BackgroundSim {
+    /// Keeps simulating the time between frames, clamped like after any stall.
+    #[default]
+    Clamp,
+    /// Stands still; the time in the background is never simulated.
+    Freeze,
+}
+
+/// When the event loop should redraw, from `App::pacing`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FramePacing {
+    /// Back to back.
+    Continuous,
+    /// No sooner than `next_frame`, as the window is unfocused and capped by `--background-fps`.
+    Throttled { next_frame: Instant },
+    /// Not until an event changes that; see `App::is_idle`.
+    Idle,
+}
+
+// Caps the time one frame simulates, on top of `--max-substeps`, after a stall such as a window drag.
+const MAX_FRAME_DT: f32 = 0.1;
+// Time advanced by the step key while paused.
+const STEP_DT: f32 = 1.0 / 60.0;
+const ATTRACTOR_STRENGTH: f32 = 1.0;
+// Attractors dropped with W: their pull and the radius inside which it stops growing.
+const WELL_STRENGTH: f32 = 0.05;
+const WELL_RADIUS: f32 = 0.1;
+const GRAVITY_STEP: f32 = 0.1;
+const BOUNCE_RESTITUTION: f32 = 0.8;
+const FLOCKING_WEIGHT_STEP: f32 = 0.5;
+const FLOCKING_WEIGHT_MAX: f32 = 3.0;
+// Used by the X key when `--repulsion` was left at 0.
+const REPULSION_STRENGTH: f32 = 0.5;
+// 3D camera controls: radians per arrow key press, radians per pixel dragged,
+// and the zoom factor per scroll line.
+const CAMERA_ROTATE_STEP: f32 = 0.1;
+const CAMERA_DRAG_SPEED: f32 = 0.01;
+const CAMERA_ZOOM_STEP: f32 = 0.9;
+// Pixels of touchpad scrolling that count as one line.
+const PIXELS_PER_SCROLL_LINE: f64 = 40.0;
+// Where F5 and F9 save and load snapshots without `--load`.
+const DEFAULT_SNAPSHOT_PATH: &str = "snapshot.bin";
+// Where F6 writes the scene without `--dump-scene`.
+const DEFAULT_SCENE_PATH: &str = "scene.toml";
+// Particle counts the Up and Down keys step between, by factors of two.
+const MIN_KEY_PARTICLES: u32 = 1_000;
+const MAX_KEY_PARTICLES: u32 = 5_000_000;
+// Static colors given to systems added with Insert, in turn.
+const SYSTEM_COLORS: [[f32; 4]; 4] = [
+    [1.0, 0.3, 0.2, 1.0],
+    [0.2, 0.5, 1.0, 1.0],
+    [0.3, 1.0, 0.4, 1.0],
+    [1.0, 0.8, 0.2, 1.0],
+];
+
+/// Owns every Vulkan object of the demo and drives one frame per redraw.
+///
+/// Fields drop in declaration order: the Vulkan objects first, then the
+/// context, and the window last, so the device and surface are destroyed only
+/// once everything built on them is gone.
+pub struct App {
+    /// Simulated and drawn in order, all with `sim_pipelines`.
+    particle_systems: Vec<ParticleSystem>,
+    /// Removed systems frames in flight may still use, filed under the frame
+    /// submitted last before their removal and dropped once it has been waited for.
+    retired_systems: [Vec<ParticleSystem>; FRAMES_IN_FLIGHT],
+    sim_pipelines: SimPipelines,
+    renderer: Renderer,
+    frame_sync: FrameSync,
+    /// The window `--second-window` opened, until either window closes.
+    second_view: Option<SecondView>,
+    gpu_timer: GpuTimer,
+    /// Speeds of the selected system, for the overlay and the stats.
+    speed_histogram: SpeedHistogram,
+    stats: FrameStats,
+    /// Records the scene pass on other threads; `None` with `--record-threads 1`.
+    secondary_commands: Option<SecondaryCommands>,
+    shader_watcher: Option<ShaderWatcher>,
+    recorder: Option<FrameRecorder>,
+    overlay: Overlay,
+    context: VulkanContext,
+    window: Window,
+    running: bool,
+
+    start_time: Instant,
+    last_frame: Instant,
+    clock: StepClock,
+    /// Advances `clock` by this instead of the wall-clock frame time.
+    fixed_dt: Option<f32>,
+    cursor_position: PhysicalPosition<f64>,
+    attract_held: bool,
+    repel_held: bool,
+    paused: bool,
+    step_requested: bool,
+    /// Index into `particle_systems` of the system keys and the overlay edit.
+    selected_system: usize,
+    emitter_preset: EmitterPreset,
+    /// Strength the X key switches repulsion back on with.
+    repulsion_strength: f32,
+    /// The 3D view; unused in 2D.
+    camera: OrbitCamera,
+    orbit_held: bool,
+    pan_held: bool,
+    /// Set by F12; the next presented frame is saved as a PNG.
+    screenshot_requested: bool,
+    /// Saved to with F5 and loaded from with F9.
+    snapshot_path: PathBuf,
+    /// Where F6 writes the scene, and `shutdown` too with `--dump-scene`.
+    scene_path: PathBuf,
+    dump_scene_on_exit: bool,
+    /// The window has no area or is hidden; no frames are rendered meanwhile.
+    minimized: bool,
+    occluded: bool,
+    focused: bool,
+    /// Redraws a second while unfocused, if capped.
+    background_fps: Option<f32>,
+    background_sim: BackgroundSim,
+    /// Between `suspend` and `resume`: there is no surface to render to.
+    suspended: bool,
+    /// The window was resized while the swapchain couldn't be recreated; it is
+    /// recreated before the next frame instead.
+    swapchain_stale: bool,
+}
+
+impl App {
+    /// With `config.shader_dir` set, shaders are loaded from that directory and
+    /// reloaded whenever a file in it changes, instead of using the embedded copies.
+    /// `second_window`, from `--second-window`, shows the same simulation.
+    pub fn new(window: Window, second_window: Option<Window>, config: &AppConfig) -> Result<Self, VulkanDemoError> {
+        let size = window.inner_size();
+        let context = VulkanContext::new(&window, config.gpu_index, config.validation, &FeatureRequest::default())?;
+        let scene = match &config.scene {
+            Some(path) => ScenePreset::load(path)?,
+            None => ScenePreset::from_config(config),
+        };
+        let mut renderer = Renderer::new(
+            &context,
+            context.surface,
+            size.width,
+            size.height,
+            scene.renderer.present_mode,
+            config.swapchain_images,
+            scene.renderer.blend_mode,
+        )?;
+        scene.renderer.apply(&mut renderer);
+        if let Some(path) = &config.sprite {
+            renderer.set_sprite(&context.device, Texture::load_png(&context, path)?);
+        }
+        renderer.set_render_scale(&context, config.render_scale)?;
+        let tuning = dispatch_tuning(&context, config.particle_format(), config.autotune)?;
+        let sim_pipelines = SimPipelines::new(&context, config.particle_format(), tuning, config.bda)?;
+        renderer.set_particle_format(&context.device, config.particle_format())?;
+        let mut particle_systems = scene.build_systems(&context, &sim_pipelines, config.sim)?;
+        for particle_system in &particle_systems {
+            log::info!("Seed: {}", particle_system.seed);
+        }
+        if let Some(path) = &config.load {
+            // Nothing has run on the GPU yet, so the old buffers can go right away.
+            particle_systems[0].restore(&context, &sim_pipelines, &read_snapshot(path)?)?;
+        }
+        let repulsion = particle_systems[0].params.repulsion_strength;
+        let compute_queue_family = context.has_async_compute().then_some(context.compute_queue_family_index);
+        let frame_sync = FrameSync::new(&context, compute_queue_family, renderer.images.len())?;
+        let second_view = second_window
+            .map(|window| SecondView::new(&context, window, config, &scene, sim_pipelines.format))
+            .transpose()?;
+        let gpu_timer = GpuTimer::new(&context)?;
+        let speed_histogram = SpeedHistogram::new(&context, sim_pipelines.workgroup_size, sim_pipelines.format)?;
+        let stats = FrameStats::new(config.stats_csv.as_deref())?;
+        let secondary_commands = match config.record_threads() {
+            1 => None,
+            threads => Some(SecondaryCommands::new(&context, threads)?),
+        };
+        let shader_watcher = config.shader_dir.as_deref().map(ShaderWatcher::new).transpose()?;
+        let recorder = config
+            .record
+            .as_deref()
+            .map(|dir| FrameRecorder::new(&context, &renderer, dir, config.record_frames))
+            .transpose()?;
+        let fixed_dt = if recorder.is_some() { Some(RECORD_FRAME_DT) } else { config.fixed_dt };
+        let overlay = Overlay::new(&context, &window, &renderer)?;
+
+        let start_time = Instant::now();
+        let mut app = Self {
+            particle_systems,
+            retired_systems: Default::default(),
+            sim_pipelines,
+            renderer,
+            frame_sync,
+            second_view,
+            gpu_timer,
+            speed_histogram,
+            stats,
+            secondary_commands,
+            shader_watcher,
+            recorder,
+            overlay,
+            context,
+            window,
+            running: true,
+            start_time,
+            last_frame: start_time,
+            clock: StepClock::new(config.substep_dt, config.max_substeps),
+            fixed_dt,
+            cursor_position: PhysicalPosition::new(0.0, 0.0),
+            attract_held: false,
+            repel_held: false,
+            paused: false,
+            step_requested: false,
+            selected_system: 0,
+            emitter_preset: config.emitter,
+            repulsion_strength: if repulsion > 0.0 { repulsion } else { REPULSION_STRENGTH },
+            camera: OrbitCamera::default(),
+            orbit_held: false,
+            pan_held: false,
+            screenshot_requested: false,
+            snapshot_path: config.load.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_SNAPSHOT_PATH)),
+            scene_path: config.dump_scene.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_SCENE_PATH)),
+            dump_scene_on_exit: config.dump_scene.is_some(),
+            minimized: false,
+            occluded: false,
+            focused: true,
+            background_fps: config.background_fps,
+            background_sim: config.background_sim,
+            suspended: false,
+            swapchain_stale: false,
+        };
+        app.reload_shaders(&shader_files());
+        Ok(app)
+    }
+
+    pub fn window(&self) -> &Window {
+        &self.window
+    }
+
+    /// Handles an event of window `window_id`, the main window or the second.
+    #[profiling::function]
+    pub fn handle_event(&mut self, window_id: WindowId, event: &WindowEvent) -> Result<(), VulkanDemoError> {
+        if !self.running {
+            return Ok(());
+        }
+        if window_id != self.window.id() {
+            return self.handle_view_event(event);
+        }
+
+        match *event {
+            WindowEvent::Resized(_) => self.recreate_swapchain()?,
+            // The physical size changes along with it; the next frame rebuilds at it.
+            WindowEvent::ScaleFactorChanged { .. } => self.swapchain_stale = true,
+            WindowEvent::Occluded(occluded) => self.set_background(occluded, self.focused),
+            WindowEvent::Focused(focused) => self.set_background(self.occluded, focused),
+            WindowEvent::CursorMoved { position, .. } => {
+                let dx = (position.x - self.cursor_position.x) as f32;
+                let dy = (position.y - self.cursor_position.y) as f32;
+                self.cursor_position = position;
+                if self.orbit_held {
+                    self.camera.rotate(dx * CAMERA_DRAG_SPEED, dy * CAMERA_DRAG_SPEED);
+                }
+                if self.pan_held {
+                    let height = self.window.inner_size().height.max(1) as f32;
+                    self.camera.pan(dx / height, dy / height);
+                }
+            }
+            // In 3D the left button orbits the camera instead of attracting, as the
+            // cursor no longer maps onto a single point of the simulation.
+            WindowEvent::MouseInput { state, button, .. } => {
+                let pressed = state == ElementState::Pressed;
+                let three_d = self.is_3d();
+                match button {
+                    MouseButton::Left if three_d => self.orbit_held = pressed,
+                    MouseButton::Left => self.attract_held = pressed,
+                    MouseButton::Right => self.repel_held = pressed,
+                    MouseButton::Middle if three_d => self.pan_held = pressed,
+                    _ => (),
+                }
+            }
+            WindowEvent::MouseWheel { delta, .. } if self.is_3d() => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => y,
+                    MouseScrollDelta::PixelDelta(position) => (position.y / PIXELS_PER_SCROLL_LINE) as f32,
+                };
+                self.camera.zoom(CAMERA_ZOOM_STEP.powf(lines));
+            }
+            WindowEvent::KeyboardInput {
+                event: KeyEvent { physical_key: PhysicalKey::Code(key), state: ElementState::Pressed, repeat: false, .. },
+                ..
+            } => self.handle_key(key)?,
+            WindowEvent::RedrawRequested => self.redraw()?,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Handles an event of the second window. Its keys steer the simulation
+    /// like the main window's, except those changing how it is drawn; the
+    /// mouse is left to the main window.
+    fn handle_view_event(&mut self, event: &WindowEvent) -> Result<(), VulkanDemoError> {
+        let Some(view) = &mut self.second_view else {
+            return Ok(());
+        };
+        match *event {
+            // Both recreate the swapchain before the view is next drawn.
+            WindowEvent::Resized(_) | WindowEvent::ScaleFactorChanged { .. } => view.swapchain_stale = true,
+            WindowEvent::Occluded(occluded) => view.occluded = occluded,
+            WindowEvent::Focused(focused) => {
+                let was_background = self.in_background();
+                if let Some(view) = &mut self.second_view {
+                    view.focused = focused;
+                }
+                self.background_changed(was_background);
+            }
+            WindowEvent::KeyboardInput {
+                event: KeyEvent { physical_key: PhysicalKey::Code(key), state: ElementState::Pressed, repeat: false, .. },
+                ..
+            } => match key {
+                KeyCode::F11 => toggle_fullscreen(&view.window),
+                _ if handle_renderer_key(&self.context, &mut view.renderer, key)? => (),
+                _ => self.handle_key(key)?,
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Closes the window `window_id` belongs to. With `--second-window` the
+    /// other one keeps running: when the main window closes, the second takes
+    /// its place, overlay and all. Returns whether no window is left, when the
+    /// app should exit.
+    pub fn close_window(&mut self, window_id: WindowId) -> Result<bool, VulkanDemoError> {
+        let Some(view) = self.second_view.take() else {
+            return Ok(true);
+        };
+        unsafe { self.context.device.device_wait_idle()? };
+        if view.window.id() == window_id {
+            println!("Closed the second window");
+            return Ok(false);
+        }
+        let SecondView { renderer, surface, window, minimized, occluded, focused, swapchain_stale, surface_lost, .. } = view;
+        // The old renderer's swapchain goes before the surface it was made for.
+        self.renderer = renderer;
+        self.context.adopt_surface(surface);
+        self.window = window;
+        self.window.set_title(WINDOW_TITLE);
+        self.overlay = Overlay::new(&self.context, &self.window, &self.renderer)?;
+        self.frame_sync.resize(&self.context.device, self.renderer.images.len())?;
+        (self.minimized, self.occluded, self.focused) = (minimized, occluded, focused);
+        // A lost surface shows up again on the next acquire.
+        self.swapchain_stale = swapchain_stale && !surface_lost;
+        println!("Closed the main window; the second window carries on");
+        Ok(false)
+    }
+
+    /// Whether any system is 3D, which puts the whole view under the orbit camera.
+    fn is_3d(&self) -> bool {
+        self.particle_systems.iter().any(ParticleSystem::is_3d)
+    }
+
+    /// The system keys and the overlay edit.
+    fn selected_system_mut(&mut self) -> &mut ParticleSystem {
+        &mut self.particle_systems[self.selected_system]
+    }
+
+    fn handle_key(&mut self, key: KeyCode) -> Result<(), VulkanDemoError> {
+        if handle_renderer_key(&self.context, &mut self.renderer, key)? {
+            return Ok(());
+        }
+        let params = self.selected_system_mut().params;
+        match key {
+            KeyCode::Space => {
+                self.paused = !self.paused;
+                println!("{}", if self.paused { "Paused" } else { "Resumed" });
+            }
+            // Stepping only makes sense while paused.
+            KeyCode::Period => self.step_requested = self.paused,
+            KeyCode::KeyR => {
+                self.wait_for_frames()?;
+                self.particle_systems[self.selected_system].reset(&self.context)?;
+                println!("Particles reset");
+            }
+            KeyCode::KeyC => {
+                let color_mode = params.color_mode().next();
+                self.selected_system_mut().update_params(&SimParams { color_mode: color_mode as u32, ..params })?;
+                println!("Color mode: {color_mode:?}");
+            }
+            KeyCode::KeyG | KeyCode::KeyH => {
+                let step = if key == KeyCode::KeyG { GRAVITY_STEP } else { -GRAVITY_STEP };
+                let [gx, gy] = params.gravity;
+                self.selected_system_mut().set_gravity([gx, gy + step])?;
+                println!("Gravity: {:.2}", gy + step);
+            }
+            KeyCode::KeyD => {
+                let drag = if params.drag >= 2.0 { 0.0 } else { params.drag + 0.5 };
+                self.selected_system_mut().set_drag(drag)?;
+                println!("Drag: {drag:.1}");
+            }
+            KeyCode::KeyM => {
+                let max_speed = if params.max_speed >= 2.0 { 0.25 } else { params.max_speed * 2.0 };
+                self.selected_system_mut().set_max_speed(max_speed)?;
+                println!("Max speed: {max_speed:.2}");
+            }
+            KeyCode::KeyB => {
+                let boundary_mode = match params.boundary_mode() {
+                    BoundaryMode::Bounce { .. } => BoundaryMode::Kill,
+                    BoundaryMode::Kill => BoundaryMode::Wrap,
+                    BoundaryMode::Wrap => BoundaryMode::Bounce { restitution: BOUNCE_RESTITUTION },
+                };
+                self.selected_system_mut().set_boundary_mode(boundary_mode)?;
+                println!("Boundary mode: {boundary_mode:?}");
+            }
+            KeyCode::KeyN => {
+                // Skip modes this system is too large for or its backend lacks;
+                // the simple mode is always accepted.
+                let system = self.selected_system_mut();
+                let mut mode = system.mode().next();
+                while !system.set_mode(mode) {
+                    mode = mode.next();
+                }
+                println!("Simulation mode: {mode:?}");
+            }
+            KeyCode::Digit1 | KeyCode::Digit2 | KeyCode::Digit3 => {
+                let mut weights = [params.separation_weight, params.alignment_weight, params.cohesion_weight];
+                let rule = match key {
+                    KeyCode::Digit1 => 0,
+                    KeyCode::Digit2 => 1,
+                    _ => 2,
+                };
+                weights[rule] = if weights[rule] >= FLOCKING_WEIGHT_MAX { 0.0 } else { weights[rule] + FLOCKING_WEIGHT_STEP };
+                self.selected_system_mut().set_flocking_weights(weights)?;
+                println!("Flocking weights (separation, alignment, cohesion): {weights:?}");
+            }
+            KeyCode::KeyE => {
+                self.emitter_preset = self.emitter_preset.next();
+                // The kind mix belongs to the system, not the preset.
+                let kind_weights = self.particle_systems[self.selected_system].emitter.kind_weights;
+                let emitter = EmitterConfig { kind_weights, ..self.emitter_preset.config() };
+                self.selected_system_mut().set_emitter(&emitter)?;
+                println!("Emitter: {:?}", self.emitter_preset);
+            }
+            KeyCode::KeyW => {
+                let pos = cursor_to_world(self.cursor_position, self.window.inner_size());
+                let mut attractors = self.selected_system_mut().attractors.clone();
+                attractors.push(Attractor { pos, strength: WELL_STRENGTH, radius: WELL_RADIUS });
+                self.selected_system_mut().set_attractors(&attractors)?;
+                println!("Attractors: {}", self.selected_system_mut().attractors.len());
+            }
+            KeyCode::KeyQ => {
+                self.selected_system_mut().set_attractors(&[])?;
+                println!("Attractors cleared");
+            }
+            KeyCode::KeyO => {
+                let obstacles = if self.selected_system_mut().obstacles.is_empty() { default_obstacles() } else { Vec::new() };
+                self.wait_for_frames()?;
+                self.particle_systems[self.selected_system].set_obstacles(&self.context, &obstacles)?;
+                println!("Obstacles: {}", if obstacles.is_empty() { "off" } else { "on" });
+            }
+            KeyCode::KeyX => {
+                let repulsion = if params.repulsion_strength > 0.0 { 0.0 } else { self.repulsion_strength };
+                self.selected_system_mut().set_repulsion(repulsion)?;
+                println!("Repulsion: {repulsion:.2}");
+            }
+            KeyCode::KeyV => {
+                let present_mode = match self.renderer.present_mode {
+                    PresentMode::Fifo => PresentMode::Mailbox,
+                    PresentMode::Mailbox => PresentMode::Immediate,
+                    PresentMode::Immediate => PresentMode::Fifo,
+                };
+                self.renderer.set_present_mode(&self.context, present_mode)?;
+                self.frame_sync.resize(&self.context.device, self.renderer.images.len())?;
+                println!("Present mode: {present_mode:?} (using {:?})", self.renderer.active_present_mode);
+            }
+            KeyCode::ArrowLeft | KeyCode::ArrowRight if self.is_3d() => {
+                let step = if key == KeyCode::ArrowLeft { -CAMERA_ROTATE_STEP } else { CAMERA_ROTATE_STEP };
+                self.camera.rotate(step, 0.0);
+            }
+            KeyCode::ArrowUp | KeyCode::ArrowDown if self.is_3d() => {
+                let step = if key == KeyCode::ArrowUp { CAMERA_ROTATE_STEP } else { -CAMERA_ROTATE_STEP };
+                self.camera.rotate(0.0, step);
+            }
+            // In 2D, where they don't steer the camera, the arrows resize the selected system.
+            KeyCode::ArrowUp | KeyCode::ArrowDown => {
+                let count = self.selected_system_mut().count;
+                let count = if key == KeyCode::ArrowUp { count.saturating_mul(2) } else { count / 2 };
+                self.set_particle_count(count.clamp(MIN_KEY_PARTICLES, MAX_KEY_PARTICLES))?;
+            }
+            KeyCode::KeyZ => {
+                let depth_sort = !self.particle_systems[0].is_depth_sorted();
+                self.wait_for_frames()?;
+                for particle_system in &mut self.particle_systems {
+                    particle_system.set_depth_sort(&self.context, depth_sort)?;
+                }
+                println!("Depth sort: {}", if depth_sort { "on" } else { "off" });
+            }
+            KeyCode::F12 => self.screenshot_requested = true,
+            KeyCode::F5 => {
+                self.wait_for_frames()?;
+                self.save_snapshot();
+            }
+            KeyCode::F6 => self.save_scene(),
+            KeyCode::F9 => {
+                self.wait_for_frames()?;
+                self.load_snapshot()?;
+            }
+            KeyCode::F1 => self.overlay.visible = !self.overlay.visible,
+            // The resize that follows recreates the swapchain.
+            KeyCode::F11 => toggle_fullscreen(&self.window),
+            KeyCode::Insert => self.add_system()?,
+            KeyCode::Delete => self.remove_system()?,
+            KeyCode::Tab => {
+                self.selected_system = (self.selected_system + 1) % self.particle_systems.len();
+                println!("Selected system {} of {}", self.selected_system + 1, self.particle_systems.len());
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the pipelines whose shaders are in `changed`. Failures leave
+    /// the running pipeline in place; compile errors are shown in the overlay,
+    /// anything else is logged.
+    fn reload_shaders(&mut self, changed: &BTreeSet<String>) {
+        let Some(dir) = self.shader_watcher.as_ref().map(|watcher| watcher.dir().to_path_buf()) else {
+            return;
+        };
+        let device = &self.context.device;
+        let options = ShaderCompileOptions::with_include_dir(&dir);
+        // An edited include may be used by any of the shaders.
+        let include_changed = SHADER_INCLUDES.iter().any(|(file, _)| changed.contains(*file));
+        let changed = |file: &str| include_changed || changed.contains(file);
+
+        let mut reloads = Vec::new();
+        for &mode in SimulationMode::all() {
+            let file = mode.shader_file();
+            if changed(file) {
+                if let Some(source) = read_shader(&dir, file) {
+                    reloads.push((file, self.sim_pipelines.reload_pipeline(device, mode, &source, &options)));
+                }
+            }
+            for (index, &(file, _)) in mode.behavior().prepasses().iter().enumerate() {
+                if !changed(file) {
+                    continue;
+                }
+                if let Some(source) = read_shader(&dir, file) {
+                    reloads.push((file, self.sim_pipelines.reload_prepass(device, mode, index, &source, &options)));
+                }
+            }
+        }
+        if changed("particle.vert") || changed("particle.frag") {
+            if let (Some(vertex), Some(fragment)) = (read_shader(&dir, "particle.vert"), read_shader(&dir, "particle.frag")) {
+                reloads.push(("particle.vert and particle.frag", self.renderer.reload_pipeline(device, &vertex, &fragment, &options)));
+            }
+        }
+
+        for (shader, result) in reloads {
+            let diagnostics = match result {
+                Ok(()) => {
+                    log::info!("Reloaded {shader}");
+                    Vec::new()
+                }
+                Err(VulkanDemoError::ShaderCompilation { diagnostics, .. }) if !diagnostics.is_empty() && self.overlay.visible => {
+                    log::error!("Failed to reload {shader}, see the overlay");
+                    diagnostics
+                }
+                Err(e) => {
+                    log::error!("{e}");
+                    Vec::new()
+                }
+            };
+            self.overlay.set_shader_diagnostics(shader, diagnostics);
+        }
+    }
+
+    /// Blocks until every frame in flight is done with buffers the host is about to rewrite.
+    fn wait_for_frames(&self) -> Result<(), vk::Result> {
+        self.frame_sync.wait(&self.context.device)
+    }
+
+    /// Keeps `particle_system` alive until the frames that may still use it have finished.
+    fn retire_system(&mut self, particle_system: ParticleSystem) {
+        self.retired_systems[self.frame_sync.previous_frame()].push(particle_system);
+    }
+
+    /// Gives the settings overlay, in the main window, the first look at that
+    /// window's events. Returns whether it consumed the event, which then must
+    /// not reach `handle_event`.
+    pub fn overlay_consumes(&mut self, window_id: WindowId, event: &WindowEvent) -> bool {
+        window_id == self.window.id() && self.overlay.on_window_event(&self.window, event)
+    }
+
+    /// Runs the settings overlay for this frame and applies what it changed.
+    /// The current frame's resources must be free.
+    #[profiling::function]
+    fn update_overlay(&mut self) -> Result<(), VulkanDemoError> {
+        let before = Settings {
+            params: self.particle_systems[self.selected_system].params,
+            particle_count: self.particle_systems[self.selected_system].count,
+            system: self.selected_system,
+            system_count: self.particle_systems.len(),
+            present_mode: self.renderer.present_mode,
+            clear_color: self.renderer.background.clear_color,
+            background: self.renderer.background.mode,
+            trails: self.renderer.trails,
+            trail_strength: self.renderer.trail_strength,
+            bloom: self.renderer.bloom.enabled,
+            bloom_threshold: self.renderer.bloom.threshold,
+            bloom_intensity: self.renderer.bloom.intensity,
+        };
+        let mut settings = before;
+        self.overlay.update(&self.context, &self.window, self.renderer.extent, &mut settings, self.speed_histogram.latest())?;
+
+        if bytemuck::bytes_of(&settings.params) != bytemuck::bytes_of(&before.params) {
+            self.selected_system_mut().update_params(&settings.params)?;
+        }
+        // These only change push constants, clear values or the render pass begun,
+        // so they apply without any rebuild.
+        self.renderer.background.clear_color = settings.clear_color;
+        self.renderer.background.mode = settings.background;
+        self.renderer.trails = settings.trails;
+        self.renderer.set_trail_strength(settings.trail_strength);
+        self.renderer.bloom.enabled = settings.bloom;
+        self.renderer.bloom.threshold = settings.bloom_threshold;
+        self.renderer.bloom.intensity = settings.bloom_intensity;
+        if settings.particle_count != before.particle_count {
+            self.set_particle_count(settings.particle_count)?;
+        }
+        if settings.present_mode != before.present_mode {
+            self.renderer.set_present_mode(&self.context, settings.present_mode)?;
+            self.frame_sync.resize(&self.context.device, self.renderer.images.len())?;
+            println!("Present mode: {:?} (using {:?})", settings.present_mode, self.renderer.active_present_mode);
+        }
+        Ok(())
+    }
+
+    /// Resizes the selected system to `count` particles, keeping as many of
+    /// the current ones as fit. Its old buffers are retired like a removed system.
+    fn set_particle_count(&mut self, count: u32) -> Result<(), VulkanDemoError> {
+        if count == self.selected_system_mut().count {
+            return Ok(());
+        }
+        self.wait_for_frames()?;
+        let particle_system = &mut self.particle_systems[self.selected_system];
+        // Too many for the memory budget keeps the current particles.
+        let old = match particle_system.resize(&self.context, &self.sim_pipelines, count) {
+            Err(e @ VulkanDemoError::InsufficientMemory { .. }) => {
+                log::error!("Can't resize to {count} particles: {e}");
+                return Ok(());
+            }
+            result => result?,
+        };
+        self.retire_system(old);
+        println!("Particle count: {count}");
+        Ok(())
+    }
+
+    /// Adds a system alongside the others, with the next emitter preset and a
+    /// static color of its own, and selects it.
+    fn add_system(&mut self) -> Result<(), VulkanDemoError> {
+        let selected = &self.particle_systems[self.selected_system];
+        let (count, three_d, backend, depth_sorted) = (selected.count, selected.is_3d(), selected.backend, selected.is_depth_sorted());
+        let kind_weights = selected.emitter.kind_weights;
+        self.emitter_preset = self.emitter_preset.next();
+        let emitter = EmitterConfig { kind_weights, ..self.emitter_preset.config() };
+        // Derived from the first system's seed, so a seeded run stays reproducible.
+        let seed = self.particle_systems[0].seed.wrapping_add(self.particle_systems.len() as u64);
+        let mut particle_system =
+            ParticleSystem::new(&self.context, &self.sim_pipelines, count, seed, &emitter, three_d, backend)?;
+        particle_system.set_depth_sort(&self.context, depth_sorted)?;
+        let params = particle_system.params;
+        particle_system.update_params(&SimParams {
+            color_mode: ColorMode::Static as u32,
+            base_color: SYSTEM_COLORS[self.particle_systems.len() % SYSTEM_COLORS.len()],
+            ..params
+        })?;
+        self.particle_systems.push(particle_system);
+        self.selected_system = self.particle_systems.len() - 1;
+        println!("Added system {} ({:?})", self.particle_systems.len(), self.emitter_preset);
+        Ok(())
+    }
+
+    /// Removes the selected system, unless it is the last one. The frame in
+    /// flight may still use it, so it is only dropped after the next frame wait;
+    /// one still loading waits for its upload first.
+    fn remove_system(&mut self) -> Result<(), VulkanDemoError> {
+        if self.particle_systems.len() == 1 {
+            return Ok(());
+        }
+        let removed = self.particle_systems.remove(self.selected_system);
+        removed.finish_upload(&self.context)?;
+        self.retire_system(removed);
+        self.selected_system = self.selected_system.min(self.particle_systems.len() - 1);
+        println!("Removed a system; {} left", self.particle_systems.len());
+        Ok(())
+    }
+
+    /// Whether the window can't be seen, so there is no point rendering. The
+    /// event loop stops requesting redraws meanwhile.
+    pub fn is_idle(&self) -> bool {
+        self.minimized || self.occluded || self.suspended
+    }
+
+    /// How often the event loop should redraw: as often as it can, unless the
+    /// window can't be seen or is unfocused with `--background-fps`.
+    pub fn pacing(&self) -> FramePacing {
+        if self.is_idle() {
+            return FramePacing::Idle;
+        }
+        match self.background_fps.filter(|_| !self.any_focused()) {
+            Some(fps) => FramePacing::Throttled { next_frame: self.last_frame + Duration::from_secs_f32(1.0 / fps) },
+            None => FramePacing::Continuous,
+        }
+    }
+
+    /// Whether either window has the focus.
+    fn any_focused(&self) -> bool {
+        self.focused || self.second_view.as_ref().is_some_and(|view| view.focused)
+    }
+
+    /// Covered, or unfocused with redraws capped.
+    fn in_background(&self) -> bool {
+        self.occluded || (!self.any_focused() && self.background_fps.is_some())
+    }
+
+    /// Records whether the window is covered and focused.
+    fn set_background(&mut self, occluded: bool, focused: bool) {
+        let was_background = self.in_background();
+        self.occluded = occluded;
+        self.focused = focused;
+        self.background_changed(was_background);
+    }
+
+    /// With `BackgroundSim::Freeze`, coming back skips the time spent away.
+    fn background_changed(&mut self, was_background: bool) {
+        if was_background && !self.in_background() && self.background_sim == BackgroundSim::Freeze {
+            self.last_frame = Instant::now();
+        }
+    }
+
+    /// Gives up the surface, and the swapchain with it, when the platform
+    /// takes the window away; `resume` makes them again. A second window is
+    /// closed, as the platforms that suspend don't have more than one.
+    pub fn suspend(&mut self) -> Result<(), VulkanDemoError> {
+        if self.suspended {
+            return Ok(());
+        }
+        self.renderer.release_swapchain(&self.context)?;
+        if self.second_view.take().is_some() {
+            log::warn!("Closing the second window while suspended");
+        }
+        self.context.destroy_surface();
+        self.suspended = true;
+        self.swapchain_stale = true;
+        Ok(())
+    }
+
+    /// Makes a new surface for the window after `suspend`. Winit also resumes
+    /// once at startup, when the surface made with the context is kept.
+    pub fn resume(&mut self) -> Result<(), VulkanDemoError> {
+        if !self.suspended {
+            return Ok(());
+        }
+        self.context.recreate_surface(&self.window)?;
+        self.renderer.set_surface(self.context.surface);
+        self.suspended = false;
+        self.recreate_swapchain()
+    }
+
+    /// Replaces a lost surface, and the swapchain with it, with new ones for
+    /// the same window, as after a compositor restart.
+    fn recreate_surface(&mut self) -> Result<(), VulkanDemoError> {
+        log::warn!("Window surface lost; recreating it");
+        self.renderer.release_swapchain(&self.context)?;
+        self.context.recreate_surface(&self.window)?;
+        self.renderer.set_surface(self.context.surface);
+        self.recreate_swapchain()
+    }
+
+    fn recreate_swapchain(&mut self) -> Result<(), VulkanDemoError> {
+        if self.suspended {
+            return Ok(());
+        }
+        let size = self.window.inner_size();
+        self.minimized = size.width == 0 || size.height == 0;
+        if self.minimized || !self.renderer.recreate(&self.context, size.width, size.height)? {
+            self.swapchain_stale = true;
+            return Ok(());
+        }
+        self.swapchain_stale = false;
+        self.frame_sync.resize(&self.context.device, self.renderer.images.len())?;
+        Ok(())
+    }
+
+    /// Renders a frame, then recovers from whatever kept it off the screen.
+    fn redraw(&mut self) -> Result<(), VulkanDemoError> {
+        let outcome = FrameOutcome::classify(self.render_frame());
+        profiling::finish_frame!();
+        match outcome? {
+            FrameOutcome::Rendered | FrameOutcome::Skipped => Ok(()),
+            FrameOutcome::SwapchainOutOfDate => self.recreate_swapchain(),
+            FrameOutcome::SurfaceLost => self.recreate_surface(),
+        }
+    }
+
+    /// Records, submits and presents one frame. Errors the next frame can
+    /// recover from are left for `FrameOutcome::classify` to sort out.
+    #[profiling::function]
+    pub fn render_frame(&mut self) -> Result<FrameOutcome, VulkanDemoError> {
+        if self.is_idle() {
+            return Ok(FrameOutcome::Skipped);
+        }
+        if self.swapchain_stale {
+            self.recreate_swapchain()?;
+            if self.swapchain_stale {
+                return Ok(FrameOutcome::Skipped);
+            }
+        }
+        if let Some(watcher) = &self.shader_watcher {
+            let changed = watcher.changed_files();
+            if !changed.is_empty() {
+                self.reload_shaders(&changed);
+            }
+        }
+        self.poll_uploads()?;
+
+        let device = &self.context.device;
+        let frame = {
+            profiling::scope!("wait for frame");
+            self.frame_sync.begin_frame(device)?
+        };
+        self.retired_systems[frame].clear();
+        self.renderer.set_frame_in_flight(frame);
+        if let Some(view) = &mut self.second_view {
+            view.renderer.set_frame_in_flight(frame);
+        }
+        self.overlay.begin_frame(device, frame);
+
+        self.gpu_timer.begin_frame(device, frame)?;
+        if let Some(timings) = self.gpu_timer.average() {
+            self.stats.set_gpu_timings(timings);
+        }
+        self.speed_histogram.begin_frame(frame)?;
+        if let Some(secondary_commands) = &mut self.secondary_commands {
+            secondary_commands.begin_frame(frame)?;
+        }
+        if let Some(speeds) = self.speed_histogram.latest() {
+            self.stats.set_speeds(speeds.mean(), speeds.max);
+        }
+
+        let now = Instant::now();
+        self.stats.record_frame(now - self.last_frame);
+
+        self.update_overlay()?;
+        // Parameters changed since this frame's copies were last written are
+        // written now that the GPU is done with them.
+        for particle_system in &mut self.particle_systems {
+            particle_system.set_frame_in_flight(frame)?;
+        }
+        if let Some(report) = self.stats.report() {
+            // Read back when this frame was last recorded, which `begin_frame` waited for.
+            let live_count = self.particle_systems.iter().map(ParticleSystem::live_count).sum::<Result<u32, _>>()?;
+            self.window.set_title(self.stats.title(WINDOW_TITLE, live_count, &report));
+            if self.particle_systems.iter().any(|particle_system| particle_system.backend == SimBackend::Verify) {
+                // Compared against the latest step, so the other frames in flight have to finish too.
+                self.wait_for_frames()?;
+                for (index, particle_system) in self.particle_systems.iter().enumerate() {
+                    if let Some(divergence) = particle_system.cpu_divergence(&self.context)? {
+                        println!("System {}: {divergence}", index + 1);
+                    }
+                }
+            }
+        }
+        self.renderer.background.animate((now - self.start_time).as_secs_f32());
+        if let Some(view) = &mut self.second_view {
+            view.renderer.background.animate((now - self.start_time).as_secs_f32());
+        }
+
+        let frame_dt = self.fixed_dt.unwrap_or_else(|| (now - self.last_frame).as_secs_f32().min(MAX_FRAME_DT));
+        self.last_frame = now;
+        let frozen = self.background_sim == BackgroundSim::Freeze && self.in_background();
+        let dt = if !self.paused && !frozen {
+            Some(frame_dt)
+        } else if std::mem::take(&mut self.step_requested) {
+            Some(STEP_DT)
+        } else {
+            None
+        };
+
+        let attractor_strength = match (self.attract_held, self.repel_held) {
+            (true, false) => ATTRACTOR_STRENGTH,
+            (false, true) => -ATTRACTOR_STRENGTH,
+            _ => 0.0,
+        };
+        let template = SimPushConstants {
+            attractor: cursor_to_world(self.cursor_position, self.window.inner_size()),
+            attractor_strength,
+            attractor_active: (attractor_strength != 0.0) as u32,
+            ..SimPushConstants::default()
+        };
+        self.clock.limit_step_dt(self.particle_systems.iter().filter_map(ParticleSystem::max_step_dt).reduce(f32::min));
+        let substeps = dt.map_or_else(Vec::new, |dt| self.clock.substeps(dt, template));
+
+        // CPU steps are uploaded into buffers earlier frames may still be drawing.
+        let any_on_cpu = self.particle_systems.iter().any(ParticleSystem::simulated_on_cpu);
+        if any_on_cpu && !substeps.is_empty() {
+            self.wait_for_frames()?;
+        }
+        for particle_system in &mut self.particle_systems {
+            profiling::scope!("cpu step");
+            particle_system.step_on_cpu(&substeps)?;
+        }
+
+        // With a separate compute queue the steps are submitted before acquiring,
+        // so they run while the previous frame is still being presented. CPU steps
+        // are recorded with the frame, so any of them keeps everything inline.
+        let step = if substeps.is_empty() {
+            SimStep::Skip
+        } else if self.frame_sync.compute.is_some() && !any_on_cpu {
+            self.submit_compute(&substeps)?;
+            SimStep::Async(substeps.len())
+        } else {
+            SimStep::Inline(&substeps)
+        };
+
+        let acquired = unsafe {
+            profiling::scope!("acquire");
+            self.renderer.swapchain_loader.acquire_next_image(
+                self.renderer.swapchain(),
+                u64::MAX,
+                self.frame_sync.frame().image_available.handle(),
+                vk::Fence::null(),
+            )
+        };
+        let image_index = match acquired {
+            Ok((image_index, _)) => image_index,
+            Err(e @ (vk::Result::ERROR_OUT_OF_DATE_KHR | vk::Result::ERROR_SURFACE_LOST_KHR)) => {
+                if let SimStep::Async(_) = step {
+                    self.drain_compute(&substeps)?;
+                }
+                return FrameOutcome::classify(Err(e.into()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // Everything per image is sized by the images the swapchain reported
+        // having; an index past them would be a driver bug, caught here rather
+        // than as an out-of-bounds access somewhere in recording.
+        assert!(
+            (image_index as usize) < self.renderer.images.len().min(self.frame_sync.render_finished.len()),
+            "acquired swapchain image {image_index}, but only {} images and {} render-finished semaphores exist",
+            self.renderer.images.len(),
+            self.frame_sync.render_finished.len(),
+        );
+        // Only once the main window has an image, so an acquired one is always drawn and presented.
+        let view_image_index = match &mut self.second_view {
+            Some(view) => view.acquire(&self.context, frame)?,
+            None => None,
+        };
+
+        let cmd = self.frame_sync.frame().command_buffer;
+        let record_start = Instant::now();
+        self.record_commands(cmd, image_index, view_image_index, &step)?;
+        self.stats.add_record_time(record_start.elapsed());
+
+        let view_image = self
+            .second_view
+            .as_mut()
+            .zip(view_image_index)
+            .map(|(view, view_image_index)| (view_image_index, view.sync.signal(frame, view_image_index)));
+        let submit = GraphicsSubmit {
+            command_buffer: Some(cmd),
+            image_index: Some(image_index),
+            after_compute: matches!(step, SimStep::Async(_)),
+            view_image: view_image.map(|(_, view_image)| view_image),
+        };
+        {
+            profiling::scope!("submit");
+            self.frame_sync.submit_graphics(&self.context.device, self.context.graphics_queue, submit)?;
+        }
+        self.frame_sync.end_frame();
+        self.advance(&substeps);
+        // Frames older than those in flight finished before this frame's wait;
+        // read them back while the GPU works on the rest.
+        if let Some(recorder) = &mut self.recorder {
+            recorder.collect(FRAMES_IN_FLIGHT)?;
+        }
+        // The image still belongs to us until it's presented.
+        if std::mem::take(&mut self.screenshot_requested) {
+            self.save_screenshot(image_index);
+        }
+
+        if let (Some(view), Some((view_image_index, view_image))) = (&mut self.second_view, view_image) {
+            view.present(&self.context, view_image_index, view_image.render_finished)?;
+        }
+
+        // The present queue may be of another family; the semaphore orders it
+        // after rendering either way.
+        let wait_semaphores = [self.frame_sync.render_finished[image_index as usize].handle()];
+        let swapchains = [self.renderer.swapchain()];
+        let image_indices = [image_index];
+        let present_info = vk::PresentInfoKHR::default()
+            .wait_semaphores(&wait_semaphores)
+            .swapchains(&swapchains)
+            .image_indices(&image_indices);
+
+        let present_result = {
+            profiling::scope!("present");
+            unsafe { self.renderer.swapchain_loader.queue_present(self.context.present_queue, &present_info) }
+        };
+        self.frame_sync.presented(image_index);
+        match present_result {
+            Ok(false) => Ok(FrameOutcome::Rendered),
+            Ok(true) => Ok(FrameOutcome::SwapchainOutOfDate),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Prints `error` before the app exits, along with what's known about the
+    /// GPU if it was lost.
+    pub fn report_error(&self, error: &VulkanDemoError) {
+        eprintln!("Error: {error}");
+        if matches!(error, VulkanDemoError::Vk(vk::Result::ERROR_DEVICE_LOST)) {
+            eprintln!(
+                "The GPU was lost after {:.1} s of simulation: {}",
+                self.clock.sim_time,
+                self.context.device_description()
+            );
+            eprintln!("This is usually a driver reset after a hang; rerun with VALIDATION=1 VALIDATION_SYNC=1 to look for the cause.");
+        }
+    }
+
+    /// Writes swapchain image `image_index` to a timestamped PNG. Failures are
+    /// logged rather than stopping the demo.
+    fn save_screenshot(&self, image_index: u32) {
+        let path = screenshot_path();
+        let result = self
+            .renderer
+            .read_pixels(&self.context, image_index)
+            .and_then(|pixels| write_png(&path, self.renderer.extent, &pixels));
+        match result {
+            Ok(()) => println!("Saved {}", path.display()),
+            Err(e) => log::error!("Screenshot failed: {e}"),
+        }
+    }
+
+    /// Writes the selected system's particles to `snapshot_path`. Failures are
+    /// logged rather than stopping the demo.
+    fn save_snapshot(&self) {
+        let result = self.particle_systems[self.selected_system]
+            .snapshot(&self.context)
+            .and_then(|particles| write_snapshot(&self.snapshot_path, &particles));
+        match result {
+            Ok(()) => println!("Saved {}", self.snapshot_path.display()),
+            Err(e) => log::error!("Snapshot failed: {e}"),
+        }
+    }
+
+    /// Writes every system and the renderer settings to `scene_path`. Failures
+    /// are logged rather than stopping the demo.
+    fn save_scene(&self) {
+        match ScenePreset::capture(&self.particle_systems, &self.renderer).save(&self.scene_path) {
+            Ok(()) => println!("Saved {}", self.scene_path.display()),
+            Err(e) => log::error!("Saving the scene failed: {e}"),
+        }
+    }
+
+    /// Replaces the selected system's particles with those in `snapshot_path`.
+    /// A missing or incompatible file is logged and changes nothing.
+    fn load_snapshot(&mut self) -> Result<(), VulkanDemoError> {
+        let particles = match read_snapshot(&self.snapshot_path) {
+            Ok(particles) => particles,
+            Err(e) => {
+                log::error!("{e}");
+                return Ok(());
+            }
+        };
+        let particle_system = &mut self.particle_systems[self.selected_system];
+        if let Some(old) = particle_system.restore(&self.context, &self.sim_pipelines, &particles)? {
+            self.retire_system(old);
+        }
+        println!("Loaded {} ({} particles)", self.snapshot_path.display(), particles.len());
+        Ok(())
+    }
+
+    /// Streams on the uploads in progress, and has this frame's first submission
+    /// wait for those that finished. Systems join the frame once theirs has.
+    fn poll_uploads(&mut self) -> Result<(), VulkanDemoError> {
+        profiling::scope!("uploads");
+        self.context.uploads().poll()?;
+        for particle_system in &self.particle_systems {
+            if let Some(dependency) = particle_system.poll_upload(&self.context) {
+                self.frame_sync.wait_for_upload(&dependency);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the simulation steps into the compute command buffer and submits
+    /// them on the compute queue.
+    #[profiling::function]
+    fn submit_compute(&mut self, substeps: &[SimPushConstants]) -> Result<(), vk::Result> {
+        let Some(cmd) = self.frame_sync.frame().compute_command_buffer else {
+            return Ok(());
+        };
+        let device = &self.context.device;
+        unsafe {
+            device.reset_command_buffer(cmd, vk::CommandBufferResetFlags::empty())?;
+            device.begin_command_buffer(cmd, &vk::CommandBufferBeginInfo::default())?;
+            record_compute_pass(
+                device,
+                cmd,
+                &self.sim_pipelines,
+                &self.particle_systems,
+                &mut self.gpu_timer,
+                &self.context.debug,
+                substeps,
+            );
+            device.end_command_buffer(cmd)?;
+        }
+        self.frame_sync.submit_compute(device, self.context.compute_queue)
+    }
+
+    /// Finishes compute steps whose frame could not be drawn, consuming their
+    /// semaphore if it is binary so it is not signaled twice. The steps' result is kept.
+    fn drain_compute(&mut self, substeps: &[SimPushConstants]) -> Result<(), vk::Result> {
+        if self.frame_sync.needs_compute_drain() {
+            let submit = GraphicsSubmit { after_compute: true, ..GraphicsSubmit::default() };
+            self.frame_sync.submit_graphics(&self.context.device, self.context.graphics_queue, submit)?;
+        }
+        self.advance(substeps);
+        Ok(())
+    }
+
+    /// Moves on past submitted `substeps`.
+    fn advance(&mut self, substeps: &[SimPushConstants]) {
+        for particle_system in &mut self.particle_systems {
+            particle_system.advance(substeps.len());
+        }
+        self.clock.advance(substeps);
+    }
+
+    /// Records the frame, drawing the second window into `view_image_index` too if it has one.
+    #[profiling::function]
+    fn record_commands(
+        &mut self,
+        cmd: vk::CommandBuffer,
+        image_index: u32,
+        view_image_index: Option<u32>,
+        step: &SimStep,
+    ) -> Result<(), vk::Result> {
+        // Written every frame, as each frame in flight has its own copy. Recomputed
+        // from the current extent, so resizes keep the aspect ratio.
+        let three_d = self.is_3d();
+        let view_projection = |aspect_ratio| {
+            if three_d {
+                self.camera.view_projection(aspect_ratio)
+            } else {
+                world_projection(aspect_ratio)
+            }
+        };
+        self.renderer.update_camera(view_projection(self.renderer.aspect_ratio()))?;
+        if let Some(view) = &self.second_view {
+            view.renderer.update_camera(view_projection(view.renderer.aspect_ratio()))?;
+        }
+        let focal_point = if self.is_3d() { self.camera.eye().to_array() } else { [0.0; 3] };
+        record_frame(
+            &self.context.device,
+            cmd,
+            &self.sim_pipelines,
+            &self.particle_systems,
+            &mut self.renderer,
+            &mut self.gpu_timer,
+            image_index,
+            step,
+            FrameExtras {
+                overlay: Some(&self.overlay),
+                recorder: self.recorder.as_mut(),
+                focal_point,
+                speed_histogram: Some((&mut self.speed_histogram, self.selected_system)),
+                secondary_commands: self.secondary_commands.as_ref(),
+                second_view: self.second_view.as_mut().zip(view_image_index).map(|(view, index)| (&mut view.renderer, index)),
+            },
+        )
+    }
+
+    /// Whether the app has done what it was started for and should exit:
+    /// a `--record` run that has captured all its frames.
+    pub fn finished(&self) -> bool {
+        self.recorder.as_ref().is_some_and(FrameRecorder::is_done)
+    }
+
+    /// Waits for the GPU to go idle so the Vulkan objects can be dropped safely.
+    pub fn shutdown(&mut self) {
+        if !self.running {
+            return;
+        }
+        self.running = false;
+
+        unsafe {
+            if let Err(e) = self.context.device.device_wait_idle() {
+                log::error!("device_wait_idle failed during shutdown: {e}");
+            }
+        }
+        if self.dump_scene_on_exit {
+            self.save_scene();
+        }
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.finish() {
+                log::error!("Recording failed: {e}");
+            }
+        }
+    }
+}
+
+impl Drop for App {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// The window `--second-window` opens, drawing the same systems with a renderer,
+/// surface and swapchain of its own. It is drawn by the main window's frames,
+/// after the main view in the same command buffer, so both show the same step
+/// and the frame graph orders its draws after the simulation like any other
+/// pass. While the main window can't be seen, neither view is updated.
+///
+/// Fields drop in declaration order: the swapchain before its surface, and the window last.
+struct SecondView {
+    renderer: Renderer,
+    sync: ViewSync,
+    surface: OwnedSurface,
+    window: Window,
+    minimized: bool,
+    occluded: bool,
+    focused: bool,
+    /// The swapchain must be recreated before the view is drawn again.
+    swapchain_stale: bool,
+    /// The surface must be replaced first.
+    surface_lost: bool,
+}
+
+impl SecondView {
+    /// Starts out drawing like the main window, as the scene sets it up.
+    fn new(
+        context: &VulkanContext,
+        window: Window,
+        config: &AppConfig,
+        scene: &ScenePreset,
+        particle_format: ParticleFormat,
+    ) -> Result<Self, VulkanDemoError> {
+        let size = window.inner_size();
+        let surface = context.window_surface(&window)?;
+        let mut renderer = Renderer::new(
+            context,
+            surface.handle(),
+            size.width,
+            size.height,
+            scene.renderer.present_mode,
+            config.swapchain_images,
+            scene.renderer.blend_mode,
+        )?;
+        scene.renderer.apply(&mut renderer);
+        if let Some(path) = &config.sprite {
+            renderer.set_sprite(&context.device, Texture::load_png(context, path)?);
+        }
+        renderer.set_render_scale(context, config.render_scale)?;
+        renderer.set_particle_format(&context.device, particle_format)?;
+        let sync = ViewSync::new(&context.device, renderer.images.len())?;
+        Ok(Self {
+            renderer,
+            sync,
+            surface,
+            window,
+            minimized: false,
+            occluded: false,
+            focused: false,
+            swapchain_stale: false,
+            surface_lost: false,
+        })
+    }
+
+    /// Rebuilds the swapchain at the window's size, replacing a lost surface first.
+    fn recreate_swapchain(&mut self, context: &VulkanContext) -> Result<(), VulkanDemoError> {
+        if self.surface_lost {
+            log::warn!("Second window surface lost; recreating it");
+            self.renderer.release_swapchain(context)?;
+            self.surface = context.window_surface(&self.window)?;
+            self.renderer.set_surface(self.surface.handle());
+            self.surface_lost = false;
+        }
+        let size = self.window.inner_size();
+        self.minimized = size.width == 0 || size.height == 0;
+        self.swapchain_stale = self.minimized || !self.renderer.recreate(context, size.width, size.height)?;
+        if !self.swapchain_stale {
+            self.sync.resize(&context.device, self.renderer.images.len())?;
+        }
+        Ok(())
+    }
+
+    /// Acquires the image frame in flight `frame` draws the view into, or
+    /// `None` when there's nothing to draw into this frame. A minimized
+    /// window leaves the swapchain stale until it has an area again.
+    fn acquire(&mut self, context: &VulkanContext, frame: usize) -> Result<Option<u32>, VulkanDemoError> {
+        if self.occluded {
+            return Ok(None);
+        }
+        if self.swapchain_stale || self.surface_lost {
+            self.recreate_swapchain(context)?;
+            if self.swapchain_stale {
+                return Ok(None);
+            }
+        }
+        let acquired = unsafe {
+            self.renderer.swapchain_loader.acquire_next_image(
+                self.renderer.swapchain(),
+                u64::MAX,
+                self.sync.image_available(frame),
+                vk::Fence::null(),
+            )
+        };
+        match acquired {
+            Ok((image_index, _)) => Ok(Some(image_index)),
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                self.swapchain_stale = true;
+                Ok(None)
+            }
+            Err(vk::Result::ERROR_SURFACE_LOST_KHR) => {
+                self.surface_lost = true;
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Presents `image_index` once `render_finished` signals. A swapchain or
+    /// surface that stopped working is left for the next `acquire` to replace.
+    fn present(&mut self, context: &VulkanContext, image_index: u32, render_finished: vk::Semaphore) -> Result<(), VulkanDemoError> {
+        let wait_semaphores = [render_finished];
+        let swapchains = [self.renderer.swapchain()];
+        let image_indices = [image_index];
+        let present_info = vk::PresentInfoKHR::default()
+            .wait_semaphores(&wait_semaphores)
+            .swapchains(&swapchains)
+            .image_indices(&image_indices);
+        let result = unsafe { self.renderer.swapchain_loader.queue_present(context.present_queue, &present_info) };
+        self.sync.presented(image_index);
+        match result {
+            Ok(false) => (),
+            Ok(true) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => self.swapchain_stale = true,
+            Err(vk::Result::ERROR_SURFACE_LOST_KHR) => self.surface_lost = true,
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+}
+
+/// How rendering a frame ended, short of an error the app can't recover from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameOutcome {
+    /// Submitted and presented.
+    Rendered,
+    /// Nothing to draw into: the window is minimized or hidden, or its
+    /// swapchain can't be created at the current size.
+    Skipped,
+    /// The swapchain no longer matches the surface and must be recreated.
+    SwapchainOutOfDate,
+    /// The window surface is gone, and with it the swapchain.
+    SurfaceLost,
+}
+
+impl FrameOutcome {
+    /// Turns the errors a frame can end with without the device being at
+    /// fault into the outcome they stand for. Any other error is kept.
+    /// `SUBOPTIMAL_KHR` is a success code, but counts as out of date should
+    /// it ever surface as an error.
+    pub fn classify(result: Result<Self, VulkanDemoError>) -> Result<Self, VulkanDemoError> {
+        match result {
+            Err(VulkanDemoError::Vk(vk::Result::ERROR_OUT_OF_DATE_KHR | vk::Result::SUBOPTIMAL_KHR)) => Ok(Self::SwapchainOutOfDate),
+            Err(VulkanDemoError::Vk(vk::Result::ERROR_SURFACE_LOST_KHR)) => Ok(Self::SurfaceLost),
+            result => result,
+        }
+    }
+}
+
+/// Where the simulation steps drawn by a frame run.
+pub(crate) enum SimStep<'a> {
+    /// No step; the current state is drawn again.
+    Skip,
+    /// Recorded into the frame's own command buffer ahead of the draw.
+    Inline(&'a [SimPushConstants]),
+    /// This many steps already submitted on the async compute queue; the
+    /// graphics submission waits on its semaphore.
+    Async(usize),
+}
+
+impl SimStep<'_> {
+    /// How many steps past the current state the frame draws.
+    fn steps(&self) -> usize {
+        match self {
+            SimStep::Skip => 0,
+            SimStep::Inline(substeps) => substeps.len(),
+            SimStep::Async(steps) => *steps,
+        }
+    }
+}
+
+/// Records the compute dispatches for `substeps` of every loaded system not
+/// simulated on the CPU, each step's dispatches behind a barrier on the previous one's writes.
+pub(crate) fn record_compute_pass(
+    device: &ash::Device,
+    cmd: vk::CommandBuffer,
+    pipelines: &SimPipelines,
+    particle_systems: &[ParticleSystem],
+    gpu_timer: &mut GpuTimer,
+    debug: &DebugUtils,
+    substeps: &[SimPushConstants],
+) {
+    unsafe {
+        debug.cmd_begin_label(cmd, "simulation", COMPUTE_LABEL_COLOR);
+        gpu_timer.begin_compute(device, cmd);
+        for (substep, push_constants) in substeps.iter().enumerate() {
+            // The previous step's writes to this step's input, in submission order on this queue.
+            let barrier = vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ);
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
+            // Systems share no buffers, so their dispatches need no barriers between them.
+            let gpu_systems = particle_systems.iter().filter(|particle_system| !particle_system.simulated_on_cpu());
+            for particle_system in gpu_systems.filter(|particle_system| !particle_system.is_loading()) {
+                record_sim_dispatch(device, cmd, pipelines, particle_system, substep, push_constants);
+            }
+        }
+        gpu_timer.end_compute(device, cmd);
+        debug.cmd_end_label(cmd);
+    }
+}
+
+/// Records the dispatches of step `substep` of one system: its neighbor grid
+/// when the mode uses one, then the step itself.
+pub(crate) fn record_sim_dispatch(
+    device: &ash::Device,
+    cmd: vk::CommandBuffer,
+    pipelines: &SimPipelines,
+    particle_system: &ParticleSystem,
+    substep: usize,
+    push_constants: &SimPushConstants,
+) {
+    particle_system.record_prepass(device, cmd, &pipelines.scan, substep);
+    particle_system.record_update(device, cmd, pipelines, substep, push_constants);
+}
+
+/// Optional work recorded into a frame along with the particles.
+#[derive(Default)]
+pub(crate) struct FrameExtras<'a> {
+    /// Drawn over the particles in the overlay pass.
+    pub overlay: Option<&'a Overlay>,
+    /// Copies the finished image out for `--record`.
+    pub recorder: Option<&'a mut FrameRecorder>,
+    /// What depth-sorted systems are sorted far to near from: the camera's eye
+    /// in 3D, the middle of the world in 2D.
+    pub focal_point: [f32; 3],
+    /// Bins the speeds of the system at the index once it is drawn.
+    pub speed_histogram: Option<(&'a mut SpeedHistogram, usize)>,
+    /// Records the scene pass's background and draws on its threads when
+    /// there's more than one system; otherwise they're recorded inline.
+    pub secondary_commands: Option<&'a SecondaryCommands>,
+    /// Draws every system again with the second window's renderer, into its
+    /// swapchain image at the index.
+    pub second_view: Option<(&'a mut Renderer, u32)>,
+}
+
+/// Records drawing every loaded system's particles into the scene image of `renderer`
+/// and, unless it is headless, copying that into swapchain image `image_index`.
+/// Preceded by the compute dispatches for `SimStep::Inline` steps, along with
+/// whatever `extras` asks for, each as a pass of the frame's `FrameGraph`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn record_frame(
+    device: &ash::Device,
+    cmd: vk::CommandBuffer,
+    pipelines: &SimPipelines,
+    particle_systems: &[ParticleSystem],
+    renderer: &mut Renderer,
+    gpu_timer: &mut GpuTimer,
+    image_index: u32,
+    step: &SimStep,
+    extras: FrameExtras,
+) -> Result<(), vk::Result> {
+    let steps = step.steps();
+    let mut graph = FrameGraph::new();
+    if let SimStep::Inline(substeps) = step {
+        graph.add_pass(SimulationPass { pipelines, particle_systems, substeps });
+    }
+    if particle_systems.iter().any(ParticleSystem::is_depth_sorted) {
+        graph.add_pass(DepthSortPass { particle_systems, steps, focal_point: extras.focal_point });
+    }
+    graph.add_pass(ScenePass { particle_systems, steps, secondary_commands: extras.secondary_commands });
+    if !renderer.is_headless() {
+        graph.add_pass(PresentPass { overlay: extras.overlay });
+    }
+    if let Some((renderer, image_index)) = extras.second_view {
+        graph.add_pass(ViewPass { renderer, image_index, particle_systems, steps });
+    }
+    if let Some((speed_histogram, index)) = extras.speed_histogram.filter(|&(_, index)| !particle_systems[index].is_loading()) {
+        graph.add_pass(speed_histogram.pass(&particle_systems[index], steps));
+    }
+    graph.add_pass(LiveCountPass { particle_systems });
+    if let Some(recorder) = extras.recorder {
+        graph.add_pass(recorder);
+    }
+
+    let mut frame = renderer.begin_frame(device, cmd, image_index, gpu_timer)?;
+    graph.record(&mut frame)?;
+    frame.end_frame()
+}
+
+/// The inline steps of every system, GPU-simulated or uploaded from the CPU.
+struct SimulationPass<'a> {
+    pipelines: &'a SimPipelines,
+    particle_systems: &'a [ParticleSystem],
+    substeps: &'a [SimPushConstants],
+}
+
+impl RecordPass for SimulationPass<'_> {
+    fn buffer_usages(&self) -> Vec<BufferUsage> {
+        let steps = self.substeps.len();
+        let write = |buffer| BufferUsage::new(buffer, vk::PipelineStageFlags2::COMPUTE_SHADER, vk::AccessFlags2::SHADER_STORAGE_WRITE);
+        self.particle_systems
+            .iter()
+            .filter(|particle_system| !particle_system.simulated_on_cpu() && !particle_system.is_loading())
+            .flat_map(|particle_system| [write(particle_system.buffer_after(steps)), write(particle_system.indirect_buffer())])
+            .collect()
+    }
+
+    fn record(&mut self, frame: &mut RenderFrame) -> Result<(), vk::Result> {
+        let (device, cmd) = (frame.device, frame.cmd);
+        unsafe {
+            // The steps overwrite what earlier frames in flight draw and read back.
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::DRAW_INDIRECT | vk::PipelineStageFlags::VERTEX_INPUT | vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[],
+            );
+        }
+        record_compute_pass(device, cmd, self.pipelines, self.particle_systems, frame.gpu_timer, &frame.renderer.debug, self.substeps);
+        for particle_system in self.particle_systems.iter().filter(|particle_system| particle_system.simulated_on_cpu()) {
+            particle_system.record_cpu_step(device, cmd);
+        }
+        Ok(())
+    }
+}
+
+/// Sorts the depth-sorted systems far to near from `focal_point`.
+struct DepthSortPass<'a> {
+    particle_systems: &'a [ParticleSystem],
+    steps: usize,
+    focal_point: [f32; 3],
+}
+
+impl RecordPass for DepthSortPass<'_> {
+    fn record(&mut self, frame: &mut RenderFrame) -> Result<(), vk::Result> {
+        let (device, cmd) = (frame.device, frame.cmd);
+        frame.renderer.debug.cmd_begin_label(cmd, "depth sort", COMPUTE_LABEL_COLOR);
+        frame.gpu_timer.begin_sort(device, cmd);
+        for particle_system in self.particle_systems.iter().filter(|particle_system| !particle_system.is_loading()) {
+            particle_system.record_depth_sort(device, cmd, self.steps, self.focal_point);
+        }
+        frame.gpu_timer.end_sort(device, cmd);
+        frame.renderer.debug.cmd_end_label(cmd);
+        Ok(())
+    }
+}
+
+/// Draws the background and every system into the scene image. The timer's
+/// graphics zone runs on through the `PresentPass`, if there is one.
+struct ScenePass<'a> {
+    particle_systems: &'a [ParticleSystem],
+    steps: usize,
+    secondary_commands: Option<&'a SecondaryCommands>,
+}
+
+impl RecordPass for ScenePass<'_> {
+    fn buffer_usages(&self) -> Vec<BufferUsage> {
+        draw_usages(self.particle_systems, self.steps)
+    }
+
+    fn record(&mut self, frame: &mut RenderFrame) -> Result<(), vk::Result> {
+        let (device, cmd) = (frame.device, frame.cmd);
+        frame.gpu_timer.begin_graphics(device, cmd);
+        frame.renderer.debug.cmd_begin_label(cmd, "particles", GRAPHICS_LABEL_COLOR);
+        // For async steps the semaphore wait makes the compute queue's writes visible to the draws.
+        let draws: Vec<_> = self
+            .particle_systems
+            .iter()
+            .filter(|particle_system| !particle_system.is_loading())
+            .map(|particle_system| particle_system.draw(self.steps))
+            .collect();
+        match self.secondary_commands.filter(|_| draws.len() > 1) {
+            Some(secondary_commands) => {
+                frame.begin_main_pass(vk::SubpassContents::SECONDARY_COMMAND_BUFFERS);
+                let scene = frame.renderer.scene_draw();
+                // The background first, then each system, in the order they're executed in.
+                let passes = std::iter::once(None)
+                    .chain(draws.iter().map(Some))
+                    .map(|draw| {
+                        move |cmd| match draw {
+                            None => scene.record_background(device, cmd),
+                            Some(draw) => {
+                                scene.bind_particles(device, cmd);
+                                draw.record(device, cmd);
+                            }
+                        }
+                    })
+                    .collect();
+                let secondaries = secondary_commands.record(frame.renderer.scene_inheritance(), passes)?;
+                unsafe { device.cmd_execute_commands(cmd, &secondaries) };
+            }
+            None => {
+                frame.begin_main_pass(vk::SubpassContents::INLINE);
+                let scene = frame.renderer.scene_draw();
+                scene.record_background(device, cmd);
+                scene.bind_particles(device, cmd);
+                for draw in &draws {
+                    draw.record(device, cmd);
+                }
+            }
+        }
+        frame.end_main_pass();
+        frame.renderer.debug.cmd_end_label(cmd);
+        if frame.renderer.is_headless() {
+            frame.gpu_timer.end_graphics(device, cmd);
+        }
+        Ok(())
+    }
+}
+
+/// What drawing every loaded system `steps` steps on reads.
+fn draw_usages(particle_systems: &[ParticleSystem], steps: usize) -> Vec<BufferUsage> {
+    particle_systems
+        .iter()
+        .filter(|particle_system| !particle_system.is_loading())
+        .flat_map(|particle_system| {
+            [
+                BufferUsage::new(
+                    particle_system.vertex_buffer(steps),
+                    vk::PipelineStageFlags2::VERTEX_ATTRIBUTE_INPUT,
+                    vk::AccessFlags2::VERTEX_ATTRIBUTE_READ,
+                ),
+                BufferUsage::new(
+                    particle_system.indirect_buffer(),
+                    vk::PipelineStageFlags2::DRAW_INDIRECT,
+                    vk::AccessFlags2::INDIRECT_COMMAND_READ,
+                ),
+            ]
+        })
+        .collect()
+}
+
+/// Blooms the scene and copies it into the swapchain image, with the overlay
+/// drawn over it.
+struct PresentPass<'a> {
+    overlay: Option<&'a Overlay>,
+}
+
+impl RecordPass for PresentPass<'_> {
+    fn record(&mut self, frame: &mut RenderFrame) -> Result<(), vk::Result> {
+        let (device, cmd, image_index) = (frame.device, frame.cmd, frame.image_index);
+        let renderer = &*frame.renderer;
+        if renderer.bloom.enabled {
+            renderer.debug.cmd_begin_label(cmd, "bloom", GRAPHICS_LABEL_COLOR);
+            frame.gpu_timer.begin_bloom(device, cmd);
+            renderer.record_bloom(device, cmd);
+            frame.gpu_timer.end_bloom(device, cmd);
+            renderer.debug.cmd_end_label(cmd);
+        }
+        renderer.debug.cmd_begin_label(cmd, "present", GRAPHICS_LABEL_COLOR);
+        renderer.begin_overlay_pass(device, cmd, image_index);
+        if let Some(overlay) = self.overlay {
+            overlay.record(device, cmd);
+        }
+        renderer.end_overlay_pass(device, cmd, image_index);
+        renderer.debug.cmd_end_label(cmd);
+        frame.gpu_timer.end_graphics(device, cmd);
+        Ok(())
+    }
+}
+
+/// Draws every system into the second window with its own renderer: the scene,
+/// bloom and the copy into its swapchain image, with no overlay or timing.
+struct ViewPass<'a> {
+    renderer: &'a mut Renderer,
+    image_index: u32,
+    particle_systems: &'a [ParticleSystem],
+    steps: usize,
+}
+
+impl RecordPass for ViewPass<'_> {
+    fn buffer_usages(&self) -> Vec<BufferUsage> {
+        draw_usages(self.particle_systems, self.steps)
+    }
+
+    fn record(&mut self, frame: &mut RenderFrame) -> Result<(), vk::Result> {
+        let (device, cmd) = (frame.device, frame.cmd);
+        let renderer = &mut *self.renderer;
+        renderer.debug.cmd_begin_label(cmd, "second window", GRAPHICS_LABEL_COLOR);
+        renderer.begin_scene_pass(device, cmd, vk::SubpassContents::INLINE);
+        let scene = renderer.scene_draw();
+        scene.record_background(device, cmd);
+        scene.bind_particles(device, cmd);
+        for particle_system in self.particle_systems.iter().filter(|particle_system| !particle_system.is_loading()) {
+            particle_system.draw(self.steps).record(device, cmd);
+        }
+        renderer.end_scene_pass(device, cmd);
+        if renderer.bloom.enabled {
+            renderer.record_bloom(device, cmd);
+        }
+        renderer.begin_overlay_pass(device, cmd, self.image_index);
+        renderer.end_overlay_pass(device, cmd, self.image_index);
+        renderer.debug.cmd_end_label(cmd);
+        Ok(())
+    }
+}
+
+/// Copies out every system's live count for `ParticleSystem::live_count`.
+struct LiveCountPass<'a> {
+    particle_systems: &'a [ParticleSystem],
+}
+
+impl RecordPass for LiveCountPass<'_> {
+    fn buffer_usages(&self) -> Vec<BufferUsage> {
+        self.particle_systems
+            .iter()
+            .filter(|particle_system| !particle_system.is_loading())
+            .map(|particle_system| {
+                BufferUsage::new(particle_system.indirect_buffer(), vk::PipelineStageFlags2::COPY, vk::AccessFlags2::TRANSFER_READ)
+            })
+            .collect()
+    }
+
+    fn record(&mut self, frame: &mut RenderFrame) -> Result<(), vk::Result> {
+        for particle_system in self.particle_systems.iter().filter(|particle_system| !particle_system.is_loading()) {
+            particle_system.record_live_count_readback(frame.device, frame.cmd);
+        }
+        Ok(())
+    }
+}
+
+/// Applies the keys that only change how `renderer` draws, which go to the
+/// renderer of the window they were pressed in. Returns whether `key` was one.
+fn handle_renderer_key(context: &VulkanContext, renderer: &mut Renderer, key: KeyCode) -> Result<bool, VulkanDemoError> {
+    match key {
+        KeyCode::KeyA => {
+            let blend_mode = match renderer.blend_mode {
+                BlendMode::Opaque => BlendMode::Alpha,
+                BlendMode::Alpha => BlendMode::Additive,
+                BlendMode::Additive => BlendMode::Opaque,
+            };
+            renderer.set_blend_mode(&context.device, blend_mode)?;
+            println!("Blend mode: {blend_mode:?}");
+        }
+        KeyCode::Equal | KeyCode::NumpadAdd | KeyCode::Minus | KeyCode::NumpadSubtract => {
+            let factor = if matches!(key, KeyCode::Equal | KeyCode::NumpadAdd) {
+                POINT_SIZE_SCALE_STEP
+            } else {
+                1.0 / POINT_SIZE_SCALE_STEP
+            };
+            renderer.set_point_size_scale(renderer.point_size_scale * factor);
+            println!("Point size scale: {:.2}", renderer.point_size_scale);
+        }
+        KeyCode::KeyT => {
+            renderer.trails = !renderer.trails;
+            println!("Trails: {}", if renderer.trails { "on" } else { "off" });
+        }
+        KeyCode::KeyL => {
+            renderer.bloom.enabled = !renderer.bloom.enabled;
+            println!("Bloom: {}", if renderer.bloom.enabled { "on" } else { "off" });
+        }
+        KeyCode::KeyP => {
+            renderer.point_shape = match renderer.point_shape {
+                PointShape::Disc => PointShape::Square,
+                PointShape::Square if renderer.has_sprite() => PointShape::Sprite,
+                PointShape::Square | PointShape::Sprite => PointShape::Disc,
+            };
+            println!("Point shape: {:?}", renderer.point_shape);
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Every shader `reload_shaders` rebuilds: each mode's and its prepasses',
+/// and the particle vertex and fragment shaders. Loaded from `--shader-dir`
+/// at startup.
+fn shader_files() -> BTreeSet<String> {
+    let mut files = BTreeSet::from(["particle.vert".to_string(), "particle.frag".to_string()]);
+    for &mode in SimulationMode::all() {
+        files.insert(mode.shader_file().to_string());
+        files.extend(mode.behavior().prepasses().iter().map(|&(file, _)| file.to_string()));
+    }
+    files
+}
+
+fn read_shader(dir: &Path, name: &str) -> Option<String> {
+    let path = dir.join(name);
+    std::fs::read_to_string(&path)
+        .map_err(|e| log::error!("Failed to read {}: {e}", path.display()))
+        .ok()
+}
+
+/// Maps a cursor position in window pixels to the world space particles live
+/// in, undoing the 2D view's letterboxing.
+fn cursor_to_world(position: PhysicalPosition<f64>, size: PhysicalSize<u32>) -> [f32; 2] {
+    let width = size.width.max(1) as f64;
+    let height = size.height.max(1) as f64;
+    let ndc = glam::Vec3::new((position.x / width * 2.0 - 1.0) as f32, (position.y / height * 2.0 - 1.0) as f32, 0.0);
+    let world = world_projection((width / height) as f32).inverse().transform_point3(ndc);
+    [world.x, world.y]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(result: vk::Result) -> Result<FrameOutcome, VulkanDemoError> {
+        FrameOutcome::classify(Err(result.into()))
+    }
+
+    #[test]
+    fn stale_swapchains_are_recreated() {
+        assert_eq!(classify(vk::Result::ERROR_OUT_OF_DATE_KHR).unwrap(), FrameOutcome::SwapchainOutOfDate);
+        assert_eq!(classify(vk::Result::SUBOPTIMAL_KHR).unwrap(), FrameOutcome::SwapchainOutOfDate);
+    }
+
+    #[test]
+    fn lost_surfaces_are_recreated() {
+        assert_eq!(classify(vk::Result::ERROR_SURFACE_LOST_KHR).unwrap(), FrameOutcome::SurfaceLost);
+    }
+
+    #[test]
+    fn fatal_errors_propagate() {
+        for fatal in [vk::Result::ERROR_DEVICE_LOST, vk::Result::ERROR_OUT_OF_DEVICE_MEMORY, vk::Result::ERROR_INITIALIZATION_FAILED] {
+            assert!(matches!(classify(fatal), Err(VulkanDemoError::Vk(result)) if result == fatal), "{fatal} was recovered from");
+        }
+    }
+
+    #[test]
+    fn outcomes_pass_through() {
+        for outcome in [FrameOutcome::Rendered, FrameOutcome::Skipped] {
+            assert_eq!(FrameOutcome::classify(Ok(outcome)).unwrap(), outcome);
+        }
+    }
+}
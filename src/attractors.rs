@@ -0,0 +1,19 @@
+use bytemuck::{Pod, Zeroable};
+use serde::{Deserialize, Serialize};
+
+/// Most attractors a particle system feels; more are dropped with a warning.
+pub const MAX_ATTRACTORS: usize = 16;
+
+/// A fixed point pulling particles in with inverse-square falloff, or pushing
+/// them away with a negative strength. Matches `Attractor` in `attractors.glsl` (std430).
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Pod, Zeroable, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Attractor {
+    /// Position in world space.
+    pub pos: [f32; 2],
+    pub strength: f32,
+    /// Distance inside which the force stops growing, so particles passing
+    /// through the center aren't flung away.
+    pub radius: f32,
+}
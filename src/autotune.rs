@@ -0,0 +1,223 @@
+use ash::vk;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use crate::behaviors::UpdateResources;
+use crate::cpu_sim::SimBackend;
+use crate::emitter::EmitterConfig;
+use crate::error::VulkanDemoError;
+use crate::particles::{default_workgroup_size, ParticleFormat, ParticleSystem, SimPipelines, SimPushConstants, SimulationMode};
+use crate::resources::OwnedQueryPool;
+use crate::spatial_grid::memory_barrier;
+use crate::vulkan_context::VulkanContext;
+
+/// Workgroup widths `--autotune` tries, those above the device limits aside.
+const WORKGROUP_SIZES: [u32; 4] = [64, 128, 256, 512];
+const PARTICLES_PER_INVOCATION: [u32; 3] = [1, 2, 4];
+
+// Particles stepped in every trial, enough to keep any GPU busy.
+const TRIAL_PARTICLES: u32 = 1 << 20;
+// Steps before the timed ones of each trial, for the clocks to ramp up and
+// the new pipeline to settle in.
+const WARMUP_STEPS: u32 = 20;
+const TIMED_STEPS: u32 = 50;
+
+/// How the particle update is dispatched, specialized into its compute
+/// shaders. The workgroup size is shared by every other compute shader.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DispatchTuning {
+    /// Invocations per workgroup, `local_size_x_id = 1`.
+    pub workgroup_size: u32,
+    /// Particles each invocation steps, `PARTICLES_PER_INVOCATION` in the
+    /// shaders of behaviors that batch them.
+    pub particles_per_invocation: u32,
+}
+
+impl DispatchTuning {
+    /// 256-wide workgroups or as near as the device allows, one particle per invocation.
+    pub fn default_for(context: &VulkanContext) -> Self {
+        Self { workgroup_size: default_workgroup_size(context), particles_per_invocation: 1 }
+    }
+
+    /// Workgroups covering `count` particles, `batched` when each invocation
+    /// steps `particles_per_invocation` of them.
+    pub fn workgroup_count(&self, count: u32, batched: bool) -> u32 {
+        let per_invocation = if batched { self.particles_per_invocation } else { 1 };
+        count.div_ceil(self.workgroup_size * per_invocation)
+    }
+}
+
+impl fmt::Display for DispatchTuning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} invocations per workgroup, {} particles per invocation", self.workgroup_size, self.particles_per_invocation)
+    }
+}
+
+/// What to build `SimPipelines` with. With `autotune`, the fastest dispatch
+/// for this device and driver: cached by an earlier run if there is one,
+/// otherwise found by timing every candidate and cached for the next. Without
+/// it, or without GPU timestamps to time with, `DispatchTuning::default_for`.
+pub fn dispatch_tuning(context: &VulkanContext, format: ParticleFormat, autotune: bool) -> Result<DispatchTuning, VulkanDemoError> {
+    if !autotune {
+        return Ok(DispatchTuning::default_for(context));
+    }
+    let key = device_key(context);
+    let path = tuning_path();
+    let mut cached = path.as_deref().map(load_tunings).unwrap_or_default();
+    if let Some(tuning) = cached.get(&key) {
+        log::info!("Using the cached dispatch tuning for {}: {tuning}", context.device_description());
+        return Ok(*tuning);
+    }
+    let Some(tuning) = fastest_dispatch(context, format)? else {
+        return Ok(DispatchTuning::default_for(context));
+    };
+    if let Some(path) = path {
+        cached.insert(key, tuning);
+        save_tunings(&path, &cached);
+    }
+    Ok(tuning)
+}
+
+/// Times `TIMED_STEPS` simple-mode steps of `TRIAL_PARTICLES` particles with
+/// every candidate dispatch, logging each, and returns the fastest. `None`
+/// if the device can't time them.
+fn fastest_dispatch(context: &VulkanContext, format: ParticleFormat) -> Result<Option<DispatchTuning>, VulkanDemoError> {
+    let device = &context.device;
+    let properties = unsafe { context.instance.get_physical_device_properties(context.physical_device) };
+    let queue_families = unsafe { context.instance.get_physical_device_queue_family_properties(context.physical_device) };
+    let valid_bits = queue_families[context.queue_family_index as usize].timestamp_valid_bits;
+    if valid_bits == 0 {
+        log::warn!("The graphics queue family has no timestamp support, using the default dispatch");
+        return Ok(None);
+    }
+    let valid_bits_mask = if valid_bits >= 64 { u64::MAX } else { (1u64 << valid_bits) - 1 };
+
+    let limits = properties.limits;
+    let candidates: Vec<_> = WORKGROUP_SIZES
+        .into_iter()
+        .filter(|&size| size <= limits.max_compute_work_group_invocations && size <= limits.max_compute_work_group_size[0])
+        .flat_map(|workgroup_size| {
+            PARTICLES_PER_INVOCATION.map(|particles_per_invocation| DispatchTuning { workgroup_size, particles_per_invocation })
+        })
+        .collect();
+    log::info!("Timing {} particle update dispatches on {}", candidates.len(), context.device_description());
+
+    // Timed through descriptors, the default access path.
+    let pipelines = SimPipelines::new(context, format, DispatchTuning::default_for(context), false)?;
+    let system = ParticleSystem::new(
+        context,
+        &pipelines,
+        TRIAL_PARTICLES,
+        0,
+        &EmitterConfig::default(),
+        false,
+        SimBackend::Gpu,
+    )?;
+    system.finish_upload(context)?;
+    let mode = SimulationMode::Simple;
+    let behavior = mode.behavior();
+    let pool_info = vk::QueryPoolCreateInfo::default().query_type(vk::QueryType::TIMESTAMP).query_count(2);
+    let query_pool = OwnedQueryPool::new(device, unsafe { device.create_query_pool(&pool_info, None)? });
+
+    let mut fastest: Option<(DispatchTuning, f64)> = None;
+    for tuning in candidates {
+        // Dropped at the end of the iteration, once `one_time_submit` has waited for it.
+        let pipeline = pipelines.trial_pipeline(device, mode, tuning)?;
+        context.one_time_submit(|cmd| unsafe {
+            device.cmd_reset_query_pool(cmd, query_pool.handle(), 0, 2);
+            for step in 0..WARMUP_STEPS + TIMED_STEPS {
+                if step == WARMUP_STEPS {
+                    device.cmd_write_timestamp(cmd, vk::PipelineStageFlags::TOP_OF_PIPE, query_pool.handle(), 0);
+                }
+                let substep = step as usize % 2;
+                system.record_prepass(device, cmd, &pipelines.scan, substep);
+                let push_constants = SimPushConstants { dt: 1.0 / 60.0, elapsed: step as f32 / 60.0, frame: step, ..SimPushConstants::default() };
+                let resources = UpdateResources {
+                    pipeline: pipeline.handle(),
+                    // The simple mode has none.
+                    prepasses: &[],
+                    pipeline_layout: pipelines.layout.pipeline_layout.handle(),
+                    descriptor_set: system.descriptor_set(substep),
+                    push_constants: &push_constants,
+                    workgroup_count: tuning.workgroup_count(system.count, behavior.batches_particles()),
+                };
+                behavior.record_update(device, cmd, &resources);
+                // The next step reads what this one wrote.
+                memory_barrier(
+                    device,
+                    cmd,
+                    (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+                    (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE),
+                );
+            }
+            device.cmd_write_timestamp(cmd, vk::PipelineStageFlags::BOTTOM_OF_PIPE, query_pool.handle(), 1);
+        })?;
+
+        let mut ticks = [0u64; 2];
+        unsafe {
+            device.get_query_pool_results(
+                query_pool.handle(),
+                0,
+                &mut ticks,
+                vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+            )?;
+        }
+        let elapsed = (ticks[1] & valid_bits_mask).wrapping_sub(ticks[0] & valid_bits_mask) & valid_bits_mask;
+        let step_ms = elapsed as f64 * limits.timestamp_period as f64 / 1_000_000.0 / TIMED_STEPS as f64;
+        log::info!("{tuning}: {step_ms:.3} ms per step");
+        if fastest.is_none_or(|(_, fastest_ms)| step_ms < fastest_ms) {
+            fastest = Some((tuning, step_ms));
+        }
+    }
+
+    let fastest = fastest.map(|(tuning, step_ms)| {
+        log::info!("Fastest dispatch: {tuning}, {step_ms:.3} ms per step");
+        tuning
+    });
+    Ok(fastest)
+}
+
+/// Identifies the device and driver a tuning was found on, as the key of its
+/// entry in the cache file.
+fn device_key(context: &VulkanContext) -> String {
+    let properties = unsafe { context.instance.get_physical_device_properties(context.physical_device) };
+    format!("{:04x}-{:04x}-{}", properties.vendor_id, properties.device_id, properties.driver_version)
+}
+
+/// Where tunings are kept between runs, beside the pipeline cache.
+fn tuning_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join(env!("CARGO_PKG_NAME")).join("dispatch_tuning.toml"))
+}
+
+/// The tunings cached at `path` by device key. A missing or unreadable file
+/// holds none, so they are found again.
+fn load_tunings(path: &Path) -> BTreeMap<String, DispatchTuning> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            log::debug!("No dispatch tunings read from {}: {e}", path.display());
+            return BTreeMap::new();
+        }
+    };
+    toml::from_str(&text).unwrap_or_else(|e| {
+        log::warn!("Ignoring dispatch tunings in {}: {e}", path.display());
+        BTreeMap::new()
+    })
+}
+
+/// Writes `tunings` to `path`. Failures are logged, since losing them only
+/// costs timing the dispatches again.
+fn save_tunings(path: &Path, tunings: &BTreeMap<String, DispatchTuning>) {
+    let written = toml::to_string(tunings).map_err(|e| e.to_string()).and_then(|text| {
+        path.parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| std::fs::write(path, text))
+            .map_err(|e| e.to_string())
+    });
+    match written {
+        Ok(()) => log::info!("Wrote the dispatch tuning to {}", path.display()),
+        Err(e) => log::warn!("Could not write the dispatch tuning to {}: {e}", path.display()),
+    }
+}
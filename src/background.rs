@@ -0,0 +1,197 @@
+use ash::{vk, Device};
+use bytemuck::{Pod, Zeroable};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use crate::error::VulkanDemoError;
+use crate::pipeline_utils::{compile_shader, GraphicsPipelineBuilder, RenderTarget, ShaderCompileOptions, ShaderInterface};
+use crate::renderer::{is_srgb_format, BlendMode};
+use crate::resources::{OwnedPipeline, OwnedPipelineLayout};
+
+// Added to the clear color for the bottom of the gradient.
+const GRADIENT_TINT: [f32; 3] = [0.10, 0.06, 0.20];
+// Hue cycles per second of the `Hue` background, and the bottom color's saturation and value.
+const HUE_SPEED: f32 = 0.02;
+const HUE_SATURATION: f32 = 0.6;
+const HUE_VALUE: f32 = 0.3;
+
+/// What is drawn behind the particles.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Background {
+    /// Just the clear color.
+    #[default]
+    Solid,
+    /// A vertical gradient from the clear color at the top to a brighter tint.
+    Gradient,
+    /// Like the gradient, but the bottom color slowly cycles through hues.
+    Hue,
+}
+
+/// Colors pushed to `background.frag`, in the color attachment's encoding. The
+/// alpha is only used when fading.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct BackgroundPushConstants {
+    top: [f32; 4],
+    bottom: [f32; 4],
+}
+
+/// Clears the frame and, unless the background is `Solid`, draws a
+/// fullscreen triangle over it before the particles, in subpass 0. With trails
+/// it instead blends the background translucently over the previous frame.
+///
+/// The colors are push constants, so animating or changing them never
+/// rebuilds the pipeline.
+pub struct BackgroundPass {
+    pipeline: OwnedPipeline,
+    fade_pipeline: OwnedPipeline,
+    pipeline_layout: OwnedPipelineLayout,
+    pub mode: Background,
+    /// In sRGB, like the particle colors.
+    pub clear_color: [f32; 3],
+    /// Seconds since start, for the `Hue` animation.
+    time: f32,
+    /// Colors are converted to linear for sRGB attachments, which encode on write.
+    srgb_target: bool,
+}
+
+impl BackgroundPass {
+    pub fn new(
+        device: &Arc<Device>,
+        pipeline_cache: vk::PipelineCache,
+        target: RenderTarget,
+        target_format: vk::Format,
+    ) -> Result<Self, VulkanDemoError> {
+        let vert_spirv = compile_shader(
+            include_str!("shaders/fullscreen.vert"),
+            "fullscreen.vert",
+            shaderc::ShaderKind::Vertex,
+            &ShaderCompileOptions::default(),
+        )?;
+        let frag_spirv = compile_shader(
+            include_str!("shaders/background.frag"),
+            "background.frag",
+            shaderc::ShaderKind::Fragment,
+            &ShaderCompileOptions::default(),
+        )?;
+        let interface = ShaderInterface::reflect(&[
+            (vk::ShaderStageFlags::VERTEX, &vert_spirv),
+            (vk::ShaderStageFlags::FRAGMENT, &frag_spirv),
+        ])?;
+        interface.expect_push_constants::<BackgroundPushConstants>()?;
+
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default().push_constant_ranges(interface.push_constant_ranges());
+        let pipeline_layout =
+            OwnedPipelineLayout::new(device, unsafe { device.create_pipeline_layout(&pipeline_layout_info, None)? });
+        let [pipeline, fade_pipeline] = [BlendMode::Opaque, BlendMode::Alpha].map(|blend_mode| {
+            create_fullscreen_pipeline(
+                device,
+                pipeline_cache,
+                target,
+                pipeline_layout.handle(),
+                blend_mode,
+                &vert_spirv,
+                &frag_spirv,
+            )
+        });
+
+        Ok(Self {
+            pipeline: pipeline?,
+            fade_pipeline: fade_pipeline?,
+            pipeline_layout,
+            mode: Background::default(),
+            clear_color: [0.0; 3],
+            time: 0.0,
+            srgb_target: is_srgb_format(target_format),
+        })
+    }
+
+    /// Advances the `Hue` animation to `time` seconds since start.
+    pub fn animate(&mut self, time: f32) {
+        self.time = time;
+    }
+
+    /// The render pass clear value for the color attachment.
+    pub fn clear_value(&self) -> vk::ClearValue {
+        let [r, g, b] = self.encode(self.clear_color);
+        vk::ClearValue { color: vk::ClearColorValue { float32: [r, g, b, 1.0] } }
+    }
+
+    /// Records the background draw, if any. Must be called in subpass 0 with
+    /// the viewport and scissor set.
+    pub fn record(&self, device: &Device, cmd: vk::CommandBuffer) {
+        if self.mode != Background::Solid {
+            self.draw(device, cmd, self.pipeline.handle(), 1.0);
+        }
+    }
+
+    /// Records blending the background over the previous frame with `opacity`,
+    /// instead of clearing, so that it fades out into trails.
+    pub fn record_fade(&self, device: &Device, cmd: vk::CommandBuffer, opacity: f32) {
+        self.draw(device, cmd, self.fade_pipeline.handle(), opacity);
+    }
+
+    fn draw(&self, device: &Device, cmd: vk::CommandBuffer, pipeline: vk::Pipeline, alpha: f32) {
+        let bottom = match self.mode {
+            Background::Solid => self.clear_color,
+            Background::Gradient => std::array::from_fn(|i| (self.clear_color[i] + GRADIENT_TINT[i]).min(1.0)),
+            Background::Hue => hsv_to_rgb((self.time * HUE_SPEED).fract(), HUE_SATURATION, HUE_VALUE),
+        };
+        let ([tr, tg, tb], [br, bg, bb]) = (self.encode(self.clear_color), self.encode(bottom));
+        let push_constants = BackgroundPushConstants { top: [tr, tg, tb, alpha], bottom: [br, bg, bb, alpha] };
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, pipeline);
+            device.cmd_push_constants(
+                cmd,
+                self.pipeline_layout.handle(),
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                bytemuck::bytes_of(&push_constants),
+            );
+            device.cmd_draw(cmd, 3, 1, 0, 0);
+        }
+    }
+
+    fn encode(&self, srgb: [f32; 3]) -> [f32; 3] {
+        if self.srgb_target {
+            srgb.map(srgb_to_linear)
+        } else {
+            srgb
+        }
+    }
+}
+
+/// A pipeline drawing `fullscreen.vert`'s triangle into `target`, without
+/// vertex input and without touching the depth buffer.
+pub(crate) fn create_fullscreen_pipeline(
+    device: &Arc<Device>,
+    pipeline_cache: vk::PipelineCache,
+    target: RenderTarget,
+    pipeline_layout: vk::PipelineLayout,
+    blend_mode: BlendMode,
+    vert_spirv: &[u32],
+    frag_spirv: &[u32],
+) -> Result<OwnedPipeline, VulkanDemoError> {
+    GraphicsPipelineBuilder::new(target, pipeline_layout)
+        .shader(vk::ShaderStageFlags::VERTEX, vert_spirv)
+        .shader(vk::ShaderStageFlags::FRAGMENT, frag_spirv)
+        .blend_mode(blend_mode)
+        .build(device, pipeline_cache)
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// `h`, `s` and `v` in [0, 1].
+fn hsv_to_rgb(h: f32, s: f32, v: f32) -> [f32; 3] {
+    let channel = |n: f32| {
+        let k = (n + h * 6.0) % 6.0;
+        v - v * s * (k.min(4.0 - k)).clamp(0.0, 1.0)
+    };
+    [channel(5.0), channel(3.0), channel(1.0)]
+}
@@ -0,0 +1,24 @@
+use crate::particles::SimParams;
+use super::{SimulationBehavior, SimulationMode};
+
+/// `SimulationMode::Boids`: flocking in `particle_boids.comp`, with neighbors
+/// from the spatial grid.
+pub struct Boids;
+
+impl SimulationBehavior for Boids {
+    fn mode(&self) -> SimulationMode {
+        SimulationMode::Boids
+    }
+
+    fn shader_file(&self) -> &'static str {
+        "particle_boids.comp"
+    }
+
+    fn shader_source(&self) -> &'static str {
+        include_str!("../shaders/particle_boids.comp")
+    }
+
+    fn uses_grid(&self, _params: &SimParams) -> bool {
+        true
+    }
+}
@@ -0,0 +1,33 @@
+use ash::vk;
+use crate::particles::{ComputeSpecialization, SimParams};
+use super::{SimulationBehavior, SimulationMode};
+
+/// `SimulationMode::CurlNoise`: `particle.comp` specialized with `CURL_NOISE`.
+pub struct CurlNoise;
+
+impl SimulationBehavior for CurlNoise {
+    fn mode(&self) -> SimulationMode {
+        SimulationMode::CurlNoise
+    }
+
+    fn shader_file(&self) -> &'static str {
+        "particle.comp"
+    }
+
+    fn shader_source(&self) -> &'static str {
+        include_str!("../shaders/particle.comp")
+    }
+
+    fn specialize(&self, constants: ComputeSpecialization) -> ComputeSpecialization {
+        ComputeSpecialization { curl_noise: vk::TRUE, ..constants }
+    }
+
+    /// Only with repulsion on.
+    fn uses_grid(&self, params: &SimParams) -> bool {
+        params.repulsion_strength > 0.0
+    }
+
+    fn supports_bda(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,43 @@
+use crate::particles::SimParams;
+use super::{SimulationBehavior, SimulationMode};
+
+/// Longest step the fluid stays stable with at the default stiffness.
+pub const FLUID_MAX_STEP_DT: f32 = 1.0 / 480.0;
+
+/// `SimulationMode::Fluid`: 2D smoothed-particle hydrodynamics. Each step
+/// builds the spatial grid, finds every particle's density and pressure in
+/// `fluid_density.comp`, then applies pressure and viscosity and integrates in
+/// `particle_fluid.comp`.
+///
+/// The defaults in `SimParams` suit about 20k particles: at rest they settle
+/// half a smoothing radius apart, which fills about a third of the world.
+/// Steps are held to `FLUID_MAX_STEP_DT`, so keeping up at 60 fps takes the
+/// default 8 steps a frame; stiffer settings need shorter steps still.
+pub struct Fluid;
+
+impl SimulationBehavior for Fluid {
+    fn mode(&self) -> SimulationMode {
+        SimulationMode::Fluid
+    }
+
+    fn shader_file(&self) -> &'static str {
+        "particle_fluid.comp"
+    }
+
+    fn shader_source(&self) -> &'static str {
+        include_str!("../shaders/particle_fluid.comp")
+    }
+
+    fn prepasses(&self) -> &'static [(&'static str, &'static str)] {
+        &[("fluid_density.comp", include_str!("../shaders/fluid_density.comp"))]
+    }
+
+    /// Always; both passes look up neighbors in it.
+    fn uses_grid(&self, _params: &SimParams) -> bool {
+        true
+    }
+
+    fn max_step_dt(&self) -> Option<f32> {
+        Some(FLUID_MAX_STEP_DT)
+    }
+}
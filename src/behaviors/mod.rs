@@ -0,0 +1,184 @@
+//! What each `SimulationMode` runs: one `SimulationBehavior` per file.
+//!
+//! Adding a mode takes a file implementing the trait, a variant of
+//! `SimulationMode` and its arm in `SimulationMode::behavior`. `SimPipelines`
+//! builds a pipeline for every variant, and the N key cycles through them.
+
+mod boids;
+mod curl_noise;
+mod fluid;
+mod nbody;
+mod simple;
+
+use ash::vk;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use crate::particles::{ComputeSpecialization, SimParams, SimPushConstants};
+use crate::spatial_grid::memory_barrier;
+
+pub use boids::Boids;
+pub use curl_noise::CurlNoise;
+pub use fluid::{Fluid, FLUID_MAX_STEP_DT};
+pub use nbody::{NBody, NBODY_MAX_PARTICLES};
+pub use simple::Simple;
+
+/// Which compute shader advances the particles.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SimulationMode {
+    /// Independent particles under gravity, drag and the cursor attractor.
+    #[default]
+    Simple,
+    /// Every particle also attracts every other one; lifetimes are frozen.
+    NBody,
+    /// Flocking by separation, alignment and cohesion with neighbors found
+    /// through the spatial grid; lifetimes are frozen.
+    Boids,
+    /// Like `Simple`, but particles are carried along an animated
+    /// divergence-free noise field, like smoke.
+    CurlNoise,
+    /// A 2D liquid: particles push apart where they crowd, through pressure
+    /// from their density, and drag each other along through viscosity;
+    /// lifetimes are frozen.
+    Fluid,
+}
+
+impl SimulationMode {
+    pub fn behavior(self) -> Box<dyn SimulationBehavior> {
+        match self {
+            Self::Simple => Box::new(Simple),
+            Self::NBody => Box::new(NBody),
+            Self::Boids => Box::new(Boids),
+            Self::CurlNoise => Box::new(CurlNoise),
+            Self::Fluid => Box::new(Fluid),
+        }
+    }
+
+    /// Every mode, in the order the N key cycles through them.
+    pub fn all() -> &'static [Self] {
+        Self::value_variants()
+    }
+
+    pub fn shader_file(self) -> &'static str {
+        self.behavior().shader_file()
+    }
+
+    /// The mode the N key switches to: the next variant, wrapping around.
+    pub fn next(self) -> Self {
+        let modes = Self::all();
+        let index = modes.iter().position(|&mode| mode == self).expect("mode is a variant");
+        modes[(index + 1) % modes.len()]
+    }
+}
+
+/// What one step of a `ParticleSystem` binds and dispatches, as handed to
+/// `SimulationBehavior::record_update`.
+#[derive(Copy, Clone, Debug)]
+pub struct UpdateResources<'a> {
+    /// The behavior's pipeline from `SimPipelines`.
+    pub pipeline: vk::Pipeline,
+    /// Pipelines of its `SimulationBehavior::prepasses`, in order.
+    pub prepasses: &'a [vk::Pipeline],
+    /// Shared by every behavior's pipeline.
+    pub pipeline_layout: vk::PipelineLayout,
+    /// The system's set for the step, with its particles, parameters, grid and draw counts.
+    pub descriptor_set: vk::DescriptorSet,
+    pub push_constants: &'a SimPushConstants,
+    /// Workgroups covering the system's particles, from `SimPipelines::update_workgroup_count`.
+    pub workgroup_count: u32,
+}
+
+/// One way of advancing the particles: the compute shader it runs and what
+/// a step of it needs around that.
+///
+/// Every behavior's shader is compiled into a pipeline sharing one layout,
+/// reflected from all of them, so a shader may use any binding another
+/// declares; switching between behaviors only switches pipelines, keeping
+/// the particle buffers and descriptor sets.
+pub trait SimulationBehavior: Send + Sync {
+    fn mode(&self) -> SimulationMode;
+
+    /// Name of the shader under `src/shaders`, for diagnostics and hot reload.
+    fn shader_file(&self) -> &'static str;
+
+    /// The built-in GLSL of the shader.
+    fn shader_source(&self) -> &'static str;
+
+    /// Shaders dispatched before that one every step, in order, as the name
+    /// under `src/shaders` and built-in GLSL of each. They share its layout,
+    /// specialization and workgroup count, and run after the grid is built.
+    fn prepasses(&self) -> &'static [(&'static str, &'static str)] {
+        &[]
+    }
+
+    /// `constants` with whatever this behavior sets on top of the workgroup
+    /// size and particle format every compute shader gets.
+    fn specialize(&self, constants: ComputeSpecialization) -> ComputeSpecialization {
+        constants
+    }
+
+    /// Whether a step with `params` needs the spatial grid built first.
+    fn uses_grid(&self, params: &SimParams) -> bool;
+
+    /// Most particles it stays interactive with, if it has a limit.
+    fn max_particles(&self) -> Option<u32> {
+        None
+    }
+
+    /// Whether `cpu_sim` implements it, for systems not simulated on the GPU.
+    fn runs_on_cpu(&self) -> bool {
+        false
+    }
+
+    /// Longest step it stays stable with, if steps of `--substep-dt` may be
+    /// too long; see `StepClock::limit_step_dt`.
+    fn max_step_dt(&self) -> Option<f32> {
+        None
+    }
+
+    /// Whether its shader can be built with `PARTICLE_BDA`, taking the
+    /// particle buffers by the addresses in its push constants, for `--bda`.
+    fn supports_bda(&self) -> bool {
+        false
+    }
+
+    /// Whether its shader steps `PARTICLES_PER_INVOCATION` particles per
+    /// invocation, and so is dispatched with that many fewer workgroups.
+    fn batches_particles(&self) -> bool {
+        true
+    }
+
+    /// Records the step's dispatches, after the system's prepass: those of
+    /// `prepasses`, each followed by a barrier, then the main one.
+    fn record_update(&self, device: &ash::Device, cmd: vk::CommandBuffer, resources: &UpdateResources) {
+        unsafe {
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                resources.pipeline_layout,
+                0,
+                &[resources.descriptor_set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                resources.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                bytemuck::bytes_of(resources.push_constants),
+            );
+            for prepass in resources.prepasses {
+                device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, *prepass);
+                device.cmd_dispatch(cmd, resources.workgroup_count, 1, 1);
+                memory_barrier(
+                    device,
+                    cmd,
+                    (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+                    (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ),
+                );
+            }
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, resources.pipeline);
+            device.cmd_dispatch(cmd, resources.workgroup_count, 1, 1);
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use crate::particles::SimParams;
+use super::{SimulationBehavior, SimulationMode};
+
+/// N-body costs O(N²) per step; above this it stops being interactive on most GPUs.
+pub const NBODY_MAX_PARTICLES: u32 = 32_768;
+
+/// `SimulationMode::NBody`: all-pairs gravity in `particle_nbody.comp`.
+pub struct NBody;
+
+impl SimulationBehavior for NBody {
+    fn mode(&self) -> SimulationMode {
+        SimulationMode::NBody
+    }
+
+    fn shader_file(&self) -> &'static str {
+        "particle_nbody.comp"
+    }
+
+    fn shader_source(&self) -> &'static str {
+        include_str!("../shaders/particle_nbody.comp")
+    }
+
+    /// Never; it has its own all-pairs force.
+    fn uses_grid(&self, _params: &SimParams) -> bool {
+        false
+    }
+
+    fn max_particles(&self) -> Option<u32> {
+        Some(NBODY_MAX_PARTICLES)
+    }
+
+    /// No; each invocation loads one particle into every tile.
+    fn batches_particles(&self) -> bool {
+        false
+    }
+}
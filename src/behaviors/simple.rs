@@ -0,0 +1,32 @@
+use crate::particles::SimParams;
+use super::{SimulationBehavior, SimulationMode};
+
+/// `SimulationMode::Simple`: `particle.comp` as is.
+pub struct Simple;
+
+impl SimulationBehavior for Simple {
+    fn mode(&self) -> SimulationMode {
+        SimulationMode::Simple
+    }
+
+    fn shader_file(&self) -> &'static str {
+        "particle.comp"
+    }
+
+    fn shader_source(&self) -> &'static str {
+        include_str!("../shaders/particle.comp")
+    }
+
+    /// Only with repulsion on.
+    fn uses_grid(&self, params: &SimParams) -> bool {
+        params.repulsion_strength > 0.0
+    }
+
+    fn runs_on_cpu(&self) -> bool {
+        true
+    }
+
+    fn supports_bda(&self) -> bool {
+        true
+    }
+}
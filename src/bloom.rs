@@ -0,0 +1,338 @@
+use ash::{vk, Device};
+use bytemuck::{Pod, Zeroable};
+use crate::background::create_fullscreen_pipeline;
+use crate::error::VulkanDemoError;
+use crate::memory::create_image;
+use crate::particles::create_compute_pipeline;
+use crate::pipeline_utils::{compile_shader, RenderTarget, ShaderCompileOptions, ShaderInterface};
+use crate::renderer::{create_image_views, BlendMode};
+use crate::resources::{
+    OwnedDescriptorPool, OwnedDescriptorSetLayout, OwnedImage, OwnedImageView, OwnedPipeline, OwnedPipelineLayout,
+    OwnedSampler,
+};
+use crate::vulkan_context::VulkanContext;
+
+pub const DEFAULT_BLOOM_THRESHOLD: f32 = 0.6;
+pub const DEFAULT_BLOOM_INTENSITY: f32 = 1.0;
+
+// Half-float so that blurred highlights don't band.
+const BLOOM_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+// `local_size_x` and `local_size_y` of the bloom compute shaders.
+const WORKGROUP_SIZE: u32 = 8;
+// Horizontal and vertical blur pairs run per frame; each widens the halo.
+const BLUR_ITERATIONS: u32 = 2;
+// Where the input and output images of the compute shaders are bound.
+const INPUT_BINDING: u32 = 0;
+const OUTPUT_BINDING: u32 = 1;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct ThresholdPushConstants {
+    threshold: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct BlurPushConstants {
+    direction: [i32; 2],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct CompositePushConstants {
+    intensity: f32,
+}
+
+/// Makes bright regions of the scene glow: they are thresholded into a
+/// half-resolution image, blurred by ping-ponging between two such images with
+/// a separable Gaussian, and added back while compositing the scene into the
+/// swapchain image.
+///
+/// The thresholding and blurring are compute passes recorded between the scene
+/// and overlay passes; the composite is a fullscreen draw at the start of the
+/// overlay pass, replacing the plain copy of the scene.
+pub struct Bloom {
+    threshold_pipeline: OwnedPipeline,
+    blur_pipeline: OwnedPipeline,
+    composite_pipeline: OwnedPipeline,
+    threshold_layout: OwnedPipelineLayout,
+    blur_layout: OwnedPipelineLayout,
+    composite_layout: OwnedPipelineLayout,
+    _descriptor_pool: OwnedDescriptorPool,
+    _set_layouts: [OwnedDescriptorSetLayout; 3],
+    threshold_set: vk::DescriptorSet,
+    /// Horizontal (first into second image) and vertical (back into the first).
+    blur_sets: [vk::DescriptorSet; 2],
+    composite_set: vk::DescriptorSet,
+    targets: BloomTargets,
+    sampler: OwnedSampler,
+    pub enabled: bool,
+    /// Brightness, from 0 to 1, above which the scene starts to glow.
+    pub threshold: f32,
+    /// How strongly the blurred highlights are added back.
+    pub intensity: f32,
+}
+
+/// The two half-resolution images the blur ping-pongs between.
+struct BloomTargets {
+    views: [OwnedImageView; 2],
+    images: [OwnedImage; 2],
+    extent: vk::Extent2D,
+}
+
+impl BloomTargets {
+    fn new(context: &VulkanContext, scene_extent: vk::Extent2D) -> Result<Self, VulkanDemoError> {
+        let extent = vk::Extent2D {
+            width: scene_extent.width.div_ceil(2),
+            height: scene_extent.height.div_ceil(2),
+        };
+        let usage = vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED;
+        let images = [create_image(context, extent, BLOOM_FORMAT, usage)?, create_image(context, extent, BLOOM_FORMAT, usage)?];
+        let mut views = create_image_views(&context.device, &[images[0].handle(), images[1].handle()], BLOOM_FORMAT)?.into_iter();
+        let views = [views.next().unwrap(), views.next().unwrap()];
+        Ok(Self { views, images, extent })
+    }
+}
+
+impl Bloom {
+    /// `composite_target` is what the composite draws into, with the overlay. The scene image behind `scene_view` must have sampled usage.
+    pub fn new(
+        context: &VulkanContext,
+        composite_target: RenderTarget,
+        scene_view: vk::ImageView,
+        scene_extent: vk::Extent2D,
+    ) -> Result<Self, VulkanDemoError> {
+        let device = &context.device;
+        let options = ShaderCompileOptions::default();
+        let threshold_spirv = compile_shader(
+            include_str!("shaders/bloom_threshold.comp"),
+            "bloom_threshold.comp",
+            shaderc::ShaderKind::Compute,
+            &options,
+        )?;
+        let blur_spirv = compile_shader(include_str!("shaders/bloom_blur.comp"), "bloom_blur.comp", shaderc::ShaderKind::Compute, &options)?;
+        let vert_spirv = compile_shader(include_str!("shaders/fullscreen.vert"), "fullscreen.vert", shaderc::ShaderKind::Vertex, &options)?;
+        let composite_spirv = compile_shader(
+            include_str!("shaders/bloom_composite.frag"),
+            "bloom_composite.frag",
+            shaderc::ShaderKind::Fragment,
+            &options,
+        )?;
+
+        let threshold_interface = ShaderInterface::reflect(&[(vk::ShaderStageFlags::COMPUTE, &threshold_spirv)])?;
+        threshold_interface.expect_binding(0, INPUT_BINDING, vk::DescriptorType::COMBINED_IMAGE_SAMPLER)?;
+        threshold_interface.expect_binding(0, OUTPUT_BINDING, vk::DescriptorType::STORAGE_IMAGE)?;
+        threshold_interface.expect_push_constants::<ThresholdPushConstants>()?;
+        let blur_interface = ShaderInterface::reflect(&[(vk::ShaderStageFlags::COMPUTE, &blur_spirv)])?;
+        blur_interface.expect_binding(0, INPUT_BINDING, vk::DescriptorType::STORAGE_IMAGE)?;
+        blur_interface.expect_binding(0, OUTPUT_BINDING, vk::DescriptorType::STORAGE_IMAGE)?;
+        blur_interface.expect_push_constants::<BlurPushConstants>()?;
+        let composite_interface = ShaderInterface::reflect(&[
+            (vk::ShaderStageFlags::VERTEX, &vert_spirv),
+            (vk::ShaderStageFlags::FRAGMENT, &composite_spirv),
+        ])?;
+        composite_interface.expect_binding(0, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER)?;
+        composite_interface.expect_binding(0, 1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER)?;
+        composite_interface.expect_push_constants::<CompositePushConstants>()?;
+
+        let set_layouts = [
+            threshold_interface.create_set_layout(device, 0)?,
+            blur_interface.create_set_layout(device, 0)?,
+            composite_interface.create_set_layout(device, 0)?,
+        ];
+        let create_layout = |interface: &ShaderInterface, set_layout: &OwnedDescriptorSetLayout| {
+            let set_layouts = [set_layout.handle()];
+            let layout_info = vk::PipelineLayoutCreateInfo::default()
+                .set_layouts(&set_layouts)
+                .push_constant_ranges(interface.push_constant_ranges());
+            unsafe { device.create_pipeline_layout(&layout_info, None) }.map(|layout| OwnedPipelineLayout::new(device, layout))
+        };
+        let threshold_layout = create_layout(&threshold_interface, &set_layouts[0])?;
+        let blur_layout = create_layout(&blur_interface, &set_layouts[1])?;
+        let composite_layout = create_layout(&composite_interface, &set_layouts[2])?;
+
+        let threshold_pipeline =
+            create_compute_pipeline(device, context.pipeline_cache, threshold_layout.handle(), &threshold_spirv, None)?;
+        let blur_pipeline = create_compute_pipeline(device, context.pipeline_cache, blur_layout.handle(), &blur_spirv, None)?;
+        let composite_pipeline = create_fullscreen_pipeline(
+            device,
+            context.pipeline_cache,
+            composite_target,
+            composite_layout.handle(),
+            BlendMode::Opaque,
+            &vert_spirv,
+            &composite_spirv,
+        )?;
+
+        let pool_sizes = [threshold_interface.pool_sizes(0, 1), blur_interface.pool_sizes(0, 2), composite_interface.pool_sizes(0, 1)].concat();
+        let pool_info = vk::DescriptorPoolCreateInfo::default()
+            .pool_sizes(&pool_sizes)
+            .max_sets(4);
+        let descriptor_pool = OwnedDescriptorPool::new(device, unsafe { device.create_descriptor_pool(&pool_info, None)? });
+        let layouts = [set_layouts[0].handle(), set_layouts[1].handle(), set_layouts[1].handle(), set_layouts[2].handle()];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool.handle())
+            .set_layouts(&layouts);
+        let sets = unsafe { device.allocate_descriptor_sets(&alloc_info)? };
+
+        let sampler_info = vk::SamplerCreateInfo::default()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let sampler = OwnedSampler::new(device, unsafe { device.create_sampler(&sampler_info, None)? });
+
+        let bloom = Self {
+            threshold_pipeline,
+            blur_pipeline,
+            composite_pipeline,
+            threshold_layout,
+            blur_layout,
+            composite_layout,
+            _descriptor_pool: descriptor_pool,
+            _set_layouts: set_layouts,
+            threshold_set: sets[0],
+            blur_sets: [sets[1], sets[2]],
+            composite_set: sets[3],
+            targets: BloomTargets::new(context, scene_extent)?,
+            sampler,
+            enabled: false,
+            threshold: DEFAULT_BLOOM_THRESHOLD,
+            intensity: DEFAULT_BLOOM_INTENSITY,
+        };
+        bloom.write_descriptor_sets(device, scene_view);
+        Ok(bloom)
+    }
+
+    /// Recreates the half-resolution images for a new scene image. The GPU
+    /// must be done with the old ones.
+    pub fn resize(&mut self, context: &VulkanContext, scene_view: vk::ImageView, scene_extent: vk::Extent2D) -> Result<(), VulkanDemoError> {
+        self.targets = BloomTargets::new(context, scene_extent)?;
+        self.write_descriptor_sets(&context.device, scene_view);
+        Ok(())
+    }
+
+    fn write_descriptor_sets(&self, device: &Device, scene_view: vk::ImageView) {
+        let sampled = |view: vk::ImageView, layout| {
+            [vk::DescriptorImageInfo::default().sampler(self.sampler.handle()).image_view(view).image_layout(layout)]
+        };
+        let storage = |index: usize| {
+            [vk::DescriptorImageInfo::default().image_view(self.targets.views[index].handle()).image_layout(vk::ImageLayout::GENERAL)]
+        };
+        let scene = sampled(scene_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        let blurred = sampled(self.targets.views[0].handle(), vk::ImageLayout::GENERAL);
+        let [first, second] = [storage(0), storage(1)];
+        fn write(
+            set: vk::DescriptorSet,
+            binding: u32,
+            descriptor_type: vk::DescriptorType,
+            info: &[vk::DescriptorImageInfo],
+        ) -> vk::WriteDescriptorSet<'_> {
+            vk::WriteDescriptorSet::default()
+                .dst_set(set)
+                .dst_binding(binding)
+                .descriptor_type(descriptor_type)
+                .image_info(info)
+        }
+        let writes = [
+            write(self.threshold_set, INPUT_BINDING, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, &scene),
+            write(self.threshold_set, OUTPUT_BINDING, vk::DescriptorType::STORAGE_IMAGE, &first),
+            write(self.blur_sets[0], INPUT_BINDING, vk::DescriptorType::STORAGE_IMAGE, &first),
+            write(self.blur_sets[0], OUTPUT_BINDING, vk::DescriptorType::STORAGE_IMAGE, &second),
+            write(self.blur_sets[1], INPUT_BINDING, vk::DescriptorType::STORAGE_IMAGE, &second),
+            write(self.blur_sets[1], OUTPUT_BINDING, vk::DescriptorType::STORAGE_IMAGE, &first),
+            write(self.composite_set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, &scene),
+            write(self.composite_set, 1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, &blurred),
+        ];
+        unsafe { device.update_descriptor_sets(&writes, &[]) };
+    }
+
+    /// Records thresholding the scene image and blurring the result. The scene
+    /// image must be in `SHADER_READ_ONLY_OPTIMAL`, its writes visible to compute
+    /// shaders. Leaves the blurred highlights visible to fragment shaders.
+    pub fn record(&self, device: &Device, cmd: vk::CommandBuffer) {
+        let group_counts = [self.targets.extent.width, self.targets.extent.height].map(|size| size.div_ceil(WORKGROUP_SIZE));
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        // Last frame's contents are discarded; its composite must be done reading them.
+        let to_general = self.targets.images.each_ref().map(|image| {
+            vk::ImageMemoryBarrier::default()
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::GENERAL)
+                .src_access_mask(vk::AccessFlags::empty())
+                .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image.handle())
+                .subresource_range(subresource_range)
+        });
+        let between_passes = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        let dispatch = |pipeline: &OwnedPipeline, layout: &OwnedPipelineLayout, set: vk::DescriptorSet, push_constants: &[u8]| unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, pipeline.handle());
+            device.cmd_bind_descriptor_sets(cmd, vk::PipelineBindPoint::COMPUTE, layout.handle(), 0, &[set], &[]);
+            device.cmd_push_constants(cmd, layout.handle(), vk::ShaderStageFlags::COMPUTE, 0, push_constants);
+            device.cmd_dispatch(cmd, group_counts[0], group_counts[1], 1);
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[between_passes],
+                &[],
+                &[],
+            );
+        };
+
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &to_general,
+            );
+        }
+        let threshold = ThresholdPushConstants { threshold: self.threshold };
+        dispatch(&self.threshold_pipeline, &self.threshold_layout, self.threshold_set, bytemuck::bytes_of(&threshold));
+        for _ in 0..BLUR_ITERATIONS {
+            for (set, direction) in self.blur_sets.into_iter().zip([[1, 0], [0, 1]]) {
+                dispatch(&self.blur_pipeline, &self.blur_layout, set, bytemuck::bytes_of(&BlurPushConstants { direction }));
+            }
+        }
+    }
+
+    /// Records drawing the scene with the highlights added over the whole
+    /// target. Must be called in the composite render pass with the viewport set.
+    pub fn record_composite(&self, device: &Device, cmd: vk::CommandBuffer) {
+        let push_constants = CompositePushConstants { intensity: self.intensity };
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.composite_pipeline.handle());
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.composite_layout.handle(),
+                0,
+                &[self.composite_set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.composite_layout.handle(),
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                bytemuck::bytes_of(&push_constants),
+            );
+            device.cmd_draw(cmd, 3, 1, 0, 0);
+        }
+    }
+}
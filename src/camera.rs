@@ -0,0 +1,86 @@
+use glam::{Mat4, Vec3};
+use crate::particles::WORLD_HALF_EXTENTS;
+
+// Vertical field of view and clip planes of the perspective projection.
+const FOV_Y: f32 = std::f32::consts::FRAC_PI_4;
+const NEAR_PLANE: f32 = 0.05;
+const FAR_PLANE: f32 = 100.0;
+
+// Just short of straight up or down, where the view's up vector would flip.
+const PITCH_LIMIT: f32 = 1.55;
+const MIN_DISTANCE: f32 = 0.5;
+const MAX_DISTANCE: f32 = 20.0;
+pub const DEFAULT_DISTANCE: f32 = 3.5;
+
+/// A camera circling `target` at `distance`, turned by `yaw` around the y axis
+/// and tilted by `pitch`. At zero yaw and pitch it looks straight along +z, so
+/// it sees the z = 0 plane like the flat 2D view: +x right and +y down.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OrbitCamera {
+    pub target: Vec3,
+    pub yaw: f32,
+    /// Positive pitch looks down on the particles from above (-y).
+    pub pitch: f32,
+    pub distance: f32,
+}
+
+impl Default for OrbitCamera {
+    fn default() -> Self {
+        Self { target: Vec3::ZERO, yaw: 0.0, pitch: 0.0, distance: DEFAULT_DISTANCE }
+    }
+}
+
+impl OrbitCamera {
+    /// Turns the camera by the given angles in radians. Pitch is clamped short
+    /// of the poles.
+    pub fn rotate(&mut self, yaw: f32, pitch: f32) {
+        self.yaw = (self.yaw + yaw) % std::f32::consts::TAU;
+        self.pitch = (self.pitch + pitch).clamp(-PITCH_LIMIT, PITCH_LIMIT);
+    }
+
+    /// Multiplies the distance to the target by `factor`, within the zoom limits.
+    pub fn zoom(&mut self, factor: f32) {
+        self.distance = (self.distance * factor).clamp(MIN_DISTANCE, MAX_DISTANCE);
+    }
+
+    /// Slides the target across the view plane. `right` and `down` are fractions
+    /// of the view height, so a drag follows the cursor at the target's depth.
+    pub fn pan(&mut self, right: f32, down: f32) {
+        let forward = -self.offset().normalize();
+        let right_axis = Vec3::Y.cross(forward).normalize();
+        let down_axis = forward.cross(right_axis);
+        let view_height = 2.0 * self.distance * (FOV_Y * 0.5).tan();
+        self.target -= (right_axis * right + down_axis * down) * view_height;
+    }
+
+    pub fn eye(&self) -> Vec3 {
+        self.target + self.offset()
+    }
+
+    /// Combined view and projection matrix for a render target of the given
+    /// width over height. Depth runs from 0 at the near plane to 1 at the far one.
+    pub fn view_projection(&self, aspect: f32) -> Mat4 {
+        // Left-handed look-at with +y as "up" keeps +y pointing down the screen
+        // after Vulkan's y-down clip space, matching the 2D view.
+        let view = Mat4::look_at_lh(self.eye(), self.target, Vec3::Y);
+        let projection = Mat4::perspective_lh(FOV_Y, aspect, NEAR_PLANE, FAR_PLANE);
+        projection * view
+    }
+
+    /// From the target to the eye.
+    fn offset(&self) -> Vec3 {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        Vec3::new(cos_pitch * sin_yaw, -sin_pitch, -cos_pitch * cos_yaw) * self.distance
+    }
+}
+
+/// The flat 2D view: scales the world rectangle uniformly to the largest size
+/// that fits a render target of the given width over height, centered. The
+/// rest of the target is left to the background, so a resize letterboxes the
+/// world instead of stretching it.
+pub fn world_projection(aspect: f32) -> Mat4 {
+    let [half_width, half_height] = WORLD_HALF_EXTENTS;
+    let scale = 1.0 / half_height.max(half_width / aspect);
+    Mat4::from_scale(Vec3::new(scale / aspect, scale, 1.0))
+}
@@ -0,0 +1,281 @@
+use ash::vk;
+use bytemuck::Pod;
+use std::mem::size_of;
+use crate::allocator::MemoryLocation;
+use crate::error::VulkanDemoError;
+use crate::memory::{create_buffer, read_from_buffer};
+use crate::particles::create_compute_pipeline;
+use crate::pipeline_utils::{compile_shader, ShaderCompileOptions};
+use crate::resources::{OwnedBuffer, OwnedDescriptorPool, OwnedDescriptorSetLayout, OwnedPipeline, OwnedPipelineLayout};
+use crate::spatial_grid::memory_barrier;
+use crate::vulkan_context::VulkanContext;
+
+/// Runs compute shaders once over buffers filled from Rust slices and reads
+/// the results back, for checking a shader's output without a frame around
+/// it. Works with any `VulkanContext`, headless ones included.
+///
+/// Buffers are host-visible storage buffers, so they can be read back
+/// directly. `run` submits whatever a caller records, such as a `GpuScan`,
+/// and waits for it with its writes visible to the host.
+pub struct ComputeHarness<'a> {
+    context: &'a VulkanContext,
+}
+
+/// A compute pipeline built by `ComputeHarness::pipeline`: storage buffers at
+/// bindings `0..bindings` of set 0, and push constants of up to
+/// `push_constant_size` bytes.
+pub struct HarnessPipeline {
+    pipeline: OwnedPipeline,
+    pipeline_layout: OwnedPipelineLayout,
+    descriptor_set_layout: OwnedDescriptorSetLayout,
+    bindings: u32,
+}
+
+impl<'a> ComputeHarness<'a> {
+    pub fn new(context: &'a VulkanContext) -> Self {
+        Self { context }
+    }
+
+    /// A storage buffer holding `items`.
+    pub fn buffer<T: Pod>(&self, items: &[T]) -> Result<OwnedBuffer, VulkanDemoError> {
+        let buffer = self.storage_buffer(size_of_val(items))?;
+        buffer.write_slice(0, items)?;
+        Ok(buffer)
+    }
+
+    /// A storage buffer of `count` zeroed `T`s, for a shader to write into.
+    pub fn zeroed_buffer<T: Pod>(&self, count: usize) -> Result<OwnedBuffer, VulkanDemoError> {
+        self.buffer(&vec![T::zeroed(); count])
+    }
+
+    /// Compiles `source`, GLSL named `name` in diagnostics, into a pipeline
+    /// with `bindings` storage buffers and `push_constant_size` bytes of push
+    /// constants.
+    pub fn pipeline(
+        &self,
+        name: &str,
+        source: &str,
+        bindings: u32,
+        push_constant_size: u32,
+    ) -> Result<HarnessPipeline, VulkanDemoError> {
+        let device = &self.context.device;
+        let layout_bindings: Vec<_> = (0..bindings).map(|binding| {
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(binding)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        }).collect();
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(&layout_bindings);
+        let descriptor_set_layout = OwnedDescriptorSetLayout::new(
+            device,
+            unsafe { device.create_descriptor_set_layout(&layout_info, None)? },
+        );
+
+        let set_layouts = [descriptor_set_layout.handle()];
+        let push_constant_ranges = [vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .size(push_constant_size)];
+        let mut pipeline_layout_info = vk::PipelineLayoutCreateInfo::default().set_layouts(&set_layouts);
+        if push_constant_size > 0 {
+            pipeline_layout_info = pipeline_layout_info.push_constant_ranges(&push_constant_ranges);
+        }
+        let pipeline_layout = OwnedPipelineLayout::new(
+            device,
+            unsafe { device.create_pipeline_layout(&pipeline_layout_info, None)? },
+        );
+
+        let spirv = compile_shader(source, name, shaderc::ShaderKind::Compute, &ShaderCompileOptions::default())?;
+        let pipeline = create_compute_pipeline(device, self.context.pipeline_cache, pipeline_layout.handle(), &spirv, None)?;
+        self.context.debug.set_object_name(pipeline.handle(), name);
+        Ok(HarnessPipeline { pipeline, pipeline_layout, descriptor_set_layout, bindings })
+    }
+
+    /// Dispatches `groups` workgroups of `pipeline` once, with `buffers` at
+    /// its bindings in order, and waits for it to finish.
+    pub fn dispatch(
+        &self,
+        pipeline: &HarnessPipeline,
+        buffers: &[&OwnedBuffer],
+        push_constants: &[u8],
+        groups: [u32; 3],
+    ) -> Result<(), VulkanDemoError> {
+        assert_eq!(buffers.len() as u32, pipeline.bindings, "buffers for every binding of the pipeline");
+        let device = &self.context.device;
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(pipeline.bindings.max(1))];
+        let pool_info = vk::DescriptorPoolCreateInfo::default()
+            .pool_sizes(&pool_sizes)
+            .max_sets(1);
+        let descriptor_pool = OwnedDescriptorPool::new(
+            device,
+            unsafe { device.create_descriptor_pool(&pool_info, None)? },
+        );
+
+        let set_layouts = [pipeline.descriptor_set_layout.handle()];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool.handle())
+            .set_layouts(&set_layouts);
+        let descriptor_set = unsafe { device.allocate_descriptor_sets(&alloc_info)? }[0];
+
+        let infos: Vec<_> = buffers.iter().map(|buffer| {
+            vk::DescriptorBufferInfo::default()
+                .buffer(buffer.handle())
+                .offset(0)
+                .range(vk::WHOLE_SIZE)
+        }).collect();
+        let writes: Vec<_> = infos.iter().zip(0..).map(|(info, binding)| {
+            vk::WriteDescriptorSet::default()
+                .dst_set(descriptor_set)
+                .dst_binding(binding)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(std::slice::from_ref(info))
+        }).collect();
+        unsafe { device.update_descriptor_sets(&writes, &[]) };
+
+        let layout = pipeline.pipeline_layout.handle();
+        self.run(|cmd| unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, pipeline.pipeline.handle());
+            device.cmd_bind_descriptor_sets(cmd, vk::PipelineBindPoint::COMPUTE, layout, 0, &[descriptor_set], &[]);
+            if !push_constants.is_empty() {
+                device.cmd_push_constants(cmd, layout, vk::ShaderStageFlags::COMPUTE, 0, push_constants);
+            }
+            device.cmd_dispatch(cmd, groups[0], groups[1], groups[2]);
+        })
+    }
+
+    /// Submits the compute work `record` records and waits for it, with its
+    /// shader writes made visible to the host.
+    pub fn run(&self, record: impl FnOnce(vk::CommandBuffer)) -> Result<(), VulkanDemoError> {
+        let device = &self.context.device;
+        self.context.one_time_submit(|cmd| unsafe {
+            // Host writes are made visible by the submission itself.
+            record(cmd);
+            memory_barrier(
+                device,
+                cmd,
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+                (vk::PipelineStageFlags::HOST, vk::AccessFlags::HOST_READ),
+            );
+        })?;
+        Ok(())
+    }
+
+    /// The first `count` `T`s of `buffer`, one made by this harness.
+    pub fn read<T: Pod>(&self, buffer: &OwnedBuffer, count: usize) -> Result<Vec<T>, VulkanDemoError> {
+        let bytes = read_from_buffer(buffer, count * size_of::<T>())?;
+        Ok(bytes.chunks_exact(size_of::<T>()).map(bytemuck::pod_read_unaligned).collect())
+    }
+
+    fn storage_buffer(&self, size: usize) -> Result<OwnedBuffer, VulkanDemoError> {
+        // Zero-sized buffers are invalid; an empty slice still gets one element's worth.
+        let size = size.max(size_of::<u32>()) as vk::DeviceSize;
+        let buffer = create_buffer(
+            self.context,
+            size,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST,
+            MemoryLocation::CpuToGpu,
+        )?;
+        self.context.debug.set_object_name(buffer.handle(), "harness buffer");
+        Ok(buffer)
+    }
+}
+
+/// A headless context for tests that need a GPU, or `None`, logged, when the
+/// machine has no Vulkan device, so those tests pass vacuously there.
+#[cfg(test)]
+pub(crate) fn test_context() -> Option<VulkanContext> {
+    match VulkanContext::new_headless(None, Some(false), &crate::device_features::FeatureRequest::default()) {
+        Ok(context) => Some(context),
+        Err(e) => {
+            eprintln!("skipping: no Vulkan device ({e})");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autotune::DispatchTuning;
+    use crate::cpu_sim::SimBackend;
+    use crate::emitter::EmitterConfig;
+    use crate::particles::{
+        BoundaryMode, Particle, ParticleFormat, ParticleSystem, SimPipelines, SimPushConstants, SimulationMode, WORLD_HALF_EXTENTS,
+    };
+
+    const DT: f32 = 0.1;
+
+    /// A live kind-0 particle at `pos` going at `vel`, which won't die this step.
+    fn particle(pos: [f32; 3], vel: [f32; 3]) -> Particle {
+        Particle {
+            pos,
+            size: 4.0,
+            vel,
+            mass: 1.0,
+            color: [1.0; 4],
+            life: 10.0,
+            max_life: 10.0,
+            id: 0,
+            kind: 0,
+        }
+    }
+
+    /// `particle` after one simple-mode step of `DT` through `particle.comp`,
+    /// with `configure` applied to the system first.
+    fn step(
+        context: &VulkanContext,
+        particle: Particle,
+        configure: impl FnOnce(&mut ParticleSystem) -> Result<(), VulkanDemoError>,
+    ) -> Result<Particle, VulkanDemoError> {
+        let pipelines = SimPipelines::new(context, ParticleFormat::default(), DispatchTuning::default_for(context), false)?;
+        let mut system = ParticleSystem::new(context, &pipelines, 1, 0, &EmitterConfig::default(), false, SimBackend::Gpu)?;
+        system.set_mode(SimulationMode::Simple);
+        system.restore(context, &pipelines, &[particle])?;
+        system.finish_upload(context)?;
+        configure(&mut system)?;
+        system.set_frame_in_flight(0)?;
+
+        let push_constants = SimPushConstants { dt: DT, ..SimPushConstants::default() };
+        ComputeHarness::new(context).run(|cmd| {
+            system.record_prepass(&context.device, cmd, &pipelines.scan, 0);
+            system.record_update(&context.device, cmd, &pipelines, 0, &push_constants);
+        })?;
+        system.advance(1);
+        Ok(system.snapshot(context)?[0])
+    }
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-5, "{actual:?} is not {expected:?}");
+        }
+    }
+
+    #[test]
+    fn integrates_position() {
+        let Some(context) = test_context() else { return };
+        let stepped = step(&context, particle([0.1, -0.2, 0.0], [0.5, 0.25, 0.0]), |_| Ok(())).unwrap();
+        assert_close(stepped.pos, [0.1 + 0.5 * DT, -0.2 + 0.25 * DT, 0.0]);
+        assert_close(stepped.vel, [0.5, 0.25, 0.0]);
+    }
+
+    #[test]
+    fn bounce_reflects_velocity_with_restitution() {
+        let Some(context) = test_context() else { return };
+        let edge = WORLD_HALF_EXTENTS[0];
+        let start = particle([edge - 0.01, 0.0, 0.0], [1.0, 0.0, 0.0]);
+        let stepped = step(&context, start, |system| system.set_boundary_mode(BoundaryMode::Bounce { restitution: 0.5 })).unwrap();
+        assert_close(stepped.vel, [-0.5, 0.0, 0.0]);
+        assert_close(stepped.pos, [edge, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn drag_reduces_speed() {
+        let Some(context) = test_context() else { return };
+        let drag = 1.0;
+        let stepped = step(&context, particle([0.0; 3], [0.6, 0.8, 0.0]), |system| system.set_drag(drag)).unwrap();
+        let speed = stepped.vel.iter().map(|v| v * v).sum::<f32>().sqrt();
+        assert!((speed - (-drag * DT).exp()).abs() < 1e-5, "speed {speed} after drag");
+    }
+}
@@ -0,0 +1,277 @@
+use ash::{vk, Device};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use crate::error::VulkanDemoError;
+use crate::particles::create_compute_pipeline;
+use crate::pipeline_utils::{compile_shader, specialization_info, ShaderCompileOptions, ShaderInterface, SpecializationConstants};
+use crate::resources::{OwnedDescriptorPool, OwnedDescriptorSetLayout, OwnedPipeline, OwnedPipelineLayout};
+use crate::vulkan_context::VulkanContext;
+
+// What each pool of the shared descriptor pool holds; another is added when
+// one runs out.
+const POOL_MAX_SETS: u32 = 256;
+const POOL_STORAGE_BUFFERS: u32 = 2048;
+const POOL_UNIFORM_BUFFERS: u32 = 256;
+
+/// The kind of buffer a compute shader binds at one binding of set 0.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BufferBinding {
+    Storage,
+    Uniform,
+}
+
+impl BufferBinding {
+    pub fn descriptor_type(self) -> vk::DescriptorType {
+        match self {
+            Self::Storage => vk::DescriptorType::STORAGE_BUFFER,
+            Self::Uniform => vk::DescriptorType::UNIFORM_BUFFER,
+        }
+    }
+}
+
+/// A compute pipeline layout of one descriptor set of buffers and an optional
+/// push constant block, which any number of pipelines can share.
+pub struct ComputeLayout {
+    pub pipeline_layout: OwnedPipelineLayout,
+    pub set_layout: OwnedDescriptorSetLayout,
+    /// The type of every binding of the set, to write descriptors with.
+    bindings: BTreeMap<u32, vk::DescriptorType>,
+}
+
+impl ComputeLayout {
+    /// Set 0 holds `bindings`, and `push_constant_size` bytes are pushed from
+    /// offset 0, if any.
+    pub fn new(device: &Arc<Device>, bindings: &[(u32, BufferBinding)], push_constant_size: u32) -> Result<Self, vk::Result> {
+        let layout_bindings: Vec<_> = bindings.iter().map(|&(binding, kind)| {
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(binding)
+                .descriptor_type(kind.descriptor_type())
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        }).collect();
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(&layout_bindings);
+        let set_layout = OwnedDescriptorSetLayout::new(device, unsafe { device.create_descriptor_set_layout(&layout_info, None)? });
+        let push_constant_ranges: Vec<_> = (push_constant_size > 0)
+            .then(|| vk::PushConstantRange::default().stage_flags(vk::ShaderStageFlags::COMPUTE).size(push_constant_size))
+            .into_iter()
+            .collect();
+        let bindings = bindings.iter().map(|&(binding, kind)| (binding, kind.descriptor_type())).collect();
+        Self::with_set_layout(device, set_layout, &push_constant_ranges, bindings)
+    }
+
+    /// Set 0 and the push constants as `interface` reflects them.
+    pub fn from_interface(device: &Arc<Device>, interface: &ShaderInterface) -> Result<Self, vk::Result> {
+        let set_layout = interface.create_set_layout(device, 0)?;
+        let bindings = interface
+            .set_layout_bindings(0)
+            .iter()
+            .map(|binding| (binding.binding, binding.descriptor_type))
+            .collect();
+        Self::with_set_layout(device, set_layout, interface.push_constant_ranges(), bindings)
+    }
+
+    fn with_set_layout(
+        device: &Arc<Device>,
+        set_layout: OwnedDescriptorSetLayout,
+        push_constant_ranges: &[vk::PushConstantRange],
+        bindings: BTreeMap<u32, vk::DescriptorType>,
+    ) -> Result<Self, vk::Result> {
+        let set_layouts = [set_layout.handle()];
+        let layout_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(push_constant_ranges);
+        let pipeline_layout = OwnedPipelineLayout::new(device, unsafe { device.create_pipeline_layout(&layout_info, None)? });
+        Ok(Self { pipeline_layout, set_layout, bindings })
+    }
+}
+
+/// The descriptor pool every `DescriptorSets` allocates from, through
+/// `VulkanContext::descriptor_pool`. Grows by another pool of the same size
+/// when the last one is out of space.
+pub struct SharedDescriptorPool {
+    device: Arc<Device>,
+    pools: Mutex<Vec<OwnedDescriptorPool>>,
+}
+
+impl SharedDescriptorPool {
+    pub fn new(device: &Arc<Device>) -> Result<Self, vk::Result> {
+        let pool = Self::create_pool(device)?;
+        Ok(Self { device: Arc::clone(device), pools: Mutex::new(vec![pool]) })
+    }
+
+    fn create_pool(device: &Arc<Device>) -> Result<OwnedDescriptorPool, vk::Result> {
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default().ty(vk::DescriptorType::STORAGE_BUFFER).descriptor_count(POOL_STORAGE_BUFFERS),
+            vk::DescriptorPoolSize::default().ty(vk::DescriptorType::UNIFORM_BUFFER).descriptor_count(POOL_UNIFORM_BUFFERS),
+        ];
+        // Sets go back one by one as their owners drop.
+        let pool_info = vk::DescriptorPoolCreateInfo::default()
+            .flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
+            .pool_sizes(&pool_sizes)
+            .max_sets(POOL_MAX_SETS);
+        Ok(OwnedDescriptorPool::new(device, unsafe { device.create_descriptor_pool(&pool_info, None)? }))
+    }
+
+    /// `count` sets of `set_layout` from the newest pool, or from a new one if
+    /// it has no room, with the pool they came from.
+    fn allocate(&self, set_layout: vk::DescriptorSetLayout, count: usize) -> Result<(vk::DescriptorPool, Vec<vk::DescriptorSet>), vk::Result> {
+        let mut pools = self.pools.lock().unwrap_or_else(PoisonError::into_inner);
+        let set_layouts = vec![set_layout; count];
+        let allocate = |pool: vk::DescriptorPool| {
+            let alloc_info = vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(pool)
+                .set_layouts(&set_layouts);
+            unsafe { self.device.allocate_descriptor_sets(&alloc_info) }
+        };
+        let newest = pools.last().expect("the shared pool starts with one").handle();
+        match allocate(newest) {
+            Ok(sets) => Ok((newest, sets)),
+            Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY | vk::Result::ERROR_FRAGMENTED_POOL) => {
+                log::debug!("Descriptor pool {} is full, adding another", pools.len());
+                let pool = Self::create_pool(&self.device)?;
+                let handle = pool.handle();
+                pools.push(pool);
+                Ok((handle, allocate(handle)?))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn free(&self, pool: vk::DescriptorPool, sets: &[vk::DescriptorSet]) {
+        let _pools = self.pools.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = unsafe { self.device.free_descriptor_sets(pool, sets) } {
+            log::warn!("Failed to free {} descriptor sets: {e}", sets.len());
+        }
+    }
+}
+
+/// Descriptor sets of one `ComputeLayout`, allocated from the context's
+/// shared pool and returned to it on drop. Their buffers can be rebound any
+/// time the sets aren't in use by a pending command buffer.
+pub struct DescriptorSets {
+    pool: Arc<SharedDescriptorPool>,
+    pool_handle: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>,
+    bindings: BTreeMap<u32, vk::DescriptorType>,
+}
+
+impl DescriptorSets {
+    pub fn allocate(context: &VulkanContext, layout: &ComputeLayout, count: usize) -> Result<Self, vk::Result> {
+        let pool = Arc::clone(&context.descriptor_pool);
+        let (pool_handle, sets) = pool.allocate(layout.set_layout.handle(), count)?;
+        Ok(Self { pool, pool_handle, sets, bindings: layout.bindings.clone() })
+    }
+
+    pub fn set(&self, index: usize) -> vk::DescriptorSet {
+        self.sets[index]
+    }
+
+    /// Points `bindings` of set `index` at the whole of their buffers, each
+    /// written as the type its layout declares.
+    pub fn bind_buffers(&self, device: &Device, index: usize, bindings: &[(u32, vk::Buffer)]) {
+        let infos: Vec<_> = bindings.iter().map(|&(_, buffer)| {
+            vk::DescriptorBufferInfo::default()
+                .buffer(buffer)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)
+        }).collect();
+        let writes: Vec<_> = bindings.iter().zip(&infos).map(|(&(binding, _), info)| {
+            vk::WriteDescriptorSet::default()
+                .dst_set(self.sets[index])
+                .dst_binding(binding)
+                .descriptor_type(self.bindings[&binding])
+                .buffer_info(std::slice::from_ref(info))
+        }).collect();
+        unsafe { device.update_descriptor_sets(&writes, &[]) };
+    }
+}
+
+impl Drop for DescriptorSets {
+    fn drop(&mut self) {
+        self.pool.free(self.pool_handle, &self.sets);
+    }
+}
+
+/// Where a `ComputePass` gets its shader from.
+pub enum ComputeShader<'a> {
+    Glsl { source: &'a str, filename: &'a str, options: &'a ShaderCompileOptions },
+    Spirv(&'a [u32]),
+}
+
+/// A compute shader's pipeline, its layout and the descriptor sets it is
+/// dispatched with: everything a pass of buffers in and out needs.
+pub struct ComputePass {
+    pipeline: OwnedPipeline,
+    layout: ComputeLayout,
+    descriptors: DescriptorSets,
+}
+
+impl ComputePass {
+    /// Builds `shader` with a layout of `bindings` and `push_constant_size`
+    /// bytes of push constants, checked against what it declares, and
+    /// allocates `sets` descriptor sets for it.
+    pub fn new<T: SpecializationConstants>(
+        context: &VulkanContext,
+        shader: ComputeShader,
+        bindings: &[(u32, BufferBinding)],
+        push_constant_size: u32,
+        specialization: Option<&T>,
+        sets: usize,
+    ) -> Result<Self, VulkanDemoError> {
+        let device = &context.device;
+        let spirv = match shader {
+            ComputeShader::Glsl { source, filename, options } => compile_shader(source, filename, shaderc::ShaderKind::Compute, options)?,
+            ComputeShader::Spirv(spirv) => spirv.to_vec(),
+        };
+        let interface = ShaderInterface::reflect(&[(vk::ShaderStageFlags::COMPUTE, &spirv)])?;
+        for &(binding, kind) in bindings {
+            interface.expect_binding(0, binding, kind.descriptor_type())?;
+        }
+        let declared = interface.push_constant_ranges().first().map_or(0, |range| range.size);
+        if declared != push_constant_size {
+            return Err(VulkanDemoError::ShaderInterface(format!(
+                "{push_constant_size} bytes of push constants are pushed but the shader's span {declared}",
+            )));
+        }
+
+        let layout = ComputeLayout::new(device, bindings, push_constant_size)?;
+        let specialization = specialization.map(specialization_info);
+        let pipeline = create_compute_pipeline(
+            device,
+            context.pipeline_cache,
+            layout.pipeline_layout.handle(),
+            &spirv,
+            specialization.as_ref(),
+        )?;
+        let descriptors = DescriptorSets::allocate(context, &layout, sets)?;
+        Ok(Self { pipeline, layout, descriptors })
+    }
+
+    pub fn pipeline(&self) -> vk::Pipeline {
+        self.pipeline.handle()
+    }
+
+    pub fn descriptors(&self) -> &DescriptorSets {
+        &self.descriptors
+    }
+
+    /// `DescriptorSets::bind_buffers` on this pass's sets.
+    pub fn bind_buffers(&self, device: &Device, set: usize, bindings: &[(u32, vk::Buffer)]) {
+        self.descriptors.bind_buffers(device, set, bindings);
+    }
+
+    /// Records dispatching `group_counts` workgroups with descriptor set `set`,
+    /// pushing `push_bytes` first if there are any.
+    pub fn record(&self, device: &Device, cmd: vk::CommandBuffer, set: usize, group_counts: [u32; 3], push_bytes: &[u8]) {
+        let layout = self.layout.pipeline_layout.handle();
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.pipeline.handle());
+            device.cmd_bind_descriptor_sets(cmd, vk::PipelineBindPoint::COMPUTE, layout, 0, &[self.descriptors.set(set)], &[]);
+            if !push_bytes.is_empty() {
+                device.cmd_push_constants(cmd, layout, vk::ShaderStageFlags::COMPUTE, 0, push_bytes);
+            }
+            let [x, y, z] = group_counts;
+            device.cmd_dispatch(cmd, x, y, z);
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use clap::Parser;
+use std::path::PathBuf;
+use crate::background::Background;
+use crate::bloom::{DEFAULT_BLOOM_INTENSITY, DEFAULT_BLOOM_THRESHOLD};
+use crate::app::BackgroundSim;
+use crate::emitter::{EmitterConfig, EmitterPreset};
+use crate::golden::{GoldenTolerance, DEFAULT_CHANNEL_TOLERANCE, DEFAULT_MAX_DIFFERING_PIXELS};
+use crate::cpu_sim::SimBackend;
+use crate::particles::{ParticleFormat, ParticleLayout, ParticlePrecision, SimulationMode, DEFAULT_FLOCKING_WEIGHTS, MAX_PARTICLE_KINDS};
+use crate::renderer::{PresentMode, DEFAULT_EDGE_SOFTNESS, DEFAULT_IMAGE_COUNT, DEFAULT_TRAIL_STRENGTH};
+use crate::secondary_commands::DEFAULT_MAX_THREADS;
+use crate::spatial_grid::{DEFAULT_GRID_SIZE, MAX_GRID_SIZE};
+use crate::step_clock::{DEFAULT_MAX_STEPS, DEFAULT_STEP_DT};
+
+/// Largest particle count a single 1D dispatch of 256-wide workgroups can cover.
+pub const MAX_PARTICLES: u32 = 65_535 * 256;
+pub const DEFAULT_PARTICLES: u32 = 10_000;
+const MAX_DIMENSION: u32 = 16_384;
+
+/// Command-line settings for the demo.
+#[derive(Parser, Clone, Debug)]
+#[command(version, about = "GPU particle simulation on Vulkan compute")]
+pub struct AppConfig {
+    /// Number of particles to simulate.
+    #[arg(long, default_value_t = DEFAULT_PARTICLES, value_parser = clap::value_parser!(u32).range(1..=MAX_PARTICLES as i64))]
+    pub particles: u32,
+
+    /// Window (or off-screen image) width in pixels.
+    #[arg(long, default_value_t = 800, value_parser = clap::value_parser!(u32).range(1..=MAX_DIMENSION as i64))]
+    pub width: u32,
+
+    /// Window (or off-screen image) height in pixels.
+    #[arg(long, default_value_t = 600, value_parser = clap::value_parser!(u32).range(1..=MAX_DIMENSION as i64))]
+    pub height: u32,
+
+    /// Start in borderless fullscreen, on the monitor with this index or on the
+    /// current one. F11 toggles fullscreen at runtime.
+    #[arg(long, value_name = "MONITOR", conflicts_with = "headless")]
+    pub fullscreen: Option<Option<usize>>,
+
+    /// With `--fullscreen`, switch the monitor to `--width` x `--height` instead
+    /// of borderless, if it has such a video mode.
+    #[arg(long, requires = "fullscreen")]
+    pub exclusive: bool,
+
+    /// Open a second window showing the same simulation through a renderer of
+    /// its own. Closing either window leaves the other running.
+    #[arg(long, conflicts_with_all = ["headless", "record"])]
+    pub second_window: bool,
+
+    /// Initial simulation; cycle through the modes at runtime with N.
+    #[arg(long, value_enum, default_value_t = SimulationMode::Simple)]
+    pub mode: SimulationMode,
+
+    /// Simulate in a 3D cube under a perspective camera instead of the flat plane.
+    /// Left-drag or the arrow keys orbit the camera, middle-drag pans and the
+    /// scroll wheel zooms.
+    #[arg(long = "3d")]
+    pub three_d: bool,
+
+    /// Where particles spawn; cycle through the presets at runtime with E.
+    #[arg(long, value_enum, default_value_t = EmitterPreset::Spray)]
+    pub emitter: EmitterPreset,
+
+    /// Relative shares of each particle kind, comma-separated: 70,30 makes 70%
+    /// of the particles kind 0, which feels gravity, and 30% kind 1, which
+    /// drifts with the noise field. Mixing kinds colors particles by kind;
+    /// C cycles the color mode.
+    #[arg(long, value_name = "WEIGHTS", default_value = "1", value_parser = parse_kind_mix)]
+    pub kind_mix: [f32; MAX_PARTICLE_KINDS],
+
+    /// Multiplier for every particle's point size; adjust at runtime with +/-.
+    #[arg(long, value_name = "SCALE", default_value_t = 1.0)]
+    pub point_size: f32,
+
+    /// Fraction of a particle's radius over which its edge fades out, from 0 to 1.
+    #[arg(long, value_name = "FRACTION", default_value_t = DEFAULT_EDGE_SOFTNESS)]
+    pub edge_softness: f32,
+
+    /// Cells along each axis of the spatial grid used for neighbor repulsion.
+    #[arg(long, default_value_t = DEFAULT_GRID_SIZE, value_parser = clap::value_parser!(u32).range(1..=MAX_GRID_SIZE as i64))]
+    pub grid_size: u32,
+
+    /// Start with two circular obstacles for the particles to bounce off.
+    /// Toggle at runtime with O.
+    #[arg(long)]
+    pub obstacles: bool,
+
+    /// Strength of the short-range push between nearby particles; 0 disables it.
+    /// Toggle at runtime with X.
+    #[arg(long, value_name = "STRENGTH", default_value_t = 0.0)]
+    pub repulsion: f32,
+
+    /// Boids weight for steering away from crowding neighbors; cycle with 1.
+    #[arg(long, value_name = "WEIGHT", default_value_t = DEFAULT_FLOCKING_WEIGHTS[0])]
+    pub separation: f32,
+
+    /// Boids weight for matching the neighbors' heading; cycle with 2.
+    #[arg(long, value_name = "WEIGHT", default_value_t = DEFAULT_FLOCKING_WEIGHTS[1])]
+    pub alignment: f32,
+
+    /// Boids weight for steering toward the neighbors' center; cycle with 3.
+    #[arg(long, value_name = "WEIGHT", default_value_t = DEFAULT_FLOCKING_WEIGHTS[2])]
+    pub cohesion: f32,
+
+    /// Draw particles as this PNG, tinted by their color, instead of discs.
+    /// P cycles back to the disc and square shapes.
+    #[arg(long, value_name = "PNG")]
+    pub sprite: Option<PathBuf>,
+
+    /// Color behind the particles as comma-separated sRGB components from 0 to 1.
+    #[arg(long, value_name = "R,G,B", default_value = "0,0,0", value_parser = parse_color)]
+    pub clear_color: [f32; 3],
+
+    /// What to draw behind the particles; the gradients start from `--clear-color`.
+    #[arg(long, value_enum, default_value_t = Background::Solid)]
+    pub background: Background,
+
+    /// Fade the previous frame out instead of clearing it, so particles leave
+    /// trails. Toggle at runtime with T.
+    #[arg(long)]
+    pub trails: bool,
+
+    /// Fraction of the previous frame kept each frame with trails on; higher
+    /// values give longer trails. Capped at 0.99.
+    #[arg(long, value_name = "STRENGTH", default_value_t = DEFAULT_TRAIL_STRENGTH)]
+    pub trail_strength: f32,
+
+    /// Sort particles far to near before drawing them, so alpha blending layers
+    /// them in order. Toggle at runtime with Z.
+    #[arg(long)]
+    pub depth_sort: bool,
+
+    /// Make bright regions glow, best with additive blending. Toggle at runtime with L.
+    #[arg(long, conflicts_with = "headless")]
+    pub bloom: bool,
+
+    /// Brightness, from 0 to 1, above which the scene glows with bloom on.
+    #[arg(long, value_name = "BRIGHTNESS", default_value_t = DEFAULT_BLOOM_THRESHOLD)]
+    pub bloom_threshold: f32,
+
+    /// How strongly bloom adds the glow back onto the scene.
+    #[arg(long, value_name = "INTENSITY", default_value_t = DEFAULT_BLOOM_INTENSITY)]
+    pub bloom_intensity: f32,
+
+    /// Resolution particles are rendered at relative to the window, from 0.25
+    /// to 2; the result is scaled to the window with linear filtering.
+    #[arg(long, value_name = "SCALE", default_value_t = 1.0, conflicts_with = "headless")]
+    pub render_scale: f32,
+
+    /// Presentation strategy; falls back to what the surface supports.
+    #[arg(long, value_enum, default_value_t = PresentMode::Fifo)]
+    pub present_mode: PresentMode,
+
+    /// Redraw at most this many times a second while the window is unfocused,
+    /// instead of as fast as presenting allows. Covered windows don't redraw at all.
+    #[arg(long, value_name = "FPS", value_parser = parse_positive, conflicts_with = "headless")]
+    pub background_fps: Option<f32>,
+
+    /// What the simulation does while the window is covered, or unfocused with
+    /// `--background-fps`: `clamp` simulates the time between frames as usual,
+    /// at most 0.1 s of it per frame, and `freeze` stops until the window is back.
+    #[arg(long, value_enum, default_value_t = BackgroundSim::Clamp)]
+    pub background_sim: BackgroundSim,
+
+    /// Swapchain images to ask for: 2 for the least latency, 3 for throughput.
+    /// Clamped to what the surface supports.
+    #[arg(long, value_name = "COUNT", default_value_t = DEFAULT_IMAGE_COUNT, value_parser = clap::value_parser!(u32).range(2..=8))]
+    pub swapchain_images: u32,
+
+    /// Use this adapter from the Vulkan device list instead of the best-ranked one.
+    /// Overrides `VK_DEVICE_INDEX`.
+    #[arg(long)]
+    pub gpu_index: Option<usize>,
+
+    /// Seed for the initial particle layout and respawns; with `--fixed-dt`
+    /// runs with the same seed simulate identically. Defaults to one derived from the time.
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Advance the simulation by this many seconds every frame instead of the
+    /// measured frame time. Headless runs default to 1/60.
+    #[arg(long, value_name = "SECONDS", value_parser = parse_positive)]
+    pub fixed_dt: Option<f32>,
+
+    /// Length of one simulation step. Each frame takes as many steps as its
+    /// time covers, so the simulation evolves the same at any frame rate.
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_STEP_DT, value_parser = parse_positive)]
+    pub substep_dt: f32,
+
+    /// Most steps per frame; time beyond them is dropped after a stall.
+    #[arg(long, default_value_t = DEFAULT_MAX_STEPS, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_substeps: u32,
+
+    /// Where to run the simulation. `cpu` is a reference implementation of the
+    /// simple mode; `verify` runs it next to the GPU and logs how far they drift.
+    #[arg(long, value_enum, default_value_t = SimBackend::Gpu)]
+    pub sim: SimBackend,
+
+    /// How particle buffers order each particle's data: interleaved (`aos`), or
+    /// one array per attribute (`soa`), which fetches less when drawing.
+    #[arg(long, value_enum, default_value_t = ParticleLayout::Aos)]
+    pub layout: ParticleLayout,
+
+    /// What particle buffers store velocity, mass and color as. `f16` makes
+    /// each particle 48 bytes instead of 64.
+    #[arg(long, value_enum, default_value_t = ParticlePrecision::F32)]
+    pub precision: ParticlePrecision,
+
+    /// Time the particle update with every workgroup size and particles per
+    /// invocation the device allows, and run with the fastest. The winner is
+    /// cached per device and driver in `dispatch_tuning.toml` beside the
+    /// pipeline cache, so later runs with the flag skip the timing.
+    #[arg(long)]
+    pub autotune: bool,
+
+    /// Have the simple and curl-noise modes read and write particles through
+    /// buffer device addresses pushed with every step, instead of through
+    /// descriptors. Falls back to descriptors, with a warning, on devices
+    /// without `bufferDeviceAddress`.
+    #[arg(long)]
+    pub bda: bool,
+
+    /// Threads recording the scene pass into secondary command buffers when
+    /// there's more than one particle system; 1 records it all on the main
+    /// thread. Defaults to one per core, up to 8.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=64))]
+    pub record_threads: Option<u32>,
+
+    /// Set up the particle systems and how they're drawn from this TOML scene
+    /// instead of the flags covering the same things; see `ScenePreset`.
+    #[arg(long, value_name = "TOML")]
+    pub scene: Option<PathBuf>,
+
+    /// Write the scene as it is on exit to this TOML file, loadable with
+    /// `--scene`. F6 also writes it at any time.
+    #[arg(long, value_name = "TOML", conflicts_with = "headless")]
+    pub dump_scene: Option<PathBuf>,
+
+    /// Start from a particle snapshot saved with F5. F5 and F9 also save to and
+    /// load from this file instead of `snapshot.bin`.
+    #[arg(long, value_name = "FILE")]
+    pub load: Option<PathBuf>,
+
+    /// Force the Khronos validation layer on or off. Overrides `VALIDATION`;
+    /// defaults to on in debug builds.
+    #[arg(long, value_name = "on|off", value_parser = parse_on_off)]
+    pub validation: Option<bool>,
+
+    /// Load shaders from this directory and reload them when they change.
+    #[arg(long, value_name = "DIR")]
+    pub shader_dir: Option<PathBuf>,
+
+    /// Render off-screen without a window and print frame time statistics.
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Number of frames to render in headless mode.
+    #[arg(long, default_value_t = 1000, requires = "headless", value_parser = clap::value_parser!(u32).range(1..))]
+    pub frames: u32,
+
+    /// Save the last headless frame to this PNG file.
+    #[arg(long, value_name = "PNG", requires = "headless")]
+    pub output: Option<PathBuf>,
+
+    /// Compare the last headless frame against this reference PNG and fail if
+    /// it differs, saving where next to it. With `BLESS_GOLDEN` set, overwrite
+    /// the reference instead.
+    #[arg(long, value_name = "PNG", requires_all = ["headless", "seed"])]
+    pub golden: Option<PathBuf>,
+
+    /// Per-channel difference from the `--golden` reference that still counts as equal.
+    #[arg(long, value_name = "LEVELS", default_value_t = DEFAULT_CHANNEL_TOLERANCE, requires = "golden")]
+    pub golden_tolerance: u8,
+
+    /// Pixels allowed to differ from the `--golden` reference by more than `--golden-tolerance`.
+    #[arg(long, value_name = "PIXELS", default_value_t = DEFAULT_MAX_DIFFERING_PIXELS, requires = "golden")]
+    pub golden_max_pixels: usize,
+
+    /// Instead of the benchmark, step the same particles in `f32` and `f16`
+    /// for `--frames` frames and print how far apart they end up.
+    #[arg(long, requires = "headless")]
+    pub compare_precision: bool,
+
+    /// Instead of the benchmark, step the same particles through descriptors
+    /// and through buffer device addresses for `--frames` frames and print how
+    /// far apart they end up, which should be not at all.
+    #[arg(long, requires = "headless", conflicts_with = "compare_precision")]
+    pub compare_bda: bool,
+
+    /// Write every rendered frame to this directory as numbered PNGs, stepping
+    /// the simulation at a fixed 60 fps, and exit after `--record-frames`.
+    #[arg(long, value_name = "DIR", conflicts_with = "headless")]
+    pub record: Option<PathBuf>,
+
+    /// Always compile shaders with shaderc instead of reusing SPIR-V cached from
+    /// earlier runs.
+    #[arg(long)]
+    pub no_shader_cache: bool,
+
+    /// Append frame statistics to this CSV file, one row per second.
+    #[arg(long, value_name = "CSV", conflicts_with = "headless")]
+    pub stats_csv: Option<PathBuf>,
+
+    /// Number of frames to write with `--record`.
+    #[arg(long, default_value_t = 600, requires = "record", value_parser = clap::value_parser!(u32).range(1..))]
+    pub record_frames: u32,
+}
+
+impl AppConfig {
+    /// `--layout` and `--precision` together.
+    pub fn particle_format(&self) -> ParticleFormat {
+        ParticleFormat { layout: self.layout, precision: self.precision }
+    }
+
+    /// `--emitter` with the `--kind-mix` weights.
+    pub fn emitter_config(&self) -> EmitterConfig {
+        EmitterConfig { kind_weights: self.kind_mix, ..self.emitter.config() }
+    }
+
+    /// `--golden-tolerance` and `--golden-max-pixels` together.
+    pub fn golden_tolerance(&self) -> GoldenTolerance {
+        GoldenTolerance { channel: self.golden_tolerance, max_differing_pixels: self.golden_max_pixels }
+    }
+
+    /// `--record-threads`, or its default for this machine.
+    pub fn record_threads(&self) -> usize {
+        match self.record_threads {
+            Some(threads) => threads as usize,
+            None => std::thread::available_parallelism().map_or(1, usize::from).min(DEFAULT_MAX_THREADS),
+        }
+    }
+}
+
+fn parse_positive(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(number) if number > 0.0 => Ok(number),
+        Ok(_) => Err("must be greater than 0".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_color(value: &str) -> Result<[f32; 3], String> {
+    let components = value
+        .split(',')
+        .map(|component| component.trim().parse::<f32>().map_err(|e| format!("{component:?}: {e}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    match <[f32; 3]>::try_from(components) {
+        Ok(color) if color.iter().all(|c| (0.0..=1.0).contains(c)) => Ok(color),
+        Ok(_) => Err("components must be between 0 and 1".to_string()),
+        Err(components) => Err(format!("expected 3 components, got {}", components.len())),
+    }
+}
+
+fn parse_kind_mix(value: &str) -> Result<[f32; MAX_PARTICLE_KINDS], String> {
+    let weights = value
+        .split(',')
+        .map(|weight| weight.trim().parse::<f32>().map_err(|e| format!("{weight:?}: {e}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    if weights.len() > MAX_PARTICLE_KINDS {
+        return Err(format!("at most {MAX_PARTICLE_KINDS} kinds, got {}", weights.len()));
+    }
+    if weights.iter().any(|weight| *weight < 0.0) || !weights.iter().any(|weight| *weight > 0.0) {
+        return Err("weights must not be negative, and at least one must be positive".to_string());
+    }
+    let mut mix = [0.0; MAX_PARTICLE_KINDS];
+    mix[..weights.len()].copy_from_slice(&weights);
+    Ok(mix)
+}
+
+fn parse_on_off(value: &str) -> Result<bool, String> {
+    match value {
+        "on" | "1" | "true" => Ok(true),
+        "off" | "0" | "false" => Ok(false),
+        _ => Err(format!("expected on or off, got {value}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ValueEnum;
+    use std::fmt::Debug;
+
+    fn parse(args: &[&str]) -> Result<AppConfig, clap::Error> {
+        AppConfig::try_parse_from(std::iter::once("vulkan-particle-demo").chain(args.iter().copied()))
+    }
+
+    /// Every value of `T` parses from its name after `flag` into the field `field` reads.
+    fn check_value_enum<T: ValueEnum + PartialEq + Debug>(flag: &str, field: impl Fn(&AppConfig) -> T) {
+        for value in T::value_variants() {
+            let name = value.to_possible_value().expect("no skipped variants").get_name().to_string();
+            let config = parse(&[flag, &name]).unwrap_or_else(|e| panic!("{flag} {name}: {e}"));
+            assert_eq!(&field(&config), value, "{flag} {name}");
+        }
+        assert!(parse(&[flag, "no-such-value"]).is_err(), "{flag} accepted an unknown value");
+    }
+
+    #[test]
+    fn defaults() {
+        let config = parse(&[]).unwrap();
+        assert_eq!(config.particles, DEFAULT_PARTICLES);
+        assert_eq!((config.width, config.height), (800, 600));
+        assert_eq!(config.mode, SimulationMode::Simple);
+        assert_eq!(config.emitter, EmitterPreset::Spray);
+        assert_eq!(config.background, Background::Solid);
+        assert_eq!(config.present_mode, PresentMode::Fifo);
+        assert_eq!(config.background_sim, BackgroundSim::Clamp);
+        assert_eq!(config.sim, SimBackend::Gpu);
+        assert_eq!(config.particle_format(), ParticleFormat { layout: ParticleLayout::Aos, precision: ParticlePrecision::F32 });
+        assert_eq!(config.grid_size, DEFAULT_GRID_SIZE);
+        assert!(!config.headless && !config.three_d);
+        assert_eq!((config.seed, config.fixed_dt), (None, None));
+    }
+
+    #[test]
+    fn value_enums() {
+        check_value_enum("--mode", |config| config.mode);
+        check_value_enum("--emitter", |config| config.emitter);
+        check_value_enum("--background", |config| config.background);
+        check_value_enum("--present-mode", |config| config.present_mode);
+        check_value_enum("--background-sim", |config| config.background_sim);
+        check_value_enum("--sim", |config| config.sim);
+        check_value_enum("--layout", |config| config.layout);
+        check_value_enum("--precision", |config| config.precision);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let invalid: [&[&str]; 9] = [
+            &["--particles", "0"],
+            &["--particles", &(MAX_PARTICLES as u64 + 1).to_string()],
+            &["--width", "0"],
+            &["--fixed-dt", "0"],
+            &["--fixed-dt", "-1"],
+            &["--clear-color", "0.5,0.5"],
+            &["--clear-color", "0.5,0.5,2"],
+            &["--kind-mix", "0,0"],
+            &["--frames", "10"],
+        ];
+        for args in invalid {
+            assert!(parse(args).is_err(), "accepted {args:?}");
+        }
+    }
+
+    #[test]
+    fn custom_parsers() {
+        let config = parse(&["--clear-color", "0.1, 0.2,0.3", "--kind-mix", "7,3"]).unwrap();
+        assert_eq!(config.clear_color, [0.1, 0.2, 0.3]);
+        assert_eq!(config.kind_mix, [7.0, 3.0, 0.0, 0.0]);
+        assert_eq!(parse_on_off("on"), Ok(true));
+        assert_eq!(parse_on_off("0"), Ok(false));
+        assert!(parse_on_off("maybe").is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use std::f32::consts::{PI, TAU};
+use std::fmt;
+use glam::{Vec2, Vec3};
+use crate::attractors::Attractor;
+use crate::obstacles::{Obstacle, ObstacleShape};
+use crate::particles::{BoundaryMode, ColorMode, Particle, SimParams, SimPushConstants, MAX_PARTICLE_KINDS};
+
+/// Where particles are stepped.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SimBackend {
+    /// Compute shaders.
+    #[default]
+    Gpu,
+    /// `step` on the host, uploaded into host-visible buffers every frame.
+    /// Covers the simple mode without neighbor repulsion.
+    Cpu,
+    /// Compute shaders, with `step` run alongside to report how far apart the
+    /// two end up.
+    Verify,
+}
+
+/// Advances `particles` by one step of `particle.comp` in `SimulationMode::Simple`,
+/// without neighbor repulsion. Returns the new state, live particles first and
+/// dead ones packed at the back like the shader leaves them, and the live count.
+pub fn step(
+    particles: &[Particle],
+    params: &SimParams,
+    push_constants: &SimPushConstants,
+    obstacles: &[Obstacle],
+    attractors: &[Attractor],
+) -> (Vec<Particle>, u32) {
+    let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+    let chunk_size = particles.len().div_ceil(threads).max(1);
+    let stepped: Vec<Particle> = std::thread::scope(|scope| {
+        let workers: Vec<_> = particles
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|particle| step_particle(particle, params, push_constants, obstacles, attractors))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        workers.into_iter().flat_map(|worker| worker.join().expect("CPU simulation thread panicked")).collect()
+    });
+
+    let live: Vec<_> = stepped.iter().filter(|particle| particle.life > 0.0).copied().collect();
+    let live_count = live.len() as u32;
+    let mut packed = live;
+    packed.extend(stepped.iter().rev().filter(|particle| particle.life <= 0.0));
+    (packed, live_count)
+}
+
+/// The largest differences between matching particles of two states, matched
+/// by `Particle::id` as the GPU doesn't keep them in order.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Divergence {
+    pub position: f32,
+    pub velocity: f32,
+    /// Particles of one state missing from the other.
+    pub unmatched: usize,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "max position divergence {:.3e}, max velocity divergence {:.3e}", self.position, self.velocity)?;
+        if self.unmatched > 0 {
+            write!(f, ", {} unmatched particles", self.unmatched)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares two states of the same particles, e.g. a GPU snapshot and `step`'s result.
+pub fn divergence(a: &[Particle], b: &[Particle]) -> Divergence {
+    let mut a: Vec<_> = a.to_vec();
+    let mut b: Vec<_> = b.to_vec();
+    a.sort_unstable_by_key(|particle| particle.id);
+    b.sort_unstable_by_key(|particle| particle.id);
+    let mut divergence = Divergence { unmatched: a.len().abs_diff(b.len()), ..Divergence::default() };
+    for (a, b) in a.iter().zip(&b) {
+        if a.id != b.id {
+            divergence.unmatched += 1;
+            continue;
+        }
+        divergence.position = divergence.position.max((Vec3::from(a.pos) - Vec3::from(b.pos)).abs().max_element());
+        divergence.velocity = divergence.velocity.max((Vec3::from(a.vel) - Vec3::from(b.vel)).abs().max_element());
+    }
+    divergence
+}
+
+/// `main` of `particle.comp` for a single particle, in the same order of operations.
+fn step_particle(
+    particle: &Particle,
+    params: &SimParams,
+    push_constants: &SimPushConstants,
+    obstacles: &[Obstacle],
+    attractors: &[Attractor],
+) -> Particle {
+    let dt = push_constants.dt;
+    let mut pos = Vec3::from(particle.pos);
+    let mut vel = Vec3::from(particle.vel);
+    let mut life = particle.life - dt;
+    let mut max_life = particle.max_life;
+
+    if life <= 0.0 {
+        let mut seed = particle.id ^ pcg_hash(push_constants.frame ^ pcg_hash(params.seed));
+        (pos, vel) = spawn(&mut seed, params);
+        max_life = params.lifetime * (0.5 + random(&mut seed));
+        life = max_life;
+    }
+
+    if push_constants.attractor_active != 0 {
+        let to_attractor = Vec2::from(push_constants.attractor).extend(0.0) - pos;
+        let dist_sq = to_attractor.length_squared() + 0.01;
+        vel += to_attractor / dist_sq.sqrt() / dist_sq * push_constants.attractor_strength * dt;
+    }
+
+    // The kind's share of the noise flow is left to the GPU.
+    let kind = &params.kinds[(particle.kind as usize).min(MAX_PARTICLE_KINDS - 1)];
+    vel += attractor_force(pos, attractors) * dt;
+    vel += Vec2::from(params.gravity).extend(0.0) * kind.gravity_scale * dt;
+    vel *= (-params.drag * kind.drag_scale * dt).exp();
+
+    let speed = vel.length();
+    if speed > params.max_speed {
+        vel *= params.max_speed / speed;
+    }
+
+    pos += vel * dt;
+    collide_obstacles(&mut pos, &mut vel, obstacles);
+
+    let bounds = Vec2::from(params.world_half_extents).extend(1.0);
+    if pos.abs().cmpgt(bounds).any() {
+        match params.boundary_mode() {
+            // GLSL's floor-based mod().
+            BoundaryMode::Wrap => pos = (pos + bounds) - 2.0 * bounds * ((pos + bounds) / (2.0 * bounds)).floor() - bounds,
+            BoundaryMode::Kill => {
+                pos = pos.clamp(-bounds, bounds);
+                life = 0.0;
+            }
+            BoundaryMode::Bounce { restitution } => {
+                for axis in 0..3 {
+                    if pos[axis].abs() > bounds[axis] {
+                        vel[axis] = -vel[axis] * restitution;
+                        pos[axis] = pos[axis].clamp(-bounds[axis], bounds[axis]);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut color = params.base_color;
+    if params.color_mode == ColorMode::Velocity as u32 {
+        let t = (vel.length() * params.color_speed_scale).clamp(0.0, 1.0);
+        let rgb = hsv_to_rgb(Vec3::new((1.0 - t) * 0.66, 0.9, 1.0));
+        color = [rgb.x, rgb.y, rgb.z, params.base_color[3]];
+    } else if params.color_mode == ColorMode::Kind as u32 {
+        color = kind.color;
+    }
+    color[3] *= (life / max_life).clamp(0.0, 1.0);
+
+    Particle { pos: pos.into(), vel: vel.into(), color, life, max_life, ..*particle }
+}
+
+/// `spawn` in `particle.comp`.
+fn spawn(seed: &mut u32, params: &SimParams) -> (Vec3, Vec3) {
+    let mut angle = params.emit_direction + (random(seed) - 0.5) * params.emit_spread;
+    let mut offset = Vec2::ZERO;
+    match params.emitter_shape {
+        // Line
+        1 => {
+            let along = Vec2::new(-params.emit_direction.sin(), params.emit_direction.cos());
+            offset = along * (random(seed) - 0.5) * params.emitter_size;
+        }
+        // Ring and disc
+        2 | 3 => {
+            angle = random(seed) * TAU;
+            let mut radius = params.emitter_size;
+            if params.emitter_shape == 3 {
+                radius *= random(seed).sqrt();
+            }
+            offset = Vec2::new(angle.cos(), angle.sin()) * radius;
+        }
+        _ => (),
+    }
+    let pos = (Vec2::from(params.emitter_position) + offset).extend(0.0);
+    let speed = params.emit_speed_min + (params.emit_speed_max - params.emit_speed_min) * random(seed);
+    let z = if params.dimensions == 3 {
+        (random(seed) * 2.0 - 1.0) * (params.emit_spread.min(PI) * 0.5).sin()
+    } else {
+        0.0
+    };
+    let vel = (Vec2::new(angle.cos(), angle.sin()) * (1.0 - z * z).sqrt()).extend(z) * speed;
+    (pos, vel)
+}
+
+/// `attractorForce` in `attractors.glsl`.
+fn attractor_force(pos: Vec3, attractors: &[Attractor]) -> Vec3 {
+    let mut force = Vec3::ZERO;
+    for attractor in attractors {
+        let to_attractor = Vec2::from(attractor.pos).extend(0.0) - pos;
+        let dist = to_attractor.length();
+        if dist > 0.0 {
+            let clamped = dist.max(attractor.radius);
+            force += to_attractor / dist * attractor.strength / (clamped * clamped);
+        }
+    }
+    force
+}
+
+/// `collideObstacles` in `obstacles.glsl`.
+fn collide_obstacles(pos: &mut Vec3, vel: &mut Vec3, obstacles: &[Obstacle]) {
+    for obstacle in obstacles {
+        let offset = pos.truncate() - Vec2::from(obstacle.center);
+        let (depth, normal) = match obstacle.shape {
+            ObstacleShape::Circle { radius } => {
+                let dist = offset.length();
+                (radius - dist, if dist > 0.0 { offset / dist } else { Vec2::Y })
+            }
+            ObstacleShape::Box { half_extents } => {
+                let penetration = Vec2::from(half_extents) - offset.abs();
+                if penetration.x < penetration.y {
+                    (penetration.x, Vec2::new(if offset.x < 0.0 { -1.0 } else { 1.0 }, 0.0))
+                } else {
+                    (penetration.y, Vec2::new(0.0, if offset.y < 0.0 { -1.0 } else { 1.0 }))
+                }
+            }
+        };
+        if depth <= 0.0 {
+            continue;
+        }
+        *pos += (normal * depth).extend(0.0);
+        let into = vel.truncate().dot(normal);
+        if into < 0.0 {
+            *vel -= (normal * (1.0 + obstacle.restitution) * into).extend(0.0);
+        }
+    }
+}
+
+fn hsv_to_rgb(c: Vec3) -> Vec3 {
+    let p = ((Vec3::splat(c.x) + Vec3::new(1.0, 2.0 / 3.0, 1.0 / 3.0)).fract() * 6.0 - 3.0).abs();
+    c.z * Vec3::ONE.lerp((p - 1.0).clamp(Vec3::ZERO, Vec3::ONE), c.y)
+}
+
+/// `pcgHash` in `particle.comp`.
+fn pcg_hash(v: u32) -> u32 {
+    let state = v.wrapping_mul(747_796_405).wrapping_add(2_891_336_453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277_803_737);
+    (word >> 22) ^ word
+}
+
+fn random(seed: &mut u32) -> f32 {
+    *seed = pcg_hash(*seed);
+    *seed as f32 / 4_294_967_295.0
+}
@@ -0,0 +1,55 @@
+use ash::ext::debug_utils;
+use ash::vk::{self, Handle};
+use ash::{Device, Instance};
+use std::ffi::CString;
+
+/// Label colors, so passes are told apart at a glance in a capture.
+pub const COMPUTE_LABEL_COLOR: [f32; 4] = [0.3, 0.6, 1.0, 1.0];
+pub const GRAPHICS_LABEL_COLOR: [f32; 4] = [1.0, 0.6, 0.2, 1.0];
+
+/// Names objects and labels command buffer regions through `VK_EXT_debug_utils`,
+/// which shows up in validation messages and in captures from tools such as
+/// RenderDoc. Every call does nothing when the extension isn't enabled.
+#[derive(Clone)]
+pub struct DebugUtils {
+    loader: Option<debug_utils::Device>,
+}
+
+impl DebugUtils {
+    /// Loads the entry points for `device` if the instance enabled the extension.
+    pub(crate) fn new(instance: &Instance, device: &Device, enabled: bool) -> Self {
+        Self { loader: enabled.then(|| debug_utils::Device::new(instance, device)) }
+    }
+
+    pub fn set_object_name<H: Handle>(&self, handle: H, name: &str) {
+        let Some(loader) = &self.loader else {
+            return;
+        };
+        let Ok(name) = CString::new(name) else {
+            return;
+        };
+        let info = vk::DebugUtilsObjectNameInfoEXT::default().object_handle(handle).object_name(&name);
+        // An unnamed object only makes captures harder to read.
+        if let Err(e) = unsafe { loader.set_debug_utils_object_name(&info) } {
+            log::warn!("Failed to name {}: {e}", name.to_string_lossy());
+        }
+    }
+
+    /// Opens a region of `cmd` named `name`, closed by `cmd_end_label`. Regions nest.
+    pub fn cmd_begin_label(&self, cmd: vk::CommandBuffer, name: &str, color: [f32; 4]) {
+        let Some(loader) = &self.loader else {
+            return;
+        };
+        let Ok(name) = CString::new(name) else {
+            return;
+        };
+        let label = vk::DebugUtilsLabelEXT::default().label_name(&name).color(color);
+        unsafe { loader.cmd_begin_debug_utils_label(cmd, &label) };
+    }
+
+    pub fn cmd_end_label(&self, cmd: vk::CommandBuffer) {
+        if let Some(loader) = &self.loader {
+            unsafe { loader.cmd_end_debug_utils_label(cmd) };
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use std::mem::size_of;
+use crate::allocator::MemoryLocation;
+use crate::error::VulkanDemoError;
+use crate::memory::create_buffer;
+use crate::particles::{create_compute_pipeline, ComputeSpecialization, ParticleFormat};
+use crate::pipeline_utils::{compile_shader, specialization_info, ShaderCompileOptions};
+use crate::resources::{OwnedBuffer, OwnedDescriptorPool, OwnedDescriptorSetLayout, OwnedPipeline, OwnedPipelineLayout};
+use crate::spatial_grid::memory_barrier;
+use crate::vulkan_context::VulkanContext;
+
+// Binding numbers shared by the sort shaders.
+const BINDING_PARTICLES: u32 = 0;
+const BINDING_DRAW_COUNTS: u32 = 1;
+const BINDING_KEYS: u32 = 2;
+const BINDING_SORTED_PARTICLES: u32 = 3;
+
+/// `SortKey` in particle.glsl.
+const KEY_SIZE: vk::DeviceSize = 2 * size_of::<u32>() as vk::DeviceSize;
+
+/// Push constants of every sort pass; `block_size` and `compare_distance` only
+/// mean something to `bitonic_sort.comp`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct SortPushConstants {
+    focal_point: [f32; 3],
+    block_size: u32,
+    compare_distance: u32,
+}
+
+/// Orders a particle system's particles far to near from a focal point before
+/// they are drawn, so alpha blending composites them back to front. Ordered
+/// by distance rather than view depth, which for points gives the same order
+/// near the view axis; in 2D the focal point is just where the view looks.
+///
+/// Three passes: `sort_keys.comp` keys every particle, `bitonic_sort.comp`
+/// runs once for each step of a bitonic merge sort over the keys, and
+/// `sort_gather.comp` copies the particles in key order into `sorted_particles`,
+/// which is then drawn instead. The key array is padded to a power of two with
+/// keys that sort after every particle. Dead particles also sort after the
+/// live ones, so the draw's live count still covers exactly the live ones.
+pub struct DepthSort {
+    keys_pipeline: OwnedPipeline,
+    sort_pipeline: OwnedPipeline,
+    gather_pipeline: OwnedPipeline,
+    pipeline_layout: OwnedPipelineLayout,
+    /// `descriptor_sets[i]` sorts particle buffer `i`.
+    descriptor_sets: [vk::DescriptorSet; 2],
+    _descriptor_pool: OwnedDescriptorPool,
+    _descriptor_set_layout: OwnedDescriptorSetLayout,
+    _keys: OwnedBuffer,
+    sorted_particles: OwnedBuffer,
+    count: u32,
+    padded_count: u32,
+    workgroup_size: u32,
+}
+
+impl DepthSort {
+    /// Bytes of device-local memory the sort of `count` particles in `format` takes.
+    pub fn memory_size(count: u32, format: ParticleFormat) -> vk::DeviceSize {
+        count.next_power_of_two() as vk::DeviceSize * KEY_SIZE + format.buffer_size(count)
+    }
+
+    /// `particle_buffers` are the ping-pong pair and `draw_buffer` the system's
+    /// `DrawCounts`, whose live count the keys are made with. `sorted_particles`
+    /// gets the same `format` as the particle buffers.
+    pub fn new(
+        context: &VulkanContext,
+        particle_buffers: &[OwnedBuffer; 2],
+        draw_buffer: &OwnedBuffer,
+        count: u32,
+        workgroup_size: u32,
+        format: ParticleFormat,
+    ) -> Result<Self, VulkanDemoError> {
+        let device = &context.device;
+        context.check_memory_budget(MemoryLocation::GpuOnly, Self::memory_size(count, format))?;
+        let padded_count = count.next_power_of_two();
+        let keys = create_buffer(
+            context,
+            padded_count as vk::DeviceSize * KEY_SIZE,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            MemoryLocation::GpuOnly,
+        )?;
+        let sorted_particles = create_buffer(
+            context,
+            format.buffer_size(count),
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::VERTEX_BUFFER,
+            MemoryLocation::GpuOnly,
+        )?;
+        context.debug.set_object_name(keys.handle(), "depth sort keys");
+        context.debug.set_object_name(sorted_particles.handle(), "depth sorted particles");
+
+        let binding = |binding: u32| {
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(binding)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        };
+        let layout_bindings = [
+            binding(BINDING_PARTICLES),
+            binding(BINDING_DRAW_COUNTS),
+            binding(BINDING_KEYS),
+            binding(BINDING_SORTED_PARTICLES),
+        ];
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(&layout_bindings);
+        let descriptor_set_layout = OwnedDescriptorSetLayout::new(
+            device,
+            unsafe { device.create_descriptor_set_layout(&layout_info, None)? },
+        );
+
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(2 * layout_bindings.len() as u32)];
+        let pool_info = vk::DescriptorPoolCreateInfo::default()
+            .pool_sizes(&pool_sizes)
+            .max_sets(2);
+        let descriptor_pool = OwnedDescriptorPool::new(
+            device,
+            unsafe { device.create_descriptor_pool(&pool_info, None)? },
+        );
+
+        let set_layouts = [descriptor_set_layout.handle(); 2];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool.handle())
+            .set_layouts(&set_layouts);
+        let allocated_sets = unsafe { device.allocate_descriptor_sets(&alloc_info)? };
+        let descriptor_sets = [allocated_sets[0], allocated_sets[1]];
+
+        let whole = |buffer: &OwnedBuffer| {
+            vk::DescriptorBufferInfo::default()
+                .buffer(buffer.handle())
+                .offset(0)
+                .range(vk::WHOLE_SIZE)
+        };
+        for (particles, &descriptor_set) in particle_buffers.iter().zip(&descriptor_sets) {
+            let infos = [
+                (BINDING_PARTICLES, whole(particles)),
+                (BINDING_DRAW_COUNTS, whole(draw_buffer)),
+                (BINDING_KEYS, whole(&keys)),
+                (BINDING_SORTED_PARTICLES, whole(&sorted_particles)),
+            ];
+            let writes: Vec<_> = infos.iter().map(|(binding, info)| {
+                vk::WriteDescriptorSet::default()
+                    .dst_set(descriptor_set)
+                    .dst_binding(*binding)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(std::slice::from_ref(info))
+            }).collect();
+            unsafe { device.update_descriptor_sets(&writes, &[]) };
+        }
+
+        let push_constant_ranges = [vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .size(size_of::<SortPushConstants>() as u32)];
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&set_layouts[..1])
+            .push_constant_ranges(&push_constant_ranges);
+        let pipeline_layout = OwnedPipelineLayout::new(
+            device,
+            unsafe { device.create_pipeline_layout(&pipeline_layout_info, None)? },
+        );
+
+        let constants = ComputeSpecialization::new(workgroup_size, format);
+        let specialization = specialization_info(&constants);
+        let pipeline = |name: &str, source: &str| -> Result<OwnedPipeline, VulkanDemoError> {
+            let spirv = compile_shader(source, name, shaderc::ShaderKind::Compute, &ShaderCompileOptions::default())?;
+            Ok(create_compute_pipeline(device, context.pipeline_cache, pipeline_layout.handle(), &spirv, Some(&specialization))?)
+        };
+        let keys_pipeline = pipeline("sort_keys.comp", include_str!("shaders/sort_keys.comp"))?;
+        let sort_pipeline = pipeline("bitonic_sort.comp", include_str!("shaders/bitonic_sort.comp"))?;
+        let gather_pipeline = pipeline("sort_gather.comp", include_str!("shaders/sort_gather.comp"))?;
+
+        Ok(Self {
+            keys_pipeline,
+            sort_pipeline,
+            gather_pipeline,
+            pipeline_layout,
+            descriptor_sets,
+            _descriptor_pool: descriptor_pool,
+            _descriptor_set_layout: descriptor_set_layout,
+            _keys: keys,
+            sorted_particles,
+            count,
+            padded_count,
+            workgroup_size,
+        })
+    }
+
+    /// The particles in drawing order, once `record` has run.
+    pub fn sorted_particles(&self) -> vk::Buffer {
+        self.sorted_particles.handle()
+    }
+
+    /// Records sorting particle buffer `buffer_index` far to near from
+    /// `focal_point` into `sorted_particles`. Starts with a barrier after
+    /// whatever wrote the particles and the live count on this queue, and the
+    /// previous sort and its draw; ends with one making the sorted particles
+    /// visible to vertex input.
+    pub fn record(&self, device: &ash::Device, cmd: vk::CommandBuffer, buffer_index: usize, focal_point: [f32; 3]) {
+        let layout = self.pipeline_layout.handle();
+        let push = |block_size: u32, compare_distance: u32| unsafe {
+            let constants = SortPushConstants { focal_point, block_size, compare_distance };
+            device.cmd_push_constants(cmd, layout, vk::ShaderStageFlags::COMPUTE, 0, bytemuck::bytes_of(&constants));
+        };
+        let compute_to_compute = (
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE),
+        );
+        let key_groups = self.padded_count.div_ceil(self.workgroup_size);
+        unsafe {
+            memory_barrier(
+                device,
+                cmd,
+                (
+                    vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::TRANSFER | vk::PipelineStageFlags::VERTEX_INPUT,
+                    vk::AccessFlags::SHADER_WRITE | vk::AccessFlags::TRANSFER_WRITE,
+                ),
+                compute_to_compute.1,
+            );
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                layout,
+                0,
+                &[self.descriptor_sets[buffer_index]],
+                &[],
+            );
+
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.keys_pipeline.handle());
+            push(0, 0);
+            device.cmd_dispatch(cmd, key_groups, 1, 1);
+            memory_barrier(device, cmd, compute_to_compute.0, compute_to_compute.1);
+
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.sort_pipeline.handle());
+            let mut block_size = 2;
+            while block_size <= self.padded_count {
+                let mut distance = block_size / 2;
+                while distance > 0 {
+                    push(block_size, distance);
+                    device.cmd_dispatch(cmd, key_groups, 1, 1);
+                    memory_barrier(device, cmd, compute_to_compute.0, compute_to_compute.1);
+                    distance /= 2;
+                }
+                block_size *= 2;
+            }
+
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.gather_pipeline.handle());
+            device.cmd_dispatch(cmd, self.count.div_ceil(self.workgroup_size), 1, 1);
+            memory_barrier(
+                device,
+                cmd,
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+                (vk::PipelineStageFlags::VERTEX_INPUT, vk::AccessFlags::VERTEX_ATTRIBUTE_READ),
+            );
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use ash::khr::{
+    buffer_device_address, create_renderpass2, depth_stencil_resolve, dynamic_rendering, shader_float16_int8, synchronization2,
+    timeline_semaphore,
+};
+use ash::{vk, Instance};
+use std::ffi::CStr;
+
+/// Declares `DeviceFeatures` with one flag per feature, named in messages by
+/// its Vulkan name.
+macro_rules! device_features {
+    ($($(#[$doc:meta])* $field:ident: $name:literal,)*) => {
+        /// A set of device features, as supported by a GPU, requested by the
+        /// demo or enabled on the device.
+        #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+        pub struct DeviceFeatures {
+            $($(#[$doc])* pub $field: bool,)*
+        }
+
+        impl DeviceFeatures {
+            pub fn union(self, other: Self) -> Self {
+                Self { $($field: self.$field || other.$field,)* }
+            }
+
+            pub fn intersection(self, other: Self) -> Self {
+                Self { $($field: self.$field && other.$field,)* }
+            }
+
+            /// The features in `self` but not in `other`.
+            pub fn difference(self, other: Self) -> Self {
+                Self { $($field: self.$field && !other.$field,)* }
+            }
+
+            /// Vulkan names of the features in the set.
+            pub fn names(self) -> Vec<&'static str> {
+                [$((self.$field, $name),)*].into_iter().filter_map(|(set, name)| set.then_some(name)).collect()
+            }
+        }
+    };
+}
+
+device_features! {
+    /// `gl_PointSize` other than 1.
+    large_points: "largePoints",
+    /// Line and point polygon modes, for wireframe rendering.
+    fill_mode_non_solid: "fillModeNonSolid",
+    shader_float64: "shaderFloat64",
+    /// Storage buffer writes and atomics in vertex shaders.
+    vertex_pipeline_stores_and_atomics: "vertexPipelineStoresAndAtomics",
+    /// Core in Vulkan 1.2, `VK_KHR_timeline_semaphore` before.
+    timeline_semaphore: "timelineSemaphore",
+    /// Core in Vulkan 1.3, `VK_KHR_synchronization2` before.
+    synchronization2: "synchronization2",
+    /// Core in Vulkan 1.3, `VK_KHR_dynamic_rendering` before.
+    dynamic_rendering: "dynamicRendering",
+    /// Core in Vulkan 1.2, `VK_KHR_buffer_device_address` before.
+    buffer_device_address: "bufferDeviceAddress",
+    /// Core in Vulkan 1.3 only; the extension isn't worth enabling before it.
+    maintenance4: "maintenance4",
+    /// Half float arithmetic in shaders. Core in Vulkan 1.2,
+    /// `VK_KHR_shader_float16_int8` before.
+    shader_float16: "shaderFloat16",
+    /// Half floats in storage buffers, for `F16` particles to be read and
+    /// written without packing. Core in Vulkan 1.1.
+    storage_buffer_16bit_access: "storageBuffer16BitAccess",
+}
+
+// `VK_KHR_dynamic_rendering` and the extensions it depends on before Vulkan 1.2.
+const DYNAMIC_RENDERING_EXTENSIONS: [&CStr; 3] = [dynamic_rendering::NAME, depth_stencil_resolve::NAME, create_renderpass2::NAME];
+
+impl DeviceFeatures {
+    /// The features `pdevice` supports at `api_version`, the version the
+    /// device is used at. Features core at that version need only their
+    /// feature bit; earlier, their extension too. Querying either needs 1.1.
+    pub fn supported(instance: &Instance, pdevice: vk::PhysicalDevice, api_version: u32) -> Self {
+        let core = unsafe { instance.get_physical_device_features(pdevice) };
+        let mut supported = Self {
+            large_points: core.large_points == vk::TRUE,
+            fill_mode_non_solid: core.fill_mode_non_solid == vk::TRUE,
+            shader_float64: core.shader_float64 == vk::TRUE,
+            vertex_pipeline_stores_and_atomics: core.vertex_pipeline_stores_and_atomics == vk::TRUE,
+            ..Self::default()
+        };
+        if api_version < vk::API_VERSION_1_1 {
+            return supported;
+        }
+
+        let mut timeline_features = vk::PhysicalDeviceTimelineSemaphoreFeatures::default();
+        let mut synchronization2_features = vk::PhysicalDeviceSynchronization2Features::default();
+        let mut dynamic_rendering_features = vk::PhysicalDeviceDynamicRenderingFeatures::default();
+        let mut buffer_device_address_features = vk::PhysicalDeviceBufferDeviceAddressFeatures::default();
+        let mut maintenance4_features = vk::PhysicalDeviceMaintenance4Features::default();
+        let mut float16_features = vk::PhysicalDeviceShaderFloat16Int8Features::default();
+        let mut storage16_features = vk::PhysicalDevice16BitStorageFeatures::default();
+        let mut features = vk::PhysicalDeviceFeatures2::default()
+            .push_next(&mut timeline_features)
+            .push_next(&mut synchronization2_features)
+            .push_next(&mut dynamic_rendering_features)
+            .push_next(&mut buffer_device_address_features)
+            .push_next(&mut maintenance4_features)
+            .push_next(&mut float16_features)
+            .push_next(&mut storage16_features);
+        unsafe { instance.get_physical_device_features2(pdevice, &mut features) };
+        let promoted = |core_version: u32, extensions: &[&CStr]| {
+            api_version >= core_version || supports_device_extensions(instance, pdevice, extensions)
+        };
+        supported.timeline_semaphore =
+            timeline_features.timeline_semaphore == vk::TRUE && promoted(vk::API_VERSION_1_2, &[timeline_semaphore::NAME]);
+        supported.synchronization2 =
+            synchronization2_features.synchronization2 == vk::TRUE && promoted(vk::API_VERSION_1_3, &[synchronization2::NAME]);
+        supported.dynamic_rendering = dynamic_rendering_features.dynamic_rendering == vk::TRUE
+            && promoted(vk::API_VERSION_1_3, &DYNAMIC_RENDERING_EXTENSIONS);
+        supported.buffer_device_address = buffer_device_address_features.buffer_device_address == vk::TRUE
+            && promoted(vk::API_VERSION_1_2, &[buffer_device_address::NAME]);
+        supported.maintenance4 = maintenance4_features.maintenance4 == vk::TRUE && api_version >= vk::API_VERSION_1_3;
+        supported.shader_float16 =
+            float16_features.shader_float16 == vk::TRUE && promoted(vk::API_VERSION_1_2, &[shader_float16_int8::NAME]);
+        supported.storage_buffer_16bit_access = storage16_features.storage_buffer16_bit_access == vk::TRUE;
+        supported
+    }
+
+    /// The core features in the set, for `DeviceCreateInfo::enabled_features`.
+    pub fn core(self) -> vk::PhysicalDeviceFeatures {
+        vk::PhysicalDeviceFeatures::default()
+            .large_points(self.large_points)
+            .fill_mode_non_solid(self.fill_mode_non_solid)
+            .shader_float64(self.shader_float64)
+            .vertex_pipeline_stores_and_atomics(self.vertex_pipeline_stores_and_atomics)
+    }
+
+    /// The device extensions the features in the set need enabled at
+    /// `api_version`: none for those already core.
+    pub fn extensions(self, api_version: u32) -> Vec<&'static CStr> {
+        let mut extensions = Vec::new();
+        if api_version >= vk::API_VERSION_1_3 {
+            return extensions;
+        }
+        if self.synchronization2 {
+            extensions.push(synchronization2::NAME);
+        }
+        if self.dynamic_rendering {
+            extensions.extend(DYNAMIC_RENDERING_EXTENSIONS);
+        }
+        if api_version >= vk::API_VERSION_1_2 {
+            return extensions;
+        }
+        if self.timeline_semaphore {
+            extensions.push(timeline_semaphore::NAME);
+        }
+        if self.buffer_device_address {
+            extensions.push(buffer_device_address::NAME);
+        }
+        if self.shader_float16 {
+            extensions.push(shader_float16_int8::NAME);
+        }
+        extensions
+    }
+}
+
+/// The features a `VulkanContext` is created with: `required` ones fail
+/// device creation when missing, `preferred` ones are enabled when supported.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FeatureRequest {
+    pub required: DeviceFeatures,
+    pub preferred: DeviceFeatures,
+}
+
+impl Default for FeatureRequest {
+    /// Nothing required; everything the demo has a fallback for preferred.
+    fn default() -> Self {
+        Self {
+            required: DeviceFeatures::default(),
+            preferred: DeviceFeatures {
+                large_points: true,
+                fill_mode_non_solid: true,
+                timeline_semaphore: true,
+                synchronization2: true,
+                dynamic_rendering: true,
+                buffer_device_address: true,
+                maintenance4: true,
+                shader_float16: true,
+                storage_buffer_16bit_access: true,
+                ..DeviceFeatures::default()
+            },
+        }
+    }
+}
+
+pub(crate) fn supports_device_extensions(instance: &Instance, pdevice: vk::PhysicalDevice, required: &[&CStr]) -> bool {
+    let Ok(available) = (unsafe { instance.enumerate_device_extension_properties(pdevice) }) else {
+        return false;
+    };
+    required.iter().all(|&required| {
+        available.iter().any(|ext| ext.extension_name_as_c_str().is_ok_and(|name| name == required))
+    })
+}
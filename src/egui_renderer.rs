@@ -0,0 +1,523 @@
+use ash::{vk, Device};
+use bytemuck::{Pod, Zeroable};
+use egui::epaint::textures::TexturesDelta;
+use egui::epaint::{ClippedPrimitive, ImageData, ImageDelta, Primitive, TextureId, Vertex};
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::allocator::MemoryLocation;
+use crate::error::VulkanDemoError;
+use crate::memory::{create_buffer, create_image};
+use crate::pipeline_utils::{GraphicsPipelineBuilder, RenderTarget, ShaderCompileOptions, VertexLayout};
+use crate::renderer::is_srgb_format;
+use crate::sync::FRAMES_IN_FLIGHT;
+use crate::resources::{
+    OwnedBuffer, OwnedDescriptorPool, OwnedDescriptorSetLayout, OwnedImage, OwnedImageView, OwnedPipeline,
+    OwnedPipelineLayout, OwnedSampler,
+};
+use crate::vulkan_context::VulkanContext;
+
+// egui normally needs a font atlas and a handful of user images at most.
+const MAX_TEXTURES: u32 = 64;
+// Smallest vertex/index buffer allocation, in elements.
+const MIN_BUFFER_ELEMENTS: usize = 4096;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct EguiPushConstants {
+    screen_size: [f32; 2],
+    linear_output: u32,
+}
+
+/// A texture egui asked for, sampled through its own descriptor set.
+struct EguiTexture {
+    descriptor_set: vk::DescriptorSet,
+    _sampler: OwnedSampler,
+    _view: OwnedImageView,
+    image: OwnedImage,
+    extent: vk::Extent2D,
+}
+
+/// One mesh of the current frame, as a range of its vertex and index buffers.
+struct EguiDraw {
+    texture: TextureId,
+    scissor: vk::Rect2D,
+    first_index: u32,
+    index_count: u32,
+    vertex_offset: i32,
+}
+
+/// Host-visible buffer rewritten every frame, grown when a frame needs more.
+struct DynamicBuffer {
+    buffer: OwnedBuffer,
+    capacity: vk::DeviceSize,
+}
+
+/// What one frame in flight draws from, rewritten once the GPU is done with it.
+#[derive(Default)]
+struct EguiFrame {
+    vertex_buffer: Option<DynamicBuffer>,
+    index_buffer: Option<DynamicBuffer>,
+    /// Textures freed or replaced while this frame was current. Earlier frames
+    /// may still sample them, so they're destroyed when this frame comes around again.
+    retired: Vec<EguiTexture>,
+}
+
+/// Draws egui's tessellated output into a subpass of an existing render pass:
+/// the pipeline, the textures egui manages (the font atlas first of all) and
+/// the vertex and index buffers of each frame in flight.
+pub struct EguiRenderer {
+    pipeline: OwnedPipeline,
+    pipeline_layout: OwnedPipelineLayout,
+    textures: HashMap<TextureId, EguiTexture>,
+    /// Freed by egui after the last frame drew with them.
+    pending_free: Vec<TextureId>,
+    descriptor_pool: OwnedDescriptorPool,
+    descriptor_set_layout: OwnedDescriptorSetLayout,
+    frames: [EguiFrame; FRAMES_IN_FLIGHT],
+    /// Index into `frames` of the frame being recorded.
+    current: usize,
+    draws: Vec<EguiDraw>,
+    /// Render target size in points, pushed to the vertex shader.
+    screen_size: [f32; 2],
+    linear_output: bool,
+}
+
+impl EguiRenderer {
+    /// `target_format` is the format of the color attachment `target` draws
+    /// into; sRGB targets get egui's gamma-space colors converted on output.
+    pub fn new(
+        context: &VulkanContext,
+        target: RenderTarget,
+        target_format: vk::Format,
+    ) -> Result<Self, VulkanDemoError> {
+        let device = &context.device;
+
+        let binding = vk::DescriptorSetLayoutBinding::default()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT);
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(std::slice::from_ref(&binding));
+        let descriptor_set_layout =
+            OwnedDescriptorSetLayout::new(device, unsafe { device.create_descriptor_set_layout(&layout_info, None)? });
+
+        let pool_size = vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(MAX_TEXTURES);
+        let pool_info = vk::DescriptorPoolCreateInfo::default()
+            .flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
+            .pool_sizes(std::slice::from_ref(&pool_size))
+            .max_sets(MAX_TEXTURES);
+        let descriptor_pool = OwnedDescriptorPool::new(device, unsafe { device.create_descriptor_pool(&pool_info, None)? });
+
+        let push_constant_range = vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)
+            .offset(0)
+            .size(std::mem::size_of::<EguiPushConstants>() as u32);
+        let set_layouts = [descriptor_set_layout.handle()];
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(std::slice::from_ref(&push_constant_range));
+        let pipeline_layout =
+            OwnedPipelineLayout::new(device, unsafe { device.create_pipeline_layout(&pipeline_layout_info, None)? });
+
+        let pipeline = create_egui_pipeline(device, context.pipeline_cache, target, pipeline_layout.handle())?;
+
+        let linear_output = is_srgb_format(target_format);
+
+        Ok(Self {
+            pipeline,
+            pipeline_layout,
+            textures: HashMap::new(),
+            pending_free: Vec::new(),
+            descriptor_pool,
+            descriptor_set_layout,
+            frames: Default::default(),
+            current: 0,
+            draws: Vec::new(),
+            screen_size: [1.0, 1.0],
+            linear_output,
+        })
+    }
+
+    /// Switches to the buffers of frame in flight `frame` and destroys the
+    /// textures retired while it was last current. The GPU must be done with that frame.
+    pub fn begin_frame(&mut self, device: &Device, frame: usize) {
+        self.current = frame;
+        for texture in std::mem::take(&mut self.frames[frame].retired) {
+            self.destroy_texture(device, texture);
+        }
+    }
+
+    /// Applies egui's texture changes for the coming frame, and retires the
+    /// textures it dropped after the previous one.
+    pub fn update_textures(&mut self, context: &VulkanContext, delta: &TexturesDelta) -> Result<(), VulkanDemoError> {
+        for id in std::mem::take(&mut self.pending_free) {
+            self.retire_texture(id);
+        }
+        for (id, image_delta) in &delta.set {
+            self.set_texture(context, *id, image_delta)?;
+        }
+        self.pending_free.extend_from_slice(&delta.free);
+        Ok(())
+    }
+
+    /// Uploads this frame's meshes for a `extent` sized target into the
+    /// current frame's buffers.
+    pub fn upload(
+        &mut self,
+        context: &VulkanContext,
+        primitives: &[ClippedPrimitive],
+        pixels_per_point: f32,
+        extent: vk::Extent2D,
+    ) -> Result<(), VulkanDemoError> {
+        self.draws.clear();
+        self.screen_size = [extent.width as f32 / pixels_per_point, extent.height as f32 / pixels_per_point];
+
+        let meshes = primitives.iter().filter_map(|primitive| match &primitive.primitive {
+            Primitive::Mesh(mesh) if !mesh.indices.is_empty() => Some((primitive.clip_rect, mesh)),
+            // Paint callbacks are for custom renderers, which this one has none of.
+            _ => None,
+        });
+        let (vertex_count, index_count) = meshes
+            .clone()
+            .fold((0, 0), |(vertices, indices), (_, mesh)| (vertices + mesh.vertices.len(), indices + mesh.indices.len()));
+        if index_count == 0 {
+            return Ok(());
+        }
+
+        let vertex_bytes = (vertex_count * std::mem::size_of::<Vertex>()) as vk::DeviceSize;
+        let index_bytes = (index_count * std::mem::size_of::<u32>()) as vk::DeviceSize;
+        let frame = &mut self.frames[self.current];
+        let vertex_buffer = ensure_capacity(
+            context,
+            &mut frame.vertex_buffer,
+            vertex_bytes,
+            std::mem::size_of::<Vertex>(),
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+        )?;
+        let index_buffer = ensure_capacity(
+            context,
+            &mut frame.index_buffer,
+            index_bytes,
+            std::mem::size_of::<u32>(),
+            vk::BufferUsageFlags::INDEX_BUFFER,
+        )?;
+
+        unsafe {
+            let vertices = vertex_buffer.mapped_ptr() as *mut Vertex;
+            let indices = index_buffer.mapped_ptr() as *mut u32;
+
+            let mut vertex_offset = 0;
+            let mut first_index = 0;
+            for (clip_rect, mesh) in meshes {
+                std::ptr::copy_nonoverlapping(mesh.vertices.as_ptr(), vertices.add(vertex_offset), mesh.vertices.len());
+                std::ptr::copy_nonoverlapping(mesh.indices.as_ptr(), indices.add(first_index), mesh.indices.len());
+
+                if let Some(scissor) = scissor_rect(clip_rect, pixels_per_point, extent) {
+                    self.draws.push(EguiDraw {
+                        texture: mesh.texture_id,
+                        scissor,
+                        first_index: first_index as u32,
+                        index_count: mesh.indices.len() as u32,
+                        vertex_offset: vertex_offset as i32,
+                    });
+                }
+                vertex_offset += mesh.vertices.len();
+                first_index += mesh.indices.len();
+            }
+        }
+        vertex_buffer.flush(0, vertex_bytes)?;
+        index_buffer.flush(0, index_bytes)?;
+        Ok(())
+    }
+
+    /// Records drawing the uploaded meshes. Must be called inside the subpass
+    /// the renderer was created for; leaves the scissor changed.
+    pub fn record(&self, device: &Device, cmd: vk::CommandBuffer) {
+        let frame = &self.frames[self.current];
+        let (Some(vertex_buffer), Some(index_buffer)) = (&frame.vertex_buffer, &frame.index_buffer) else {
+            return;
+        };
+        if self.draws.is_empty() {
+            return;
+        }
+        let push_constants = EguiPushConstants {
+            screen_size: self.screen_size,
+            linear_output: self.linear_output as u32,
+        };
+
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.pipeline.handle());
+            device.cmd_bind_vertex_buffers(cmd, 0, &[vertex_buffer.buffer.handle()], &[0]);
+            device.cmd_bind_index_buffer(cmd, index_buffer.buffer.handle(), 0, vk::IndexType::UINT32);
+            device.cmd_push_constants(
+                cmd,
+                self.pipeline_layout.handle(),
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                0,
+                bytemuck::bytes_of(&push_constants),
+            );
+
+            for draw in &self.draws {
+                let Some(texture) = self.textures.get(&draw.texture) else {
+                    continue;
+                };
+                device.cmd_set_scissor(cmd, 0, std::slice::from_ref(&draw.scissor));
+                device.cmd_bind_descriptor_sets(
+                    cmd,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    self.pipeline_layout.handle(),
+                    0,
+                    &[texture.descriptor_set],
+                    &[],
+                );
+                device.cmd_draw_indexed(cmd, draw.index_count, 1, draw.first_index, draw.vertex_offset, 0);
+            }
+        }
+    }
+
+    fn set_texture(&mut self, context: &VulkanContext, id: TextureId, delta: &ImageDelta) -> Result<(), VulkanDemoError> {
+        let pixels: Vec<u8> = match &delta.image {
+            ImageData::Color(image) => bytemuck::cast_slice(&image.pixels).to_vec(),
+            ImageData::Font(image) => image.srgba_pixels(None).flat_map(|color| color.to_array()).collect(),
+        };
+        let [width, height] = delta.image.size();
+        let region_extent = vk::Extent2D { width: width as u32, height: height as u32 };
+
+        let (texture, offset, fresh) = match delta.pos {
+            // A partial update of a texture egui created earlier.
+            Some([x, y]) => {
+                let Some(texture) = self.textures.get(&id) else {
+                    log::warn!("egui updated unknown texture {id:?}");
+                    return Ok(());
+                };
+                debug_assert!(x + width <= texture.extent.width as usize && y + height <= texture.extent.height as usize);
+                (texture, vk::Offset3D { x: x as i32, y: y as i32, z: 0 }, false)
+            }
+            None => {
+                let texture = self.create_texture(context, region_extent, delta.options)?;
+                self.retire_texture(id);
+                self.textures.insert(id, texture);
+                (&self.textures[&id], vk::Offset3D::default(), true)
+            }
+        };
+        upload_texture_region(context, texture.image.handle(), offset, region_extent, &pixels, fresh)
+    }
+
+    fn create_texture(
+        &self,
+        context: &VulkanContext,
+        extent: vk::Extent2D,
+        options: egui::TextureOptions,
+    ) -> Result<EguiTexture, VulkanDemoError> {
+        let device = &context.device;
+        // egui's texels are gamma-encoded and blended as such, so no sRGB decode on sampling.
+        let format = vk::Format::R8G8B8A8_UNORM;
+        let image = create_image(context, extent, format, vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)?;
+
+        let view_info = vk::ImageViewCreateInfo::default()
+            .image(image.handle())
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(color_subresource_range());
+        let view = OwnedImageView::new(device, unsafe { device.create_image_view(&view_info, None)? });
+
+        let filter = |filter| match filter {
+            egui::TextureFilter::Nearest => vk::Filter::NEAREST,
+            egui::TextureFilter::Linear => vk::Filter::LINEAR,
+        };
+        let address_mode = match options.wrap_mode {
+            egui::TextureWrapMode::ClampToEdge => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            egui::TextureWrapMode::Repeat => vk::SamplerAddressMode::REPEAT,
+            egui::TextureWrapMode::MirroredRepeat => vk::SamplerAddressMode::MIRRORED_REPEAT,
+        };
+        let sampler_info = vk::SamplerCreateInfo::default()
+            .mag_filter(filter(options.magnification))
+            .min_filter(filter(options.minification))
+            .address_mode_u(address_mode)
+            .address_mode_v(address_mode)
+            .address_mode_w(address_mode)
+            .max_lod(vk::LOD_CLAMP_NONE);
+        let sampler = OwnedSampler::new(device, unsafe { device.create_sampler(&sampler_info, None)? });
+
+        let set_layouts = [self.descriptor_set_layout.handle()];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(self.descriptor_pool.handle())
+            .set_layouts(&set_layouts);
+        let descriptor_set = unsafe { device.allocate_descriptor_sets(&alloc_info)?[0] };
+        let image_info = vk::DescriptorImageInfo::default()
+            .sampler(sampler.handle())
+            .image_view(view.handle())
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        let write = vk::WriteDescriptorSet::default()
+            .dst_set(descriptor_set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(std::slice::from_ref(&image_info));
+        unsafe { device.update_descriptor_sets(&[write], &[]) };
+
+        Ok(EguiTexture { descriptor_set, _sampler: sampler, _view: view, image, extent })
+    }
+
+    /// Stops drawing with texture `id`, keeping it alive for the frames in flight.
+    fn retire_texture(&mut self, id: TextureId) {
+        if let Some(texture) = self.textures.remove(&id) {
+            self.frames[self.current].retired.push(texture);
+        }
+    }
+
+    fn destroy_texture(&self, device: &Device, texture: EguiTexture) {
+        if let Err(e) = unsafe { device.free_descriptor_sets(self.descriptor_pool.handle(), &[texture.descriptor_set]) } {
+            log::error!("Failed to free the descriptor set of an egui texture: {e}");
+        }
+    }
+}
+
+/// Returns the buffer in `slot`, replaced by a larger one first if it holds
+/// fewer than `size` bytes. Sizes grow in powers of two to avoid frequent reallocation.
+fn ensure_capacity<'a>(
+    context: &VulkanContext,
+    slot: &'a mut Option<DynamicBuffer>,
+    size: vk::DeviceSize,
+    element_size: usize,
+    usage: vk::BufferUsageFlags,
+) -> Result<&'a OwnedBuffer, VulkanDemoError> {
+    if slot.as_ref().is_none_or(|buffer| buffer.capacity < size) {
+        let capacity = size.next_power_of_two().max((MIN_BUFFER_ELEMENTS * element_size) as vk::DeviceSize);
+        let buffer = create_buffer(
+            context,
+            capacity,
+            usage,
+            MemoryLocation::CpuToGpu,
+        )?;
+        *slot = Some(DynamicBuffer { buffer, capacity });
+    }
+    Ok(&slot.as_ref().expect("buffer was just ensured").buffer)
+}
+
+/// Converts an egui clip rectangle in points to a scissor in pixels, clamped to
+/// the target. `None` if nothing of it is visible.
+fn scissor_rect(clip_rect: egui::Rect, pixels_per_point: f32, extent: vk::Extent2D) -> Option<vk::Rect2D> {
+    let min_x = (clip_rect.min.x * pixels_per_point).round().clamp(0.0, extent.width as f32) as u32;
+    let min_y = (clip_rect.min.y * pixels_per_point).round().clamp(0.0, extent.height as f32) as u32;
+    let max_x = (clip_rect.max.x * pixels_per_point).round().clamp(min_x as f32, extent.width as f32) as u32;
+    let max_y = (clip_rect.max.y * pixels_per_point).round().clamp(min_y as f32, extent.height as f32) as u32;
+    (max_x > min_x && max_y > min_y).then(|| vk::Rect2D {
+        offset: vk::Offset2D { x: min_x as i32, y: min_y as i32 },
+        extent: vk::Extent2D { width: max_x - min_x, height: max_y - min_y },
+    })
+}
+
+fn color_subresource_range() -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
+
+/// Copies tightly packed RGBA8 `pixels` into a region of `image` through the
+/// staging ring, leaving the image ready for sampling. A `fresh` image has no
+/// contents to keep yet.
+fn upload_texture_region(
+    context: &VulkanContext,
+    image: vk::Image,
+    offset: vk::Offset3D,
+    extent: vk::Extent2D,
+    pixels: &[u8],
+    fresh: bool,
+) -> Result<(), VulkanDemoError> {
+    let staged = context.staging().stage(pixels)?;
+    let device = &context.device;
+
+    let old_layout = if fresh { vk::ImageLayout::UNDEFINED } else { vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL };
+    context.one_time_submit(|cmd| unsafe {
+        let to_transfer = vk::ImageMemoryBarrier::default()
+            .old_layout(old_layout)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .src_access_mask(vk::AccessFlags::SHADER_READ)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(color_subresource_range());
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[to_transfer],
+        );
+
+        let region = vk::BufferImageCopy::default()
+            .image_subresource(vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .buffer_offset(staged.offset)
+            .image_offset(offset)
+            .image_extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 });
+        device.cmd_copy_buffer_to_image(cmd, staged.buffer, image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &[region]);
+
+        let to_shader = vk::ImageMemoryBarrier::default()
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(color_subresource_range());
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[to_shader],
+        );
+    })?;
+    Ok(())
+}
+
+fn create_egui_pipeline(
+    device: &Arc<Device>,
+    pipeline_cache: vk::PipelineCache,
+    target: RenderTarget,
+    pipeline_layout: vk::PipelineLayout,
+) -> Result<OwnedPipeline, VulkanDemoError> {
+    let vertex_layout = VertexLayout::new()
+        .binding(std::mem::size_of::<Vertex>() as u32)
+        .attribute(0, vk::Format::R32G32_SFLOAT, std::mem::offset_of!(Vertex, pos) as u32)
+        .attribute(1, vk::Format::R32G32_SFLOAT, std::mem::offset_of!(Vertex, uv) as u32)
+        .attribute(2, vk::Format::R8G8B8A8_UNORM, std::mem::offset_of!(Vertex, color) as u32);
+
+    // Premultiplied alpha.
+    let color_blend_attachment = vk::PipelineColorBlendAttachmentState::default()
+        .color_write_mask(vk::ColorComponentFlags::RGBA)
+        .blend_enable(true)
+        .src_color_blend_factor(vk::BlendFactor::ONE)
+        .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+        .color_blend_op(vk::BlendOp::ADD)
+        .src_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_DST_ALPHA)
+        .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+        .alpha_blend_op(vk::BlendOp::ADD);
+
+    // The viewport is shared with the particle subpass and the scissor set per
+    // mesh. egui doesn't wind its triangles consistently, so nothing is culled.
+    let options = ShaderCompileOptions::default();
+    GraphicsPipelineBuilder::new(target, pipeline_layout)
+        .glsl(vk::ShaderStageFlags::VERTEX, include_str!("shaders/egui.vert"), "egui.vert", &options)
+        .glsl(vk::ShaderStageFlags::FRAGMENT, include_str!("shaders/egui.frag"), "egui.frag", &options)
+        .vertex_layout(vertex_layout)
+        .color_blend(color_blend_attachment)
+        .build(device, pipeline_cache)
+}
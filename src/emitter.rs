@@ -0,0 +1,206 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::{FRAC_PI_2, PI, TAU};
+use crate::particles::MAX_PARTICLE_KINDS;
+
+/// Every particle of kind 0.
+pub const DEFAULT_KIND_WEIGHTS: [f32; MAX_PARTICLE_KINDS] = [1.0, 0.0, 0.0, 0.0];
+
+/// Region new particles appear in.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum EmitterShape {
+    /// Everything starts at the emitter position.
+    Point,
+    /// A segment of `length` through the emitter position, perpendicular to the
+    /// launch direction.
+    Line { length: f32 },
+    /// The outline of a circle. Particles fly radially outward, so `direction`
+    /// and `spread` are ignored.
+    Ring { radius: f32 },
+    /// A filled circle, otherwise like `Ring`.
+    Disc { radius: f32 },
+}
+
+impl EmitterShape {
+    /// The discriminant `particle.comp` switches on.
+    pub(crate) fn id(self) -> u32 {
+        match self {
+            Self::Point => 0,
+            Self::Line { .. } => 1,
+            Self::Ring { .. } => 2,
+            Self::Disc { .. } => 3,
+        }
+    }
+
+    /// Line length or circle radius, 0 for a point.
+    pub(crate) fn size(self) -> f32 {
+        match self {
+            Self::Point => 0.0,
+            Self::Line { length } => length,
+            Self::Ring { radius } | Self::Disc { radius } => radius,
+        }
+    }
+}
+
+/// Where particles spawn and how they are launched, both for the initial cloud
+/// and for respawns on the GPU.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmitterConfig {
+    pub shape: EmitterShape,
+    /// Center of the emitter in world space.
+    pub position: [f32; 2],
+    /// Launch direction in radians; 0 is +x and, as +y points down, -π/2 is up.
+    pub direction: f32,
+    /// Full angle of the launch cone around `direction`, from 0 for a beam to
+    /// 2π for all directions. In 3D it also tilts launches out of the z = 0 plane.
+    pub spread: f32,
+    pub min_speed: f32,
+    pub max_speed: f32,
+    /// Relative share of the initial particles given each kind; `[7.0, 3.0, 0.0, 0.0]`
+    /// makes 70% kind 0 and 30% kind 1.
+    pub kind_weights: [f32; MAX_PARTICLE_KINDS],
+}
+
+impl Default for EmitterConfig {
+    fn default() -> Self {
+        EmitterPreset::default().config()
+    }
+}
+
+impl EmitterConfig {
+    /// Draws a spawn position and velocity. Mirrors `spawn` in `particle.comp`.
+    /// Positions always lie in the z = 0 plane; with `three_d` the velocity is
+    /// tilted out of it, covering the whole sphere for a full spread.
+    pub fn sample(&self, rng: &mut impl Rng, three_d: bool) -> ([f32; 3], [f32; 3]) {
+        let mut angle = self.direction + (rng.gen::<f32>() - 0.5) * self.spread;
+        let offset = match self.shape {
+            EmitterShape::Point => [0.0, 0.0],
+            EmitterShape::Line { length } => {
+                let t = (rng.gen::<f32>() - 0.5) * length;
+                [-self.direction.sin() * t, self.direction.cos() * t]
+            }
+            EmitterShape::Ring { radius } => {
+                angle = rng.gen::<f32>() * TAU;
+                [angle.cos() * radius, angle.sin() * radius]
+            }
+            EmitterShape::Disc { radius } => {
+                angle = rng.gen::<f32>() * TAU;
+                // sqrt keeps the disc uniformly filled instead of crowding its center.
+                let r = radius * rng.gen::<f32>().sqrt();
+                [angle.cos() * r, angle.sin() * r]
+            }
+        };
+        let speed = self.min_speed + (self.max_speed - self.min_speed) * rng.gen::<f32>();
+        // Uniform z on [-1, 1] is uniform on the sphere (Archimedes' hat-box theorem).
+        let z = if three_d { (rng.gen::<f32>() * 2.0 - 1.0) * (self.spread.min(PI) * 0.5).sin() } else { 0.0 };
+        let planar = (1.0 - z * z).sqrt() * speed;
+        (
+            [self.position[0] + offset[0], self.position[1] + offset[1], 0.0],
+            [angle.cos() * planar, angle.sin() * planar, z * speed],
+        )
+    }
+
+    /// Whether more than one kind has a share of the particles.
+    pub fn mixes_kinds(&self) -> bool {
+        self.kind_weights.iter().filter(|&&weight| weight > 0.0).count() > 1
+    }
+
+    /// Draws a kind in proportion to `kind_weights`. With a single weighted
+    /// kind nothing is drawn, leaving the rest of `rng`'s sequence as it was
+    /// before kinds existed.
+    pub fn sample_kind(&self, rng: &mut impl Rng) -> u32 {
+        let weights = self.kind_weights.map(|weight| weight.max(0.0));
+        let last = weights.iter().rposition(|&weight| weight > 0.0).unwrap_or(0);
+        if !self.mixes_kinds() {
+            return last as u32;
+        }
+        let mut pick = rng.gen::<f32>() * weights.iter().sum::<f32>();
+        for (kind, weight) in weights.iter().enumerate() {
+            if pick < *weight {
+                return kind as u32;
+            }
+            pick -= weight;
+        }
+        last as u32
+    }
+}
+
+/// Built-in emitters, selectable with `--emitter` and cycled with the E key.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EmitterPreset {
+    /// A point in the center spraying in all directions.
+    #[default]
+    Spray,
+    /// A narrow upward cone from the bottom of the screen.
+    Fountain,
+    /// An expanding ring.
+    Ring,
+    /// A line along the top edge shooting downward.
+    Rain,
+    /// A filled disc pushing outward.
+    Disc,
+}
+
+impl EmitterPreset {
+    pub fn config(self) -> EmitterConfig {
+        match self {
+            Self::Spray => EmitterConfig {
+                shape: EmitterShape::Point,
+                position: [0.0, 0.0],
+                direction: 0.0,
+                spread: TAU,
+                min_speed: 0.125,
+                max_speed: 0.5,
+                kind_weights: DEFAULT_KIND_WEIGHTS,
+            },
+            Self::Fountain => EmitterConfig {
+                shape: EmitterShape::Point,
+                position: [0.0, 0.95],
+                direction: -FRAC_PI_2,
+                spread: 0.4,
+                min_speed: 0.8,
+                max_speed: 1.2,
+                kind_weights: DEFAULT_KIND_WEIGHTS,
+            },
+            Self::Ring => EmitterConfig {
+                shape: EmitterShape::Ring { radius: 0.2 },
+                position: [0.0, 0.0],
+                direction: 0.0,
+                spread: 0.0,
+                min_speed: 0.3,
+                max_speed: 0.4,
+                kind_weights: DEFAULT_KIND_WEIGHTS,
+            },
+            Self::Rain => EmitterConfig {
+                shape: EmitterShape::Line { length: 2.0 },
+                position: [0.0, -1.0],
+                direction: FRAC_PI_2,
+                spread: 0.1,
+                min_speed: 0.4,
+                max_speed: 0.8,
+                kind_weights: DEFAULT_KIND_WEIGHTS,
+            },
+            Self::Disc => EmitterConfig {
+                shape: EmitterShape::Disc { radius: 0.5 },
+                position: [0.0, 0.0],
+                direction: 0.0,
+                spread: 0.0,
+                min_speed: 0.05,
+                max_speed: 0.2,
+                kind_weights: DEFAULT_KIND_WEIGHTS,
+            },
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            Self::Spray => Self::Fountain,
+            Self::Fountain => Self::Ring,
+            Self::Ring => Self::Rain,
+            Self::Rain => Self::Disc,
+            Self::Disc => Self::Spray,
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use ash::vk;
+use std::fmt;
+use std::path::PathBuf;
+use winit::raw_window_handle::HandleError;
+use crate::shader_diagnostics::{format_diagnostics, ShaderDiagnostic};
+
+/// Errors produced while setting up or driving the demo.
+#[derive(Debug)]
+pub enum VulkanDemoError {
+    /// The Vulkan loader library could not be found or loaded.
+    Loading(ash::LoadingError),
+    /// The Vulkan loader is older than the demo's minimum, by version.
+    UnsupportedApiVersion(String),
+    Vk(vk::Result),
+    /// shaderc failed on `file`: `diagnostics` as parsed from its `log`, or
+    /// none when it failed before compiling anything.
+    ShaderCompilation { file: String, diagnostics: Vec<ShaderDiagnostic>, log: String },
+    NoSuitableGpu,
+    InvalidDeviceIndex { index: usize, count: usize },
+    MissingMemoryType,
+    /// The GPU lacks device features the caller required, by Vulkan name.
+    MissingDeviceFeatures(Vec<&'static str>),
+    WindowHandle(HandleError),
+    SurfaceCreation(vk::Result),
+    ShaderWatch(notify::Error),
+    ThreadPool(rayon::ThreadPoolBuildError),
+    ImageWrite { path: PathBuf, error: png::EncodingError },
+    ImageRead { path: PathBuf, error: png::DecodingError },
+    StatsWrite { path: PathBuf, error: std::io::Error },
+    SnapshotIo { path: PathBuf, error: std::io::Error },
+    /// A snapshot file is damaged or was written by an incompatible version.
+    InvalidSnapshot { path: PathBuf, reason: String },
+    SceneIo { path: PathBuf, error: std::io::Error },
+    /// A scene file isn't valid TOML or doesn't describe a scene.
+    InvalidScene { path: PathBuf, reason: String },
+    /// Shaders don't declare the resources the Rust side binds, or declare them inconsistently.
+    ShaderInterface(String),
+    /// The window surface lacks something the renderer relies on.
+    UnsupportedSurface(String),
+    /// The rendered image can't be read back for a screenshot.
+    UnsupportedCapture(String),
+    /// The headless frame is further from its `--golden` reference than allowed.
+    GoldenMismatch { reference: PathBuf, reason: String },
+    /// Refused up front: the allocation would not fit in what is left of the heap's budget.
+    InsufficientMemory { requested: vk::DeviceSize, available: vk::DeviceSize, device_local: bool },
+}
+
+impl fmt::Display for VulkanDemoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Loading(e) => write!(f, "no Vulkan driver found ({e}); install a Vulkan-capable GPU driver"),
+            Self::UnsupportedApiVersion(version) => write!(f, "the Vulkan loader only supports Vulkan {version}, 1.1 is required"),
+            Self::Vk(e) => write!(f, "Vulkan call failed: {e}"),
+            Self::ShaderCompilation { file, diagnostics, log } => match diagnostics.is_empty() {
+                true => write!(f, "failed to compile shader {file}:\n{log}"),
+                false => write!(f, "failed to compile shader {file}:\n{}", format_diagnostics(diagnostics)),
+            },
+            Self::NoSuitableGpu => write!(f, "no GPU supports graphics, compute and presentation to this window"),
+            Self::InvalidDeviceIndex { index, count } => {
+                write!(f, "GPU index {index} is out of range, {count} device(s) available")
+            }
+            Self::MissingMemoryType => write!(f, "no GPU memory type matches the requested properties"),
+            Self::MissingDeviceFeatures(names) => write!(f, "the GPU lacks required device features: {}", names.join(", ")),
+            Self::WindowHandle(e) => write!(f, "could not access the native window: {e}"),
+            Self::SurfaceCreation(e) => write!(f, "failed to create a Vulkan surface for the window: {e}"),
+            Self::ShaderWatch(e) => write!(f, "could not watch the shader directory: {e}"),
+            Self::ThreadPool(e) => write!(f, "could not start the command recording threads: {e}"),
+            Self::ImageWrite { path, error } => write!(f, "failed to write {}: {error}", path.display()),
+            Self::ImageRead { path, error } => write!(f, "failed to read {}: {error}", path.display()),
+            Self::StatsWrite { path, error } => write!(f, "failed to write {}: {error}", path.display()),
+            Self::SnapshotIo { path, error } => write!(f, "failed to access snapshot {}: {error}", path.display()),
+            Self::InvalidSnapshot { path, reason } => write!(f, "cannot load snapshot {}: {reason}", path.display()),
+            Self::SceneIo { path, error } => write!(f, "failed to access scene {}: {error}", path.display()),
+            Self::InvalidScene { path, reason } => write!(f, "invalid scene {}: {reason}", path.display()),
+            Self::ShaderInterface(reason) => write!(f, "shader interface mismatch: {reason}"),
+            Self::UnsupportedSurface(reason) => write!(f, "cannot render to the window surface: {reason}"),
+            Self::UnsupportedCapture(reason) => write!(f, "cannot capture the frame: {reason}"),
+            Self::GoldenMismatch { reference, reason } => write!(f, "frame does not match {}: {reason}", reference.display()),
+            Self::InsufficientMemory { requested, available, device_local } => write!(
+                f,
+                "requested {:.1} GiB but only {:.1} GiB available on the {} heap",
+                gib(*requested),
+                gib(*available),
+                if *device_local { "device-local" } else { "host" },
+            ),
+        }
+    }
+}
+
+fn gib(bytes: vk::DeviceSize) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0 * 1024.0)
+}
+
+impl std::error::Error for VulkanDemoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Loading(e) => Some(e),
+            Self::Vk(e) | Self::SurfaceCreation(e) => Some(e),
+            Self::WindowHandle(e) => Some(e),
+            Self::ShaderWatch(e) => Some(e),
+            Self::ThreadPool(e) => Some(e),
+            Self::ImageWrite { error, .. } => Some(error),
+            Self::ImageRead { error, .. } => Some(error),
+            Self::StatsWrite { error, .. } | Self::SnapshotIo { error, .. } | Self::SceneIo { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<vk::Result> for VulkanDemoError {
+    fn from(e: vk::Result) -> Self {
+        Self::Vk(e)
+    }
+}
+
+impl From<ash::LoadingError> for VulkanDemoError {
+    fn from(e: ash::LoadingError) -> Self {
+        Self::Loading(e)
+    }
+}
+
+impl From<HandleError> for VulkanDemoError {
+    fn from(e: HandleError) -> Self {
+        Self::WindowHandle(e)
+    }
+}
+
+impl From<notify::Error> for VulkanDemoError {
+    fn from(e: notify::Error) -> Self {
+        Self::ShaderWatch(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use std::io;
+
+    #[test]
+    fn from_vk_result() {
+        let error = VulkanDemoError::from(vk::Result::ERROR_DEVICE_LOST);
+        assert!(matches!(error, VulkanDemoError::Vk(vk::Result::ERROR_DEVICE_LOST)));
+        assert_eq!(error.to_string(), format!("Vulkan call failed: {}", vk::Result::ERROR_DEVICE_LOST));
+        let source = error.source().and_then(|source| source.downcast_ref::<vk::Result>());
+        assert_eq!(source, Some(&vk::Result::ERROR_DEVICE_LOST));
+    }
+
+    #[test]
+    fn from_handle_and_watch_errors() {
+        let error = VulkanDemoError::from(HandleError::Unavailable);
+        assert!(matches!(error, VulkanDemoError::WindowHandle(HandleError::Unavailable)));
+        assert!(error.to_string().starts_with("could not access the native window: "));
+        assert!(error.source().is_some());
+
+        let error = VulkanDemoError::from(notify::Error::io(io::Error::new(io::ErrorKind::NotFound, "no such directory")));
+        assert!(matches!(error, VulkanDemoError::ShaderWatch(_)));
+        assert!(error.to_string().starts_with("could not watch the shader directory: "));
+        assert!(error.source().is_some_and(|source| source.is::<notify::Error>()));
+    }
+
+    #[test]
+    fn shader_compilation_display() {
+        let log = "particle.comp:3: error: 'foo' : undeclared identifier\n1 error generated.".to_string();
+        let error = VulkanDemoError::ShaderCompilation { file: "particle.comp".to_string(), diagnostics: Vec::new(), log: log.clone() };
+        assert_eq!(error.to_string(), format!("failed to compile shader particle.comp:\n{log}"));
+        assert!(error.source().is_none());
+
+        let diagnostics = ShaderDiagnostic::parse_log(&log, "particle.comp", |_| None);
+        let expected = format!("failed to compile shader particle.comp:\n{}", format_diagnostics(&diagnostics));
+        let error = VulkanDemoError::ShaderCompilation { file: "particle.comp".to_string(), diagnostics, log };
+        assert_eq!(error.to_string(), expected);
+    }
+
+    #[test]
+    fn io_errors_keep_their_path_and_source() {
+        let path = PathBuf::from("saves/snapshot.bin");
+        let error = VulkanDemoError::SnapshotIo { path, error: io::Error::new(io::ErrorKind::PermissionDenied, "denied") };
+        assert_eq!(error.to_string(), "failed to access snapshot saves/snapshot.bin: denied");
+        let source = error.source().and_then(|source| source.downcast_ref::<io::Error>()).map(io::Error::kind);
+        assert_eq!(source, Some(io::ErrorKind::PermissionDenied));
+    }
+
+    #[test]
+    fn display_without_source() {
+        let error = VulkanDemoError::NoSuitableGpu;
+        assert_eq!(error.to_string(), "no GPU supports graphics, compute and presentation to this window");
+        assert!(error.source().is_none());
+
+        let error = VulkanDemoError::InvalidDeviceIndex { index: 3, count: 2 };
+        assert_eq!(error.to_string(), "GPU index 3 is out of range, 2 device(s) available");
+        assert!(error.source().is_none());
+
+        let error = VulkanDemoError::InsufficientMemory { requested: 3 << 30, available: 1 << 30, device_local: true };
+        assert_eq!(error.to_string(), "requested 3.0 GiB but only 1.0 GiB available on the device-local heap");
+
+        let error = VulkanDemoError::MissingDeviceFeatures(vec!["largePoints", "fillModeNonSolid"]);
+        assert_eq!(error.to_string(), "the GPU lacks required device features: largePoints, fillModeNonSolid");
+    }
+}
@@ -0,0 +1,163 @@
+use ash::{vk, Device};
+use std::collections::HashMap;
+use crate::gpu_timer::GpuTimer;
+use crate::renderer::Renderer;
+use crate::sync2::BufferBarrier;
+
+// Accesses that make a buffer's contents something later uses must wait for.
+const WRITE_ACCESS: vk::AccessFlags2 = vk::AccessFlags2::from_raw(
+    vk::AccessFlags2::SHADER_WRITE.as_raw()
+        | vk::AccessFlags2::SHADER_STORAGE_WRITE.as_raw()
+        | vk::AccessFlags2::TRANSFER_WRITE.as_raw()
+        | vk::AccessFlags2::HOST_WRITE.as_raw()
+        | vk::AccessFlags2::MEMORY_WRITE.as_raw(),
+);
+
+/// How a pass uses a buffer, which `FrameGraph` orders against how the
+/// passes before it did.
+#[derive(Copy, Clone, Debug)]
+pub struct BufferUsage {
+    pub buffer: vk::Buffer,
+    pub stage: vk::PipelineStageFlags2,
+    pub access: vk::AccessFlags2,
+}
+
+impl BufferUsage {
+    pub fn new(buffer: vk::Buffer, stage: vk::PipelineStageFlags2, access: vk::AccessFlags2) -> Self {
+        Self { buffer, stage, access }
+    }
+
+    fn writes(&self) -> bool {
+        self.access.intersects(WRITE_ACCESS)
+    }
+}
+
+/// One part of a frame's recording, such as the simulation step, the scene
+/// or the histogram.
+///
+/// Barriers between the buffers passes declare in `buffer_usages` are left to
+/// the `FrameGraph`; anything else a pass touches, it synchronizes itself.
+pub trait RecordPass {
+    /// Buffers the pass reads or writes, and from which stages.
+    fn buffer_usages(&self) -> Vec<BufferUsage> {
+        Vec::new()
+    }
+
+    fn record(&mut self, frame: &mut RenderFrame) -> Result<(), vk::Result>;
+}
+
+impl<P: RecordPass + ?Sized> RecordPass for &mut P {
+    fn buffer_usages(&self) -> Vec<BufferUsage> {
+        (**self).buffer_usages()
+    }
+
+    fn record(&mut self, frame: &mut RenderFrame) -> Result<(), vk::Result> {
+        (**self).record(frame)
+    }
+}
+
+/// The frame being recorded, handed to each pass: its command buffer and
+/// swapchain image, along with the renderer and timer that record into it.
+/// Made by `Renderer::begin_frame`.
+pub struct RenderFrame<'a> {
+    pub device: &'a Device,
+    pub cmd: vk::CommandBuffer,
+    /// Swapchain image drawn to; meaningless for a headless renderer.
+    pub image_index: u32,
+    pub renderer: &'a mut Renderer,
+    pub gpu_timer: &'a mut GpuTimer,
+}
+
+impl RenderFrame<'_> {
+    /// The swapchain image being drawn to, or `None` when headless.
+    pub fn swapchain_image(&self) -> Option<vk::Image> {
+        self.renderer.images.get(self.image_index as usize).copied()
+    }
+
+    pub fn swapchain_view(&self) -> Option<vk::ImageView> {
+        self.renderer.image_views.get(self.image_index as usize).map(|view| view.handle())
+    }
+
+    /// Begins the scene pass the particles are drawn in.
+    pub fn begin_main_pass(&mut self, contents: vk::SubpassContents) {
+        self.renderer.begin_scene_pass(self.device, self.cmd, contents);
+    }
+
+    pub fn end_main_pass(&mut self) {
+        self.renderer.end_scene_pass(self.device, self.cmd);
+    }
+
+    /// Finishes recording the frame's command buffer.
+    pub fn end_frame(self) -> Result<(), vk::Result> {
+        unsafe { self.device.end_command_buffer(self.cmd) }
+    }
+}
+
+/// The passes of one frame, recorded in the order they were added, with
+/// barriers between them wherever a pass uses a buffer an earlier one wrote,
+/// or writes one an earlier one used.
+#[derive(Default)]
+pub struct FrameGraph<'a> {
+    passes: Vec<Box<dyn RecordPass + 'a>>,
+}
+
+/// What the passes recorded so far did to one buffer.
+#[derive(Copy, Clone, Default)]
+struct BufferState {
+    /// The last write, if any.
+    write: Option<(vk::PipelineStageFlags2, vk::AccessFlags2)>,
+    /// Reads since then that the write has already been made visible to.
+    read_stages: vk::PipelineStageFlags2,
+    read_access: vk::AccessFlags2,
+}
+
+impl<'a> FrameGraph<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_pass(&mut self, pass: impl RecordPass + 'a) {
+        self.passes.push(Box::new(pass));
+    }
+
+    /// Records every pass into `frame`. Buffers start the frame with no
+    /// recorded use; whatever earlier submissions did to them is for the
+    /// passes, or the semaphores between submissions, to wait on.
+    pub fn record(self, frame: &mut RenderFrame) -> Result<(), vk::Result> {
+        let mut buffers = HashMap::new();
+        for mut pass in self.passes {
+            let barriers = barriers_before(&mut buffers, &pass.buffer_usages());
+            if !barriers.is_empty() {
+                frame.renderer.sync2.pipeline_barrier(frame.device, frame.cmd, &barriers, &[]);
+            }
+            pass.record(frame)?;
+        }
+        Ok(())
+    }
+}
+
+/// The barriers ordering `usages` after what `buffers` says came before,
+/// updating it to include them.
+fn barriers_before(buffers: &mut HashMap<vk::Buffer, BufferState>, usages: &[BufferUsage]) -> Vec<BufferBarrier> {
+    let mut barriers = Vec::new();
+    for usage in usages {
+        let state = buffers.entry(usage.buffer).or_default();
+        let dst = (usage.stage, usage.access);
+        if usage.writes() {
+            // After the last write and every read since; reads have nothing to make available.
+            let (write_stage, write_access) = state.write.unwrap_or_default();
+            let src_stage = write_stage | state.read_stages;
+            if !src_stage.is_empty() {
+                barriers.push(BufferBarrier::new(usage.buffer, (src_stage, write_access), dst));
+            }
+            *state = BufferState { write: Some(dst), ..BufferState::default() };
+        } else if let Some(write) = state.write {
+            if !state.read_stages.contains(usage.stage) || !state.read_access.contains(usage.access) {
+                barriers.push(BufferBarrier::new(usage.buffer, write, dst));
+                state.read_stages |= usage.stage;
+                state.read_access |= usage.access;
+            }
+        }
+    }
+    barriers
+}
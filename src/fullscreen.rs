@@ -0,0 +1,50 @@
+use winit::dpi::PhysicalSize;
+use winit::monitor::{MonitorHandle, VideoMode};
+use winit::window::{Fullscreen, Window};
+
+/// Fullscreen on monitor `index` of those the window can see, or on the one
+/// it's on without an index. Borderless by default; with `exclusive_size` the
+/// monitor switches to its video mode of that size with the highest refresh
+/// rate, or stays borderless if it has no such mode.
+pub fn fullscreen(window: &Window, index: Option<usize>, exclusive_size: Option<PhysicalSize<u32>>) -> Fullscreen {
+    let monitor = match index {
+        Some(index) => {
+            let monitor = window.available_monitors().nth(index);
+            if monitor.is_none() {
+                log::warn!("No monitor {index}, going fullscreen on the current one");
+            }
+            monitor.or_else(|| window.current_monitor())
+        }
+        None => window.current_monitor(),
+    };
+    let video_mode = match (&monitor, exclusive_size) {
+        (Some(monitor), Some(size)) => {
+            let video_mode = best_video_mode(monitor, size);
+            if video_mode.is_none() {
+                log::warn!("No {}x{} video mode, using borderless fullscreen", size.width, size.height);
+            }
+            video_mode
+        }
+        _ => None,
+    };
+    match video_mode {
+        Some(video_mode) => Fullscreen::Exclusive(video_mode),
+        None => Fullscreen::Borderless(monitor),
+    }
+}
+
+/// Switches between windowed and borderless fullscreen on the current monitor.
+pub fn toggle_fullscreen(window: &Window) {
+    let fullscreen = match window.fullscreen() {
+        Some(_) => None,
+        None => Some(fullscreen(window, None, None)),
+    };
+    window.set_fullscreen(fullscreen);
+}
+
+fn best_video_mode(monitor: &MonitorHandle, size: PhysicalSize<u32>) -> Option<VideoMode> {
+    monitor
+        .video_modes()
+        .filter(|video_mode| video_mode.size() == size)
+        .max_by_key(|video_mode| (video_mode.refresh_rate_millihertz(), video_mode.bit_depth()))
+}
@@ -0,0 +1,89 @@
+use ash::vk;
+use std::path::{Path, PathBuf};
+use crate::error::VulkanDemoError;
+use crate::screenshot::{read_png, write_png};
+
+/// Set to any value to overwrite `--golden` references with the frame rendered
+/// instead of comparing against them.
+pub const BLESS_VAR: &str = "BLESS_GOLDEN";
+
+pub const DEFAULT_CHANNEL_TOLERANCE: u8 = 2;
+pub const DEFAULT_MAX_DIFFERING_PIXELS: usize = 0;
+
+/// How far a frame may stray from its reference image and still match.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GoldenTolerance {
+    /// Largest difference in any one channel of a pixel that still counts as equal.
+    pub channel: u8,
+    /// Pixels allowed to differ by more than `channel` before the frame fails.
+    pub max_differing_pixels: usize,
+}
+
+impl Default for GoldenTolerance {
+    fn default() -> Self {
+        Self { channel: DEFAULT_CHANNEL_TOLERANCE, max_differing_pixels: DEFAULT_MAX_DIFFERING_PIXELS }
+    }
+}
+
+/// How a frame compared to its reference image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GoldenOutcome {
+    /// Within tolerance of the reference.
+    Matched { differing_pixels: usize },
+    /// Written as the new reference, with `BLESS_VAR` set.
+    Blessed,
+}
+
+/// Compares the RGBA8 `rgba` frame against the PNG at `reference`, or writes it
+/// there when `BLESS_VAR` is set. A frame that doesn't match fails with
+/// `GoldenMismatch`, after saving an image of where it differs next to the
+/// reference: matching pixels dimmed, differing ones in red.
+pub fn compare_golden(
+    reference: &Path,
+    extent: vk::Extent2D,
+    rgba: &[u8],
+    tolerance: GoldenTolerance,
+) -> Result<GoldenOutcome, VulkanDemoError> {
+    if std::env::var_os(BLESS_VAR).is_some() {
+        write_png(reference, extent, rgba)?;
+        return Ok(GoldenOutcome::Blessed);
+    }
+    let mismatch = |reason: String| VulkanDemoError::GoldenMismatch { reference: reference.to_path_buf(), reason };
+    let (expected_extent, expected) = read_png(reference)?;
+    if (expected_extent.width, expected_extent.height) != (extent.width, extent.height) {
+        return Err(mismatch(format!(
+            "rendered {}x{}, reference is {}x{}",
+            extent.width, extent.height, expected_extent.width, expected_extent.height
+        )));
+    }
+
+    let mut diff = Vec::with_capacity(rgba.len());
+    let mut differing_pixels = 0;
+    for (actual, expected) in rgba.chunks_exact(4).zip(expected.chunks_exact(4)) {
+        let differs = actual.iter().zip(expected).any(|(&a, &e)| a.abs_diff(e) > tolerance.channel);
+        if differs {
+            differing_pixels += 1;
+            diff.extend_from_slice(&[u8::MAX, 0, 0, u8::MAX]);
+        } else {
+            diff.extend(actual[..3].iter().map(|&channel| channel / 4));
+            diff.push(u8::MAX);
+        }
+    }
+    if differing_pixels > tolerance.max_differing_pixels {
+        let diff_path = diff_path(reference);
+        write_png(&diff_path, extent, &diff)?;
+        return Err(mismatch(format!(
+            "{differing_pixels} pixels differ by more than {}, {} allowed; differences saved to {}",
+            tolerance.channel,
+            tolerance.max_differing_pixels,
+            diff_path.display()
+        )));
+    }
+    Ok(GoldenOutcome::Matched { differing_pixels })
+}
+
+/// `name.diff.png` beside reference `name.png`.
+fn diff_path(reference: &Path) -> PathBuf {
+    let stem = reference.file_stem().unwrap_or_default().to_string_lossy();
+    reference.with_file_name(format!("{stem}.diff.png"))
+}
@@ -0,0 +1,219 @@
+use ash::vk;
+use std::mem::size_of;
+use crate::allocator::MemoryLocation;
+use crate::error::VulkanDemoError;
+use crate::memory::create_buffer;
+use crate::particles::create_compute_pipeline;
+use crate::pipeline_utils::{compile_shader, ShaderCompileOptions};
+use crate::resources::{OwnedBuffer, OwnedDescriptorPool, OwnedDescriptorSetLayout, OwnedPipeline, OwnedPipelineLayout};
+use crate::spatial_grid::memory_barrier;
+use crate::vulkan_context::VulkanContext;
+
+// Binding numbers shared by the scan shaders.
+const BINDING_VALUES: u32 = 0;
+const BINDING_SUMS: u32 = 1;
+const BINDING_BLOCK_SUMS: u32 = 2;
+
+/// `GROUP_SIZE` in the scan shaders: values scanned per workgroup.
+const GROUP_SIZE: u32 = 256;
+
+/// Exclusive prefix sum of a `u32` array on the GPU, for turning per-element
+/// counts into offsets.
+///
+/// Three passes: `scan_blocks.comp` scans each block of `GROUP_SIZE` values in
+/// shared memory and writes the block totals, `scan_block_sums.comp` scans
+/// those in a single workgroup, and `scan_add.comp` adds each block's offset to
+/// its sums. The pipelines are made once and shared; each pair of buffers to
+/// scan between gets a `ScanBinding` from `bind`.
+pub struct GpuScan {
+    blocks_pipeline: OwnedPipeline,
+    block_sums_pipeline: OwnedPipeline,
+    add_pipeline: OwnedPipeline,
+    pipeline_layout: OwnedPipelineLayout,
+    descriptor_set_layout: OwnedDescriptorSetLayout,
+}
+
+/// The values and sums buffers of one scan, with the block totals it needs in
+/// between. Made by `GpuScan::bind`.
+pub struct ScanBinding {
+    descriptor_set: vk::DescriptorSet,
+    _descriptor_pool: OwnedDescriptorPool,
+    _block_sums: OwnedBuffer,
+    capacity: u32,
+}
+
+impl GpuScan {
+    pub fn new(context: &VulkanContext) -> Result<Self, VulkanDemoError> {
+        let device = &context.device;
+        let binding = |binding: u32| {
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(binding)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        };
+        let layout_bindings = [binding(BINDING_VALUES), binding(BINDING_SUMS), binding(BINDING_BLOCK_SUMS)];
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(&layout_bindings);
+        let descriptor_set_layout = OwnedDescriptorSetLayout::new(
+            device,
+            unsafe { device.create_descriptor_set_layout(&layout_info, None)? },
+        );
+
+        let set_layouts = [descriptor_set_layout.handle()];
+        let push_constant_ranges = [vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .size(size_of::<u32>() as u32)];
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+        let pipeline_layout = OwnedPipelineLayout::new(
+            device,
+            unsafe { device.create_pipeline_layout(&pipeline_layout_info, None)? },
+        );
+
+        // The workgroup size is fixed by the shared arrays, not specialized.
+        let pipeline = |name: &str, source: &str| -> Result<OwnedPipeline, VulkanDemoError> {
+            let spirv = compile_shader(source, name, shaderc::ShaderKind::Compute, &ShaderCompileOptions::default())?;
+            let pipeline = create_compute_pipeline(device, context.pipeline_cache, pipeline_layout.handle(), &spirv, None)?;
+            context.debug.set_object_name(pipeline.handle(), name);
+            Ok(pipeline)
+        };
+        Ok(Self {
+            blocks_pipeline: pipeline("scan_blocks.comp", include_str!("shaders/scan_blocks.comp"))?,
+            block_sums_pipeline: pipeline("scan_block_sums.comp", include_str!("shaders/scan_block_sums.comp"))?,
+            add_pipeline: pipeline("scan_add.comp", include_str!("shaders/scan_add.comp"))?,
+            pipeline_layout,
+            descriptor_set_layout,
+        })
+    }
+
+    /// Binds a scan of up to `capacity` values from `values` into `sums`,
+    /// both storage buffers of at least that many `u32`s. They may be the same
+    /// buffer, for a scan in place.
+    pub fn bind(
+        &self,
+        context: &VulkanContext,
+        values: &OwnedBuffer,
+        sums: &OwnedBuffer,
+        capacity: u32,
+    ) -> Result<ScanBinding, VulkanDemoError> {
+        let device = &context.device;
+        let block_sums = create_buffer(
+            context,
+            capacity.div_ceil(GROUP_SIZE).max(1) as vk::DeviceSize * size_of::<u32>() as vk::DeviceSize,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            MemoryLocation::GpuOnly,
+        )?;
+        context.debug.set_object_name(block_sums.handle(), "scan block sums");
+
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(3)];
+        let pool_info = vk::DescriptorPoolCreateInfo::default()
+            .pool_sizes(&pool_sizes)
+            .max_sets(1);
+        let descriptor_pool = OwnedDescriptorPool::new(
+            device,
+            unsafe { device.create_descriptor_pool(&pool_info, None)? },
+        );
+
+        let set_layouts = [self.descriptor_set_layout.handle()];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool.handle())
+            .set_layouts(&set_layouts);
+        let descriptor_set = unsafe { device.allocate_descriptor_sets(&alloc_info)? }[0];
+
+        let whole = |buffer: &OwnedBuffer| {
+            vk::DescriptorBufferInfo::default()
+                .buffer(buffer.handle())
+                .offset(0)
+                .range(vk::WHOLE_SIZE)
+        };
+        let infos = [
+            (BINDING_VALUES, whole(values)),
+            (BINDING_SUMS, whole(sums)),
+            (BINDING_BLOCK_SUMS, whole(&block_sums)),
+        ];
+        let writes: Vec<_> = infos.iter().map(|(binding, info)| {
+            vk::WriteDescriptorSet::default()
+                .dst_set(descriptor_set)
+                .dst_binding(*binding)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(std::slice::from_ref(info))
+        }).collect();
+        unsafe { device.update_descriptor_sets(&writes, &[]) };
+
+        Ok(ScanBinding { descriptor_set, _descriptor_pool: descriptor_pool, _block_sums: block_sums, capacity })
+    }
+
+    /// Records scanning the first `count` values of `binding`, at most its
+    /// capacity. The values must already be visible to compute shaders; ends
+    /// with a barrier making the sums visible to the following dispatches.
+    /// Leaves the scan's own pipeline and descriptor set bound.
+    pub fn record(&self, device: &ash::Device, cmd: vk::CommandBuffer, binding: &ScanBinding, count: u32) {
+        assert!(count <= binding.capacity, "scan of {count} values bound for {}", binding.capacity);
+        let layout = self.pipeline_layout.handle();
+        let groups = count.div_ceil(GROUP_SIZE);
+        if groups == 0 {
+            return;
+        }
+        let compute_to_compute = (
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE),
+        );
+        unsafe {
+            device.cmd_bind_descriptor_sets(cmd, vk::PipelineBindPoint::COMPUTE, layout, 0, &[binding.descriptor_set], &[]);
+            device.cmd_push_constants(cmd, layout, vk::ShaderStageFlags::COMPUTE, 0, bytemuck::bytes_of(&count));
+
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.blocks_pipeline.handle());
+            device.cmd_dispatch(cmd, groups, 1, 1);
+            memory_barrier(device, cmd, compute_to_compute.0, compute_to_compute.1);
+
+            // A single block is already scanned.
+            if groups > 1 {
+                device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.block_sums_pipeline.handle());
+                device.cmd_dispatch(cmd, 1, 1, 1);
+                memory_barrier(device, cmd, compute_to_compute.0, compute_to_compute.1);
+
+                device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.add_pipeline.handle());
+                device.cmd_dispatch(cmd, groups, 1, 1);
+                memory_barrier(device, cmd, compute_to_compute.0, compute_to_compute.1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compute_harness::{test_context, ComputeHarness};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn matches_cpu_exclusive_scan() {
+        let Some(context) = test_context() else { return };
+        let harness = ComputeHarness::new(&context);
+        let scan = GpuScan::new(&context).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        // Single blocks, partial last blocks, and enough blocks for the second pass to run in runs.
+        for count in [1, 255, 256, 257, GROUP_SIZE * GROUP_SIZE + 3] {
+            let values: Vec<u32> = (0..count).map(|_| rng.gen_range(0..16)).collect();
+            let expected: Vec<u32> = values
+                .iter()
+                .scan(0, |sum, &value| {
+                    let offset = *sum;
+                    *sum += value;
+                    Some(offset)
+                })
+                .collect();
+
+            let values_buffer = harness.buffer(&values).unwrap();
+            let sums_buffer = harness.zeroed_buffer::<u32>(count as usize).unwrap();
+            let binding = scan.bind(&context, &values_buffer, &sums_buffer, count).unwrap();
+            harness.run(|cmd| scan.record(&context.device, cmd, &binding, count)).unwrap();
+            let sums: Vec<u32> = harness.read(&sums_buffer, count as usize).unwrap();
+            assert_eq!(sums, expected, "scan of {count} values");
+        }
+    }
+}
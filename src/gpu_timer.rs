@@ -0,0 +1,288 @@
+use ash::{vk, Device};
+use std::time::{Duration, Instant};
+use crate::error::VulkanDemoError;
+use crate::resources::OwnedQueryPool;
+use crate::sync::FRAMES_IN_FLIGHT;
+#[cfg(feature = "tracy")]
+use crate::tracy_gpu::TracyGpu;
+use crate::vulkan_context::VulkanContext;
+
+const COMPUTE_BEGIN: u32 = 0;
+const COMPUTE_END: u32 = 1;
+const GRAPHICS_BEGIN: u32 = 2;
+const GRAPHICS_END: u32 = 3;
+const BLOOM_BEGIN: u32 = 4;
+const BLOOM_END: u32 = 5;
+const SORT_BEGIN: u32 = 6;
+const SORT_END: u32 = 7;
+const QUERY_COUNT: u32 = 8;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// GPU time spent in each pass, in milliseconds.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GpuTimings {
+    pub compute_ms: f64,
+    pub graphics_ms: f64,
+    /// Part of `graphics_ms`; zero with bloom off.
+    pub bloom_ms: f64,
+    /// Depth sorting before the graphics pass; zero with no system sorted.
+    pub sort_ms: f64,
+}
+
+/// Measures the compute, depth sort, graphics and bloom passes with timestamp queries.
+///
+/// Every frame in flight writes its own set of queries, read back once
+/// `begin_frame` is called for it again after the frame has been waited for,
+/// so reading them never stalls the GPU. Devices whose graphics or compute queue family
+/// reports no valid timestamp bits get a disabled timer whose methods do nothing.
+///
+/// Each pass resets its own pair of queries, so the compute pass can be
+/// recorded into a command buffer for a different queue, or skipped.
+pub struct GpuTimer {
+    query_pool: Option<OwnedQueryPool>,
+    timestamp_period_ns: f64,
+    valid_bits_mask: u64,
+    /// The frame in flight whose queries are being written.
+    frame: usize,
+    pending: [PendingQueries; FRAMES_IN_FLIGHT],
+    sum: GpuTimings,
+    compute_samples: u32,
+    graphics_samples: u32,
+    bloom_samples: u32,
+    sort_samples: u32,
+    last_report: Instant,
+    /// Zones for each pass on Tracy's timeline, from the same timestamps.
+    #[cfg(feature = "tracy")]
+    tracy: Option<TracyGpu>,
+}
+
+/// Passes of one frame in flight whose timestamps are written but not yet read.
+#[derive(Copy, Clone, Debug, Default)]
+struct PendingQueries {
+    compute: bool,
+    graphics: bool,
+    bloom: bool,
+    sort: bool,
+}
+
+impl GpuTimer {
+    pub fn new(context: &VulkanContext) -> Result<Self, VulkanDemoError> {
+        let properties = unsafe { context.instance.get_physical_device_properties(context.physical_device) };
+        let queue_families = unsafe {
+            context.instance.get_physical_device_queue_family_properties(context.physical_device)
+        };
+        let valid_bits = queue_families[context.queue_family_index as usize].timestamp_valid_bits
+            .min(queue_families[context.compute_queue_family_index as usize].timestamp_valid_bits);
+
+        let query_pool = if valid_bits == 0 {
+            log::warn!("Queue family has no timestamp support, GPU timings are disabled");
+            None
+        } else {
+            let pool_info = vk::QueryPoolCreateInfo::default()
+                .query_type(vk::QueryType::TIMESTAMP)
+                .query_count(QUERY_COUNT * FRAMES_IN_FLIGHT as u32);
+            let pool = unsafe { context.device.create_query_pool(&pool_info, None)? };
+            Some(OwnedQueryPool::new(&context.device, pool))
+        };
+        #[cfg(feature = "tracy")]
+        let tracy = match query_pool {
+            Some(_) => TracyGpu::new(context, properties.limits.timestamp_period)?,
+            None => None,
+        };
+
+        Ok(Self {
+            query_pool,
+            timestamp_period_ns: properties.limits.timestamp_period as f64,
+            valid_bits_mask: if valid_bits >= 64 { u64::MAX } else { (1u64 << valid_bits) - 1 },
+            frame: 0,
+            pending: [PendingQueries::default(); FRAMES_IN_FLIGHT],
+            sum: GpuTimings::default(),
+            compute_samples: 0,
+            graphics_samples: 0,
+            bloom_samples: 0,
+            sort_samples: 0,
+            last_report: Instant::now(),
+            #[cfg(feature = "tracy")]
+            tracy,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.query_pool.is_some()
+    }
+
+    /// Switches to the queries of frame in flight `frame`, first collecting
+    /// the timestamps it wrote last time. Must be called after that frame has
+    /// been waited for.
+    pub fn begin_frame(&mut self, device: &Device, frame: usize) -> Result<(), vk::Result> {
+        self.collect(device, frame)?;
+        self.frame = frame;
+        #[cfg(feature = "tracy")]
+        if let Some(tracy) = &self.tracy {
+            tracy.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Reads the timestamps frame in flight `frame` wrote. Must be called after
+    /// that frame has been waited for.
+    pub fn collect(&mut self, device: &Device, frame: usize) -> Result<(), vk::Result> {
+        let pending = std::mem::take(&mut self.pending[frame]);
+        let read = |written: bool, begin: u32| if written { self.read_ticks(device, frame, begin).map(Some) } else { Ok(None) };
+        let ticks = [
+            read(pending.compute, COMPUTE_BEGIN)?,
+            read(pending.graphics, GRAPHICS_BEGIN)?,
+            read(pending.bloom, BLOOM_BEGIN)?,
+            read(pending.sort, SORT_BEGIN)?,
+        ];
+        if let Some(ticks) = ticks[0] {
+            self.sum.compute_ms += self.ticks_ms(ticks);
+            self.compute_samples += 1;
+        }
+        if let Some(ticks) = ticks[1] {
+            self.sum.graphics_ms += self.ticks_ms(ticks);
+            self.graphics_samples += 1;
+        }
+        if let Some(ticks) = ticks[2] {
+            self.sum.bloom_ms += self.ticks_ms(ticks);
+            self.bloom_samples += 1;
+        }
+        if let Some(ticks) = ticks[3] {
+            self.sum.sort_ms += self.ticks_ms(ticks);
+            self.sort_samples += 1;
+        }
+        #[cfg(feature = "tracy")]
+        if let Some(tracy) = &mut self.tracy {
+            tracy.upload(frame, &ticks.map(|ticks| ticks.map(|ticks| ticks.map(|tick| tick as i64))));
+        }
+        Ok(())
+    }
+
+    /// The timestamp at `begin` and the one right after it, in `frame`'s queries.
+    fn read_ticks(&self, device: &Device, frame: usize, begin: u32) -> Result<[u64; 2], vk::Result> {
+        let mut ticks = [0u64; 2];
+        if let Some(query_pool) = &self.query_pool {
+            unsafe {
+                device.get_query_pool_results(
+                    query_pool.handle(),
+                    query_index(frame, begin),
+                    &mut ticks,
+                    vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+                )?;
+            }
+        }
+        Ok(ticks.map(|tick| tick & self.valid_bits_mask))
+    }
+
+    /// Milliseconds between a pair of timestamps from `read_ticks`.
+    fn ticks_ms(&self, [begin, end]: [u64; 2]) -> f64 {
+        let delta = end.wrapping_sub(begin) & self.valid_bits_mask;
+        delta as f64 * self.timestamp_period_ns / 1_000_000.0
+    }
+
+    /// Returns the average timings once per reporting interval.
+    pub fn average(&mut self) -> Option<GpuTimings> {
+        if self.graphics_samples == 0 || self.last_report.elapsed() < REPORT_INTERVAL {
+            return None;
+        }
+        let average = GpuTimings {
+            compute_ms: self.sum.compute_ms / self.compute_samples.max(1) as f64,
+            graphics_ms: self.sum.graphics_ms / self.graphics_samples as f64,
+            bloom_ms: self.sum.bloom_ms / self.bloom_samples.max(1) as f64,
+            sort_ms: self.sum.sort_ms / self.sort_samples.max(1) as f64,
+        };
+        self.sum = GpuTimings::default();
+        self.compute_samples = 0;
+        self.graphics_samples = 0;
+        self.bloom_samples = 0;
+        self.sort_samples = 0;
+        self.last_report = Instant::now();
+        Some(average)
+    }
+
+    /// Record outside of any render pass.
+    pub fn begin_compute(&mut self, device: &Device, cmd: vk::CommandBuffer) {
+        self.reset(device, cmd, COMPUTE_BEGIN);
+        self.write(device, cmd, vk::PipelineStageFlags::TOP_OF_PIPE, COMPUTE_BEGIN);
+        self.begin_zone(COMPUTE_BEGIN);
+    }
+
+    pub fn end_compute(&mut self, device: &Device, cmd: vk::CommandBuffer) {
+        self.write(device, cmd, vk::PipelineStageFlags::BOTTOM_OF_PIPE, COMPUTE_END);
+        self.end_zone(COMPUTE_BEGIN);
+        self.pending[self.frame].compute = self.enabled();
+    }
+
+    /// Record outside of any render pass.
+    pub fn begin_graphics(&mut self, device: &Device, cmd: vk::CommandBuffer) {
+        self.reset(device, cmd, GRAPHICS_BEGIN);
+        self.write(device, cmd, vk::PipelineStageFlags::TOP_OF_PIPE, GRAPHICS_BEGIN);
+        self.begin_zone(GRAPHICS_BEGIN);
+    }
+
+    pub fn end_graphics(&mut self, device: &Device, cmd: vk::CommandBuffer) {
+        self.write(device, cmd, vk::PipelineStageFlags::BOTTOM_OF_PIPE, GRAPHICS_END);
+        self.end_zone(GRAPHICS_BEGIN);
+        self.pending[self.frame].graphics = self.enabled();
+    }
+
+    /// Record outside of any render pass, within the graphics pass.
+    pub fn begin_bloom(&mut self, device: &Device, cmd: vk::CommandBuffer) {
+        self.reset(device, cmd, BLOOM_BEGIN);
+        self.write(device, cmd, vk::PipelineStageFlags::TOP_OF_PIPE, BLOOM_BEGIN);
+        self.begin_zone(BLOOM_BEGIN);
+    }
+
+    pub fn end_bloom(&mut self, device: &Device, cmd: vk::CommandBuffer) {
+        self.write(device, cmd, vk::PipelineStageFlags::BOTTOM_OF_PIPE, BLOOM_END);
+        self.end_zone(BLOOM_BEGIN);
+        self.pending[self.frame].bloom = self.enabled();
+    }
+
+    /// Record outside of any render pass, before the graphics pass.
+    pub fn begin_sort(&mut self, device: &Device, cmd: vk::CommandBuffer) {
+        self.reset(device, cmd, SORT_BEGIN);
+        self.write(device, cmd, vk::PipelineStageFlags::TOP_OF_PIPE, SORT_BEGIN);
+        self.begin_zone(SORT_BEGIN);
+    }
+
+    pub fn end_sort(&mut self, device: &Device, cmd: vk::CommandBuffer) {
+        self.write(device, cmd, vk::PipelineStageFlags::BOTTOM_OF_PIPE, SORT_END);
+        self.end_zone(SORT_BEGIN);
+        self.pending[self.frame].sort = self.enabled();
+    }
+
+    /// Opens the Tracy zone of the pass whose queries start at `begin`.
+    fn begin_zone(&mut self, _begin: u32) {
+        #[cfg(feature = "tracy")]
+        if let Some(tracy) = &mut self.tracy {
+            tracy.begin(self.frame, (_begin / 2) as usize);
+        }
+    }
+
+    fn end_zone(&mut self, _begin: u32) {
+        #[cfg(feature = "tracy")]
+        if let Some(tracy) = &mut self.tracy {
+            tracy.end(self.frame, (_begin / 2) as usize);
+        }
+    }
+
+    fn reset(&self, device: &Device, cmd: vk::CommandBuffer, first_query: u32) {
+        if let Some(query_pool) = &self.query_pool {
+            unsafe { device.cmd_reset_query_pool(cmd, query_pool.handle(), query_index(self.frame, first_query), 2) };
+        }
+    }
+
+    fn write(&self, device: &Device, cmd: vk::CommandBuffer, stage: vk::PipelineStageFlags, query: u32) {
+        if let Some(query_pool) = &self.query_pool {
+            unsafe { device.cmd_write_timestamp(cmd, stage, query_pool.handle(), query_index(self.frame, query)) };
+        }
+    }
+
+}
+
+/// Index in the pool of frame in flight `frame`'s copy of `query`.
+fn query_index(frame: usize, query: u32) -> u32 {
+    frame as u32 * QUERY_COUNT + query
+}
@@ -0,0 +1,358 @@
+use ash::vk;
+use std::time::{Duration, Instant};
+use crate::app::{record_frame, record_sim_dispatch, FrameExtras, SimStep};
+use crate::autotune::dispatch_tuning;
+use crate::camera::{world_projection, OrbitCamera};
+use crate::config::AppConfig;
+use crate::cpu_sim::{self, Divergence, SimBackend};
+use crate::device_features::{DeviceFeatures, FeatureRequest};
+use crate::error::VulkanDemoError;
+use crate::golden::{compare_golden, GoldenOutcome};
+use crate::gpu_timer::{GpuTimer, GpuTimings};
+use crate::obstacles::default_obstacles;
+use crate::particles::{time_seed, ParticleFormat, ParticlePrecision, ParticleSystem, SimPipelines, SimPushConstants};
+use crate::renderer::Renderer;
+use crate::scene::ScenePreset;
+use crate::secondary_commands::SecondaryCommands;
+use crate::screenshot::write_png;
+use crate::snapshot::read_snapshot;
+use crate::spatial_grid::memory_barrier;
+use crate::step_clock::StepClock;
+use crate::sync::{FrameSync, GraphicsSubmit};
+use crate::texture::Texture;
+use crate::vulkan_context::VulkanContext;
+
+// Simulated time per frame, so runs are comparable regardless of how fast the GPU is.
+const FRAME_DT: f32 = 1.0 / 60.0;
+// Fixed three-quarter view used for 3D runs.
+const CAMERA_YAW: f32 = 0.6;
+const CAMERA_PITCH: f32 = 0.4;
+
+/// Frame time statistics of a finished benchmark run.
+#[derive(Clone, Debug)]
+pub struct BenchmarkReport {
+    pub frames: u32,
+    pub particle_count: u32,
+    pub min_ms: f64,
+    pub avg_ms: f64,
+    pub p99_ms: f64,
+    pub particles_per_second: f64,
+    /// Compared across runs with different `--layout`s and `--precision`s.
+    pub format: ParticleFormat,
+    /// Average GPU pass times, when the device supports timestamps.
+    pub gpu: Option<GpuTimings>,
+    /// Average CPU time spent recording a frame's commands, compared across
+    /// runs with different `--record-threads`.
+    pub record_ms: f64,
+    pub record_threads: usize,
+    /// Whether particles were read through buffer device addresses, compared
+    /// across runs with and without `--bda`.
+    pub bda: bool,
+}
+
+impl BenchmarkReport {
+    fn from_frame_times(
+        particle_count: u32,
+        frame_times: &mut [Duration],
+        record_time: Duration,
+        record_threads: usize,
+        format: ParticleFormat,
+        bda: bool,
+        gpu: Option<GpuTimings>,
+    ) -> Self {
+        frame_times.sort_unstable();
+        let total: Duration = frame_times.iter().sum();
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let p99_index = (frame_times.len() * 99).div_ceil(100).saturating_sub(1);
+        Self {
+            frames: frame_times.len() as u32,
+            particle_count,
+            min_ms: frame_times.first().copied().map_or(0.0, ms),
+            avg_ms: ms(total) / frame_times.len().max(1) as f64,
+            p99_ms: frame_times.get(p99_index).copied().map_or(0.0, ms),
+            particles_per_second: particle_count as f64 * frame_times.len() as f64 / total.as_secs_f64().max(f64::EPSILON),
+            format,
+            gpu,
+            record_ms: ms(record_time) / frame_times.len().max(1) as f64,
+            record_threads,
+            bda,
+        }
+    }
+}
+
+impl std::fmt::Display for BenchmarkReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} frames, {} particles", self.frames, self.particle_count)?;
+        writeln!(f, "frame time: min {:.3} ms, avg {:.3} ms, p99 {:.3} ms", self.min_ms, self.avg_ms, self.p99_ms)?;
+        writeln!(f, "throughput: {:.1} M particles/s", self.particles_per_second / 1_000_000.0)?;
+        writeln!(f, "recording: avg {:.3} ms on {} thread(s)", self.record_ms, self.record_threads)?;
+        write!(
+            f,
+            "format: {:?} {:?}, {} bytes per particle, {} fetched per drawn one",
+            self.format.layout,
+            self.format.precision,
+            self.format.particle_size(),
+            self.format.vertex_fetch_size(),
+        )?;
+        if self.bda {
+            write!(f, ", read through device addresses")?;
+        }
+        if let Some(gpu) = self.gpu {
+            write!(f, ", GPU compute {:.3} ms, graphics {:.3} ms", gpu.compute_ms, gpu.graphics_ms)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the compute and graphics passes `config.frames` times into an off-screen
+/// image, without a window or swapchain, and reports the frame times. The last
+/// frame is saved to `config.output` if set, and compared against
+/// `config.golden`.
+///
+/// Each frame is submitted and waited on before the next one starts, so the
+/// measured time covers the whole GPU round trip of a single frame.
+pub fn run_benchmark(config: &AppConfig) -> Result<BenchmarkReport, VulkanDemoError> {
+    let context = VulkanContext::new_headless(config.gpu_index, config.validation, &FeatureRequest::default())?;
+    if config.golden.is_some() {
+        let properties = unsafe { context.instance.get_physical_device_properties(context.physical_device) };
+        if properties.device_type != vk::PhysicalDeviceType::CPU {
+            log::warn!(
+                "Comparing against a reference on {}; references are meant for a software implementation such as lavapipe",
+                context.device_description()
+            );
+        }
+    }
+    let scene = match &config.scene {
+        Some(path) => ScenePreset::load(path)?,
+        None => ScenePreset::from_config(config),
+    };
+    let mut renderer = Renderer::new_headless(&context, config.width, config.height, scene.renderer.blend_mode)?;
+    scene.renderer.apply(&mut renderer);
+    if let Some(path) = &config.sprite {
+        renderer.set_sprite(&context.device, Texture::load_png(&context, path)?);
+    }
+    let tuning = dispatch_tuning(&context, config.particle_format(), config.autotune)?;
+    let sim_pipelines = SimPipelines::new(&context, config.particle_format(), tuning, config.bda)?;
+    renderer.set_particle_format(&context.device, config.particle_format())?;
+    let mut particle_systems = scene.build_systems(&context, &sim_pipelines, config.sim)?;
+    for particle_system in &particle_systems {
+        println!("Seed: {}", particle_system.seed);
+    }
+    if let Some(path) = &config.load {
+        particle_systems[0].restore(&context, &sim_pipelines, &read_snapshot(path)?)?;
+    }
+    // No frame loop polls the uploads here, so big systems load up front.
+    for particle_system in &particle_systems {
+        particle_system.finish_upload(&context)?;
+    }
+    let camera = OrbitCamera { yaw: CAMERA_YAW, pitch: CAMERA_PITCH, ..OrbitCamera::default() };
+    let (view_projection, focal_point) = if particle_systems.iter().any(ParticleSystem::is_3d) {
+        (camera.view_projection(renderer.aspect_ratio()), camera.eye().to_array())
+    } else {
+        (world_projection(renderer.aspect_ratio()), [0.0; 3])
+    };
+    let mut frame_sync = FrameSync::new(&context, None, 1)?;
+    let mut gpu_timer = GpuTimer::new(&context)?;
+    // A single system is recorded inline regardless.
+    let mut secondary_commands = match config.record_threads() {
+        threads if threads > 1 && particle_systems.len() > 1 => Some(SecondaryCommands::new(&context, threads)?),
+        _ => None,
+    };
+
+    let result = (|| {
+        let device = &context.device;
+        let mut frame_times = Vec::with_capacity(config.frames as usize);
+        let mut record_time = Duration::ZERO;
+        let mut gpu_timings = None;
+
+        let dt = config.fixed_dt.unwrap_or(FRAME_DT);
+        let mut clock = StepClock::new(config.substep_dt, config.max_substeps);
+        clock.limit_step_dt(particle_systems.iter().filter_map(ParticleSystem::max_step_dt).reduce(f32::min));
+        for frame in 0..config.frames {
+            let start = Instant::now();
+            // Each frame is waited for right after it's submitted, so only the resources rotate.
+            let frame_in_flight = frame_sync.begin_frame(device)?;
+            renderer.set_frame_in_flight(frame_in_flight);
+            renderer.update_camera(view_projection)?;
+            for particle_system in &mut particle_systems {
+                particle_system.set_frame_in_flight(frame_in_flight)?;
+            }
+            gpu_timer.begin_frame(device, frame_in_flight)?;
+            if let Some(secondary_commands) = &mut secondary_commands {
+                secondary_commands.begin_frame(frame_in_flight)?;
+            }
+            let cmd = frame_sync.frame().command_buffer;
+            renderer.background.animate(clock.sim_time);
+            let substeps = clock.substeps(dt, SimPushConstants::default());
+            for particle_system in &mut particle_systems {
+                particle_system.step_on_cpu(&substeps)?;
+            }
+            let step = if substeps.is_empty() { SimStep::Skip } else { SimStep::Inline(&substeps) };
+
+            let record_start = Instant::now();
+            record_frame(
+                device,
+                cmd,
+                &sim_pipelines,
+                &particle_systems,
+                &mut renderer,
+                &mut gpu_timer,
+                0,
+                &step,
+                FrameExtras { focal_point, secondary_commands: secondary_commands.as_ref(), ..FrameExtras::default() },
+            )?;
+            record_time += record_start.elapsed();
+            let submit = GraphicsSubmit { command_buffer: Some(cmd), ..GraphicsSubmit::default() };
+            frame_sync.submit_graphics(device, context.graphics_queue, submit)?;
+            frame_sync.end_frame();
+            frame_sync.wait(device)?;
+            for particle_system in &mut particle_systems {
+                particle_system.advance(substeps.len());
+            }
+            clock.advance(&substeps);
+            frame_times.push(start.elapsed());
+
+            gpu_timer.collect(device, frame_in_flight)?;
+            if let Some(timings) = gpu_timer.average() {
+                gpu_timings = Some(timings);
+                println!("frame {frame}: compute: {:.2} ms, graphics: {:.2} ms", timings.compute_ms, timings.graphics_ms);
+            }
+        }
+
+        for particle_system in &particle_systems {
+            if let Some(divergence) = particle_system.cpu_divergence(&context)? {
+                println!("After {} frames: {divergence}", config.frames);
+            }
+        }
+
+        if config.output.is_some() || config.golden.is_some() {
+            let pixels = renderer.read_pixels(&context, 0)?;
+            if let Some(path) = &config.output {
+                write_png(path, renderer.extent, &pixels)?;
+            }
+            if let Some(reference) = &config.golden {
+                match compare_golden(reference, renderer.extent, &pixels, config.golden_tolerance())? {
+                    GoldenOutcome::Matched { differing_pixels } => {
+                        println!("Matches {} ({differing_pixels} pixels over tolerance)", reference.display());
+                    }
+                    GoldenOutcome::Blessed => println!("Wrote reference {}", reference.display()),
+                }
+            }
+        }
+
+        let particle_count = particle_systems.iter().map(|particle_system| particle_system.count).sum();
+        let record_threads = secondary_commands.as_ref().map_or(1, SecondaryCommands::thread_count);
+        Ok(BenchmarkReport::from_frame_times(
+            particle_count,
+            &mut frame_times,
+            record_time,
+            record_threads,
+            config.particle_format(),
+            sim_pipelines.bda,
+            gpu_timings,
+        ))
+    })();
+
+    // Locals drop in reverse order, so everything goes before the context.
+    unsafe {
+        if let Err(e) = context.device.device_wait_idle() {
+            log::error!("device_wait_idle failed during shutdown: {e}");
+        }
+    }
+    result
+}
+
+/// Steps the same particles in `f32` and `f16` buffers side by side for
+/// `config.frames` frames, then compares the two states, showing what half
+/// precision costs in accuracy. Runs on the GPU alone, without drawing.
+pub fn compare_precisions(config: &AppConfig) -> Result<Divergence, VulkanDemoError> {
+    let context = VulkanContext::new_headless(config.gpu_index, config.validation, &FeatureRequest::default())?;
+    let format = config.particle_format();
+    let variants = [ParticlePrecision::F32, ParticlePrecision::F16].map(|precision| (ParticleFormat { precision, ..format }, config.bda));
+    compare_variants(&context, config, variants)
+}
+
+/// Like `compare_precisions`, but steps the particles through descriptors and
+/// through buffer device addresses, which should end up identical. Fails on
+/// devices without `bufferDeviceAddress`.
+pub fn compare_bda(config: &AppConfig) -> Result<Divergence, VulkanDemoError> {
+    let features = FeatureRequest {
+        required: DeviceFeatures { buffer_device_address: true, ..DeviceFeatures::default() },
+        ..FeatureRequest::default()
+    };
+    let context = VulkanContext::new_headless(config.gpu_index, config.validation, &features)?;
+    if !config.mode.behavior().supports_bda() {
+        log::warn!("{:?} mode always reads particles through descriptors", config.mode);
+    }
+    compare_variants(&context, config, [false, true].map(|bda| (config.particle_format(), bda)))
+}
+
+/// Steps the same particles with pipelines for each of `variants`, a format
+/// and whether to use `--bda`, for `config.frames` frames and compares where
+/// they end up.
+fn compare_variants(
+    context: &VulkanContext,
+    config: &AppConfig,
+    variants: [(ParticleFormat, bool); 2],
+) -> Result<Divergence, VulkanDemoError> {
+    let seed = config.seed.unwrap_or_else(time_seed);
+    println!("Seed: {seed}");
+    let tuning = dispatch_tuning(context, config.particle_format(), config.autotune)?;
+    let mut runs = variants
+        .into_iter()
+        .map(|(format, bda)| {
+            let pipelines = SimPipelines::new(context, format, tuning, bda)?;
+            let mut particle_system = ParticleSystem::new(
+                context,
+                &pipelines,
+                config.particles,
+                seed,
+                &config.emitter_config(),
+                config.three_d,
+                SimBackend::Gpu,
+            )?;
+            particle_system.set_grid_size(config.grid_size)?;
+            particle_system.set_repulsion(config.repulsion)?;
+            particle_system.set_flocking_weights([config.separation, config.alignment, config.cohesion])?;
+            particle_system.set_mode(config.mode);
+            if config.obstacles {
+                particle_system.set_obstacles(context, &default_obstacles())?;
+            }
+            if let Some(path) = &config.load {
+                particle_system.restore(context, &pipelines, &read_snapshot(path)?)?;
+            }
+            particle_system.finish_upload(context)?;
+            Ok((pipelines, particle_system))
+        })
+        .collect::<Result<Vec<_>, VulkanDemoError>>()?;
+
+    let dt = config.fixed_dt.unwrap_or(FRAME_DT);
+    let mut clock = StepClock::new(config.substep_dt, config.max_substeps);
+    clock.limit_step_dt(runs.iter().filter_map(|(_, particle_system)| particle_system.max_step_dt()).reduce(f32::min));
+    for _ in 0..config.frames {
+        let substeps = clock.substeps(dt, SimPushConstants::default());
+        context.one_time_submit(|cmd| {
+            for (substep, push_constants) in substeps.iter().enumerate() {
+                unsafe {
+                    memory_barrier(
+                        &context.device,
+                        cmd,
+                        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+                        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ),
+                    );
+                }
+                for (pipelines, particle_system) in &runs {
+                    record_sim_dispatch(&context.device, cmd, pipelines, particle_system, substep, push_constants);
+                }
+            }
+        })?;
+        for (_, particle_system) in &mut runs {
+            particle_system.advance(substeps.len());
+        }
+        clock.advance(&substeps);
+    }
+
+    let first = runs[0].1.snapshot(context)?;
+    let second = runs[1].1.snapshot(context)?;
+    Ok(cpu_sim::divergence(&first, &second))
+}
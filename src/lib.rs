@@ -0,0 +1,61 @@
+//! GPU particle simulation and rendering on top of `ash`.
+//!
+//! [`vulkan_context::VulkanContext`] owns the instance, surface and device,
+//! [`renderer::Renderer`] the swapchain and graphics pipeline,
+//! [`particles::SimPipelines`] the compute pipelines and
+//! [`particles::ParticleSystem`] the buffers of one set of particles.
+//! [`app::App`] ties them together into the windowed demo.
+
+pub mod allocator;
+pub mod app;
+pub mod attractors;
+pub mod autotune;
+pub mod background;
+pub mod behaviors;
+pub mod bloom;
+pub mod camera;
+pub mod compute_harness;
+pub mod compute_pass;
+pub mod config;
+pub mod cpu_sim;
+pub mod debug;
+pub mod depth_sort;
+pub mod device_features;
+pub mod egui_renderer;
+pub mod emitter;
+pub mod error;
+pub mod frame_graph;
+pub mod fullscreen;
+pub mod golden;
+pub mod gpu_scan;
+pub mod gpu_timer;
+pub mod headless;
+pub mod obstacles;
+pub mod particles;
+pub mod pipeline_utils;
+pub mod promoted;
+pub mod recorder;
+pub mod renderer;
+pub mod resources;
+pub mod scene;
+pub mod screenshot;
+pub mod secondary_commands;
+pub mod shader_diagnostics;
+pub mod shader_watcher;
+pub mod snapshot;
+pub mod spatial_grid;
+pub mod speed_histogram;
+pub mod staging;
+pub mod stats;
+pub mod step_clock;
+pub mod sync;
+pub mod sync2;
+pub mod texture;
+#[cfg(feature = "tracy")]
+pub mod tracy_gpu;
+pub mod ui;
+pub mod upload;
+pub mod vulkan_context;
+
+mod memory;
+mod pipeline_cache;
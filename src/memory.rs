@@ -0,0 +1,121 @@
+use ash::{vk, Device};
+use std::sync::Arc;
+use crate::allocator::{Allocator, MemoryLocation};
+use crate::error::VulkanDemoError;
+use crate::resources::{OwnedBuffer, OwnedImage};
+use crate::vulkan_context::VulkanContext;
+
+pub(crate) fn has_dedicated_device_local_memory(mem_props: &vk::PhysicalDeviceMemoryProperties) -> bool {
+    mem_props.memory_types[..mem_props.memory_type_count as usize].iter().any(|memory_type| {
+        memory_type.property_flags.contains(vk::MemoryPropertyFlags::DEVICE_LOCAL)
+            && !memory_type.property_flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE)
+    })
+}
+
+pub(crate) fn create_buffer(
+    context: &VulkanContext,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    location: MemoryLocation,
+) -> Result<OwnedBuffer, VulkanDemoError> {
+    create_shared_buffer(context, size, usage, location, &[])
+}
+
+/// Like `create_buffer`, but with concurrent sharing between `queue_families`
+/// when there is more than one, so no ownership transfers are needed. An
+/// exclusive buffer instead moves between families with
+/// `OwnedBuffer::release` and `acquire`.
+pub(crate) fn create_shared_buffer(
+    context: &VulkanContext,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    location: MemoryLocation,
+    queue_families: &[u32],
+) -> Result<OwnedBuffer, VulkanDemoError> {
+    let mut buffer_info = vk::BufferCreateInfo::default()
+        .size(size)
+        .usage(usage)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+    if queue_families.len() > 1 {
+        buffer_info = buffer_info
+            .sharing_mode(vk::SharingMode::CONCURRENT)
+            .queue_family_indices(queue_families);
+    }
+    allocate_buffer(&context.device, &context.allocator, &buffer_info, location)
+}
+
+/// Creates a buffer from `buffer_info` and binds memory in `location` to it,
+/// for use before there is a `VulkanContext`.
+pub(crate) fn allocate_buffer(
+    device: &Arc<Device>,
+    allocator: &Arc<Allocator>,
+    buffer_info: &vk::BufferCreateInfo,
+    location: MemoryLocation,
+) -> Result<OwnedBuffer, VulkanDemoError> {
+    let buffer = unsafe { device.create_buffer(buffer_info, None)? };
+    let mem_reqs = unsafe { device.get_buffer_memory_requirements(buffer) };
+    // Taking the address of a buffer needs memory allocated for it.
+    let flags = if buffer_info.usage.contains(vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS) {
+        vk::MemoryAllocateFlags::DEVICE_ADDRESS
+    } else {
+        vk::MemoryAllocateFlags::empty()
+    };
+    let allocation = allocator.allocate(device, mem_reqs, location, true, flags).inspect_err(|_| unsafe {
+        device.destroy_buffer(buffer, None);
+    })?;
+
+    let (memory, offset) = (allocation.memory(), allocation.offset());
+    let concurrent = buffer_info.sharing_mode == vk::SharingMode::CONCURRENT;
+    let buffer = OwnedBuffer::new(device, allocator, buffer, allocation, concurrent);
+    unsafe { device.bind_buffer_memory(buffer.handle(), memory, offset)? };
+    Ok(buffer)
+}
+
+/// Reads `size` bytes from the start of host-visible `buffer`, invalidating them
+/// first if the memory is not coherent.
+pub(crate) fn read_from_buffer(buffer: &OwnedBuffer, size: usize) -> Result<Vec<u8>, vk::Result> {
+    buffer.invalidate(0, size as vk::DeviceSize)?;
+    Ok(unsafe { std::slice::from_raw_parts(buffer.mapped_ptr(), size).to_vec() })
+}
+
+/// Creates a single-mip 2D image in device-local memory.
+pub(crate) fn create_image(
+    context: &VulkanContext,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    usage: vk::ImageUsageFlags,
+) -> Result<OwnedImage, VulkanDemoError> {
+    create_mipmapped_image(context, extent, format, usage, 1)
+}
+
+pub(crate) fn create_mipmapped_image(
+    context: &VulkanContext,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    usage: vk::ImageUsageFlags,
+    mip_levels: u32,
+) -> Result<OwnedImage, VulkanDemoError> {
+    let image_info = vk::ImageCreateInfo::default()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(format)
+        .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+        .mip_levels(mip_levels)
+        .array_layers(1)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .usage(usage)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .initial_layout(vk::ImageLayout::UNDEFINED);
+
+    let image = unsafe { context.device.create_image(&image_info, None)? };
+    let mem_reqs = unsafe { context.device.get_image_memory_requirements(image) };
+    let allocation = context
+        .allocator
+        .allocate(&context.device, mem_reqs, MemoryLocation::GpuOnly, false, vk::MemoryAllocateFlags::empty())
+        .inspect_err(|_| unsafe { context.device.destroy_image(image, None) })?;
+
+    let (memory, offset) = (allocation.memory(), allocation.offset());
+    let image = OwnedImage::new(&context.device, &context.allocator, image, allocation);
+    unsafe { context.device.bind_image_memory(image.handle(), memory, offset)? };
+    Ok(image)
+}
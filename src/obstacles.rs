@@ -0,0 +1,58 @@
+use bytemuck::{Pod, Zeroable};
+use serde::{Deserialize, Serialize};
+
+/// Most obstacles a particle system collides with; more are dropped with a warning.
+pub const MAX_OBSTACLES: usize = 64;
+
+/// Outline of an obstacle, centered on `Obstacle::center`.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum ObstacleShape {
+    Circle { radius: f32 },
+    /// An axis-aligned box `2 * half_extents` across.
+    Box { half_extents: [f32; 2] },
+}
+
+/// A static shape particles bounce off. In 3D it extends along the whole z axis.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Obstacle {
+    pub shape: ObstacleShape,
+    /// Center in world space.
+    pub center: [f32; 2],
+    /// Fraction of the speed into the surface kept when bouncing off it.
+    pub restitution: f32,
+}
+
+impl Obstacle {
+    pub fn circle(center: [f32; 2], radius: f32, restitution: f32) -> Self {
+        Self { shape: ObstacleShape::Circle { radius }, center, restitution }
+    }
+
+    pub fn rect(center: [f32; 2], half_extents: [f32; 2], restitution: f32) -> Self {
+        Self { shape: ObstacleShape::Box { half_extents }, center, restitution }
+    }
+
+    /// The layout `obstacles.glsl` reads.
+    pub(crate) fn to_gpu(self) -> GpuObstacle {
+        let (shape, extent) = match self.shape {
+            ObstacleShape::Circle { radius } => (0, [radius, radius]),
+            ObstacleShape::Box { half_extents } => (1, half_extents),
+        };
+        GpuObstacle { center: self.center, extent, shape, restitution: self.restitution }
+    }
+}
+
+/// Two circles in the path of the default spray, toggled with O.
+pub fn default_obstacles() -> Vec<Obstacle> {
+    vec![Obstacle::circle([-0.5, 0.0], 0.15, 0.6), Obstacle::circle([0.45, 0.35], 0.2, 0.6)]
+}
+
+/// `Obstacle` in `obstacles.glsl` (std430).
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub(crate) struct GpuObstacle {
+    pub center: [f32; 2],
+    pub extent: [f32; 2],
+    pub shape: u32,
+    pub restitution: f32,
+}
@@ -1,167 +1,1831 @@
 use ash::vk;
-use std::mem::size_of;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::mem::{offset_of, size_of};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use bytemuck::{Pod, Zeroable};
+use half::f16;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use crate::allocator::MemoryLocation;
+use crate::emitter::EmitterConfig;
+use crate::error::VulkanDemoError;
+use crate::pipeline_utils::{
+    compile_shader, create_shader_module, specialization_entry, specialization_info, ShaderCompileOptions,
+    ShaderInterface, SpecializationConstants,
+};
+use crate::attractors::{Attractor, MAX_ATTRACTORS};
+use crate::autotune::DispatchTuning;
+use crate::behaviors::{SimulationBehavior, UpdateResources};
+use crate::compute_pass::{ComputeLayout, DescriptorSets};
+use crate::cpu_sim::{self, Divergence, SimBackend};
+use crate::debug::DebugUtils;
+use crate::depth_sort::DepthSort;
+use crate::gpu_scan::GpuScan;
+use crate::obstacles::{GpuObstacle, Obstacle, MAX_OBSTACLES};
+use crate::memory::{create_buffer, create_shared_buffer, has_dedicated_device_local_memory, read_from_buffer};
+use crate::upload::{UploadDependency, UploadHandle, UPLOAD_BYTES_PER_POLL};
+use crate::resources::{OwnedBuffer, OwnedPipeline};
+use crate::spatial_grid::{memory_barrier, SpatialGrid, DEFAULT_GRID_SIZE, MAX_GRID_SIZE};
+use crate::sync::FRAMES_IN_FLIGHT;
 use crate::vulkan_context::VulkanContext;
 
+pub use crate::behaviors::SimulationMode;
+
+/// A single particle as stored in the storage/vertex buffer (std430 layout)
+/// in the default `ParticleFormat`. The scalars fill the gaps std430 leaves
+/// after each `vec3`. In 2D the z components stay zero.
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
 pub struct Particle {
-    pub pos: [f32; 2],
-    pub vel: [f32; 2],
+    pub pos: [f32; 3],
+    /// Point size in pixels, before the renderer's global scale is applied.
+    pub size: f32,
+    pub vel: [f32; 3],
+    /// Only used by `SimulationMode::NBody`.
+    pub mass: f32,
+    pub color: [f32; 4],
+    /// Seconds left before the particle respawns at the emitter.
+    pub life: f32,
+    pub max_life: f32,
+    /// Index in the initial layout, keying the respawn hash.
+    pub id: u32,
+    /// Which of `SimParams::kinds` the particle follows, from the emitter's
+    /// `kind_weights`. Kept through respawns.
+    pub kind: u32,
+}
+
+/// A `Particle` with `ParticlePrecision::F16`: velocity, mass and color in
+/// half floats, together in the `vec4` that velocity and mass take in full
+/// precision. The layout of `packHalf2x16` pairs, see particle.glsl.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct HalfParticle {
+    pos: [f32; 3],
+    size: f32,
+    vel: [f16; 3],
+    mass: f16,
+    color: [f16; 4],
+    life: f32,
+    max_life: f32,
+    id: u32,
+    kind: u32,
+}
+
+impl From<Particle> for HalfParticle {
+    fn from(particle: Particle) -> Self {
+        Self {
+            pos: particle.pos,
+            size: particle.size,
+            vel: particle.vel.map(f16::from_f32),
+            mass: f16::from_f32(particle.mass),
+            color: particle.color.map(f16::from_f32),
+            life: particle.life,
+            max_life: particle.max_life,
+            id: particle.id,
+            kind: particle.kind,
+        }
+    }
+}
+
+impl From<HalfParticle> for Particle {
+    fn from(particle: HalfParticle) -> Self {
+        Self {
+            pos: particle.pos,
+            size: particle.size,
+            vel: particle.vel.map(f16::to_f32),
+            mass: particle.mass.to_f32(),
+            color: particle.color.map(f16::to_f32),
+            life: particle.life,
+            max_life: particle.max_life,
+            id: particle.id,
+            kind: particle.kind,
+        }
+    }
+}
+
+/// How the particle buffers order the `vec4`s of their particles.
+///
+/// With `Soa` a buffer of `count` particles holds `count` of each particle's
+/// first `vec4`, then `count` of its second and so on, so a pass that only
+/// needs positions only fetches those: drawing reads 32 bytes per particle
+/// instead of striding over 64. The shaders pick the indexing with their
+/// `PARTICLE_SOA` specialization constant; see particle.glsl.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ParticleLayout {
+    /// Array of structs: each particle's `vec4`s together.
+    #[default]
+    Aos,
+    /// Struct of arrays: one array per `vec4` of the particle.
+    Soa,
+}
+
+/// What the particle buffers store velocity, mass and color as. Positions,
+/// sizes and lifetimes are always full floats, as small steps would be lost
+/// to rounding in half ones.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ParticlePrecision {
+    #[default]
+    F32,
+    /// Half floats packed two to a word, making a particle 48 bytes instead
+    /// of 64. The simulation shaders store them as `float16_t`s on devices
+    /// with `shaderFloat16` and `storageBuffer16BitAccess`, and convert with
+    /// `packHalf2x16` otherwise, so any device has it.
+    F16,
+}
+
+/// Layout and precision of every particle buffer: what `SimPipelines`
+/// specialize the compute shaders for and the renderer reads vertices with.
+/// Chosen when the `SimPipelines` are built, since every system shares them;
+/// `ParticleSystem::new` takes it from there. Snapshots and the host side
+/// always see plain `Particle`s; `pack` and `unpack` convert.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ParticleFormat {
+    pub layout: ParticleLayout,
+    pub precision: ParticlePrecision,
+}
+
+impl ParticleFormat {
+    /// `vec4`s per particle: position and size, velocity and mass, color,
+    /// then life, max life, id and kind, with color in the velocity one in `F16`.
+    pub fn vec4s(self) -> usize {
+        match self.precision {
+            ParticlePrecision::F32 => size_of::<Particle>() / size_of::<[f32; 4]>(),
+            ParticlePrecision::F16 => size_of::<HalfParticle>() / size_of::<[f32; 4]>(),
+        }
+    }
+
+    /// Bytes per particle in the buffers.
+    pub fn particle_size(self) -> usize {
+        self.vec4s() * size_of::<[f32; 4]>()
+    }
+
+    /// Bytes of a buffer of `count` particles.
+    pub fn buffer_size(self, count: u32) -> vk::DeviceSize {
+        count as vk::DeviceSize * self.particle_size() as vk::DeviceSize
+    }
+
+    /// Byte offset of `vec4` `field` of particle `index` in a buffer of `count` particles.
+    pub fn field_offset(self, count: u32, index: u32, field: usize) -> vk::DeviceSize {
+        let slot = match self.layout {
+            ParticleLayout::Aos => index as usize * self.vec4s() + field,
+            ParticleLayout::Soa => field * count as usize + index as usize,
+        };
+        (slot * size_of::<[f32; 4]>()) as vk::DeviceSize
+    }
+
+    /// Bytes apart consecutive particles' `vec4`s are.
+    pub fn vertex_stride(self) -> u32 {
+        match self.layout {
+            ParticleLayout::Aos => self.particle_size() as u32,
+            ParticleLayout::Soa => size_of::<[f32; 4]>() as u32,
+        }
+    }
+
+    /// Bytes of cache lines drawing a particle pulls in: its position-and-size
+    /// and color `vec4`s, or with `Aos` the whole particle they're spread over.
+    pub fn vertex_fetch_size(self) -> u32 {
+        match self.layout {
+            ParticleLayout::Aos => self.particle_size() as u32,
+            ParticleLayout::Soa => 2 * size_of::<[f32; 4]>() as u32,
+        }
+    }
+
+    /// Where the position-and-size and the color of the first of `count`
+    /// particles are, for the renderer's two vertex bindings.
+    pub fn vertex_offsets(self, count: u32) -> [vk::DeviceSize; 2] {
+        let color = match self.precision {
+            ParticlePrecision::F32 => self.field_offset(count, 0, offset_of!(Particle, color) / size_of::<[f32; 4]>()),
+            ParticlePrecision::F16 => {
+                let color = offset_of!(HalfParticle, color);
+                self.field_offset(count, 0, color / size_of::<[f32; 4]>()) + (color % size_of::<[f32; 4]>()) as vk::DeviceSize
+            }
+        };
+        [self.field_offset(count, 0, 0), color]
+    }
+
+    /// The vertex format of the color binding.
+    pub fn color_format(self) -> vk::Format {
+        match self.precision {
+            ParticlePrecision::F32 => vk::Format::R32G32B32A32_SFLOAT,
+            ParticlePrecision::F16 => vk::Format::R16G16B16A16_SFLOAT,
+        }
+    }
+
+    /// `particles` as the bytes of a buffer holding exactly them.
+    pub fn pack(self, particles: &[Particle]) -> Vec<u8> {
+        let interleaved = match self.precision {
+            ParticlePrecision::F32 => bytemuck::cast_slice(particles).to_vec(),
+            ParticlePrecision::F16 => {
+                let half: Vec<HalfParticle> = particles.iter().map(|&particle| particle.into()).collect();
+                bytemuck::cast_slice(&half).to_vec()
+            }
+        };
+        match self.layout {
+            ParticleLayout::Aos => interleaved,
+            ParticleLayout::Soa => {
+                let vec4s: &[[u8; 16]] = bytemuck::cast_slice(&interleaved);
+                (0..self.vec4s()).flat_map(|field| vec4s.iter().skip(field).step_by(self.vec4s()).flatten().copied()).collect()
+            }
+        }
+    }
+
+    /// The particles of a buffer holding exactly `bytes`; undoes `pack`.
+    pub fn unpack(self, bytes: &[u8]) -> Vec<Particle> {
+        let count = bytes.len() / self.particle_size();
+        let interleaved = match self.layout {
+            ParticleLayout::Aos => bytes.to_vec(),
+            ParticleLayout::Soa => (0..count)
+                .flat_map(|index| (0..self.vec4s()).map(move |field| self.field_offset(count as u32, index as u32, field) as usize))
+                .flat_map(|offset| bytes[offset..offset + size_of::<[f32; 4]>()].iter().copied())
+                .collect(),
+        };
+        interleaved
+            .chunks_exact(self.particle_size())
+            .map(|particle| match self.precision {
+                ParticlePrecision::F32 => bytemuck::pod_read_unaligned(particle),
+                ParticlePrecision::F16 => bytemuck::pod_read_unaligned::<HalfParticle>(particle).into(),
+            })
+            .collect()
+    }
+
+    /// Regions copying the first `kept` particles of a buffer of `src_count`
+    /// into one of `dst_count`: one region, or one per `vec4` array with `Soa`.
+    pub fn copy_regions(self, kept: u32, src_count: u32, dst_count: u32) -> Vec<vk::BufferCopy> {
+        match self.layout {
+            ParticleLayout::Aos => vec![vk::BufferCopy::default().size(self.buffer_size(kept))],
+            ParticleLayout::Soa => (0..self.vec4s()).map(|field| {
+                vk::BufferCopy::default()
+                    .src_offset(self.field_offset(src_count, 0, field))
+                    .dst_offset(self.field_offset(dst_count, 0, field))
+                    .size(kept as vk::DeviceSize * size_of::<[f32; 4]>() as vk::DeviceSize)
+            }).collect(),
+        }
+    }
+}
+
+/// How `particle.comp` writes `Particle::color` each step.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[repr(u32)]
+pub enum ColorMode {
+    /// Every particle uses the base color.
+    Static = 0,
+    /// Speed is mapped onto a cool-to-warm hue ramp.
+    #[default]
+    Velocity = 1,
+    /// Each particle uses the color of its kind, see `KindParams::color`.
+    Kind = 2,
+}
+
+impl ColorMode {
+    /// The next mode, cycled with the C key.
+    pub fn next(self) -> Self {
+        match self {
+            Self::Static => Self::Velocity,
+            Self::Velocity => Self::Kind,
+            Self::Kind => Self::Static,
+        }
+    }
+}
+
+/// A system's draw as the handles recording it takes, which unlike the
+/// system itself can be shared with other threads.
+#[derive(Copy, Clone, Debug)]
+pub struct ParticleDraw {
+    vertex_buffer: vk::Buffer,
+    vertex_offsets: [vk::DeviceSize; 2],
+    indirect_buffer: vk::Buffer,
+}
+
+impl ParticleDraw {
+    /// Records the draw, with the particle pipeline bound.
+    pub fn record(&self, device: &ash::Device, cmd: vk::CommandBuffer) {
+        unsafe {
+            device.cmd_bind_vertex_buffers(cmd, 0, &[self.vertex_buffer; 2], &self.vertex_offsets);
+            // Single draws with first_instance 0 need neither multiDrawIndirect
+            // nor drawIndirectFirstInstance.
+            device.cmd_draw_indirect(cmd, self.indirect_buffer, 0, 1, 0);
+        }
+    }
+}
+
+/// Boids separation, alignment and cohesion weights.
+pub const DEFAULT_FLOCKING_WEIGHTS: [f32; 3] = [1.5, 1.0, 1.0];
+
+/// Half the width and height of the world rectangle particles move in. Its
+/// aspect is fixed, 4:3 like the default window; the 2D view scales it to fit
+/// the window and letterboxes the rest, see `camera::world_projection`.
+pub const WORLD_HALF_EXTENTS: [f32; 2] = [4.0 / 3.0, 1.0];
+
+/// Kinds of particle a system can mix, each with its own `KindParams`.
+pub const MAX_PARTICLE_KINDS: usize = 4;
+
+/// How one kind of particle departs from the system's `SimParams`, in the
+/// `kinds` array of the uniform buffer. Only `SimulationMode::Simple` and
+/// `SimulationMode::CurlNoise` apply the scales; every mode can color by kind.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KindParams {
+    /// Multiplier for `SimParams::gravity`.
+    pub gravity_scale: f32,
+    /// How much of the curl-noise flow the kind follows in `SimulationMode::Simple`,
+    /// from 0 for none to 1 for as much as `SimulationMode::CurlNoise`. Not
+    /// simulated by `cpu_sim`.
+    pub noise_weight: f32,
+    /// Multiplier for `SimParams::drag`.
+    pub drag_scale: f32,
+    #[serde(skip)]
+    pub _padding: f32,
+    /// Used with `ColorMode::Kind`.
+    pub color: [f32; 4],
+}
+
+impl Default for KindParams {
+    fn default() -> Self {
+        Self { gravity_scale: 1.0, noise_weight: 0.0, drag_scale: 1.0, _padding: 0.0, color: [1.0, 1.0, 1.0, 1.0] }
+    }
+}
+
+/// Kind 0 follows the plain parameters; kind 1 is light, barely feeling
+/// gravity and carried by the noise field.
+pub const DEFAULT_KINDS: [KindParams; MAX_PARTICLE_KINDS] = [
+    KindParams { gravity_scale: 1.0, noise_weight: 0.0, drag_scale: 1.0, _padding: 0.0, color: [1.0, 0.55, 0.2, 1.0] },
+    KindParams { gravity_scale: 0.15, noise_weight: 1.0, drag_scale: 1.0, _padding: 0.0, color: [0.35, 0.75, 1.0, 1.0] },
+    KindParams { gravity_scale: 1.0, noise_weight: 0.0, drag_scale: 1.0, _padding: 0.0, color: [0.5, 1.0, 0.4, 1.0] },
+    KindParams { gravity_scale: 1.0, noise_weight: 0.0, drag_scale: 1.0, _padding: 0.0, color: [1.0, 0.4, 0.8, 1.0] },
+];
+
+/// What happens to a particle that leaves the world rectangle.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum BoundaryMode {
+    /// Reappear on the opposite edge.
+    Wrap,
+    /// Reflect off the edge, keeping `restitution` of the speed.
+    Bounce { restitution: f32 },
+    /// Die immediately and respawn at the emitter.
+    Kill,
+}
+
+impl Default for BoundaryMode {
+    fn default() -> Self {
+        Self::Bounce { restitution: 1.0 }
+    }
 }
 
+impl BoundaryMode {
+    /// The discriminant `particle.comp` switches on.
+    pub(crate) fn id(self) -> u32 {
+        match self {
+            Self::Wrap => 0,
+            Self::Bounce { .. } => 1,
+            Self::Kill => 2,
+        }
+    }
+}
+
+/// Written by the simulation step and consumed by `cmd_draw_indirect`. Live
+/// particles are packed at the front of the output buffer, so the draw covers
+/// exactly `vertex_count` of them. Starts with the layout of `vk::DrawIndirectCommand`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct DrawCounts {
+    vertex_count: u32,
+    instance_count: u32,
+    first_vertex: u32,
+    first_instance: u32,
+    /// Dead particles, packed at the back of the output buffer.
+    dead_count: u32,
+}
+
+impl DrawCounts {
+    fn new(vertex_count: u32) -> Self {
+        Self { vertex_count, instance_count: 1, ..Self::zeroed() }
+    }
+}
+
+/// Per-dispatch values pushed to `particle.comp`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
+pub struct SimPushConstants {
+    pub dt: f32,
+    pub elapsed: f32,
+    /// Cursor position in world space.
+    pub attractor: [f32; 2],
+    /// Positive pulls particles toward the attractor, negative pushes them away.
+    pub attractor_strength: f32,
+    pub attractor_active: u32,
+    /// Incremented every frame to reseed the respawn hash.
+    pub frame: u32,
+    /// Particles each buffer holds. Set with the addresses below by
+    /// `ParticleSystem::record_update` under `--bda`.
+    pub particle_capacity: u32,
+    /// The buffer the step reads, for shaders built with `PARTICLE_BDA` to
+    /// take instead of binding 0.
+    pub particles_in: vk::DeviceAddress,
+    /// The buffer it writes, instead of binding 1.
+    pub particles_out: vk::DeviceAddress,
+}
+
+/// Tunable simulation parameters, read by `particle.comp` from a uniform buffer
+/// at binding 2. The layout matches std140.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct SimParams {
+    /// Constant acceleration; +y points down the screen.
+    pub gravity: [f32; 2],
+    /// Exponential velocity damping per second.
+    pub drag: f32,
+    pub max_speed: f32,
+    /// Fraction of speed kept when bouncing off the edges.
+    pub restitution: f32,
+    /// A `ColorMode` discriminant.
+    pub color_mode: u32,
+    /// Multiplier mapping particle speed onto the [0, 1] range of the color ramp.
+    pub color_speed_scale: f32,
+    /// A `BoundaryMode` discriminant; see `SimParams::boundary_mode`.
+    pub boundary_mode: u32,
+    pub base_color: [f32; 4],
+    /// Center of the emitter dead particles respawn from, in world space.
+    pub emitter_position: [f32; 2],
+    pub emit_speed_min: f32,
+    pub emit_speed_max: f32,
+    /// Mean lifetime in seconds; each particle gets between half and one and a half times this.
+    pub lifetime: f32,
+    /// An `EmitterShape` discriminant.
+    pub emitter_shape: u32,
+    /// Line length or circle radius of the emitter shape.
+    pub emitter_size: f32,
+    pub emit_direction: f32,
+    pub emit_spread: f32,
+    /// Distance below which N-body attraction stops growing, keeping close encounters finite.
+    pub softening: f32,
+    /// Gravitational constant times the total mass for `SimulationMode::NBody`;
+    /// individual masses are normalized by the particle count.
+    pub nbody_strength: f32,
+    /// Spatial grid cells along each axis, at most `MAX_GRID_SIZE`. Also sets the
+    /// repulsion radius, which is one cell wide.
+    pub grid_size: u32,
+    /// Strength of the short-range push between neighbors; 0 skips the grid entirely.
+    pub repulsion_strength: f32,
+    /// Boids steering weights: keep apart, match heading, move toward the local center.
+    pub separation_weight: f32,
+    pub alignment_weight: f32,
+    pub cohesion_weight: f32,
+    /// How far a boid sees its neighbors, in world units.
+    pub perception_radius: f32,
+    /// Boids never slow below this, so flocks keep moving.
+    pub min_speed: f32,
+    /// Curl-noise field frequency; higher values give smaller eddies.
+    pub noise_scale: f32,
+    /// Speed of the curl-noise flow particles are eased toward.
+    pub noise_strength: f32,
+    /// How fast the curl-noise field evolves over elapsed time.
+    pub noise_speed: f32,
+    /// 2 keeps particles in the z = 0 plane, 3 launches them into the world box,
+    /// which is one unit deep on either side of it.
+    pub dimensions: u32,
+    /// Mixed into the respawn hash, so runs with different seeds diverge.
+    pub seed: u32,
+    pub _padding: f32,
+    /// Always `WORLD_HALF_EXTENTS`; edges for the boundary mode and the spatial grid.
+    pub world_half_extents: [f32; 2],
+    pub _kinds_padding: [f32; 2],
+    /// Indexed by `Particle::kind`.
+    pub kinds: [KindParams; MAX_PARTICLE_KINDS],
+    /// How far apart `SimulationMode::Fluid` particles still push and drag
+    /// each other, in world units; no wider than a grid cell keeps the
+    /// neighbor search to the 3x3 cells around a particle.
+    pub smoothing_radius: f32,
+    /// Density fluid particles settle at: 1 when they are half a smoothing radius apart.
+    pub rest_density: f32,
+    /// Pressure per unit of density above the rest density. Stiffer fluid
+    /// compresses less, but needs shorter steps to stay stable.
+    pub stiffness: f32,
+    pub viscosity: f32,
+}
+
+impl Default for SimParams {
+    fn default() -> Self {
+        Self {
+            gravity: [0.0, 0.0],
+            drag: 0.0,
+            max_speed: 2.0,
+            restitution: 1.0,
+            color_mode: ColorMode::Velocity as u32,
+            color_speed_scale: 2.0,
+            boundary_mode: BoundaryMode::default().id(),
+            base_color: [1.0, 1.0, 1.0, 1.0],
+            lifetime: 4.0,
+            softening: 0.02,
+            nbody_strength: 0.5,
+            grid_size: DEFAULT_GRID_SIZE,
+            separation_weight: DEFAULT_FLOCKING_WEIGHTS[0],
+            alignment_weight: DEFAULT_FLOCKING_WEIGHTS[1],
+            cohesion_weight: DEFAULT_FLOCKING_WEIGHTS[2],
+            perception_radius: 0.08,
+            min_speed: 0.1,
+            noise_scale: 2.0,
+            noise_strength: 0.3,
+            noise_speed: 0.2,
+            dimensions: 2,
+            world_half_extents: WORLD_HALF_EXTENTS,
+            kinds: DEFAULT_KINDS,
+            smoothing_radius: 0.02,
+            rest_density: 1.0,
+            stiffness: 8.0,
+            viscosity: 0.002,
+            ..Self::zeroed()
+        }
+        .with_emitter(&EmitterConfig::default())
+    }
+}
+
+impl SimParams {
+    /// Copies the emitter settings into the matching uniform fields.
+    pub fn with_emitter(self, emitter: &EmitterConfig) -> Self {
+        Self {
+            emitter_position: emitter.position,
+            emit_speed_min: emitter.min_speed,
+            emit_speed_max: emitter.max_speed,
+            emitter_shape: emitter.shape.id(),
+            emitter_size: emitter.shape.size(),
+            emit_direction: emitter.direction,
+            emit_spread: emitter.spread,
+            ..self
+        }
+    }
+
+    pub fn color_mode(&self) -> ColorMode {
+        match self.color_mode {
+            0 => ColorMode::Static,
+            2 => ColorMode::Kind,
+            _ => ColorMode::Velocity,
+        }
+    }
+
+    pub fn boundary_mode(&self) -> BoundaryMode {
+        match self.boundary_mode {
+            0 => BoundaryMode::Wrap,
+            2 => BoundaryMode::Kill,
+            _ => BoundaryMode::Bounce { restitution: self.restitution },
+        }
+    }
+}
+
+/// The simulation's compute pipelines and the layout they share. Created once
+/// and used by every `ParticleSystem`, each binding its own descriptor sets.
+pub struct SimPipelines {
+    /// One for every `SimulationMode`, built from its behavior's shader.
+    pipelines: HashMap<SimulationMode, OwnedPipeline>,
+    /// Those of each mode's `SimulationBehavior::prepasses`, in order.
+    prepasses: HashMap<SimulationMode, Vec<OwnedPipeline>>,
+    /// Reflected from all of them; every system allocates its sets of it.
+    pub layout: ComputeLayout,
+    /// Builds every system's spatial grid.
+    pub scan: GpuScan,
+    /// Invocations per compute workgroup, specialized into every compute
+    /// shader; dispatches size themselves with `ParticleSystem::workgroup_count`.
+    pub workgroup_size: u32,
+    /// Particles each invocation of the update steps, for behaviors that
+    /// batch them; see `update_workgroup_count`.
+    pub particles_per_invocation: u32,
+    /// How every system's particle buffers are laid out and how precise they
+    /// are, specialized into the compute shaders like `workgroup_size`.
+    pub format: ParticleFormat,
+    /// Whether the shaders of behaviors that `supports_bda` were built with
+    /// `PARTICLE_BDA`, and so every system's buffers need device addresses.
+    pub bda: bool,
+    /// Whether the behavior shaders not built with `PARTICLE_BDA` store `F16`
+    /// particles' half floats natively, with `PARTICLE_F16_STORAGE`.
+    pub f16_storage: bool,
+    pipeline_cache: vk::PipelineCache,
+    /// Names reloaded pipelines.
+    debug: DebugUtils,
+}
+
+impl SimPipelines {
+    /// `tuning` is usually `autotune::dispatch_tuning`'s. With `bda`, behaviors
+    /// that support it take their particles by address, if the device can.
+    pub fn new(context: &VulkanContext, format: ParticleFormat, tuning: DispatchTuning, bda: bool) -> Result<Self, VulkanDemoError> {
+        log::debug!("Compute dispatch: {tuning}");
+        if bda && context.buffer_device_address.is_none() {
+            log::warn!("bufferDeviceAddress is not supported, reading particles through descriptors");
+        }
+        let bda = bda && context.buffer_device_address.is_some();
+        let f16_storage = format.precision == ParticlePrecision::F16
+            && context.features.shader_float16
+            && context.features.storage_buffer_16bit_access;
+        if format.precision == ParticlePrecision::F16 && !f16_storage {
+            log::info!("shaderFloat16 or storageBuffer16BitAccess is not supported, packing half float particles");
+        }
+
+        // Every behavior's shaders, reflected for the layout they share
+        let behaviors: Vec<_> = SimulationMode::all().iter().map(|mode| mode.behavior()).collect();
+        let compile = |file: &str, source: &str, options: &ShaderCompileOptions| {
+            compile_shader(source, file, shaderc::ShaderKind::Compute, options)
+        };
+        let spirv = behaviors
+            .iter()
+            .map(|behavior| {
+                let options = behavior_options(behavior.as_ref(), bda, f16_storage, &ShaderCompileOptions::default());
+                compile(behavior.shader_file(), behavior.shader_source(), &options)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let prepass_spirv = behaviors
+            .iter()
+            .map(|behavior| {
+                behavior
+                    .prepasses()
+                    .iter()
+                    .map(|&(file, source)| compile(file, source, &ShaderCompileOptions::default()))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let all_spirv: Vec<_> = spirv.iter().chain(prepass_spirv.iter().flatten()).map(Vec::as_slice).collect();
+        let interface = compute_shader_interface(&all_spirv)?;
+        let layout = ComputeLayout::from_interface(&context.device, &interface)?;
+
+        let mut pipelines = HashMap::new();
+        let mut prepasses = HashMap::new();
+        for ((behavior, spirv), prepass_spirv) in behaviors.iter().zip(&spirv).zip(&prepass_spirv) {
+            let create = |spirv: &[u32]| {
+                create_behavior_pipeline(
+                    &context.device,
+                    context.pipeline_cache,
+                    layout.pipeline_layout.handle(),
+                    behavior.as_ref(),
+                    tuning,
+                    format,
+                    spirv,
+                )
+            };
+            let pipeline = create(spirv)?;
+            context.debug.set_object_name(pipeline.handle(), &pipeline_name(behavior.mode()));
+            pipelines.insert(behavior.mode(), pipeline);
+            let mode_prepasses = prepass_spirv.iter().map(|spirv| create(spirv)).collect::<Result<Vec<_>, _>>()?;
+            for (pipeline, (file, _)) in mode_prepasses.iter().zip(behavior.prepasses()) {
+                context.debug.set_object_name(pipeline.handle(), &prepass_name(behavior.mode(), file));
+            }
+            prepasses.insert(behavior.mode(), mode_prepasses);
+        }
+        Ok(Self {
+            pipelines,
+            prepasses,
+            layout,
+            scan: GpuScan::new(context)?,
+            workgroup_size: tuning.workgroup_size,
+            particles_per_invocation: tuning.particles_per_invocation,
+            format,
+            bda,
+            f16_storage,
+            pipeline_cache: context.pipeline_cache,
+            debug: context.debug.clone(),
+        })
+    }
+
+    /// The compute pipeline for `mode`.
+    pub fn pipeline(&self, mode: SimulationMode) -> vk::Pipeline {
+        self.pipelines[&mode].handle()
+    }
+
+    /// The pipelines of `mode`'s prepasses, in order.
+    pub fn prepasses(&self, mode: SimulationMode) -> &[OwnedPipeline] {
+        &self.prepasses[&mode]
+    }
+
+    /// How the update pipelines are dispatched.
+    pub fn tuning(&self) -> DispatchTuning {
+        DispatchTuning { workgroup_size: self.workgroup_size, particles_per_invocation: self.particles_per_invocation }
+    }
+
+    /// Workgroups of `behavior`'s update covering `count` particles.
+    pub fn update_workgroup_count(&self, behavior: &dyn SimulationBehavior, count: u32) -> u32 {
+        self.tuning().workgroup_count(count, behavior.batches_particles())
+    }
+
+    /// A pipeline for `mode` sharing the layout of the others but specialized
+    /// with `tuning` instead, for timing other dispatches. Kept out of the
+    /// pipeline cache, since it's thrown away.
+    pub fn trial_pipeline(
+        &self,
+        device: &Arc<ash::Device>,
+        mode: SimulationMode,
+        tuning: DispatchTuning,
+    ) -> Result<OwnedPipeline, VulkanDemoError> {
+        let behavior = mode.behavior();
+        let options = behavior_options(behavior.as_ref(), self.bda, self.f16_storage, &ShaderCompileOptions::default());
+        let comp_spirv = compile_shader(behavior.shader_source(), behavior.shader_file(), shaderc::ShaderKind::Compute, &options)?;
+        let pipeline = create_behavior_pipeline(
+            device,
+            vk::PipelineCache::null(),
+            self.layout.pipeline_layout.handle(),
+            behavior.as_ref(),
+            tuning,
+            self.format,
+            &comp_spirv,
+        )?;
+        Ok(pipeline)
+    }
+
+    /// Recompiles the compute shader for `mode` from GLSL and swaps in a new pipeline
+    /// for every system. On a compile error the current pipeline is kept.
+    pub fn reload_pipeline(
+        &mut self,
+        device: &Arc<ash::Device>,
+        mode: SimulationMode,
+        source: &str,
+        options: &ShaderCompileOptions,
+    ) -> Result<(), VulkanDemoError> {
+        let behavior = mode.behavior();
+        let options = behavior_options(behavior.as_ref(), self.bda, self.f16_storage, options);
+        let comp_spirv = compile_shader(source, behavior.shader_file(), shaderc::ShaderKind::Compute, &options)?;
+        // The layout stays as it is, so the new shader can't push anything else.
+        ShaderInterface::reflect(&[(vk::ShaderStageFlags::COMPUTE, &comp_spirv)])?.expect_push_constants::<SimPushConstants>()?;
+        let pipeline = create_behavior_pipeline(
+            device,
+            self.pipeline_cache,
+            self.layout.pipeline_layout.handle(),
+            behavior.as_ref(),
+            self.tuning(),
+            self.format,
+            &comp_spirv,
+        )?;
+        unsafe { device.device_wait_idle()? };
+        self.debug.set_object_name(pipeline.handle(), &pipeline_name(mode));
+        self.pipelines.insert(mode, pipeline);
+        Ok(())
+    }
+
+    /// Like `reload_pipeline`, for the `index`th of `mode`'s prepasses.
+    pub fn reload_prepass(
+        &mut self,
+        device: &Arc<ash::Device>,
+        mode: SimulationMode,
+        index: usize,
+        source: &str,
+        options: &ShaderCompileOptions,
+    ) -> Result<(), VulkanDemoError> {
+        let behavior = mode.behavior();
+        let (file, _) = behavior.prepasses()[index];
+        // The layout stays as it is, so the new shader can't bind anything else.
+        let comp_spirv = compile_shader(source, file, shaderc::ShaderKind::Compute, options)?;
+        let pipeline = create_behavior_pipeline(
+            device,
+            self.pipeline_cache,
+            self.layout.pipeline_layout.handle(),
+            behavior.as_ref(),
+            self.tuning(),
+            self.format,
+            &comp_spirv,
+        )?;
+        unsafe { device.device_wait_idle()? };
+        self.debug.set_object_name(pipeline.handle(), &prepass_name(mode, file));
+        self.prepasses.get_mut(&mode).expect("every mode has prepasses")[index] = pipeline;
+        Ok(())
+    }
+}
+
+/// GPU particle storage plus everything one step of it binds: its own
+/// parameters, emitter and descriptor sets, used with the shared `SimPipelines`.
+/// Any number of systems are simulated and drawn side by side.
+///
+/// Particles live in two buffers used in ping-pong fashion: each step reads
+/// `buffers[frame_index]` and writes the other one, and the last step of a
+/// frame's output is drawn. Call `advance` with the steps each frame submitted.
 pub struct ParticleSystem {
-    pub buffer: vk::Buffer,
-    pub memory: vk::DeviceMemory,
+    pub buffers: [OwnedBuffer; 2],
+    pub frame_index: usize,
+    pub device_local: bool,
     pub count: u32,
-    pub descriptor_pool: vk::DescriptorPool,
-    pub descriptor_set_layout: vk::DescriptorSetLayout,
-    pub descriptor_set: vk::DescriptorSet,
-    pub pipeline_layout: vk::PipelineLayout,
-    pub compute_pipeline: vk::Pipeline,
+    /// Seeds the initial layout, `reset` and the GPU respawns.
+    pub seed: u64,
+    /// `SimPipelines::workgroup_size`.
+    pub workgroup_size: u32,
+    /// `SimPipelines::format`.
+    pub format: ParticleFormat,
+    pub params: SimParams,
+    /// Spawn settings for `reset`; also mirrored into `params` for respawns.
+    pub emitter: EmitterConfig,
+    /// `params` and `attractors` as bound at 2 and 7, and the live count read
+    /// back, one copy per frame in flight.
+    frames: Vec<SystemFrame>,
+    /// Index into `frames` of the frame being recorded.
+    frame_in_flight: usize,
+    /// `DrawCounts` of the latest step, bound at 5. Device-local, written only by the GPU.
+    draw_buffer: OwnedBuffer,
+    /// What particles collide with, as last set by `set_obstacles`.
+    pub obstacles: Vec<Obstacle>,
+    /// `obstacles` as bound at 6, in device-local memory copied into from the
+    /// staging ring by `set_obstacles`.
+    obstacle_buffer: OwnedBuffer,
+    /// As last set by `set_attractors`.
+    pub attractors: Vec<Attractor>,
+    /// Density and pressure of every particle in the grid's sorted order, as
+    /// bound at 8 for `SimulationMode::Fluid`; only used within a step.
+    _fluid_buffer: OwnedBuffer,
+    /// Set `2 * frame + i` reads `buffers[i]` and writes `buffers[1 - i]`,
+    /// binding the copies of frame in flight `frame`.
+    pub descriptors: DescriptorSets,
+    /// Device addresses of `buffers`, pushed with every step when the
+    /// pipelines are built for `--bda`.
+    buffer_addresses: Option<[vk::DeviceAddress; 2]>,
+    /// What advances the particles; see `set_mode`.
+    behavior: Box<dyn SimulationBehavior>,
+    /// Rebuilt each step that uses neighbor forces; bound at 3 and 4 of the main layout.
+    grid: SpatialGrid,
+    /// Orders the particles for drawing when on; see `set_depth_sort`.
+    depth_sort: Option<DepthSort>,
+    pub backend: SimBackend,
+    /// Kept for every backend but `SimBackend::Gpu`.
+    cpu_state: Option<CpuState>,
+    /// The upload of the particles into `buffers` until `poll_upload` has
+    /// handed on its dependency; see `is_loading`.
+    upload: Cell<Option<UploadHandle>>,
+}
+
+/// The buffers a system keeps one of per frame in flight, so the host can
+/// rewrite one while the GPU may still be reading another.
+struct SystemFrame {
+    params_buffer: OwnedBuffer,
+    attractor_buffer: OwnedBuffer,
+    /// Host-visible copy of the live count, written by the frame's readback.
+    live_count_buffer: OwnedBuffer,
+    /// Whether `params` or `attractors` changed since the buffers were written.
+    stale: bool,
+}
+
+/// The host's copy of a system simulated on the CPU, or checked against it.
+#[derive(Default)]
+struct CpuState {
+    /// Mirrors the particle buffer the next step reads.
+    current: Vec<Particle>,
+    /// The result of `step_on_cpu`, made current by `advance`.
+    next: Vec<Particle>,
+    live_count: u32,
 }
 
 impl ParticleSystem {
-    pub fn new(context: &VulkanContext, count: u32) -> Result<Self, Box<dyn std::error::Error>> {
-        let buffer_size = (count as usize * size_of::<Particle>()) as vk::DeviceSize;
-
-        let buffer_info = vk::BufferCreateInfo::default()
-            .size(buffer_size)
-            .usage(vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::VERTEX_BUFFER)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE);
-
-        let buffer = unsafe { context.device.create_buffer(&buffer_info, None)? };
-        let mem_reqs = unsafe { context.device.get_buffer_memory_requirements(buffer) };
-        
-        let mem_props = unsafe { context.instance.get_physical_device_memory_properties(context.physical_device) };
-        let mem_type_index = find_memory_type(mem_reqs.memory_type_bits, vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT, mem_props).ok_or("Failed to find memory type")?;
-
-        let alloc_info = vk::MemoryAllocateInfo::default()
-            .allocation_size(mem_reqs.size)
-            .memory_type_index(mem_type_index);
-
-        let memory = unsafe { context.device.allocate_memory(&alloc_info, None)? };
-        unsafe { context.device.bind_buffer_memory(buffer, memory, 0)? };
-
-        // Initialize particles
-        let mut particles = Vec::with_capacity(count as usize);
-        for _ in 0..count {
-            particles.push(Particle {
-                pos: [
-                    (rand::random::<f32>() * 2.0 - 1.0),
-                    (rand::random::<f32>() * 2.0 - 1.0),
-                ],
-                vel: [
-                    (rand::random::<f32>() * 2.0 - 1.0) * 0.1,
-                    (rand::random::<f32>() * 2.0 - 1.0) * 0.1,
-                ],
-            });
+    /// `seed` makes the initial particle layout reproducible. With `three_d`
+    /// particles spread through the world box instead of the z = 0 plane. The
+    /// buffers are in the format `pipelines` were made for.
+    pub fn new(
+        context: &VulkanContext,
+        pipelines: &SimPipelines,
+        count: u32,
+        seed: u64,
+        emitter: &EmitterConfig,
+        three_d: bool,
+        backend: SimBackend,
+    ) -> Result<Self, VulkanDemoError> {
+        let buffer_size = pipelines.format.buffer_size(count);
+
+        // Prefer VRAM that the host cannot see; integrated GPUs only expose host-visible
+        // device-local memory, so there the buffer is simply written through a mapping.
+        // The CPU simulation writes every step through the mapping too.
+        let device_local =
+            backend != SimBackend::Cpu && has_dedicated_device_local_memory(context.allocator.memory_properties());
+        // Copied from and into by `resize`.
+        let mut usage = vk::BufferUsageFlags::STORAGE_BUFFER
+            | vk::BufferUsageFlags::VERTEX_BUFFER
+            | vk::BufferUsageFlags::TRANSFER_SRC
+            | vk::BufferUsageFlags::TRANSFER_DST;
+        if pipelines.bda {
+            usage |= vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
         }
+        let location = if device_local { MemoryLocation::GpuOnly } else { MemoryLocation::CpuToGpu };
+        // Large counts are refused here with the sizes involved, rather than
+        // failing partway through with a bare out-of-memory error.
+        context.check_memory_budget(location, 2 * buffer_size)?;
+        context.check_memory_budget(MemoryLocation::GpuOnly, SpatialGrid::memory_size(count))?;
+        // Written by the compute queue and read by the graphics queue.
+        let queue_families = context.queue_family_indices();
+        let buffers = [
+            create_shared_buffer(context, buffer_size, usage, location, &queue_families)?,
+            create_shared_buffer(context, buffer_size, usage, location, &queue_families)?,
+        ];
+        context.debug.set_object_name(buffers[0].handle(), "particle storage buffer 0");
+        context.debug.set_object_name(buffers[1].handle(), "particle storage buffer 1");
+        let buffer_addresses = match &context.buffer_device_address {
+            Some(loader) if pipelines.bda => Some(buffers.each_ref().map(|buffer| unsafe {
+                loader.get_buffer_device_address(&vk::BufferDeviceAddressInfo::default().buffer(buffer.handle()))
+            })),
+            _ => None,
+        };
 
-        unsafe {
-            let data_ptr = context.device.map_memory(memory, 0, buffer_size, vk::MemoryMapFlags::empty())?;
-            std::ptr::copy_nonoverlapping(particles.as_ptr(), data_ptr as *mut Particle, count as usize);
-            context.device.unmap_memory(memory);
+        let particles = initial_particles(count, seed, emitter, three_d);
+
+        // Each written by `set_frame_in_flight` once the GPU is done with its frame.
+        let frames = (0..FRAMES_IN_FLIGHT)
+            .map(|frame| {
+                let params_buffer = create_buffer(
+                    context,
+                    size_of::<SimParams>() as vk::DeviceSize,
+                    vk::BufferUsageFlags::UNIFORM_BUFFER,
+                    MemoryLocation::CpuToGpu,
+                )?;
+                context.debug.set_object_name(params_buffer.handle(), &format!("sim params buffer {frame}"));
+                let attractor_buffer = create_array_buffer::<Attractor>(context, MAX_ATTRACTORS)?;
+                let live_count_buffer = create_buffer(
+                    context,
+                    size_of::<u32>() as vk::DeviceSize,
+                    vk::BufferUsageFlags::TRANSFER_DST,
+                    MemoryLocation::GpuToCpu,
+                )?;
+                Ok(SystemFrame { params_buffer, attractor_buffer, live_count_buffer, stale: true })
+            })
+            .collect::<Result<Vec<_>, VulkanDemoError>>()?;
+
+        let draw_buffer = create_shared_buffer(
+            context,
+            size_of::<DrawCounts>() as vk::DeviceSize,
+            vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::INDIRECT_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_SRC
+                | vk::BufferUsageFlags::TRANSFER_DST,
+            MemoryLocation::GpuOnly,
+            &queue_families,
+        )?;
+
+        let obstacle_buffer = create_shared_buffer(
+            context,
+            array_buffer_size::<GpuObstacle>(MAX_OBSTACLES),
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            MemoryLocation::GpuOnly,
+            &queue_families,
+        )?;
+        let fluid_buffer = create_buffer(
+            context,
+            count as vk::DeviceSize * size_of::<[f32; 2]>() as vk::DeviceSize,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            MemoryLocation::GpuOnly,
+        )?;
+        context.debug.set_object_name(fluid_buffer.handle(), "fluid density buffer");
+
+        let workgroup_size = pipelines.workgroup_size;
+        let format = pipelines.format;
+        let params_buffers: Vec<_> = frames.iter().map(|frame| &frame.params_buffer).collect();
+        let grid = SpatialGrid::new(context, &pipelines.scan, &buffers, &params_buffers, count, workgroup_size, format)?;
+
+        // Set 2 * frame + i reads buffers[i] and writes the other, with that
+        // frame's copies; the rest is shared.
+        let descriptors = DescriptorSets::allocate(context, &pipelines.layout, 2 * FRAMES_IN_FLIGHT)?;
+        for (frame, copies) in frames.iter().enumerate() {
+            for i in 0..2 {
+                let set = 2 * frame + i;
+                context.debug.set_object_name(descriptors.set(set), &format!("particle descriptor set {set}"));
+                descriptors.bind_buffers(
+                    &context.device,
+                    set,
+                    &[
+                        (0, buffers[i].handle()),
+                        (1, buffers[1 - i].handle()),
+                        (2, copies.params_buffer.handle()),
+                        (3, grid.cell_ranges().handle()),
+                        (4, grid.sorted_particles().handle()),
+                        (5, draw_buffer.handle()),
+                        (6, obstacle_buffer.handle()),
+                        (7, copies.attractor_buffer.handle()),
+                        (8, fluid_buffer.handle()),
+                    ],
+                );
+            }
         }
 
-        // Descriptors
-        let layout_binding = vk::DescriptorSetLayoutBinding::default()
-            .binding(0)
-            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-            .descriptor_count(1)
-            .stage_flags(vk::ShaderStageFlags::COMPUTE);
+        let mut system = Self {
+            buffers,
+            frame_index: 0,
+            device_local,
+            count,
+            seed,
+            workgroup_size,
+            format,
+            params: SimParams::default(),
+            emitter: *emitter,
+            frames,
+            frame_in_flight: 0,
+            draw_buffer,
+            obstacles: Vec::new(),
+            obstacle_buffer,
+            attractors: Vec::new(),
+            _fluid_buffer: fluid_buffer,
+            descriptors,
+            buffer_addresses,
+            behavior: SimulationMode::default().behavior(),
+            grid,
+            depth_sort: None,
+            backend,
+            cpu_state: (backend != SimBackend::Gpu).then(CpuState::default),
+            upload: Cell::new(None),
+        };
+        system.upload(context, &particles)?;
+        system.set_obstacles(context, &[])?;
+        system.set_attractors(&[])?;
+        let dimensions = if three_d { 3 } else { 2 };
+        // Both halves of the seed feed the 32-bit GPU hash.
+        let gpu_seed = (seed ^ (seed >> 32)) as u32;
+        system.update_params(&SimParams { dimensions, seed: gpu_seed, ..SimParams::default().with_emitter(emitter) })?;
+        // Nothing is in flight yet, so every frame's copy is written now.
+        for frame in (0..FRAMES_IN_FLIGHT).rev() {
+            system.set_frame_in_flight(frame)?;
+        }
 
-        let layout_info = vk::DescriptorSetLayoutCreateInfo::default()
-            .bindings(std::slice::from_ref(&layout_binding));
+        Ok(system)
+    }
 
-        let descriptor_set_layout = unsafe { context.device.create_descriptor_set_layout(&layout_info, None)? };
+    /// Workgroups covering every particle once.
+    pub fn workgroup_count(&self) -> u32 {
+        self.count.div_ceil(self.workgroup_size)
+    }
 
-        let pool_size = vk::DescriptorPoolSize::default()
-            .ty(vk::DescriptorType::STORAGE_BUFFER)
-            .descriptor_count(1);
+    /// Switches the behavior the next step runs, keeping the particles and
+    /// descriptor sets. Modes are refused with a warning above their
+    /// `max_particles`, and off `SimBackend::Gpu` unless the CPU runs them;
+    /// returns whether `mode` is now active.
+    pub fn set_mode(&mut self, mode: SimulationMode) -> bool {
+        let behavior = mode.behavior();
+        if self.backend != SimBackend::Gpu && !behavior.runs_on_cpu() {
+            log::warn!("The CPU simulation does not implement {mode:?} mode");
+            return false;
+        }
+        if let Some(max_particles) = behavior.max_particles().filter(|&max| self.count > max) {
+            log::warn!("{mode:?} mode is limited to {max_particles} particles, this system has {}", self.count);
+            return false;
+        }
+        self.behavior = behavior;
+        true
+    }
 
-        let pool_info = vk::DescriptorPoolCreateInfo::default()
-            .pool_sizes(std::slice::from_ref(&pool_size))
-            .max_sets(1);
+    pub fn mode(&self) -> SimulationMode {
+        self.behavior.mode()
+    }
 
-        let descriptor_pool = unsafe { context.device.create_descriptor_pool(&pool_info, None)? };
+    /// Records the main dispatch of the `substep`th next step with `pipelines`,
+    /// after `record_prepass`. Under `--bda`, the step's buffers are pushed
+    /// along with `push_constants`.
+    pub fn record_update(
+        &self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        pipelines: &SimPipelines,
+        substep: usize,
+        push_constants: &SimPushConstants,
+    ) {
+        let mut push_constants = *push_constants;
+        if let Some(addresses) = self.buffer_addresses {
+            // Like the descriptor set, reads buffer `read` and writes the other.
+            let read = (self.frame_index + substep) % 2;
+            push_constants.particle_capacity = self.count;
+            push_constants.particles_in = addresses[read];
+            push_constants.particles_out = addresses[1 - read];
+        }
+        let prepasses: Vec<_> = pipelines.prepasses(self.mode()).iter().map(OwnedPipeline::handle).collect();
+        let resources = UpdateResources {
+            pipeline: pipelines.pipeline(self.mode()),
+            prepasses: &prepasses,
+            pipeline_layout: pipelines.layout.pipeline_layout.handle(),
+            descriptor_set: self.descriptor_set(substep),
+            push_constants: &push_constants,
+            workgroup_count: pipelines.update_workgroup_count(self.behavior.as_ref(), self.count),
+        };
+        self.behavior.record_update(device, cmd, &resources);
+    }
 
-        let alloc_info = vk::DescriptorSetAllocateInfo::default()
-            .descriptor_pool(descriptor_pool)
-            .set_layouts(std::slice::from_ref(&descriptor_set_layout));
+    pub fn is_3d(&self) -> bool {
+        self.params.dimensions == 3
+    }
 
-        let descriptor_set = unsafe { context.device.allocate_descriptor_sets(&alloc_info)?[0] };
+    /// Whether the next step needs the spatial grid, as the behavior decides.
+    pub fn uses_grid(&self) -> bool {
+        self.behavior.uses_grid(&self.params)
+    }
 
-        let buffer_info = vk::DescriptorBufferInfo::default()
-            .buffer(buffer)
-            .offset(0)
-            .range(buffer_size);
+    /// Longest step the behavior stays stable with, if it has a limit.
+    pub fn max_step_dt(&self) -> Option<f32> {
+        self.behavior.max_step_dt()
+    }
 
-        let write = vk::WriteDescriptorSet::default()
-            .dst_set(descriptor_set)
-            .dst_binding(0)
-            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-            .buffer_info(std::slice::from_ref(&buffer_info));
+    /// Records the work the main dispatch of the `substep`th next step depends
+    /// on: clearing the draw counts and, if used, building the spatial grid.
+    /// Goes after the barrier on the step's input and before the main dispatch.
+    pub fn record_prepass(&self, device: &ash::Device, cmd: vk::CommandBuffer, scan: &GpuScan, substep: usize) {
+        unsafe {
+            // The previous step's counter writes on this queue. Earlier frames' indirect
+            // draws and readbacks are ordered before the steps by `record_frame`, or for
+            // async steps by the compute submission's wait.
+            let barrier = vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE);
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
+            device.cmd_update_buffer(cmd, self.draw_buffer.handle(), 0, bytemuck::bytes_of(&DrawCounts::new(0)));
+            let barrier = vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
+        }
+        if self.uses_grid() {
+            self.grid.record(device, cmd, scan, self.set_index(substep), self.params.grid_size);
+        }
+    }
 
-        unsafe { context.device.update_descriptor_sets(std::slice::from_ref(&write), &[]) };
+    /// Buffer holding the `vk::DrawIndirectCommand` for drawing the current state.
+    pub fn indirect_buffer(&self) -> vk::Buffer {
+        self.draw_buffer.handle()
+    }
 
-        // Pipeline Layout
-        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default()
-            .set_layouts(std::slice::from_ref(&descriptor_set_layout));
+    /// Records copying the live count into host-visible memory for `live_count`,
+    /// into the copy of the frame being recorded. The step's writes must
+    /// already be visible to the transfer stage.
+    pub fn record_live_count_readback(&self, device: &ash::Device, cmd: vk::CommandBuffer) {
+        let live_count_buffer = &self.frames[self.frame_in_flight].live_count_buffer;
+        unsafe {
+            let region = vk::BufferCopy::default().size(size_of::<u32>() as vk::DeviceSize);
+            device.cmd_copy_buffer(cmd, self.draw_buffer.handle(), live_count_buffer.handle(), &[region]);
+            let barrier = vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::HOST_READ);
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
+        }
+    }
 
-        let pipeline_layout = unsafe { context.device.create_pipeline_layout(&pipeline_layout_info, None)? };
+    /// Particles drawn when the current frame in flight was last recorded,
+    /// which `set_frame_in_flight` requires to have finished; cheap enough to
+    /// poll occasionally, not meant for every frame. Zero while the system is
+    /// loading, as nothing draws it.
+    pub fn live_count(&self) -> Result<u32, vk::Result> {
+        if self.is_loading() {
+            return Ok(0);
+        }
+        let bytes = read_from_buffer(&self.frames[self.frame_in_flight].live_count_buffer, size_of::<u32>())?;
+        Ok(bytemuck::pod_read_unaligned(&bytes))
+    }
 
-        // Compute Pipeline
-        let comp_source = include_str!("shaders/particle.comp");
-        let comp_spirv = crate::pipeline_utils::compile_shader(comp_source, "particle.comp", shaderc::ShaderKind::Compute)?;
-        let comp_module = crate::pipeline_utils::create_shader_module(&context.device, &comp_spirv)?;
+    /// Descriptor set for the `substep`th next step; 0 reads the current state.
+    pub fn descriptor_set(&self, substep: usize) -> vk::DescriptorSet {
+        self.descriptors.set(self.set_index(substep))
+    }
 
-        let entry_name = unsafe { std::ffi::CStr::from_bytes_with_nul_unchecked(b"main\0") };
-        let stage_info = vk::PipelineShaderStageCreateInfo::default()
-            .stage(vk::ShaderStageFlags::COMPUTE)
-            .module(comp_module)
-            .name(entry_name);
+    /// Index of the `substep`th next step's descriptor sets, here and in the grid.
+    fn set_index(&self, substep: usize) -> usize {
+        2 * self.frame_in_flight + (self.frame_index + substep) % 2
+    }
 
-        let pipeline_info = vk::ComputePipelineCreateInfo::default()
-            .stage(stage_info)
-            .layout(pipeline_layout);
+    /// Switches to the parameter and attractor buffers of frame in flight
+    /// `frame`, which the GPU must be done with, first writing whatever
+    /// changed since they were last written.
+    pub fn set_frame_in_flight(&mut self, frame: usize) -> Result<(), vk::Result> {
+        self.frame_in_flight = frame;
+        let copies = &mut self.frames[frame];
+        if copies.stale {
+            copies.params_buffer.write_pod(0, &self.params)?;
+            upload_array(&copies.attractor_buffer, &self.attractors)?;
+            copies.stale = false;
+        }
+        Ok(())
+    }
 
-        let compute_pipeline = unsafe {
-            context.device.create_compute_pipelines(vk::PipelineCache::null(), std::slice::from_ref(&pipeline_info), None)
-                .map_err(|(_, e)| e)?[0]
+    /// Turns sorting the particles far to near before drawing on or off, for
+    /// alpha blending. The GPU must not be using the system.
+    pub fn set_depth_sort(&mut self, context: &VulkanContext, enabled: bool) -> Result<(), VulkanDemoError> {
+        self.depth_sort = match (enabled, self.depth_sort.take()) {
+            (true, None) => Some(DepthSort::new(context, &self.buffers, &self.draw_buffer, self.count, self.workgroup_size, self.format)?),
+            (true, kept) => kept,
+            (false, _) => None,
         };
+        Ok(())
+    }
 
-        unsafe { context.device.destroy_shader_module(comp_module, None) };
+    pub fn is_depth_sorted(&self) -> bool {
+        self.depth_sort.is_some()
+    }
 
-        Ok(Self {
-            buffer,
-            memory,
-            count,
-            descriptor_pool,
-            descriptor_set_layout,
-            descriptor_set,
-            pipeline_layout,
-            compute_pipeline,
-        })
+    /// With the depth sort on, records sorting what the draw after `steps`
+    /// more steps reads far to near from `focal_point`.
+    pub fn record_depth_sort(&self, device: &ash::Device, cmd: vk::CommandBuffer, steps: usize, focal_point: [f32; 3]) {
+        if let Some(depth_sort) = &self.depth_sort {
+            depth_sort.record(device, cmd, (self.frame_index + steps) % 2, focal_point);
+        }
+    }
+
+    /// What the draw after `steps` more steps reads: `buffer_after(steps)`, or
+    /// its sorted copy with the depth sort on.
+    pub fn vertex_buffer(&self, steps: usize) -> vk::Buffer {
+        match &self.depth_sort {
+            Some(depth_sort) => depth_sort.sorted_particles(),
+            None => self.buffer_after(steps),
+        }
+    }
+
+    /// Offsets to bind `vertex_buffer` at for the renderer's two vertex
+    /// bindings, per `ParticleFormat::vertex_offsets`.
+    pub fn vertex_offsets(&self) -> [vk::DeviceSize; 2] {
+        self.format.vertex_offsets(self.count)
+    }
+
+    /// The draw after `steps` more steps.
+    pub fn draw(&self, steps: usize) -> ParticleDraw {
+        ParticleDraw {
+            vertex_buffer: self.vertex_buffer(steps),
+            vertex_offsets: self.vertex_offsets(),
+            indirect_buffer: self.indirect_buffer(),
+        }
+    }
+
+    /// Buffer holding the latest state, read by the next step.
+    pub fn current_buffer(&self) -> vk::Buffer {
+        self.buffer_after(0)
+    }
+
+    /// Buffer the next step writes to.
+    pub fn output_buffer(&self) -> vk::Buffer {
+        self.buffer_after(1)
+    }
+
+    /// Buffer holding the state `steps` steps on, as the buffers alternate.
+    pub fn buffer_after(&self, steps: usize) -> vk::Buffer {
+        self.buffers[(self.frame_index + steps) % 2].handle()
     }
 
-    pub fn clean(&mut self, device: &ash::Device) {
+    /// Makes the output of the last `steps` steps the input of the next one.
+    /// Systems still loading took no steps.
+    pub fn advance(&mut self, steps: usize) {
+        if self.is_loading() {
+            return;
+        }
+        self.frame_index = (self.frame_index + steps) % 2;
+        if let Some(cpu) = &mut self.cpu_state {
+            if steps > 0 {
+                cpu.current = std::mem::take(&mut cpu.next);
+            }
+        }
+    }
+
+    /// Whether steps run on the host instead of `SimPipelines`.
+    pub fn simulated_on_cpu(&self) -> bool {
+        self.backend == SimBackend::Cpu
+    }
+
+    /// Runs the next `substeps` on the host copy of the particles, unless the
+    /// backend is `SimBackend::Gpu`. With `SimBackend::Cpu` the result is also
+    /// written to the buffer drawn after them, so every frame in flight must be finished.
+    pub fn step_on_cpu(&mut self, substeps: &[SimPushConstants]) -> Result<(), VulkanDemoError> {
+        let cpu = match &mut self.cpu_state {
+            Some(cpu) if !substeps.is_empty() => cpu,
+            _ => return Ok(()),
+        };
+        let mut next = cpu.current.clone();
+        let mut live_count = cpu.live_count;
+        for push_constants in substeps {
+            (next, live_count) = cpu_sim::step(&next, &self.params, push_constants, &self.obstacles, &self.attractors);
+        }
+        if self.backend == SimBackend::Cpu {
+            self.buffers[(self.frame_index + substeps.len()) % 2].write_slice(0, &self.format.pack(&next))?;
+        }
+        cpu.next = next;
+        cpu.live_count = live_count;
+        Ok(())
+    }
+
+    /// Records what stands in for the dispatch of a CPU-simulated system:
+    /// setting the draw counts to the live count of `step_on_cpu`.
+    pub fn record_cpu_step(&self, device: &ash::Device, cmd: vk::CommandBuffer) {
+        let live_count = self.cpu_state.as_ref().map_or(0, |cpu| cpu.live_count);
         unsafe {
-            device.destroy_pipeline(self.compute_pipeline, None);
-            device.destroy_pipeline_layout(self.pipeline_layout, None);
-            device.destroy_descriptor_pool(self.descriptor_pool, None);
-            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
-            device.destroy_buffer(self.buffer, None);
-            device.free_memory(self.memory, None);
+            device.cmd_update_buffer(cmd, self.draw_buffer.handle(), 0, bytemuck::bytes_of(&DrawCounts::new(live_count)));
+            let barrier = vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::INDIRECT_COMMAND_READ | vk::AccessFlags::TRANSFER_READ);
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::DRAW_INDIRECT | vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
+        }
+    }
+
+    /// How far the GPU state has drifted from the CPU one, for `SimBackend::Verify`;
+    /// `None` for the other backends. The latest step must have finished.
+    pub fn cpu_divergence(&self, context: &VulkanContext) -> Result<Option<Divergence>, VulkanDemoError> {
+        match &self.cpu_state {
+            Some(cpu) if self.backend == SimBackend::Verify => Ok(Some(cpu_sim::divergence(&self.snapshot(context)?, &cpu.current))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Moves the system into buffers sized for `count` particles. The first
+    /// `count` particles of the current state carry over and any beyond those
+    /// are freshly spawned; parameters and, where it allows the count, the mode
+    /// are kept. Returns the old buffers and descriptors as a system of their
+    /// own, which must outlive any frame still using them.
+    ///
+    /// The latest step must have finished, as its output is what gets copied.
+    pub fn resize(&mut self, context: &VulkanContext, pipelines: &SimPipelines, count: u32) -> Result<ParticleSystem, VulkanDemoError> {
+        self.finish_upload(context)?;
+        let mut resized = ParticleSystem::new(context, pipelines, count, self.seed, &self.emitter, self.is_3d(), self.backend)?;
+        // The copy overwrites the start of the freshly spawned particles.
+        resized.finish_upload(context)?;
+        let kept = self.count.min(count) as usize;
+        if let (Some(cpu), Some(resized_cpu)) = (&self.cpu_state, &resized.cpu_state) {
+            // The host copy is the reference; the GPU buffers get the same particles.
+            let mut particles = cpu.current[..kept].to_vec();
+            particles.extend_from_slice(&resized_cpu.current[kept..]);
+            resized.upload(context, &particles)?;
+        } else {
+            let regions = self.format.copy_regions(kept as u32, self.count, count);
+            context.one_time_submit(|cmd| unsafe {
+                context.device.cmd_copy_buffer(cmd, self.current_buffer(), resized.current_buffer(), &regions);
+            })?;
+        }
+        resized.update_params(&self.params)?;
+        resized.set_obstacles(context, &self.obstacles)?;
+        resized.set_attractors(&self.attractors)?;
+        resized.set_mode(self.mode());
+        resized.set_depth_sort(context, self.depth_sort.is_some())?;
+        Ok(std::mem::replace(self, resized))
+    }
+
+    /// Copies the current state back to the host, in buffer order: live
+    /// particles first, then dead ones. The latest step must have finished.
+    pub fn snapshot(&self, context: &VulkanContext) -> Result<Vec<Particle>, VulkanDemoError> {
+        self.finish_upload(context)?;
+        let size = self.format.buffer_size(self.count) as usize;
+        let bytes = if self.device_local {
+            let staged = context.staging().allocate(size as vk::DeviceSize, 1)?;
+            context.one_time_submit(|cmd| unsafe {
+                staged.record_copy_from(&context.device, cmd, self.current_buffer(), 0);
+                let barrier = vk::MemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::HOST_READ);
+                context.device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::HOST,
+                    vk::DependencyFlags::empty(),
+                    &[barrier],
+                    &[],
+                    &[],
+                );
+            })?;
+            context.staging().read(&staged)?
+        } else {
+            read_from_buffer(&self.buffers[self.frame_index], size)?
+        };
+        Ok(self.format.unpack(&bytes))
+    }
+
+    /// Replaces the particles with `particles`, resizing the system first if
+    /// their count differs. Returns the old system from such a resize, which
+    /// must outlive any frame still using it. The GPU must not be using the
+    /// current buffers.
+    pub fn restore(
+        &mut self,
+        context: &VulkanContext,
+        pipelines: &SimPipelines,
+        particles: &[Particle],
+    ) -> Result<Option<ParticleSystem>, VulkanDemoError> {
+        let retired = if particles.len() as u32 != self.count {
+            Some(self.resize(context, pipelines, particles.len() as u32)?)
+        } else {
+            None
+        };
+        self.upload(context, particles)?;
+        Ok(retired)
+    }
+
+    /// Puts the particles back in the initial layout of `seed`, reusing the
+    /// existing buffers. The GPU must not be using them.
+    pub fn reset(&mut self, context: &VulkanContext) -> Result<(), VulkanDemoError> {
+        self.upload(context, &initial_particles(self.count, self.seed, &self.emitter, self.is_3d()))
+    }
+
+    /// Switches where particles respawn. Live particles keep flying until they die.
+    pub fn set_emitter(&mut self, emitter: &EmitterConfig) -> Result<(), VulkanDemoError> {
+        self.emitter = *emitter;
+        self.update_params(&self.params.with_emitter(emitter))
+    }
+
+    /// Replaces the obstacles particles collide with from the next step on.
+    /// Only the first `MAX_OBSTACLES` are kept. Waits for the copy into the
+    /// obstacle buffer, which no step may be using.
+    pub fn set_obstacles(&mut self, context: &VulkanContext, obstacles: &[Obstacle]) -> Result<(), VulkanDemoError> {
+        self.obstacles = truncated(obstacles, MAX_OBSTACLES, "obstacles").to_vec();
+        let gpu_obstacles: Vec<_> = self.obstacles.iter().map(|obstacle| obstacle.to_gpu()).collect();
+        let staged = context.staging().stage(&array_bytes(&gpu_obstacles))?;
+        context.one_time_submit(|cmd| unsafe {
+            let device = &context.device;
+            memory_barrier(
+                device,
+                cmd,
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::empty()),
+                (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+            );
+            staged.record_copy_to(device, cmd, self.obstacle_buffer.handle(), 0);
+            memory_barrier(
+                device,
+                cmd,
+                (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ),
+            );
+        })?;
+        Ok(())
+    }
+
+    /// Replaces the attractors pulling on particles from the next
+    /// `set_frame_in_flight` on. Only the first `MAX_ATTRACTORS` are kept.
+    pub fn set_attractors(&mut self, attractors: &[Attractor]) -> Result<(), VulkanDemoError> {
+        self.attractors = truncated(attractors, MAX_ATTRACTORS, "attractors").to_vec();
+        self.mark_frames_stale();
+        Ok(())
+    }
+
+    /// Stores `params` for the dispatches recorded from the next
+    /// `set_frame_in_flight` on, which writes them to that frame's uniform buffer.
+    pub fn update_params(&mut self, params: &SimParams) -> Result<(), VulkanDemoError> {
+        self.params = *params;
+        self.mark_frames_stale();
+        Ok(())
+    }
+
+    fn mark_frames_stale(&mut self) {
+        for copies in &mut self.frames {
+            copies.stale = true;
+        }
+    }
+
+    pub fn set_gravity(&mut self, gravity: [f32; 2]) -> Result<(), VulkanDemoError> {
+        self.update_params(&SimParams { gravity, ..self.params })
+    }
+
+    pub fn set_drag(&mut self, drag: f32) -> Result<(), VulkanDemoError> {
+        self.update_params(&SimParams { drag, ..self.params })
+    }
+
+    pub fn set_max_speed(&mut self, max_speed: f32) -> Result<(), VulkanDemoError> {
+        self.update_params(&SimParams { max_speed, ..self.params })
+    }
+
+    /// Clamped to `1..=MAX_GRID_SIZE`. Coarser grids widen the repulsion radius.
+    pub fn set_grid_size(&mut self, grid_size: u32) -> Result<(), VulkanDemoError> {
+        self.update_params(&SimParams { grid_size: grid_size.clamp(1, MAX_GRID_SIZE), ..self.params })
+    }
+
+    pub fn set_repulsion(&mut self, repulsion_strength: f32) -> Result<(), VulkanDemoError> {
+        self.update_params(&SimParams { repulsion_strength, ..self.params })
+    }
+
+    /// Sets the boids separation, alignment and cohesion weights.
+    pub fn set_flocking_weights(&mut self, weights: [f32; 3]) -> Result<(), VulkanDemoError> {
+        let [separation_weight, alignment_weight, cohesion_weight] = weights;
+        self.update_params(&SimParams { separation_weight, alignment_weight, cohesion_weight, ..self.params })
+    }
+
+    pub fn set_boundary_mode(&mut self, mode: BoundaryMode) -> Result<(), VulkanDemoError> {
+        let restitution = match mode {
+            BoundaryMode::Bounce { restitution } => restitution,
+            _ => self.params.restitution,
+        };
+        self.update_params(&SimParams { boundary_mode: mode.id(), restitution, ..self.params })
+    }
+
+    /// Whether the particles may still be streaming into the buffers on the
+    /// transfer queue, so nothing may simulate or draw them yet. Stays true
+    /// until `poll_upload` has seen the upload finish.
+    pub fn is_loading(&self) -> bool {
+        self.upload.get().is_some()
+    }
+
+    /// Once the particles have finished loading, what the next submission using
+    /// them must wait for; `None` before then and ever after.
+    pub fn poll_upload(&self, context: &VulkanContext) -> Option<UploadDependency> {
+        let dependency = context.uploads().finished(self.upload.get()?)?;
+        self.upload.set(None);
+        Some(dependency)
+    }
+
+    /// Blocks until the particles have finished loading, for work that needs
+    /// them before the frame loop would see them done. The host having waited
+    /// orders anything submitted afterwards.
+    pub fn finish_upload(&self, context: &VulkanContext) -> Result<(), VulkanDemoError> {
+        if let Some(handle) = self.upload.take() {
+            context.uploads().wait(handle)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `particles`, exactly `count` of them, to both particle buffers,
+    /// going through the transfer queue when they are not host-visible, and
+    /// marks all of them live. Uploads bigger than `UPLOAD_BYTES_PER_POLL`
+    /// stream in over the next frames; see `is_loading`.
+    fn upload(&mut self, context: &VulkanContext, particles: &[Particle]) -> Result<(), VulkanDemoError> {
+        // Copies into the same buffers from two uploads would race.
+        self.finish_upload(context)?;
+        if let Some(cpu) = &mut self.cpu_state {
+            cpu.current = particles.to_vec();
+        }
+        let bytes = self.format.pack(particles);
+        let draw_counts = DrawCounts::new(particles.len() as u32);
+        context.one_time_submit(|cmd| unsafe {
+            context.device.cmd_update_buffer(cmd, self.draw_buffer.handle(), 0, bytemuck::bytes_of(&draw_counts));
+        })?;
+
+        if !self.device_local {
+            for buffer in &self.buffers {
+                buffer.write_slice(0, &bytes)?;
+            }
+            return Ok(());
+        }
+
+        let streamed = bytes.len() as vk::DeviceSize > UPLOAD_BYTES_PER_POLL;
+        let mut uploads = context.uploads();
+        let handle = uploads.upload_async(bytes, &[(&self.buffers[0], 0), (&self.buffers[1], 0)])?;
+        if !streamed {
+            uploads.wait(handle)?;
         }
+        self.upload.set(Some(handle));
+        Ok(())
+    }
+}
+
+/// The count ahead of the array in the obstacle and attractor buffers, padded
+/// to the arrays' 8-byte alignment.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct ArrayHeader {
+    count: u32,
+    _padding: u32,
+}
+
+/// Bytes of an `ArrayHeader` followed by up to `capacity` `T`s.
+fn array_buffer_size<T: Pod>(capacity: usize) -> vk::DeviceSize {
+    (size_of::<ArrayHeader>() + capacity * size_of::<T>()) as vk::DeviceSize
+}
+
+/// A host-visible storage buffer for an `ArrayHeader` followed by up to `capacity` `T`s.
+fn create_array_buffer<T: Pod>(context: &VulkanContext, capacity: usize) -> Result<OwnedBuffer, VulkanDemoError> {
+    create_buffer(context, array_buffer_size::<T>(capacity), vk::BufferUsageFlags::STORAGE_BUFFER, MemoryLocation::CpuToGpu)
+}
+
+/// `items` and their count as laid out in a buffer from `array_buffer_size`.
+fn array_bytes<T: Pod>(items: &[T]) -> Vec<u8> {
+    let header = ArrayHeader { count: items.len() as u32, _padding: 0 };
+    let mut bytes = bytemuck::bytes_of(&header).to_vec();
+    bytes.extend_from_slice(bytemuck::cast_slice(items));
+    bytes
+}
+
+/// Writes `items` and their count into a buffer from `create_array_buffer`.
+fn upload_array<T: Pod>(buffer: &OwnedBuffer, items: &[T]) -> Result<(), vk::Result> {
+    let header = ArrayHeader { count: items.len() as u32, _padding: 0 };
+    buffer.write_pod(0, &header)?;
+    buffer.write_slice(size_of::<ArrayHeader>() as vk::DeviceSize, items)
+}
+
+/// The first `max` of `items`, warning about any dropped.
+fn truncated<'a, T>(items: &'a [T], max: usize, what: &str) -> &'a [T] {
+    if items.len() > max {
+        log::warn!("Only {max} of {} {what} are used", items.len());
     }
+    &items[..items.len().min(max)]
 }
 
-fn find_memory_type(type_filter: u32, properties: vk::MemoryPropertyFlags, mem_props: vk::PhysicalDeviceMemoryProperties) -> Option<u32> {
-    for i in 0..mem_props.memory_type_count {
-        if (type_filter & (1 << i)) != 0 && (mem_props.memory_types[i as usize].property_flags & properties) == properties {
-            return Some(i);
+/// Specialization constants of the particle and grid compute shaders. Shaders
+/// without one of them ignore it.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct ComputeSpecialization {
+    /// `CURL_NOISE` in particle.comp.
+    pub curl_noise: vk::Bool32,
+    /// `local_size_x_id = 1`.
+    pub workgroup_size: u32,
+    /// `PARTICLE_SOA` in particle.glsl.
+    pub particle_soa: vk::Bool32,
+    /// `PARTICLE_HALF` in particle.glsl.
+    pub particle_half: vk::Bool32,
+    /// `PARTICLES_PER_INVOCATION` in particle.comp and particle_boids.comp.
+    pub particles_per_invocation: u32,
+}
+
+impl ComputeSpecialization {
+    /// For the shaders other than particle.comp.
+    pub fn new(workgroup_size: u32, format: ParticleFormat) -> Self {
+        Self {
+            curl_noise: vk::FALSE,
+            workgroup_size,
+            particle_soa: vk::Bool32::from(format.layout == ParticleLayout::Soa),
+            particle_half: vk::Bool32::from(format.precision == ParticlePrecision::F16),
+            particles_per_invocation: 1,
         }
     }
-    None
+}
+
+impl SpecializationConstants for ComputeSpecialization {
+    const ENTRIES: &'static [vk::SpecializationMapEntry] = &[
+        specialization_entry(0, offset_of!(Self, curl_noise), size_of::<vk::Bool32>()),
+        specialization_entry(1, offset_of!(Self, workgroup_size), size_of::<u32>()),
+        specialization_entry(2, offset_of!(Self, particle_soa), size_of::<vk::Bool32>()),
+        specialization_entry(3, offset_of!(Self, particle_half), size_of::<vk::Bool32>()),
+        specialization_entry(4, offset_of!(Self, particles_per_invocation), size_of::<u32>()),
+    ];
+}
+
+/// What `ParticleSystem` binds in set 0 of the shared compute layout. The
+/// layout itself comes from the shaders.
+const COMPUTE_BINDINGS: [(u32, vk::DescriptorType); 9] = [
+    (0, vk::DescriptorType::STORAGE_BUFFER),
+    (1, vk::DescriptorType::STORAGE_BUFFER),
+    (2, vk::DescriptorType::UNIFORM_BUFFER),
+    (3, vk::DescriptorType::STORAGE_BUFFER),
+    (4, vk::DescriptorType::STORAGE_BUFFER),
+    (5, vk::DescriptorType::STORAGE_BUFFER),
+    (6, vk::DescriptorType::STORAGE_BUFFER),
+    (7, vk::DescriptorType::STORAGE_BUFFER),
+    (8, vk::DescriptorType::STORAGE_BUFFER),
+];
+
+/// `options` for compiling `behavior`'s shader, with `PARTICLE_BDA` defined
+/// when `bda` is on and the shader supports it, and `PARTICLE_F16_STORAGE`
+/// otherwise with `f16_storage`: its half views alias the bindings that
+/// `PARTICLE_BDA` shaders don't read.
+fn behavior_options(
+    behavior: &dyn SimulationBehavior,
+    bda: bool,
+    f16_storage: bool,
+    options: &ShaderCompileOptions,
+) -> ShaderCompileOptions {
+    let mut options = options.clone();
+    if bda && behavior.supports_bda() {
+        options.defines.push("PARTICLE_BDA");
+    } else if f16_storage {
+        options.defines.push("PARTICLE_F16_STORAGE");
+    }
+    options
+}
+
+/// Reflects the compute shaders sharing the pipeline layout, and checks they
+/// declare what `ParticleSystem` binds and pushes.
+fn compute_shader_interface(spirv: &[&[u32]]) -> Result<ShaderInterface, VulkanDemoError> {
+    let stages: Vec<_> = spirv.iter().map(|&code| (vk::ShaderStageFlags::COMPUTE, code)).collect();
+    let interface = ShaderInterface::reflect(&stages)?;
+    for (binding, descriptor_type) in COMPUTE_BINDINGS {
+        interface.expect_binding(0, binding, descriptor_type)?;
+    }
+    interface.expect_push_constants::<SimPushConstants>()?;
+    Ok(interface)
+}
+
+// Workgroup width used unless the device limits are lower.
+const PREFERRED_WORKGROUP_SIZE: u32 = 256;
+
+/// `PREFERRED_WORKGROUP_SIZE` clamped to the device's compute limits, and
+/// rounded down to whole subgroups where the limits allow.
+pub(crate) fn default_workgroup_size(context: &VulkanContext) -> u32 {
+    let mut subgroup = vk::PhysicalDeviceSubgroupProperties::default();
+    let limits = {
+        let mut properties = vk::PhysicalDeviceProperties2::default().push_next(&mut subgroup);
+        unsafe { context.instance.get_physical_device_properties2(context.physical_device, &mut properties) };
+        properties.properties.limits
+    };
+    let size = PREFERRED_WORKGROUP_SIZE
+        .min(limits.max_compute_work_group_invocations)
+        .min(limits.max_compute_work_group_size[0]);
+    let subgroup_size = subgroup.subgroup_size.max(1);
+    if size >= subgroup_size { size / subgroup_size * subgroup_size } else { size }
+}
+
+fn pipeline_name(mode: SimulationMode) -> String {
+    format!("{mode:?} compute pipeline")
+}
+
+fn prepass_name(mode: SimulationMode, file: &str) -> String {
+    format!("{mode:?} {file} pipeline")
+}
+
+/// Builds the pipeline for `behavior` from its compiled shader, specialized
+/// with `tuning` and as it asks.
+fn create_behavior_pipeline(
+    device: &Arc<ash::Device>,
+    pipeline_cache: vk::PipelineCache,
+    pipeline_layout: vk::PipelineLayout,
+    behavior: &dyn SimulationBehavior,
+    tuning: DispatchTuning,
+    format: ParticleFormat,
+    comp_spirv: &[u32],
+) -> Result<OwnedPipeline, vk::Result> {
+    let mut constants = ComputeSpecialization::new(tuning.workgroup_size, format);
+    if behavior.batches_particles() {
+        constants.particles_per_invocation = tuning.particles_per_invocation;
+    }
+    let constants = behavior.specialize(constants);
+    create_compute_pipeline(device, pipeline_cache, pipeline_layout, comp_spirv, Some(&specialization_info(&constants)))
+}
+
+pub(crate) fn create_compute_pipeline(
+    device: &Arc<ash::Device>,
+    pipeline_cache: vk::PipelineCache,
+    pipeline_layout: vk::PipelineLayout,
+    comp_spirv: &[u32],
+    specialization: Option<&vk::SpecializationInfo>,
+) -> Result<OwnedPipeline, vk::Result> {
+    let comp_module = create_shader_module(device, comp_spirv)?;
+
+    let entry_name = c"main";
+    let mut stage_info = vk::PipelineShaderStageCreateInfo::default()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(comp_module.handle())
+        .name(entry_name);
+    if let Some(specialization) = specialization {
+        stage_info = stage_info.specialization_info(specialization);
+    }
+
+    let pipeline_info = vk::ComputePipelineCreateInfo::default()
+        .stage(stage_info)
+        .layout(pipeline_layout);
+
+    let compute_pipeline = unsafe {
+        device.create_compute_pipelines(pipeline_cache, std::slice::from_ref(&pipeline_info), None)
+            .map_err(|(_, e)| e)?[0]
+    };
+
+    Ok(OwnedPipeline::new(device, compute_pipeline))
+}
+
+// Range of per-particle point sizes in pixels.
+const MIN_PARTICLE_SIZE: f32 = 2.0;
+const MAX_PARTICLE_SIZE: f32 = 5.0;
+
+/// A seed that differs between runs, for when none is given.
+pub fn time_seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
+
+/// Generates the starting particle cloud by sampling `emitter`. Lifetimes are
+/// staggered so the first wave doesn't die and respawn all at once. The same
+/// `seed` always yields the same particles. Pure CPU work, usable without a device.
+pub fn initial_particles(count: u32, seed: u64, emitter: &EmitterConfig, three_d: bool) -> Vec<Particle> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let lifetime = SimParams::default().lifetime;
+    let mut particles = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let max_life = lifetime * (0.5 + rng.gen::<f32>());
+        let (pos, vel) = emitter.sample(&mut rng, three_d);
+        particles.push(Particle {
+            pos,
+            size: rng.gen_range(MIN_PARTICLE_SIZE..=MAX_PARTICLE_SIZE),
+            vel,
+            mass: rng.gen_range(0.5..=1.5),
+            color: [1.0, 1.0, 1.0, 1.0],
+            life: max_life * rng.gen::<f32>(),
+            max_life,
+            id: particles.len() as u32,
+            kind: emitter.sample_kind(&mut rng),
+        });
+    }
+    particles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initial_particles_are_seeded() {
+        let emitter = EmitterConfig::default();
+        let first = initial_particles(1000, 42, &emitter, true);
+        let second = initial_particles(1000, 42, &emitter, true);
+        let bytes = |particles: &[Particle]| bytemuck::cast_slice::<Particle, u8>(particles).to_vec();
+        assert_eq!(bytes(&first), bytes(&second));
+        assert_ne!(bytes(&first), bytes(&initial_particles(1000, 43, &emitter, true)));
+    }
 }
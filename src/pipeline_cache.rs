@@ -0,0 +1,70 @@
+use ash::{vk, Device};
+use std::path::{Path, PathBuf};
+
+// Size of `VkPipelineCacheHeaderVersionOne`: header size, header version,
+// vendor ID, device ID and the pipeline cache UUID.
+const HEADER_SIZE: usize = 16 + vk::UUID_SIZE;
+
+/// Where the pipeline cache is kept between runs, e.g.
+/// `~/.cache/vulkan-particle-demo/pipeline_cache.bin` on Linux.
+pub(crate) fn default_cache_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join(env!("CARGO_PKG_NAME")).join("pipeline_cache.bin"))
+}
+
+/// Creates a pipeline cache seeded from `path` when the file holds data written
+/// by the same driver for the same device. Anything else starts an empty cache.
+pub(crate) fn load_pipeline_cache(
+    device: &Device,
+    properties: &vk::PhysicalDeviceProperties,
+    path: Option<&Path>,
+) -> Result<vk::PipelineCache, vk::Result> {
+    let initial_data = match path.map(|path| (path, std::fs::read(path))) {
+        Some((path, Ok(data))) if is_compatible(&data, properties) => {
+            log::info!("Pipeline cache hit: loaded {} bytes from {}", data.len(), path.display());
+            data
+        }
+        Some((path, Ok(_))) => {
+            log::warn!("Ignoring pipeline cache {} from another driver or device", path.display());
+            Vec::new()
+        }
+        Some((path, Err(e))) => {
+            log::info!("Pipeline cache miss: could not read {}: {e}", path.display());
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+
+    let create_info = vk::PipelineCacheCreateInfo::default().initial_data(&initial_data);
+    unsafe { device.create_pipeline_cache(&create_info, None) }
+}
+
+/// Writes the contents of `cache` to `path`. Failures are logged, since losing
+/// the cache only costs startup time.
+pub(crate) fn save_pipeline_cache(device: &Device, cache: vk::PipelineCache, path: &Path) {
+    let data = match unsafe { device.get_pipeline_cache_data(cache) } {
+        Ok(data) => data,
+        Err(e) => {
+            log::warn!("Could not read back the pipeline cache: {e}");
+            return;
+        }
+    };
+    let written = path.parent().map_or(Ok(()), std::fs::create_dir_all).and_then(|()| std::fs::write(path, &data));
+    match written {
+        Ok(()) => log::info!("Wrote {} bytes of pipeline cache to {}", data.len(), path.display()),
+        Err(e) => log::warn!("Could not write pipeline cache to {}: {e}", path.display()),
+    }
+}
+
+/// Checks the cache header against the current device, so a cache from a
+/// different GPU or driver version is never handed to the driver.
+fn is_compatible(data: &[u8], properties: &vk::PhysicalDeviceProperties) -> bool {
+    if data.len() < HEADER_SIZE {
+        return false;
+    }
+    let word = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+    word(0) as usize >= HEADER_SIZE
+        && word(4) == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+        && word(8) == properties.vendor_id
+        && word(12) == properties.device_id
+        && data[16..HEADER_SIZE] == properties.pipeline_cache_uuid
+}
@@ -1,20 +1,757 @@
 use ash::vk;
+use bytemuck::Pod;
+use rspirv_reflect::{BindingCount, Reflection};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use crate::error::VulkanDemoError;
+use crate::renderer::BlendMode;
+use crate::resources::{OwnedDescriptorSetLayout, OwnedPipeline, OwnedShaderModule};
+use crate::shader_diagnostics::ShaderDiagnostic;
 
+const SPIRV_MAGIC: u32 = 0x0723_0203;
 
+static SHADER_CACHE_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Files shaders can `#include`, embedded for builds without a shader directory.
+pub const SHADER_INCLUDES: [(&str, &str); 4] = [
+    ("particle.glsl", include_str!("shaders/particle.glsl")),
+    ("obstacles.glsl", include_str!("shaders/obstacles.glsl")),
+    ("attractors.glsl", include_str!("shaders/attractors.glsl")),
+    ("sph.glsl", include_str!("shaders/sph.glsl")),
+];
+
+/// How `compile_shader` resolves includes and what code it generates.
+#[derive(Clone, Debug, Default)]
+pub struct ShaderCompileOptions {
+    /// Directory `#include`s are looked up in before the embedded copies, e.g.
+    /// the `--shader-dir` being watched.
+    pub include_dir: Option<PathBuf>,
+    /// Keep source-level debug info in the SPIR-V, for RenderDoc and the like.
+    pub debug_info: bool,
+    /// Leaves shaderc's default (no optimization) when unset.
+    pub optimization: Option<shaderc::OptimizationLevel>,
+    /// Macros defined for the source, each without a value, e.g. `PARTICLE_BDA`.
+    pub defines: Vec<&'static str>,
+}
+
+impl ShaderCompileOptions {
+    /// Includes resolved against `dir` first.
+    pub fn with_include_dir(dir: &Path) -> Self {
+        Self { include_dir: Some(dir.to_path_buf()), ..Self::default() }
+    }
+}
+
+/// Specialization constants kept in a `#[repr(C)]` struct, one field per constant.
+pub trait SpecializationConstants: Pod {
+    /// Constant ID, byte offset and size of every field.
+    const ENTRIES: &'static [vk::SpecializationMapEntry];
+}
+
+/// One of `SpecializationConstants::ENTRIES`; pair with `std::mem::offset_of!`.
+pub const fn specialization_entry(constant_id: u32, offset: usize, size: usize) -> vk::SpecializationMapEntry {
+    vk::SpecializationMapEntry { constant_id, offset: offset as u32, size }
+}
+
+/// Points a `vk::SpecializationInfo` at `constants`.
+pub fn specialization_info<T: SpecializationConstants>(constants: &T) -> vk::SpecializationInfo<'_> {
+    vk::SpecializationInfo::default()
+        .map_entries(T::ENTRIES)
+        .data(bytemuck::bytes_of(constants))
+}
+
+/// A descriptor binding as declared by the shaders of one pipeline.
+#[derive(Copy, Clone, Debug)]
+struct ReflectedBinding {
+    descriptor_type: vk::DescriptorType,
+    count: u32,
+    stages: vk::ShaderStageFlags,
+}
+
+/// The descriptor bindings and push constants a pipeline's shader stages
+/// declare, read from their SPIR-V and merged across stages, so layouts follow
+/// the shaders instead of being written out by hand.
+pub struct ShaderInterface {
+    sets: BTreeMap<u32, BTreeMap<u32, ReflectedBinding>>,
+    /// A single range from offset 0 covering every stage's block.
+    push_constants: Option<vk::PushConstantRange>,
+}
+
+impl ShaderInterface {
+    /// Reflects `stages`, each the stage flag and SPIR-V of one shader. Stages
+    /// declaring the same binding with different types are an error.
+    pub fn reflect(stages: &[(vk::ShaderStageFlags, &[u32])]) -> Result<Self, VulkanDemoError> {
+        let reflect_error = |e: rspirv_reflect::ReflectError| VulkanDemoError::ShaderInterface(format!("failed to reflect SPIR-V: {e}"));
+        let mut sets: BTreeMap<u32, BTreeMap<u32, ReflectedBinding>> = BTreeMap::new();
+        let mut push_constants: Option<vk::PushConstantRange> = None;
+
+        for &(stage, spirv) in stages {
+            let reflection = Reflection::new_from_spirv(bytemuck::cast_slice(spirv)).map_err(reflect_error)?;
+            for (set, bindings) in reflection.get_descriptor_sets().map_err(reflect_error)? {
+                for (binding, info) in bindings {
+                    let descriptor_type = vk::DescriptorType::from_raw(info.ty.0 as i32);
+                    let count = match info.binding_count {
+                        BindingCount::One => 1,
+                        BindingCount::StaticSized(count) => count as u32,
+                        BindingCount::Unbounded => {
+                            return Err(VulkanDemoError::ShaderInterface(format!(
+                                "set {set} binding {binding} ({}) is an unbounded array, which isn't supported",
+                                info.name,
+                            )));
+                        }
+                    };
+                    let entry = sets.entry(set).or_default().entry(binding).or_insert(ReflectedBinding {
+                        descriptor_type,
+                        count,
+                        stages: vk::ShaderStageFlags::empty(),
+                    });
+                    if entry.descriptor_type != descriptor_type || entry.count != count {
+                        return Err(VulkanDemoError::ShaderInterface(format!(
+                            "set {set} binding {binding} is {:?}[{}] in {:?} but {descriptor_type:?}[{count}] in {stage:?}",
+                            entry.descriptor_type, entry.count, entry.stages,
+                        )));
+                    }
+                    entry.stages |= stage;
+                }
+            }
+            if let Some(range) = reflection.get_push_constant_range().map_err(reflect_error)? {
+                let end = range.offset + range.size;
+                let merged = push_constants.get_or_insert(vk::PushConstantRange::default());
+                merged.stage_flags |= stage;
+                merged.size = merged.size.max(end);
+            }
+        }
+        Ok(Self { sets, push_constants })
+    }
+
+    /// Layout bindings of descriptor set `set`; empty if no stage uses it.
+    pub fn set_layout_bindings(&self, set: u32) -> Vec<vk::DescriptorSetLayoutBinding<'static>> {
+        self.sets.get(&set).into_iter().flatten().map(|(&binding, reflected)| {
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(binding)
+                .descriptor_type(reflected.descriptor_type)
+                .descriptor_count(reflected.count)
+                .stage_flags(reflected.stages)
+        }).collect()
+    }
+
+    pub fn create_set_layout(&self, device: &Arc<ash::Device>, set: u32) -> Result<OwnedDescriptorSetLayout, vk::Result> {
+        let bindings = self.set_layout_bindings(set);
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(&bindings);
+        let layout = unsafe { device.create_descriptor_set_layout(&layout_info, None)? };
+        Ok(OwnedDescriptorSetLayout::new(device, layout))
+    }
+
+    /// Pool sizes for allocating `copies` sets of layout `set`.
+    pub fn pool_sizes(&self, set: u32, copies: u32) -> Vec<vk::DescriptorPoolSize> {
+        let mut counts: BTreeMap<i32, u32> = BTreeMap::new();
+        for reflected in self.sets.get(&set).into_iter().flat_map(BTreeMap::values) {
+            *counts.entry(reflected.descriptor_type.as_raw()).or_default() += reflected.count * copies;
+        }
+        counts.into_iter().map(|(ty, descriptor_count)| {
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::from_raw(ty))
+                .descriptor_count(descriptor_count)
+        }).collect()
+    }
+
+    pub fn push_constant_ranges(&self) -> &[vk::PushConstantRange] {
+        self.push_constants.as_slice()
+    }
+
+    /// Checks that the shaders declare `binding` of `set` as `descriptor_type`,
+    /// the kind of resource the Rust side binds there.
+    pub fn expect_binding(&self, set: u32, binding: u32, descriptor_type: vk::DescriptorType) -> Result<(), VulkanDemoError> {
+        match self.sets.get(&set).and_then(|bindings| bindings.get(&binding)) {
+            Some(reflected) if reflected.descriptor_type == descriptor_type => Ok(()),
+            Some(reflected) => Err(VulkanDemoError::ShaderInterface(format!(
+                "set {set} binding {binding} is bound as {descriptor_type:?} but the shaders declare {:?}",
+                reflected.descriptor_type,
+            ))),
+            None => Err(VulkanDemoError::ShaderInterface(format!(
+                "set {set} binding {binding} is bound as {descriptor_type:?} but no shader declares it",
+            ))),
+        }
+    }
+
+    /// Checks that the push constant block spans exactly the `T` pushed from Rust.
+    pub fn expect_push_constants<T>(&self) -> Result<(), VulkanDemoError> {
+        let expected = std::mem::size_of::<T>() as u32;
+        let declared = self.push_constants.map_or(0, |range| range.size);
+        if declared == expected {
+            return Ok(());
+        }
+        Err(VulkanDemoError::ShaderInterface(format!(
+            "{} is {expected} bytes but the shaders' push constants span {declared}",
+            std::any::type_name::<T>(),
+        )))
+    }
+}
+
+/// Wraps SPIR-V words in a `vk::ShaderModule`.
 pub fn create_shader_module(
-    device: &ash::Device,
+    device: &Arc<ash::Device>,
     code: &[u32],
-) -> Result<vk::ShaderModule, vk::Result> {
+) -> Result<OwnedShaderModule, vk::Result> {
     let create_info = vk::ShaderModuleCreateInfo::default().code(code);
-    unsafe { device.create_shader_module(&create_info, None) }
+    let module = unsafe { device.create_shader_module(&create_info, None)? };
+    Ok(OwnedShaderModule::new(device, module))
 }
 
+/// What a graphics pipeline draws into: a subpass of a render pass object, or
+/// with dynamic rendering, attachments of the given formats.
+#[derive(Copy, Clone, Debug)]
+pub enum RenderTarget {
+    Subpass { render_pass: vk::RenderPass, subpass: u32 },
+    Dynamic { color_format: vk::Format, depth_format: Option<vk::Format> },
+}
+
+impl RenderTarget {
+    /// Creates the pipeline described by `info`, pointed at this target.
+    pub fn create_pipeline(
+        self,
+        device: &Arc<ash::Device>,
+        pipeline_cache: vk::PipelineCache,
+        info: vk::GraphicsPipelineCreateInfo,
+    ) -> Result<OwnedPipeline, vk::Result> {
+        let color_formats = match self {
+            Self::Subpass { .. } => [vk::Format::UNDEFINED],
+            Self::Dynamic { color_format, .. } => [color_format],
+        };
+        let mut rendering_info = vk::PipelineRenderingCreateInfo::default().color_attachment_formats(&color_formats);
+        let info = match self {
+            Self::Subpass { render_pass, subpass } => info.render_pass(render_pass).subpass(subpass),
+            Self::Dynamic { depth_format, .. } => {
+                rendering_info = rendering_info.depth_attachment_format(depth_format.unwrap_or(vk::Format::UNDEFINED));
+                info.push_next(&mut rendering_info)
+            }
+        };
+        let pipelines = unsafe {
+            device.create_graphics_pipelines(pipeline_cache, std::slice::from_ref(&info), None).map_err(|(_, e)| e)?
+        };
+        Ok(OwnedPipeline::new(device, pipelines[0]))
+    }
+}
+
+/// The vertex buffers a graphics pipeline reads and the attributes in them.
+/// Bindings are numbered in the order they are added, from 0.
+#[derive(Clone, Debug, Default)]
+pub struct VertexLayout {
+    bindings: Vec<vk::VertexInputBindingDescription>,
+    attributes: Vec<vk::VertexInputAttributeDescription>,
+}
+
+impl VertexLayout {
+    /// No vertex input, for shaders that make up their vertices.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a binding of per-vertex data, `stride` bytes apart.
+    pub fn binding(mut self, stride: u32) -> Self {
+        self.bindings.push(
+            vk::VertexInputBindingDescription::default()
+                .binding(self.bindings.len() as u32)
+                .stride(stride)
+                .input_rate(vk::VertexInputRate::VERTEX),
+        );
+        self
+    }
+
+    /// Adds the attribute at `location`, read as `format` at `offset` into
+    /// each vertex of the last binding added.
+    pub fn attribute(mut self, location: u32, format: vk::Format, offset: u32) -> Self {
+        let binding = self.bindings.len().checked_sub(1).expect("vertex attribute added before any binding");
+        self.attributes.push(
+            vk::VertexInputAttributeDescription::default()
+                .binding(binding as u32)
+                .location(location)
+                .format(format)
+                .offset(offset),
+        );
+        self
+    }
+}
+
+/// A shader of a `GraphicsPipelineBuilder`: SPIR-V, or GLSL it compiles.
+enum ShaderCode<'a> {
+    Spirv(&'a [u32]),
+    Glsl { source: &'a str, filename: &'a str, options: &'a ShaderCompileOptions },
+}
+
+struct ShaderStage<'a> {
+    stage: vk::ShaderStageFlags,
+    code: ShaderCode<'a>,
+    specialization: Option<vk::SpecializationInfo<'a>>,
+}
+
+/// Builds a graphics pipeline from the state that differs from the common
+/// setup: triangle lists without vertex input or culling, no depth test,
+/// opaque blending into one color attachment, and a viewport and scissor set
+/// when drawing. The shader modules only live through `build`.
+pub struct GraphicsPipelineBuilder<'a> {
+    target: RenderTarget,
+    layout: vk::PipelineLayout,
+    stages: Vec<ShaderStage<'a>>,
+    vertex_layout: VertexLayout,
+    topology: vk::PrimitiveTopology,
+    cull_mode: vk::CullModeFlags,
+    front_face: vk::FrontFace,
+    /// Whether depth is written, and how it is tested; `None` leaves depth alone.
+    depth: Option<(bool, vk::CompareOp)>,
+    color_blend: vk::PipelineColorBlendAttachmentState,
+    dynamic_states: Vec<vk::DynamicState>,
+}
+
+impl<'a> GraphicsPipelineBuilder<'a> {
+    /// A pipeline with `layout` drawing into `target`, with the common setup.
+    pub fn new(target: RenderTarget, layout: vk::PipelineLayout) -> Self {
+        Self {
+            target,
+            layout,
+            stages: Vec::new(),
+            vertex_layout: VertexLayout::new(),
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            cull_mode: vk::CullModeFlags::NONE,
+            front_face: vk::FrontFace::CLOCKWISE,
+            depth: None,
+            color_blend: BlendMode::Opaque.attachment_state(),
+            dynamic_states: vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR],
+        }
+    }
+
+    /// Adds the `stage` shader, already compiled to SPIR-V.
+    pub fn shader(mut self, stage: vk::ShaderStageFlags, spirv: &'a [u32]) -> Self {
+        self.stages.push(ShaderStage { stage, code: ShaderCode::Spirv(spirv), specialization: None });
+        self
+    }
+
+    /// Adds the `stage` shader as GLSL, compiled by `build` with `compile_shader`.
+    pub fn glsl(mut self, stage: vk::ShaderStageFlags, source: &'a str, filename: &'a str, options: &'a ShaderCompileOptions) -> Self {
+        self.stages.push(ShaderStage { stage, code: ShaderCode::Glsl { source, filename, options }, specialization: None });
+        self
+    }
+
+    /// Specializes the shader added last with `constants`.
+    pub fn specialization<T: SpecializationConstants>(mut self, constants: &'a T) -> Self {
+        let stage = self.stages.last_mut().expect("specialization set before any shader");
+        stage.specialization = Some(specialization_info(constants));
+        self
+    }
+
+    pub fn vertex_layout(mut self, vertex_layout: VertexLayout) -> Self {
+        self.vertex_layout = vertex_layout;
+        self
+    }
+
+    pub fn topology(mut self, topology: vk::PrimitiveTopology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    pub fn cull_mode(mut self, cull_mode: vk::CullModeFlags, front_face: vk::FrontFace) -> Self {
+        self.cull_mode = cull_mode;
+        self.front_face = front_face;
+        self
+    }
+
+    /// Tests depth with `compare_op`, and writes it too if `write`.
+    pub fn depth_test(mut self, write: bool, compare_op: vk::CompareOp) -> Self {
+        self.depth = Some((write, compare_op));
+        self
+    }
+
+    pub fn blend_mode(self, blend_mode: BlendMode) -> Self {
+        self.color_blend(blend_mode.attachment_state())
+    }
+
+    /// Blends as `attachment` describes, for blending no `BlendMode` covers.
+    pub fn color_blend(mut self, attachment: vk::PipelineColorBlendAttachmentState) -> Self {
+        self.color_blend = attachment;
+        self
+    }
+
+    /// Replaces the viewport and scissor as the state set when drawing.
+    pub fn dynamic_states(mut self, dynamic_states: &[vk::DynamicState]) -> Self {
+        self.dynamic_states = dynamic_states.to_vec();
+        self
+    }
+
+    /// Compiles any GLSL stages and creates the pipeline, destroying the
+    /// shader modules again once it exists.
+    pub fn build(self, device: &Arc<ash::Device>, pipeline_cache: vk::PipelineCache) -> Result<OwnedPipeline, VulkanDemoError> {
+        let mut modules = Vec::with_capacity(self.stages.len());
+        for stage in &self.stages {
+            let module = match stage.code {
+                ShaderCode::Spirv(spirv) => create_shader_module(device, spirv)?,
+                ShaderCode::Glsl { source, filename, options } => {
+                    create_shader_module(device, &compile_shader(source, filename, shader_kind(stage.stage), options)?)?
+                }
+            };
+            modules.push(module);
+        }
+        let shader_stages: Vec<_> = self
+            .stages
+            .iter()
+            .zip(&modules)
+            .map(|(stage, module)| {
+                let info = vk::PipelineShaderStageCreateInfo::default().stage(stage.stage).module(module.handle()).name(c"main");
+                match &stage.specialization {
+                    Some(specialization) => info.specialization_info(specialization),
+                    None => info,
+                }
+            })
+            .collect();
+
+        let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::default()
+            .vertex_binding_descriptions(&self.vertex_layout.bindings)
+            .vertex_attribute_descriptions(&self.vertex_layout.attributes);
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::default().topology(self.topology);
+        let viewport_state = vk::PipelineViewportStateCreateInfo::default().viewport_count(1).scissor_count(1);
+        let dynamic_state = vk::PipelineDynamicStateCreateInfo::default().dynamic_states(&self.dynamic_states);
+        let rasterizer = vk::PipelineRasterizationStateCreateInfo::default()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0)
+            .cull_mode(self.cull_mode)
+            .front_face(self.front_face);
+        let multisampling = vk::PipelineMultisampleStateCreateInfo::default().rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil = match self.depth {
+            Some((write, compare_op)) => vk::PipelineDepthStencilStateCreateInfo::default()
+                .depth_test_enable(true)
+                .depth_write_enable(write)
+                .depth_compare_op(compare_op),
+            None => vk::PipelineDepthStencilStateCreateInfo::default(),
+        };
+        let color_blending =
+            vk::PipelineColorBlendStateCreateInfo::default().attachments(std::slice::from_ref(&self.color_blend));
+
+        let pipeline_info = vk::GraphicsPipelineCreateInfo::default()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_info)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterizer)
+            .multisample_state(&multisampling)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&color_blending)
+            .dynamic_state(&dynamic_state)
+            .layout(self.layout);
+        Ok(self.target.create_pipeline(device, pipeline_cache, pipeline_info)?)
+    }
+}
+
+/// What shaderc compiles the GLSL of a graphics `stage` as.
+fn shader_kind(stage: vk::ShaderStageFlags) -> shaderc::ShaderKind {
+    match stage {
+        vk::ShaderStageFlags::VERTEX => shaderc::ShaderKind::Vertex,
+        vk::ShaderStageFlags::FRAGMENT => shaderc::ShaderKind::Fragment,
+        vk::ShaderStageFlags::GEOMETRY => shaderc::ShaderKind::Geometry,
+        vk::ShaderStageFlags::TESSELLATION_CONTROL => shaderc::ShaderKind::TessControl,
+        vk::ShaderStageFlags::TESSELLATION_EVALUATION => shaderc::ShaderKind::TessEvaluation,
+        _ => panic!("{stage:?} is not a single graphics shader stage"),
+    }
+}
+
+/// Turns the on-disk SPIR-V cache used by `compile_shader` on or off for the
+/// whole process; `--no-shader-cache` turns it off.
+pub fn set_shader_cache_enabled(enabled: bool) {
+    SHADER_CACHE_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// SPIR-V compiled earlier, stored as `<key>.spv` files in one directory.
+///
+/// Entries are keyed by a hash of everything that affects the output, so
+/// stale entries are never read, only left behind.
+pub struct ShaderCache {
+    dir: PathBuf,
+}
+
+impl ShaderCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// `$XDG_CACHE_HOME/vulkan-particle-demo`, falling back to `~/.cache` and
+    /// then `%LOCALAPPDATA%`. `None` if the cache is disabled or no location is known.
+    pub fn user() -> Option<Self> {
+        if !SHADER_CACHE_ENABLED.load(Ordering::Relaxed) {
+            return None;
+        }
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))?;
+        Some(Self::new(base.join("vulkan-particle-demo")))
+    }
+
+    /// The cache key for compiling `source` with these settings. Includes are
+    /// hashed as well, as found in `options.include_dir` or embedded.
+    pub fn key(source: &str, filename: &str, shader_kind: shaderc::ShaderKind, options: &ShaderCompileOptions) -> u64 {
+        let mut hash = Fnv1a::default();
+        hash.write(source.as_bytes());
+        hash.write(filename.as_bytes());
+        hash.write(format!("{shader_kind:?} {:?} {} {:?}", options.optimization, options.debug_info, options.defines).as_bytes());
+        for (file, embedded) in SHADER_INCLUDES {
+            let on_disk = options.include_dir.as_ref().and_then(|dir| std::fs::read_to_string(dir.join(file)).ok());
+            hash.write(on_disk.as_deref().unwrap_or(embedded).as_bytes());
+        }
+        hash.0
+    }
+
+    /// The SPIR-V stored under `key`, or `None` if there is none or it doesn't
+    /// look like SPIR-V (a truncated or otherwise corrupt file).
+    pub fn load(&self, key: u64) -> Option<Vec<u32>> {
+        let bytes = std::fs::read(self.path(key)).ok()?;
+        if bytes.is_empty() || bytes.len() % 4 != 0 {
+            return None;
+        }
+        let words: Vec<u32> = bytes.chunks_exact(4).map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]])).collect();
+        (words[0] == SPIRV_MAGIC).then_some(words)
+    }
+
+    /// Stores `spirv` under `key`. The file is written under a temporary name
+    /// and renamed, so a concurrent `load` never sees half of it.
+    pub fn store(&self, key: u64, spirv: &[u32]) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.path(key);
+        let temp_path = path.with_extension(format!("spv.{}.tmp", std::process::id()));
+        let bytes: Vec<u8> = spirv.iter().flat_map(|word| word.to_le_bytes()).collect();
+        std::fs::write(&temp_path, bytes)?;
+        std::fs::rename(&temp_path, &path)
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{key:016x}.spv"))
+    }
+}
+
+/// 64-bit FNV-1a, stable across runs and Rust versions unlike `DefaultHasher`.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        // Separates consecutive fields, so ("ab", "c") and ("a", "bc") differ.
+        self.0 = (self.0 ^ 0xff).wrapping_mul(0x0000_0100_0000_01b3);
+    }
+}
+
+/// Compiles GLSL `source` to SPIR-V; `filename` names it in diagnostics and is
+/// what relative `#include`s are resolved from.
+///
+/// Results are cached in `ShaderCache::user()`, so shaderc only runs for
+/// sources it hasn't seen with the same options before.
 pub fn compile_shader(
     source: &str,
     filename: &str,
     shader_kind: shaderc::ShaderKind,
-) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
-    let compiler = shaderc::Compiler::new().map_err(|e| Box::new(e))?;
-    let artifact = compiler.compile_into_spirv(source, shader_kind, filename, "main", None)?;
+    options: &ShaderCompileOptions,
+) -> Result<Vec<u32>, VulkanDemoError> {
+    let Some(cache) = ShaderCache::user() else {
+        return compile_glsl(source, filename, shader_kind, options);
+    };
+    let key = ShaderCache::key(source, filename, shader_kind, options);
+    if let Some(spirv) = cache.load(key) {
+        log::info!("Loaded {filename} from the shader cache");
+        return Ok(spirv);
+    }
+    let spirv = compile_glsl(source, filename, shader_kind, options)?;
+    if let Err(e) = cache.store(key, &spirv) {
+        log::warn!("Could not write {filename} to the shader cache in {}: {e}", cache.dir.display());
+    }
+    Ok(spirv)
+}
+
+fn compile_glsl(
+    source: &str,
+    filename: &str,
+    shader_kind: shaderc::ShaderKind,
+    options: &ShaderCompileOptions,
+) -> Result<Vec<u32>, VulkanDemoError> {
+    let source_of = |file: &str| match file == filename {
+        true => Some(source.to_string()),
+        false => include_source(file, options.include_dir.as_deref()),
+    };
+    let compilation_error = |e: shaderc::Error| {
+        let diagnostics = match &e {
+            shaderc::Error::CompilationError(_, log) => ShaderDiagnostic::parse_log(log, filename, source_of),
+            _ => Vec::new(),
+        };
+        VulkanDemoError::ShaderCompilation { file: filename.to_string(), diagnostics, log: e.to_string() }
+    };
+    let compiler = shaderc::Compiler::new().map_err(compilation_error)?;
+    // Warnings are reported unless suppressed, and logged below.
+    let mut compile_options = shaderc::CompileOptions::new().map_err(compilation_error)?;
+    compile_options.set_include_callback(|requested, include_type, requester, _depth| {
+        resolve_include(requested, include_type, requester, options.include_dir.as_deref())
+    });
+    if options.debug_info {
+        compile_options.set_generate_debug_info();
+    }
+    if let Some(level) = options.optimization {
+        compile_options.set_optimization_level(level);
+    }
+    for name in &options.defines {
+        compile_options.add_macro_definition(name, None);
+    }
+    let artifact = compiler
+        .compile_into_spirv(source, shader_kind, filename, "main", Some(&compile_options))
+        .map_err(compilation_error)?;
+    if artifact.get_num_warnings() > 0 {
+        for warning in ShaderDiagnostic::parse_log(&artifact.get_warning_messages(), filename, source_of) {
+            log::warn!("{warning}");
+        }
+    }
     Ok(artifact.as_binary().to_vec())
 }
+
+/// Finds an `#include`d file: `"..."` includes relative to the including file,
+/// `<...>` ones from the top. Files in `include_dir` take precedence over the
+/// embedded `SHADER_INCLUDES`.
+fn resolve_include(
+    requested: &str,
+    include_type: shaderc::IncludeType,
+    requester: &str,
+    include_dir: Option<&Path>,
+) -> shaderc::IncludeCallbackResult {
+    let name = match include_type {
+        shaderc::IncludeType::Relative => Path::new(requester).parent().unwrap_or(Path::new("")).join(requested),
+        shaderc::IncludeType::Standard => PathBuf::from(requested),
+    };
+    let resolved_name = name.to_string_lossy().replace('\\', "/");
+    include_source(&resolved_name, include_dir)
+        .map(|content| shaderc::ResolvedInclude { resolved_name: resolved_name.clone(), content })
+        .ok_or_else(|| format!("{requester} includes \"{requested}\", which was not found"))
+}
+
+/// The source of include `resolved_name`, from `include_dir` if it's there,
+/// otherwise embedded.
+fn include_source(resolved_name: &str, include_dir: Option<&Path>) -> Option<String> {
+    include_dir
+        .and_then(|dir| std::fs::read_to_string(dir.join(resolved_name)).ok())
+        .or_else(|| SHADER_INCLUDES.iter().find(|(file, _)| *file == resolved_name).map(|(_, content)| content.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for the test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vulkan-particle-demo-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Uses `Particle`, from particle.glsl.
+    const USES_PARTICLE_GLSL: &str = "#version 450\n#include \"particle.glsl\"\nlayout(local_size_x = 1) in;\n\
+        layout(std430, binding = 0) buffer Out { Particle particles[]; };\nvoid main() { particles[0].life = 1.0; }\n";
+
+    #[test]
+    fn embedded_includes_resolve() {
+        let resolved = resolve_include("particle.glsl", shaderc::IncludeType::Relative, "particle.comp", None).unwrap();
+        assert_eq!(resolved.resolved_name, "particle.glsl");
+        assert_eq!(resolved.content, include_str!("shaders/particle.glsl"));
+
+        let options = ShaderCompileOptions::default();
+        let spirv = compile_glsl(USES_PARTICLE_GLSL, "test.comp", shaderc::ShaderKind::Compute, &options).unwrap();
+        assert_eq!(spirv[0], SPIRV_MAGIC);
+    }
+
+    #[test]
+    fn include_dir_takes_precedence() {
+        let dir = temp_dir("include-dir");
+        std::fs::write(dir.join("particle.glsl"), "const uint FROM_DIR = 1u;\n").unwrap();
+        std::fs::write(dir.join("extra.glsl"), "const uint EXTRA = 2u;\n").unwrap();
+
+        let resolved = resolve_include("particle.glsl", shaderc::IncludeType::Standard, "test.comp", Some(&dir)).unwrap();
+        assert_eq!(resolved.content, "const uint FROM_DIR = 1u;\n");
+        // Only in the directory.
+        let resolved = resolve_include("extra.glsl", shaderc::IncludeType::Relative, "test.comp", Some(&dir)).unwrap();
+        assert_eq!(resolved.content, "const uint EXTRA = 2u;\n");
+        // Not in the directory, so embedded.
+        let resolved = resolve_include("sph.glsl", shaderc::IncludeType::Relative, "test.comp", Some(&dir)).unwrap();
+        assert_eq!(resolved.content, include_str!("shaders/sph.glsl"));
+
+        let source = "#version 450\n#include \"extra.glsl\"\nlayout(local_size_x = 1) in;\n\
+            layout(std430, binding = 0) buffer Out { uint value; };\nvoid main() { value = EXTRA; }\n";
+        let options = ShaderCompileOptions::with_include_dir(&dir);
+        compile_glsl(source, "test.comp", shaderc::ShaderKind::Compute, &options).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_include_names_the_file() {
+        let error = resolve_include("missing.glsl", shaderc::IncludeType::Relative, "test.comp", None).unwrap_err();
+        assert!(error.contains("missing.glsl") && error.contains("test.comp"), "{error}");
+
+        let source = "#version 450\n#include \"missing.glsl\"\nlayout(local_size_x = 1) in;\nvoid main() {}\n";
+        let result = compile_glsl(source, "test.comp", shaderc::ShaderKind::Compute, &ShaderCompileOptions::default());
+        match result {
+            Err(VulkanDemoError::ShaderCompilation { file, log, .. }) => {
+                assert_eq!(file, "test.comp");
+                assert!(log.contains("missing.glsl"), "{log}");
+            }
+            other => panic!("expected a compilation error, got {other:?}"),
+        }
+    }
+
+    // Three words: the magic number, then anything.
+    const SPIRV: [u32; 3] = [SPIRV_MAGIC, 0x0001_0000, 7];
+
+    #[test]
+    fn cache_hits_what_was_stored() {
+        let cache = ShaderCache::new(temp_dir("cache-hit"));
+        let options = ShaderCompileOptions::default();
+        let key = ShaderCache::key("void main() {}", "test.comp", shaderc::ShaderKind::Compute, &options);
+        assert_eq!(cache.load(key), None);
+        cache.store(key, &SPIRV).unwrap();
+        assert_eq!(cache.load(key), Some(SPIRV.to_vec()));
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn cache_misses_after_changes() {
+        let cache = ShaderCache::new(temp_dir("cache-miss"));
+        let options = ShaderCompileOptions::default();
+        let key = ShaderCache::key("void main() {}", "test.comp", shaderc::ShaderKind::Compute, &options);
+        cache.store(key, &SPIRV).unwrap();
+
+        let edited = ShaderCache::key("void main() { }", "test.comp", shaderc::ShaderKind::Compute, &options);
+        let debug = ShaderCompileOptions { debug_info: true, ..ShaderCompileOptions::default() };
+        let with_debug_info = ShaderCache::key("void main() {}", "test.comp", shaderc::ShaderKind::Compute, &debug);
+        let include_dir = temp_dir("cache-miss-includes");
+        std::fs::write(include_dir.join("particle.glsl"), "// edited\n").unwrap();
+        let with_include = ShaderCompileOptions::with_include_dir(&include_dir);
+        let included = ShaderCache::key("void main() {}", "test.comp", shaderc::ShaderKind::Compute, &with_include);
+        for other in [edited, with_debug_info, included] {
+            assert_ne!(other, key);
+            assert_eq!(cache.load(other), None);
+        }
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+        std::fs::remove_dir_all(&include_dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_cache_entries_are_ignored_and_replaced() {
+        let cache = ShaderCache::new(temp_dir("cache-corrupt"));
+        let key = 42;
+        for corrupt in [&b""[..], &b"abc"[..], &b"not spirv"[..], &[0u8; 12][..]] {
+            std::fs::write(cache.path(key), corrupt).unwrap();
+            assert_eq!(cache.load(key), None, "{corrupt:?} loaded");
+        }
+        cache.store(key, &SPIRV).unwrap();
+        assert_eq!(cache.load(key), Some(SPIRV.to_vec()));
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+use ash::khr::{buffer_device_address, dynamic_rendering, synchronization2, timeline_semaphore};
+use ash::prelude::VkResult;
+use ash::{vk, Device, Instance};
+use std::sync::Arc;
+
+/// Entry points of an extension that a later Vulkan version promoted to core:
+/// called on the device itself when its API version has them, and through
+/// the extension's loader before that.
+#[derive(Clone)]
+pub enum Promoted<E> {
+    Core(Arc<Device>),
+    Extension(E),
+}
+
+/// `VK_KHR_timeline_semaphore`, core in Vulkan 1.2.
+pub type TimelineSemaphore = Promoted<timeline_semaphore::Device>;
+/// `VK_KHR_buffer_device_address`, core in Vulkan 1.2.
+pub type BufferDeviceAddress = Promoted<buffer_device_address::Device>;
+/// `VK_KHR_synchronization2`, core in Vulkan 1.3.
+pub type Synchronization2 = Promoted<synchronization2::Device>;
+/// `VK_KHR_dynamic_rendering`, core in Vulkan 1.3.
+pub type DynamicRendering = Promoted<dynamic_rendering::Device>;
+
+impl<E> Promoted<E> {
+    /// The core entry points when `api_version` is at least `core_version`,
+    /// otherwise the extension's, loaded by `load_extension`.
+    pub fn load(
+        instance: &Instance,
+        device: &Arc<Device>,
+        api_version: u32,
+        core_version: u32,
+        load_extension: impl FnOnce(&Instance, &Device) -> E,
+    ) -> Self {
+        if api_version >= core_version {
+            Self::Core(device.clone())
+        } else {
+            Self::Extension(load_extension(instance, device))
+        }
+    }
+
+    pub fn is_core(&self) -> bool {
+        matches!(self, Self::Core(_))
+    }
+}
+
+impl TimelineSemaphore {
+    pub(crate) unsafe fn wait_semaphores(&self, wait_info: &vk::SemaphoreWaitInfo, timeout: u64) -> VkResult<()> {
+        match self {
+            Self::Core(device) => device.wait_semaphores(wait_info, timeout),
+            Self::Extension(loader) => loader.wait_semaphores(wait_info, timeout),
+        }
+    }
+}
+
+impl BufferDeviceAddress {
+    pub(crate) unsafe fn get_buffer_device_address(&self, info: &vk::BufferDeviceAddressInfo) -> vk::DeviceAddress {
+        match self {
+            Self::Core(device) => device.get_buffer_device_address(info),
+            Self::Extension(loader) => loader.get_buffer_device_address(info),
+        }
+    }
+}
+
+impl Synchronization2 {
+    pub(crate) unsafe fn cmd_pipeline_barrier2(&self, cmd: vk::CommandBuffer, dependency_info: &vk::DependencyInfo) {
+        match self {
+            Self::Core(device) => device.cmd_pipeline_barrier2(cmd, dependency_info),
+            Self::Extension(loader) => loader.cmd_pipeline_barrier2(cmd, dependency_info),
+        }
+    }
+
+    pub(crate) unsafe fn queue_submit2(&self, queue: vk::Queue, submits: &[vk::SubmitInfo2], fence: vk::Fence) -> VkResult<()> {
+        match self {
+            Self::Core(device) => device.queue_submit2(queue, submits, fence),
+            Self::Extension(loader) => loader.queue_submit2(queue, submits, fence),
+        }
+    }
+}
+
+impl DynamicRendering {
+    pub(crate) unsafe fn cmd_begin_rendering(&self, cmd: vk::CommandBuffer, rendering_info: &vk::RenderingInfo) {
+        match self {
+            Self::Core(device) => device.cmd_begin_rendering(cmd, rendering_info),
+            Self::Extension(loader) => loader.cmd_begin_rendering(cmd, rendering_info),
+        }
+    }
+
+    pub(crate) unsafe fn cmd_end_rendering(&self, cmd: vk::CommandBuffer) {
+        match self {
+            Self::Core(device) => device.cmd_end_rendering(cmd),
+            Self::Extension(loader) => loader.cmd_end_rendering(cmd),
+        }
+    }
+}
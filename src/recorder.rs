@@ -0,0 +1,179 @@
+use ash::vk;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::JoinHandle;
+use crate::allocator::MemoryLocation;
+use crate::error::VulkanDemoError;
+use crate::frame_graph::{RecordPass, RenderFrame};
+use crate::memory::{create_buffer, read_from_buffer};
+use crate::renderer::{bgra_to_rgba, Renderer};
+use crate::resources::OwnedBuffer;
+use crate::screenshot::write_png;
+use crate::sync::FRAMES_IN_FLIGHT;
+use crate::vulkan_context::VulkanContext;
+
+/// Simulated time per recorded frame, so the video plays back at a constant
+/// 60 fps however long each frame took to capture.
+pub const RECORD_FRAME_DT: f32 = 1.0 / 60.0;
+
+// Staging buffers the GPU copies frames into. A frame is read back once the
+// frames in flight after it are submitted, so the copy overlaps with rendering them.
+const STAGING_RING_SIZE: usize = FRAMES_IN_FLIGHT + 1;
+// Frames queued for the writer thread before rendering blocks on it.
+const WRITE_QUEUE_DEPTH: usize = 4;
+const PROGRESS_INTERVAL: u32 = 60;
+
+struct RecordedFrame {
+    index: u32,
+    pixels: Vec<u8>,
+}
+
+/// Dumps every rendered frame into a directory as `frame_00000.png`,
+/// `frame_00001.png`, ... until `frame_count` frames are written.
+///
+/// Frames are copied on the GPU into a ring of host-visible buffers as part of
+/// each frame's command buffer, and encoded on a separate writer thread.
+pub struct FrameRecorder {
+    staging: Vec<OwnedBuffer>,
+    /// Ring slots holding submitted copies not yet read back, oldest first,
+    /// with the index of the frame in each.
+    pending: VecDeque<(usize, u32)>,
+    next_slot: usize,
+    /// Frames copied so far, and so the index of the next one.
+    captured: u32,
+    frame_count: u32,
+    extent: vk::Extent2D,
+    dir: PathBuf,
+    sender: Option<SyncSender<RecordedFrame>>,
+    writer: Option<JoinHandle<Result<(), VulkanDemoError>>>,
+}
+
+impl FrameRecorder {
+    /// Creates `dir` if needed and starts the writer thread. Frames are recorded
+    /// at the renderer's current extent.
+    pub fn new(context: &VulkanContext, renderer: &Renderer, dir: &Path, frame_count: u32) -> Result<Self, VulkanDemoError> {
+        let swizzle = renderer.readback_swizzle(context)?;
+        std::fs::create_dir_all(dir).map_err(|e| VulkanDemoError::ImageWrite { path: dir.to_path_buf(), error: e.into() })?;
+
+        let staging = (0..STAGING_RING_SIZE)
+            .map(|_| {
+                create_buffer(
+                    context,
+                    renderer.readback_size(),
+                    vk::BufferUsageFlags::TRANSFER_DST,
+                    MemoryLocation::GpuToCpu,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (sender, receiver) = mpsc::sync_channel::<RecordedFrame>(WRITE_QUEUE_DEPTH);
+        let extent = renderer.extent;
+        let writer_dir = dir.to_path_buf();
+        let writer = std::thread::spawn(move || {
+            for mut frame in receiver {
+                if swizzle {
+                    bgra_to_rgba(&mut frame.pixels);
+                }
+                write_png(&writer_dir.join(format!("frame_{:05}.png", frame.index)), extent, &frame.pixels)?;
+            }
+            Ok(())
+        });
+
+        Ok(Self {
+            staging,
+            pending: VecDeque::with_capacity(STAGING_RING_SIZE),
+            next_slot: 0,
+            captured: 0,
+            frame_count,
+            extent,
+            dir: dir.to_path_buf(),
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    /// Whether every requested frame has been captured.
+    pub fn is_done(&self) -> bool {
+        self.captured >= self.frame_count
+    }
+
+    /// Records copying swapchain image `image_index` into the next staging
+    /// buffer, after the render pass that drew it. Frames rendered at a
+    /// different size than the recording started with are skipped.
+    pub fn record_copy(&mut self, device: &ash::Device, cmd: vk::CommandBuffer, renderer: &Renderer, image_index: u32) {
+        if self.is_done() {
+            return;
+        }
+        if renderer.extent != self.extent {
+            log::warn!(
+                "Skipping a {}x{} frame while recording at {}x{}",
+                renderer.extent.width,
+                renderer.extent.height,
+                self.extent.width,
+                self.extent.height,
+            );
+            return;
+        }
+        // Every slot is read back `FRAMES_IN_FLIGHT` frames after its copy, so the ring never overruns.
+        debug_assert!(self.pending.len() < STAGING_RING_SIZE);
+        let slot = self.next_slot;
+        renderer.record_readback(device, cmd, image_index, self.staging[slot].handle());
+        self.pending.push_back((slot, self.captured));
+        self.next_slot = (slot + 1) % STAGING_RING_SIZE;
+        self.captured += 1;
+        if self.captured.is_multiple_of(PROGRESS_INTERVAL) || self.is_done() {
+            println!("Recording: {}/{} frames", self.captured, self.frame_count);
+        }
+    }
+
+    /// Hands every pending frame except the newest `keep` to the writer thread.
+    /// The caller must have waited for the submissions holding those copies.
+    pub fn collect(&mut self, keep: usize) -> Result<(), VulkanDemoError> {
+        let size = self.extent.width as usize * self.extent.height as usize * 4;
+        while self.pending.len() > keep {
+            let Some((slot, index)) = self.pending.pop_front() else {
+                break;
+            };
+            let pixels = read_from_buffer(&self.staging[slot], size)?;
+            let Some(sender) = &self.sender else {
+                break;
+            };
+            if sender.send(RecordedFrame { index, pixels }).is_err() {
+                // The writer only hangs up after failing to write a frame.
+                self.sender = None;
+                self.pending.clear();
+                return self.join_writer();
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands over the remaining frames and waits for the writer thread to write
+    /// them. The GPU must be done with every recorded copy.
+    pub fn finish(&mut self) -> Result<(), VulkanDemoError> {
+        let collected = self.collect(0);
+        self.sender = None;
+        let written = self.join_writer();
+        collected.and(written)?;
+        if self.is_done() {
+            println!("Recorded {} frames to {}", self.captured, self.dir.display());
+        }
+        Ok(())
+    }
+
+    fn join_writer(&mut self) -> Result<(), VulkanDemoError> {
+        match self.writer.take() {
+            Some(writer) => writer.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Copies out the swapchain image once everything before has drawn it.
+impl RecordPass for FrameRecorder {
+    fn record(&mut self, frame: &mut RenderFrame) -> Result<(), vk::Result> {
+        self.record_copy(frame.device, frame.cmd, frame.renderer, frame.image_index);
+        Ok(())
+    }
+}
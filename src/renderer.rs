@@ -1,239 +1,1850 @@
 use ash::{vk, Device};
 use ash::khr::swapchain;
+use bytemuck::{Pod, Zeroable};
+use serde::{Deserialize, Serialize};
 use swapchain::Device as SwapchainLoader;
+use crate::allocator::MemoryLocation;
+use crate::background::BackgroundPass;
+use crate::bloom::Bloom;
+use crate::debug::DebugUtils;
+use crate::error::VulkanDemoError;
+use crate::frame_graph::RenderFrame;
+use crate::gpu_timer::GpuTimer;
+use crate::memory::{create_buffer, create_image, read_from_buffer};
+use crate::particles::ParticleFormat;
+use crate::pipeline_utils::{
+    compile_shader, specialization_entry, GraphicsPipelineBuilder, RenderTarget, ShaderCompileOptions, ShaderInterface,
+    SpecializationConstants, VertexLayout,
+};
+use crate::promoted::DynamicRendering;
+use crate::resources::{
+    OwnedBuffer, OwnedDescriptorPool, OwnedDescriptorSetLayout, OwnedFramebuffer, OwnedImage, OwnedImageView, OwnedPipeline,
+    OwnedPipelineLayout, OwnedRenderPass, OwnedSwapchain,
+};
+use crate::sync::FRAMES_IN_FLIGHT;
+use crate::sync2::{ImageBarrier, Sync2};
+use crate::texture::Texture;
 use crate::vulkan_context::VulkanContext;
+use std::sync::Arc;
 
+/// Presentation strategy requested by the user. The actual `vk::PresentModeKHR`
+/// falls back to whatever the surface supports.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PresentMode {
+    /// Vsync'd FIFO presentation, always supported.
+    #[default]
+    Fifo,
+    /// Uncapped rendering without tearing, falling back to immediate.
+    Mailbox,
+    /// Uncapped rendering that may tear, falling back to mailbox.
+    Immediate,
+}
+
+impl PresentMode {
+    fn preference(self) -> &'static [vk::PresentModeKHR] {
+        match self {
+            Self::Fifo => &[vk::PresentModeKHR::FIFO],
+            Self::Mailbox => &[vk::PresentModeKHR::MAILBOX, vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::FIFO],
+            Self::Immediate => &[vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::MAILBOX, vk::PresentModeKHR::FIFO],
+        }
+    }
+}
+
+/// How particle fragments combine with what is already in the framebuffer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlendMode {
+    /// Later particles overwrite earlier ones.
+    Opaque,
+    /// Straight alpha blending, so particles fade out as their life runs down.
+    #[default]
+    Alpha,
+    /// Alpha-weighted colors add up, so dense clusters glow.
+    Additive,
+}
+
+impl BlendMode {
+    pub(crate) fn attachment_state(self) -> vk::PipelineColorBlendAttachmentState {
+        let state = vk::PipelineColorBlendAttachmentState::default()
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ONE)
+            .dst_alpha_blend_factor(vk::BlendFactor::ZERO)
+            .alpha_blend_op(vk::BlendOp::ADD);
+        match self {
+            Self::Opaque => state.blend_enable(false),
+            Self::Alpha => state
+                .blend_enable(true)
+                .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+                .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA),
+            Self::Additive => state
+                .blend_enable(true)
+                .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+                .dst_color_blend_factor(vk::BlendFactor::ONE),
+        }
+    }
+}
+
+/// What a particle's point sprite looks like.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PointShape {
+    /// The raw square point, skipping the per-fragment distance test.
+    Square = 0,
+    /// An anti-aliased disc whose edge fades out over `edge_softness`.
+    #[default]
+    Disc = 1,
+    /// The texture passed to `Renderer::set_sprite`, tinted by the particle color.
+    Sprite = 2,
+}
+
+/// Per-draw values pushed to `particle.vert` and `particle.frag`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct RenderPushConstants {
+    pub point_size_scale: f32,
+    pub point_size_range: [f32; 2],
+    /// Width of the faded rim as a fraction of the disc radius.
+    pub edge_softness: f32,
+    pub point_shape: u32,
+}
+
+/// The state drawing into the scene pass needs, borrowed from the `Renderer`
+/// as plain handles, so it can be shared with the threads recording secondary
+/// command buffers. Those inherit no state, so each sets its own.
+#[derive(Copy, Clone)]
+pub struct SceneDraw<'a> {
+    background: &'a BackgroundPass,
+    /// The background's opacity over the previous frame with trails; `None`
+    /// when the pass cleared it.
+    fade: Option<f32>,
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    descriptor_set: vk::DescriptorSet,
+    push_constants: RenderPushConstants,
+    extent: vk::Extent2D,
+}
+
+impl SceneDraw<'_> {
+    /// Sets the viewport and scissor and records the background, or with
+    /// trails on, the fade of the previous frame. Drawn before the particles.
+    pub fn record_background(&self, device: &Device, cmd: vk::CommandBuffer) {
+        set_viewport_and_scissor(device, cmd, self.extent);
+        match self.fade {
+            Some(opacity) => self.background.record_fade(device, cmd, opacity),
+            None => self.background.record(device, cmd),
+        }
+    }
+
+    /// Sets the viewport and scissor and binds the particle pipeline with the
+    /// current frame's camera and push constants, ready for vertex buffers
+    /// and draws.
+    pub fn bind_particles(&self, device: &Device, cmd: vk::CommandBuffer) {
+        set_viewport_and_scissor(device, cmd, self.extent);
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline_layout,
+                0,
+                &[self.descriptor_set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                0,
+                bytemuck::bytes_of(&self.push_constants),
+            );
+        }
+    }
+}
+
+/// What a secondary command buffer continuing a render pass inherits: the
+/// pass it draws in and, with render pass objects, the framebuffer, which may
+/// be null.
+#[derive(Copy, Clone, Debug)]
+pub struct PassInheritance {
+    pub target: RenderTarget,
+    pub framebuffer: vk::Framebuffer,
+}
+
+/// Contents of the uniform buffer `particle.vert` reads its camera from (std140).
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct CameraUniforms {
+    /// Column-major; maps particle positions to clip space.
+    pub view_projection: [[f32; 4]; 4],
+}
+
+impl CameraUniforms {
+    pub fn new(view_projection: glam::Mat4) -> Self {
+        Self { view_projection: view_projection.to_cols_array_2d() }
+    }
+}
+
+const DEPTH_FORMAT: vk::Format = vk::Format::D32_SFLOAT;
+// Where `particle.vert` reads `CameraUniforms`.
+const CAMERA_SET: u32 = 0;
+const CAMERA_BINDING: u32 = 0;
+const SPRITE_BINDING: u32 = 1;
+
+/// Subpass of the present render pass that overlays (like the settings UI) draw in.
+pub const OVERLAY_SUBPASS: u32 = 0;
+
+// Factor applied per +/- key press and the bounds of the global point size scale.
+pub const POINT_SIZE_SCALE_STEP: f32 = 1.25;
+const MIN_POINT_SIZE_SCALE: f32 = 0.1;
+const MAX_POINT_SIZE_SCALE: f32 = 100.0;
+pub const DEFAULT_EDGE_SOFTNESS: f32 = 0.3;
+pub const DEFAULT_TRAIL_STRENGTH: f32 = 0.9;
+// Bounds of the scene resolution relative to the window.
+pub const MIN_RENDER_SCALE: f32 = 0.25;
+pub const MAX_RENDER_SCALE: f32 = 2.0;
+/// Swapchain images asked for unless told otherwise: one more than the two a
+/// typical surface requires, so rendering rarely waits on presentation.
+pub const DEFAULT_IMAGE_COUNT: u32 = 3;
+// Fading by less than this per frame leaves 8-bit targets with ghosts that never go away.
+const MAX_TRAIL_STRENGTH: f32 = 0.99;
+
+/// Swapchain, render passes and the particle graphics pipeline.
+///
+/// Particles are drawn into a persistent scene image, which each frame is
+/// copied into the acquired swapchain image for the overlay to draw over. A
+/// headless renderer has no swapchain and reads the scene image back instead.
+///
+/// Fields are dropped in declaration order, so the pipeline, framebuffers and
+/// views go before the render passes and the images they refer to.
 pub struct Renderer {
+    pub graphics_pipeline: OwnedPipeline,
+    pub pipeline_layout: OwnedPipelineLayout,
+    camera: CameraBinding,
+    pub background: BackgroundPass,
+    pub bloom: Bloom,
+    /// Records the frame's barriers.
+    pub sync2: Sync2,
+    /// Labels the frame's passes.
+    pub debug: DebugUtils,
+    /// Present render pass framebuffers, one per swapchain image. Empty with
+    /// dynamic rendering.
+    pub framebuffers: Vec<OwnedFramebuffer>,
+    pub image_views: Vec<OwnedImageView>,
+    scene: SceneTarget,
+    path: RenderPath,
     pub swapchain_loader: SwapchainLoader,
-    pub swapchain: vk::SwapchainKHR,
+    /// What the swapchain presents to; null for a headless renderer. Owned by
+    /// whoever made the renderer, and destroyed only after `release_swapchain`.
+    surface: vk::SurfaceKHR,
+    /// `None` for a headless renderer, and between `release_swapchain` and
+    /// the next `recreate`.
+    swapchain: Option<OwnedSwapchain>,
+    headless: bool,
+    /// Empty for a headless renderer.
     pub images: Vec<vk::Image>,
-    pub image_views: Vec<vk::ImageView>,
-    pub render_pass: vk::RenderPass,
-    pub framebuffers: Vec<vk::Framebuffer>,
+    /// Size of the swapchain images, or of the scene image when headless.
     pub extent: vk::Extent2D,
-    pub pipeline_layout: vk::PipelineLayout,
-    pub graphics_pipeline: vk::Pipeline,
+    /// Size of the scene image: `extent` times `render_scale`.
+    pub scene_extent: vk::Extent2D,
+    pub render_scale: f32,
+    /// Filter for blitting a scene of another size than the swapchain images,
+    /// or `None` if the format can't be blitted, which locks the scale to 1.
+    scene_filter: Option<vk::Filter>,
+    max_image_dimension: u32,
+    pub format: vk::SurfaceFormatKHR,
+    pub present_mode: PresentMode,
+    pub active_present_mode: vk::PresentModeKHR,
+    /// Swapchain images asked for: 2 for the least latency, 3 for throughput.
+    /// The surface may grant a different number; `images` holds what it did.
+    pub image_count: u32,
+    pub blend_mode: BlendMode,
+    /// How the drawn particle buffers are laid out, which sets the vertex
+    /// strides, and the format of their colors.
+    pub particle_format: ParticleFormat,
+    /// Multiplies every particle's own size.
+    pub point_size_scale: f32,
+    point_size_range: [f32; 2],
+    pub point_shape: PointShape,
+    /// Sampled by `PointShape::Sprite`; a white texel until `set_sprite`.
+    sprite: Texture,
+    sprite_loaded: bool,
+    /// Fraction of the disc radius over which its edge fades out.
+    pub edge_softness: f32,
+    /// Fade the previous frame out instead of clearing it, leaving trails.
+    pub trails: bool,
+    /// Fraction of the previous frame kept each frame while `trails` is on.
+    pub trail_strength: f32,
+    /// Whether the scene image holds a frame to fade, which it doesn't until
+    /// drawn once after being (re)created.
+    scene_drawn: bool,
+    /// Whether the scene pass being recorded fades the previous frame rather
+    /// than clearing it.
+    fading: bool,
+    vertex_spirv: Vec<u32>,
+    fragment_spirv: Vec<u32>,
+    pipeline_cache: vk::PipelineCache,
 }
 
 impl Renderer {
-    pub fn new(context: &VulkanContext, width: u32, height: u32) -> Result<Self, Box<dyn std::error::Error>> {
+    /// Creates a renderer presenting to `surface`, such as the context's own.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        context: &VulkanContext,
+        surface: vk::SurfaceKHR,
+        width: u32,
+        height: u32,
+        present_mode: PresentMode,
+        image_count: u32,
+        blend_mode: BlendMode,
+    ) -> Result<Self, VulkanDemoError> {
         let swapchain_loader = swapchain::Device::new(&context.instance, &context.device);
-        
-        let surface_capabilities = unsafe {
-            context.surface_loader.get_physical_device_surface_capabilities(context.physical_device, context.surface)?
+
+        let surface_formats = unsafe { context.surface_loader.get_physical_device_surface_formats(context.physical_device, surface)? };
+        log::debug!(
+            "Surface formats: {:?}",
+            surface_formats.iter().map(|format| (format.format, format.color_space)).collect::<Vec<_>>()
+        );
+        let format = choose_surface_format(&surface_formats);
+        log::info!("Swapchain format: {:?}, {:?}", format.format, format.color_space);
+
+        let (swapchain, extent, active_present_mode) = create_swapchain(
+            context,
+            &swapchain_loader,
+            surface,
+            format,
+            present_mode,
+            image_count,
+            width,
+            height,
+            vk::SwapchainKHR::null(),
+        )?;
+        let images = unsafe { swapchain_loader.get_swapchain_images(swapchain.handle())? };
+        log::info!("Swapchain has {} images, {image_count} requested", images.len());
+        let image_views = create_image_views(&context.device, &images, format.format)?;
+        name_swapchain_images(&context.debug, &images, &image_views);
+
+        let path = RenderPath::new(context, format.format)?;
+        let scene = SceneTarget::new(context, path.scene_render_pass(), format.format, extent)?;
+        let framebuffers = path.create_framebuffers(&context.device, &image_views, extent)?;
+
+        let (vertex_spirv, fragment_spirv) = compile_particle_shaders()?;
+        let interface = particle_shader_interface(&vertex_spirv, &fragment_spirv)?;
+        let sprite = Texture::white(context)?;
+        let camera = CameraBinding::new(context, &interface, &sprite)?;
+        let pipeline_layout = create_pipeline_layout(&context.device, &interface, camera.descriptor_set_layout.handle())?;
+        let graphics_pipeline = create_graphics_pipeline(
+            &context.device,
+            context.pipeline_cache,
+            path.scene_target(format.format),
+            pipeline_layout.handle(),
+            blend_mode,
+            ParticleFormat::default(),
+            format.format,
+            &vertex_spirv,
+            &fragment_spirv,
+        )?;
+        context.debug.set_object_name(graphics_pipeline.handle(), "particle graphics pipeline");
+        let background =
+            BackgroundPass::new(&context.device, context.pipeline_cache, path.scene_target(format.format), format.format)?;
+        let bloom = Bloom::new(context, path.overlay_target(format.format), scene.view.handle(), extent)?;
+
+        Ok(Self {
+            graphics_pipeline,
+            pipeline_layout,
+            camera,
+            background,
+            bloom,
+            sync2: Sync2::new(context),
+            debug: context.debug.clone(),
+            framebuffers,
+            image_views,
+            scene,
+            path,
+            swapchain_loader,
+            surface,
+            swapchain: Some(swapchain),
+            headless: false,
+            images,
+            extent,
+            scene_extent: extent,
+            render_scale: 1.0,
+            scene_filter: choose_scene_filter(context, format.format),
+            max_image_dimension: unsafe {
+                context.instance.get_physical_device_properties(context.physical_device).limits.max_image_dimension2_d
+            },
+            format,
+            present_mode,
+            active_present_mode,
+            image_count,
+            blend_mode,
+            particle_format: ParticleFormat::default(),
+            point_size_scale: 1.0,
+            point_size_range: context.point_size_range,
+            point_shape: PointShape::default(),
+            sprite,
+            sprite_loaded: false,
+            edge_softness: DEFAULT_EDGE_SOFTNESS,
+            trails: false,
+            trail_strength: DEFAULT_TRAIL_STRENGTH,
+            scene_drawn: false,
+            fading: false,
+            vertex_spirv,
+            fragment_spirv,
+            pipeline_cache: context.pipeline_cache,
+        })
+    }
+
+    /// Creates a renderer that only draws the scene image, without a swapchain.
+    /// The image is left in `TRANSFER_SRC_OPTIMAL` after each frame so it can be
+    /// read back with `read_pixels`.
+    pub fn new_headless(context: &VulkanContext, width: u32, height: u32, blend_mode: BlendMode) -> Result<Self, VulkanDemoError> {
+        let swapchain_loader = swapchain::Device::new(&context.instance, &context.device);
+        let format = vk::SurfaceFormatKHR {
+            format: vk::Format::R8G8B8A8_UNORM,
+            color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
         };
-        
-        let extent = if surface_capabilities.current_extent.width != u32::MAX {
-            surface_capabilities.current_extent
-        } else {
-            vk::Extent2D { width, height }
+        let extent = vk::Extent2D { width, height };
+
+        let path = RenderPath::new(context, format.format)?;
+        let scene = SceneTarget::new(context, path.scene_render_pass(), format.format, extent)?;
+
+        let (vertex_spirv, fragment_spirv) = compile_particle_shaders()?;
+        let interface = particle_shader_interface(&vertex_spirv, &fragment_spirv)?;
+        let sprite = Texture::white(context)?;
+        let camera = CameraBinding::new(context, &interface, &sprite)?;
+        let pipeline_layout = create_pipeline_layout(&context.device, &interface, camera.descriptor_set_layout.handle())?;
+        let graphics_pipeline = create_graphics_pipeline(
+            &context.device,
+            context.pipeline_cache,
+            path.scene_target(format.format),
+            pipeline_layout.handle(),
+            blend_mode,
+            ParticleFormat::default(),
+            format.format,
+            &vertex_spirv,
+            &fragment_spirv,
+        )?;
+        context.debug.set_object_name(graphics_pipeline.handle(), "particle graphics pipeline");
+        let background =
+            BackgroundPass::new(&context.device, context.pipeline_cache, path.scene_target(format.format), format.format)?;
+        let bloom = Bloom::new(context, path.overlay_target(format.format), scene.view.handle(), extent)?;
+
+        Ok(Self {
+            graphics_pipeline,
+            pipeline_layout,
+            camera,
+            background,
+            bloom,
+            sync2: Sync2::new(context),
+            debug: context.debug.clone(),
+            framebuffers: Vec::new(),
+            image_views: Vec::new(),
+            scene,
+            path,
+            swapchain_loader,
+            surface: vk::SurfaceKHR::null(),
+            swapchain: None,
+            headless: true,
+            images: Vec::new(),
+            extent,
+            scene_extent: extent,
+            render_scale: 1.0,
+            scene_filter: None,
+            max_image_dimension: extent.width.max(extent.height),
+            format,
+            present_mode: PresentMode::Fifo,
+            active_present_mode: vk::PresentModeKHR::FIFO,
+            image_count: 0,
+            blend_mode,
+            particle_format: ParticleFormat::default(),
+            point_size_scale: 1.0,
+            point_size_range: context.point_size_range,
+            point_shape: PointShape::default(),
+            sprite,
+            sprite_loaded: false,
+            edge_softness: DEFAULT_EDGE_SOFTNESS,
+            trails: false,
+            trail_strength: DEFAULT_TRAIL_STRENGTH,
+            scene_drawn: false,
+            fading: false,
+            vertex_spirv,
+            fragment_spirv,
+            pipeline_cache: context.pipeline_cache,
+        })
+    }
+
+    /// The current swapchain, or a null handle for a headless renderer.
+    pub fn swapchain(&self) -> vk::SwapchainKHR {
+        self.swapchain.as_ref().map_or(vk::SwapchainKHR::null(), OwnedSwapchain::handle)
+    }
+
+    /// Rebuilds the swapchain and everything sized by it after a resize or an
+    /// out-of-date/suboptimal report from acquire or present, or builds it
+    /// for a new surface after `release_swapchain` and `set_surface`.
+    ///
+    /// Returns `false`, leaving everything as it was, while the surface has no
+    /// area to present to, as with a minimized window.
+    pub fn recreate(&mut self, context: &VulkanContext, width: u32, height: u32) -> Result<bool, VulkanDemoError> {
+        if self.swapchain.is_none() {
+            // A new surface must still take the format the pipelines were made for.
+            let formats = unsafe { context.surface_loader.get_physical_device_surface_formats(context.physical_device, self.surface)? };
+            let offered = |offered: &vk::SurfaceFormatKHR| {
+                offered.format == vk::Format::UNDEFINED
+                    || (offered.format == self.format.format && offered.color_space == self.format.color_space)
+            };
+            if !formats.iter().any(offered) {
+                return Err(VulkanDemoError::UnsupportedSurface(format!("it no longer offers {:?}", self.format.format)));
+            }
+        }
+        let surface_capabilities = unsafe {
+            context.surface_loader.get_physical_device_surface_capabilities(context.physical_device, self.surface)?
         };
+        let extent = choose_extent(&surface_capabilities, width, height);
+        if extent.width == 0 || extent.height == 0 {
+            return Ok(false);
+        }
+
+        unsafe { context.device.device_wait_idle()? };
+        self.framebuffers.clear();
+        self.image_views.clear();
+
+        let (swapchain, extent, active_present_mode) = create_swapchain(
+            context,
+            &self.swapchain_loader,
+            self.surface,
+            self.format,
+            self.present_mode,
+            self.image_count,
+            width,
+            height,
+            self.swapchain(),
+        )?;
+        // Dropping the old swapchain only now lets the driver reuse its resources.
+        self.swapchain = Some(swapchain);
+        self.extent = extent;
+        self.active_present_mode = active_present_mode;
+
+        self.images = unsafe { self.swapchain_loader.get_swapchain_images(self.swapchain())? };
+        log::info!("Swapchain has {} images, {} requested", self.images.len(), self.image_count);
+        self.image_views = create_image_views(&context.device, &self.images, self.format.format)?;
+        name_swapchain_images(&self.debug, &self.images, &self.image_views);
+        self.framebuffers = self.path.create_framebuffers(&context.device, &self.image_views, extent)?;
+        self.recreate_scene(context)?;
+
+        // Viewport and scissor are dynamic state, so the pipeline outlives the swapchain.
+        log::debug!(
+            "Swapchain recreated at {}x{}, keeping graphics pipeline {:?}",
+            extent.width,
+            extent.height,
+            self.graphics_pipeline.handle()
+        );
+        Ok(true)
+    }
+
+    /// Destroys the swapchain and its images' views and framebuffers once the
+    /// GPU is done with them, ahead of destroying the surface they belong to.
+    /// `recreate` builds them again, for the surface given to `set_surface` meanwhile.
+    pub fn release_swapchain(&mut self, context: &VulkanContext) -> Result<(), vk::Result> {
+        unsafe { context.device.device_wait_idle()? };
+        self.framebuffers.clear();
+        self.image_views.clear();
+        self.images.clear();
+        self.swapchain = None;
+        Ok(())
+    }
+
+    /// Replaces the surface presented to, after `release_swapchain`; the next
+    /// `recreate` builds the swapchain for it.
+    pub fn set_surface(&mut self, surface: vk::SurfaceKHR) {
+        debug_assert!(self.swapchain.is_none(), "surface replaced under a live swapchain");
+        self.surface = surface;
+    }
+
+    /// Sets the scene resolution relative to the window, clamped to a sane range,
+    /// and recreates the scene image at it. The scene is scaled to the window
+    /// when copied over, so this stays at 1 if the surface format can't be blitted.
+    pub fn set_render_scale(&mut self, context: &VulkanContext, scale: f32) -> Result<(), VulkanDemoError> {
+        let scale = scale.clamp(MIN_RENDER_SCALE, MAX_RENDER_SCALE);
+        if scale == self.render_scale {
+            return Ok(());
+        }
+        if self.scene_filter.is_none() && scale != 1.0 {
+            log::warn!("Render scale {scale} is unsupported: {:?} images can't be blitted", self.format.format);
+            return Ok(());
+        }
+        self.render_scale = scale;
+        unsafe { context.device.device_wait_idle()? };
+        self.recreate_scene(context)
+    }
+
+    fn recreate_scene(&mut self, context: &VulkanContext) -> Result<(), VulkanDemoError> {
+        let scale = |size: u32| ((size as f32 * self.render_scale).round() as u32).clamp(1, self.max_image_dimension);
+        self.scene_extent = vk::Extent2D { width: scale(self.extent.width), height: scale(self.extent.height) };
+        self.scene = SceneTarget::new(context, self.path.scene_render_pass(), self.format.format, self.scene_extent)?;
+        self.scene_drawn = false;
+        self.bloom.resize(context, self.scene.view.handle(), self.scene_extent)?;
+        if self.scene_extent != self.extent {
+            log::info!(
+                "Rendering at {}x{} for a {}x{} window",
+                self.scene_extent.width,
+                self.scene_extent.height,
+                self.extent.width,
+                self.extent.height,
+            );
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the graphics pipeline with a different blend state.
+    pub fn set_blend_mode(&mut self, device: &Arc<Device>, blend_mode: BlendMode) -> Result<(), VulkanDemoError> {
+        self.blend_mode = blend_mode;
+        self.rebuild_pipeline(device)
+    }
+
+    /// Rebuilds the graphics pipeline to draw from particle buffers in `format`.
+    pub fn set_particle_format(&mut self, device: &Arc<Device>, format: ParticleFormat) -> Result<(), VulkanDemoError> {
+        self.particle_format = format;
+        self.rebuild_pipeline(device)
+    }
+
+    /// Recompiles the vertex and fragment shaders from GLSL and swaps in a new
+    /// pipeline. On a compile error the current pipeline is left untouched.
+    pub fn reload_pipeline(
+        &mut self,
+        device: &Arc<Device>,
+        vertex_source: &str,
+        fragment_source: &str,
+        options: &ShaderCompileOptions,
+    ) -> Result<(), VulkanDemoError> {
+        let vertex_spirv = compile_shader(vertex_source, "particle.vert", shaderc::ShaderKind::Vertex, options)?;
+        let fragment_spirv = compile_shader(fragment_source, "particle.frag", shaderc::ShaderKind::Fragment, options)?;
+        // The pipeline layout is kept, so the new shaders must fit it.
+        particle_shader_interface(&vertex_spirv, &fragment_spirv)?;
+        self.vertex_spirv = vertex_spirv;
+        self.fragment_spirv = fragment_spirv;
+        self.rebuild_pipeline(device)
+    }
+
+    fn rebuild_pipeline(&mut self, device: &Arc<Device>) -> Result<(), VulkanDemoError> {
+        let pipeline = create_graphics_pipeline(
+            device,
+            self.pipeline_cache,
+            self.path.scene_target(self.format.format),
+            self.pipeline_layout.handle(),
+            self.blend_mode,
+            self.particle_format,
+            self.format.format,
+            &self.vertex_spirv,
+            &self.fragment_spirv,
+        )?;
+        unsafe { device.device_wait_idle()? };
+        self.debug.set_object_name(pipeline.handle(), "particle graphics pipeline");
+        self.graphics_pipeline = pipeline;
+        Ok(())
+    }
+
+    /// Switches presentation strategy, rebuilding the swapchain to apply it.
+    pub fn set_present_mode(&mut self, context: &VulkanContext, present_mode: PresentMode) -> Result<(), VulkanDemoError> {
+        self.present_mode = present_mode;
+        self.recreate(context, self.extent.width, self.extent.height)?;
+        Ok(())
+    }
+
+    /// Changes how many swapchain images to ask for, rebuilding the swapchain
+    /// to apply it. Whoever sized resources by `images` must resize them.
+    pub fn set_image_count(&mut self, context: &VulkanContext, image_count: u32) -> Result<(), VulkanDemoError> {
+        self.image_count = image_count;
+        self.recreate(context, self.extent.width, self.extent.height)?;
+        Ok(())
+    }
+
+    /// Sets the global point size multiplier, clamped to a sane range. The
+    /// resulting sizes are further clamped to what the device supports.
+    pub fn set_point_size_scale(&mut self, scale: f32) {
+        self.point_size_scale = scale.clamp(MIN_POINT_SIZE_SCALE, MAX_POINT_SIZE_SCALE);
+    }
+
+    /// Sets how much of the previous frame survives each frame with trails on,
+    /// clamped so trails always fade out completely.
+    pub fn set_trail_strength(&mut self, strength: f32) {
+        self.trail_strength = strength.clamp(0.0, MAX_TRAIL_STRENGTH);
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.headless
+    }
+
+    /// Resets and begins `cmd` for a frame drawing into swapchain image
+    /// `image_index`, for `FrameGraph` passes to record into. Finished with
+    /// `RenderFrame::end_frame`.
+    pub fn begin_frame<'a>(
+        &'a mut self,
+        device: &'a Device,
+        cmd: vk::CommandBuffer,
+        image_index: u32,
+        gpu_timer: &'a mut GpuTimer,
+    ) -> Result<RenderFrame<'a>, vk::Result> {
+        unsafe {
+            device.reset_command_buffer(cmd, vk::CommandBufferResetFlags::empty())?;
+            device.begin_command_buffer(cmd, &vk::CommandBufferBeginInfo::default())?;
+        }
+        Ok(RenderFrame { device, cmd, image_index, renderer: self, gpu_timer })
+    }
+
+    /// What overlays draw into, between `begin_overlay_pass` and `end_overlay_pass`.
+    pub fn overlay_target(&self) -> RenderTarget {
+        self.path.overlay_target(self.format.format)
+    }
+
+    /// Begins the pass drawing into the scene image. Its contents are recorded
+    /// through `scene_draw`, inline or, with `SECONDARY_COMMAND_BUFFERS`
+    /// contents, into secondary command buffers begun with `scene_inheritance`.
+    pub fn begin_scene_pass(&mut self, device: &Device, cmd: vk::CommandBuffer, contents: vk::SubpassContents) {
+        // A fresh scene image is undefined, so it's cleared once even with trails.
+        let fade = self.trails && self.scene_drawn;
+        self.scene_drawn = true;
+        self.fading = fade;
+        let clear_values = [
+            self.background.clear_value(),
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 },
+            },
+        ];
+        let render_area = vk::Rect2D::default().extent(self.scene_extent);
+        match &self.path {
+            RenderPath::RenderPasses(passes) => {
+                let framebuffer = self.scene.framebuffer.as_ref().expect("scene framebuffer without dynamic rendering");
+                let render_pass_info = vk::RenderPassBeginInfo::default()
+                    .render_pass(if fade { passes.trail.handle() } else { passes.scene.handle() })
+                    .framebuffer(framebuffer.handle())
+                    .render_area(render_area)
+                    .clear_values(&clear_values);
+                unsafe { device.cmd_begin_render_pass(cmd, &render_pass_info, contents) };
+            }
+            RenderPath::Dynamic(loader) => {
+                // The layouts and incoming dependency of the scene render passes.
+                let to_color = ImageBarrier::color(
+                    self.scene.image.handle(),
+                    (
+                        vk::PipelineStageFlags2::TRANSFER
+                            | vk::PipelineStageFlags2::COMPUTE_SHADER
+                            | vk::PipelineStageFlags2::FRAGMENT_SHADER,
+                        vk::AccessFlags2::NONE,
+                    ),
+                    (
+                        vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
+                        vk::AccessFlags2::COLOR_ATTACHMENT_READ | vk::AccessFlags2::COLOR_ATTACHMENT_WRITE,
+                    ),
+                    if fade { vk::ImageLayout::TRANSFER_SRC_OPTIMAL } else { vk::ImageLayout::UNDEFINED },
+                    vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                );
+                let to_depth = ImageBarrier {
+                    image: self.scene.depth_image.handle(),
+                    aspect_mask: vk::ImageAspectFlags::DEPTH,
+                    src_stage: vk::PipelineStageFlags2::LATE_FRAGMENT_TESTS,
+                    src_access: vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                    dst_stage: vk::PipelineStageFlags2::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags2::LATE_FRAGMENT_TESTS,
+                    dst_access: vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                    old_layout: vk::ImageLayout::UNDEFINED,
+                    new_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                };
+                self.sync2.pipeline_barrier(device, cmd, &[], &[to_color, to_depth]);
 
-        let surface_formats = unsafe {
-            context.surface_loader.get_physical_device_surface_formats(context.physical_device, context.surface)?
+                let color_attachment = vk::RenderingAttachmentInfo::default()
+                    .image_view(self.scene.view.handle())
+                    .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .load_op(if fade { vk::AttachmentLoadOp::LOAD } else { vk::AttachmentLoadOp::CLEAR })
+                    .store_op(vk::AttachmentStoreOp::STORE)
+                    .clear_value(clear_values[0]);
+                // Cleared every frame and never read back.
+                let depth_attachment = vk::RenderingAttachmentInfo::default()
+                    .image_view(self.scene.depth_view.handle())
+                    .image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                    .load_op(vk::AttachmentLoadOp::CLEAR)
+                    .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                    .clear_value(clear_values[1]);
+                let flags = if contents == vk::SubpassContents::SECONDARY_COMMAND_BUFFERS {
+                    vk::RenderingFlags::CONTENTS_SECONDARY_COMMAND_BUFFERS
+                } else {
+                    vk::RenderingFlags::empty()
+                };
+                let rendering_info = vk::RenderingInfo::default()
+                    .flags(flags)
+                    .render_area(render_area)
+                    .layer_count(1)
+                    .color_attachments(std::slice::from_ref(&color_attachment))
+                    .depth_attachment(&depth_attachment);
+                unsafe { loader.cmd_begin_rendering(cmd, &rendering_info) };
+            }
+        }
+    }
+
+    /// What recording into the scene pass begun last needs.
+    pub fn scene_draw(&self) -> SceneDraw<'_> {
+        SceneDraw {
+            background: &self.background,
+            fade: self.fading.then_some(1.0 - self.trail_strength),
+            pipeline: self.graphics_pipeline.handle(),
+            pipeline_layout: self.pipeline_layout.handle(),
+            descriptor_set: self.descriptor_set(),
+            push_constants: self.push_constants(),
+            extent: self.scene_extent,
+        }
+    }
+
+    /// What secondary command buffers recording into the scene pass inherit.
+    pub fn scene_inheritance(&self) -> PassInheritance {
+        PassInheritance {
+            target: self.path.scene_target(self.format.format),
+            framebuffer: self.scene.framebuffer.as_ref().map_or(vk::Framebuffer::null(), OwnedFramebuffer::handle),
+        }
+    }
+
+    /// Ends the pass begun by `begin_scene_pass`, leaving the scene image in
+    /// `TRANSFER_SRC_OPTIMAL` to be copied out.
+    pub fn end_scene_pass(&self, device: &Device, cmd: vk::CommandBuffer) {
+        match &self.path {
+            RenderPath::RenderPasses(_) => unsafe { device.cmd_end_render_pass(cmd) },
+            RenderPath::Dynamic(loader) => {
+                unsafe { loader.cmd_end_rendering(cmd) };
+                // The final layout and outgoing dependency of the scene render passes.
+                let to_transfer = ImageBarrier::color(
+                    self.scene.image.handle(),
+                    (vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT, vk::AccessFlags2::COLOR_ATTACHMENT_WRITE),
+                    (vk::PipelineStageFlags2::TRANSFER, vk::AccessFlags2::TRANSFER_READ),
+                    vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                );
+                self.sync2.pipeline_barrier(device, cmd, &[], &[to_transfer]);
+            }
+        }
+    }
+
+    /// Copies the finished scene into swapchain image `image_index`, scaling it
+    /// to fit with a blit if the render scale isn't 1, and begins the overlay
+    /// pass on it, for overlays to draw into. With bloom on, the scene is
+    /// instead composited with its highlights by a draw in the pass. Must
+    /// follow the scene pass, and `record_bloom` with bloom on.
+    pub fn begin_overlay_pass(&self, device: &Device, cmd: vk::CommandBuffer, image_index: u32) {
+        if self.bloom.enabled {
+            self.begin_present_rendering(device, cmd, image_index, vk::AttachmentLoadOp::DONT_CARE);
+            set_viewport_and_scissor(device, cmd, self.extent);
+            self.bloom.record_composite(device, cmd);
+            return;
+        }
+
+        let image = self.images[image_index as usize];
+        // Chained to the acquire semaphore, which is waited on at this stage.
+        let to_transfer = vk::ImageMemoryBarrier::default()
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            });
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
         };
-        let format = surface_formats.first().unwrap_or(&vk::SurfaceFormatKHR {
-            format: vk::Format::B8G8R8A8_UNORM,
-            color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
-        });
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_transfer],
+            );
+            match self.scene_filter {
+                Some(filter) if self.scene_extent != self.extent => {
+                    let corner = |extent: vk::Extent2D| vk::Offset3D { x: extent.width as i32, y: extent.height as i32, z: 1 };
+                    let region = vk::ImageBlit::default()
+                        .src_subresource(subresource)
+                        .src_offsets([vk::Offset3D::default(), corner(self.scene_extent)])
+                        .dst_subresource(subresource)
+                        .dst_offsets([vk::Offset3D::default(), corner(self.extent)]);
+                    device.cmd_blit_image(
+                        cmd,
+                        self.scene.image.handle(),
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        image,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        &[region],
+                        filter,
+                    );
+                }
+                _ => {
+                    let region = vk::ImageCopy::default()
+                        .src_subresource(subresource)
+                        .dst_subresource(subresource)
+                        .extent(vk::Extent3D { width: self.extent.width, height: self.extent.height, depth: 1 });
+                    device.cmd_copy_image(
+                        cmd,
+                        self.scene.image.handle(),
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        image,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        &[region],
+                    );
+                }
+            }
+        }
+        self.begin_present_rendering(device, cmd, image_index, vk::AttachmentLoadOp::LOAD);
+        // Overlays only set their scissor, and the scene's viewport may be smaller.
+        set_viewport_and_scissor(device, cmd, self.extent);
+    }
+
+    /// Begins drawing into swapchain image `image_index`: with `LOAD`, over the
+    /// scene just copied into it, and otherwise over whatever it held.
+    fn begin_present_rendering(&self, device: &Device, cmd: vk::CommandBuffer, image_index: u32, load_op: vk::AttachmentLoadOp) {
+        let render_area = vk::Rect2D::default().extent(self.extent);
+        match &self.path {
+            RenderPath::RenderPasses(passes) => {
+                let render_pass = if load_op == vk::AttachmentLoadOp::LOAD { &passes.present } else { &passes.composite };
+                let render_pass_info = vk::RenderPassBeginInfo::default()
+                    .render_pass(render_pass.handle())
+                    .framebuffer(self.framebuffers[image_index as usize].handle())
+                    .render_area(render_area);
+                unsafe { device.cmd_begin_render_pass(cmd, &render_pass_info, vk::SubpassContents::INLINE) };
+            }
+            RenderPath::Dynamic(loader) => {
+                // The layouts and incoming dependency of the present render passes.
+                let old_layout = if load_op == vk::AttachmentLoadOp::LOAD {
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL
+                } else {
+                    vk::ImageLayout::UNDEFINED
+                };
+                let to_color = ImageBarrier::color(
+                    self.images[image_index as usize],
+                    (
+                        vk::PipelineStageFlags2::TRANSFER | vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
+                        vk::AccessFlags2::TRANSFER_WRITE,
+                    ),
+                    (
+                        vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
+                        vk::AccessFlags2::COLOR_ATTACHMENT_READ | vk::AccessFlags2::COLOR_ATTACHMENT_WRITE,
+                    ),
+                    old_layout,
+                    vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                );
+                self.sync2.pipeline_barrier(device, cmd, &[], &[to_color]);
+
+                let color_attachment = vk::RenderingAttachmentInfo::default()
+                    .image_view(self.image_views[image_index as usize].handle())
+                    .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .load_op(load_op)
+                    .store_op(vk::AttachmentStoreOp::STORE);
+                let rendering_info = vk::RenderingInfo::default()
+                    .render_area(render_area)
+                    .layer_count(1)
+                    .color_attachments(std::slice::from_ref(&color_attachment));
+                unsafe { loader.cmd_begin_rendering(cmd, &rendering_info) };
+            }
+        }
+    }
 
-        let swapchain_create_info = vk::SwapchainCreateInfoKHR::default()
-            .surface(context.surface)
-            .min_image_count(surface_capabilities.min_image_count + 1)
-            .image_format(format.format)
-            .image_color_space(format.color_space)
-            .image_extent(extent)
-            .image_array_layers(1)
-            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
-            .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
-            .pre_transform(surface_capabilities.current_transform)
-            .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-            .present_mode(vk::PresentModeKHR::FIFO);
-
-        let swapchain = unsafe { swapchain_loader.create_swapchain(&swapchain_create_info, None)? };
-        let images = unsafe { swapchain_loader.get_swapchain_images(swapchain)? };
-        
-        let image_views: Vec<vk::ImageView> = images.iter().map(|&image| {
-            let create_info = vk::ImageViewCreateInfo::default()
+    /// Ends the pass begun by `begin_overlay_pass`, handing swapchain image
+    /// `image_index` over for presentation and returning the scene image to
+    /// the layout the next scene pass expects after bloom sampled it.
+    pub fn end_overlay_pass(&self, device: &Device, cmd: vk::CommandBuffer, image_index: u32) {
+        match &self.path {
+            RenderPath::RenderPasses(_) => unsafe { device.cmd_end_render_pass(cmd) },
+            RenderPath::Dynamic(loader) => {
+                unsafe { loader.cmd_end_rendering(cmd) };
+                // Readbacks wait on color attachment output before copying the image out.
+                let to_present = ImageBarrier::color(
+                    self.images[image_index as usize],
+                    (vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT, vk::AccessFlags2::COLOR_ATTACHMENT_WRITE),
+                    (vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT, vk::AccessFlags2::NONE),
+                    vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                    vk::ImageLayout::PRESENT_SRC_KHR,
+                );
+                self.sync2.pipeline_barrier(device, cmd, &[], &[to_present]);
+            }
+        }
+        if self.bloom.enabled {
+            let to_transfer = self.scene_barrier(
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::empty(),
+            );
+            unsafe {
+                device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    vk::PipelineStageFlags::TRANSFER | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[to_transfer],
+                );
+            }
+        }
+    }
+
+    /// Records the bloom threshold and blur passes over the finished scene,
+    /// between the scene and overlay passes.
+    pub fn record_bloom(&self, device: &Device, cmd: vk::CommandBuffer) {
+        let to_sampled = self.scene_barrier(
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            vk::AccessFlags::SHADER_READ,
+        );
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_sampled],
+            );
+        }
+        self.bloom.record(device, cmd);
+    }
+
+    fn scene_barrier(
+        &self,
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+        src_access: vk::AccessFlags,
+        dst_access: vk::AccessFlags,
+    ) -> vk::ImageMemoryBarrier<'static> {
+        vk::ImageMemoryBarrier::default()
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(self.scene.image.handle())
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+    }
+
+    /// Draws particles as `sprite` from now on. Must not be called while a frame is in flight.
+    pub fn set_sprite(&mut self, device: &Device, sprite: Texture) {
+        self.camera.set_sprite(device, &sprite);
+        self.sprite = sprite;
+        self.sprite_loaded = true;
+        self.point_shape = PointShape::Sprite;
+    }
+
+    /// Whether `set_sprite` gave `PointShape::Sprite` something to draw.
+    pub fn has_sprite(&self) -> bool {
+        self.sprite_loaded
+    }
+
+    pub fn push_constants(&self) -> RenderPushConstants {
+        RenderPushConstants {
+            point_size_scale: self.point_size_scale,
+            point_size_range: self.point_size_range,
+            edge_softness: self.edge_softness.clamp(0.0, 1.0),
+            point_shape: self.point_shape as u32,
+        }
+    }
+
+    /// Switches to the camera uniforms of frame in flight `frame`, which the
+    /// GPU must be done with.
+    pub fn set_frame_in_flight(&mut self, frame: usize) {
+        self.camera.current = frame;
+    }
+
+    /// Writes the camera matrix the current frame's draw will use.
+    pub fn update_camera(&self, view_projection: glam::Mat4) -> Result<(), vk::Result> {
+        self.camera.update(&CameraUniforms::new(view_projection))
+    }
+
+    /// Set 0 of the graphics pipeline layout for the current frame, holding its camera uniforms.
+    pub fn descriptor_set(&self) -> vk::DescriptorSet {
+        self.camera.frames[self.camera.current].descriptor_set
+    }
+
+    /// Width over height of the render target.
+    pub fn aspect_ratio(&self) -> f32 {
+        self.extent.width as f32 / self.extent.height.max(1) as f32
+    }
+
+    /// Checks that rendered images can be read back, and returns whether their
+    /// pixels come back as BGRA and need swapping to RGBA.
+    pub fn readback_swizzle(&self, context: &VulkanContext) -> Result<bool, VulkanDemoError> {
+        if !self.is_headless() {
+            let capabilities = unsafe {
+                context.surface_loader.get_physical_device_surface_capabilities(context.physical_device, self.surface)?
+            };
+            if !capabilities.supported_usage_flags.contains(vk::ImageUsageFlags::TRANSFER_SRC) {
+                return Err(VulkanDemoError::UnsupportedCapture("the swapchain images can't be copied from".into()));
+            }
+        }
+        match self.format.format {
+            vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => Ok(false),
+            vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => Ok(true),
+            format => Err(VulkanDemoError::UnsupportedCapture(format!("surface format {format:?} is not 8-bit RGBA or BGRA"))),
+        }
+    }
+
+    /// Size in bytes of one image read back by `record_readback`.
+    pub fn readback_size(&self) -> vk::DeviceSize {
+        self.extent.width as vk::DeviceSize * self.extent.height as vk::DeviceSize * 4
+    }
+
+    /// Records copying swapchain image `image_index`, or the scene image of a
+    /// headless renderer, into `buffer` as tightly packed rows, made visible to
+    /// the host once the commands complete. Must come after the render pass
+    /// that draws the image, in the same or an earlier submission.
+    pub fn record_readback(&self, device: &Device, cmd: vk::CommandBuffer, image_index: u32, buffer: vk::Buffer) {
+        let (image, layout) = if self.is_headless() {
+            (self.scene.image.handle(), vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+        } else {
+            (self.images[image_index as usize], vk::ImageLayout::PRESENT_SRC_KHR)
+        };
+        let transition = |old_layout, new_layout, src_access, dst_access| {
+            vk::ImageMemoryBarrier::default()
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_access_mask(src_access)
+                .dst_access_mask(dst_access)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
                 .image(image)
-                .view_type(vk::ImageViewType::TYPE_2D)
-                .format(format.format)
                 .subresource_range(vk::ImageSubresourceRange {
                     aspect_mask: vk::ImageAspectFlags::COLOR,
                     base_mip_level: 0,
                     level_count: 1,
                     base_array_layer: 0,
                     layer_count: 1,
-                });
-            unsafe { context.device.create_image_view(&create_info, None).unwrap() }
-        }).collect();
-
-        // Render Pass
-        let color_attachment = vk::AttachmentDescription::default()
-            .format(format.format)
-            .samples(vk::SampleCountFlags::TYPE_1)
-            .load_op(vk::AttachmentLoadOp::CLEAR)
-            .store_op(vk::AttachmentStoreOp::STORE)
-            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
-            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
-            .initial_layout(vk::ImageLayout::UNDEFINED)
-            .final_layout(vk::ImageLayout::PRESENT_SRC_KHR);
-
-        let color_attachment_ref = vk::AttachmentReference::default()
-            .attachment(0)
-            .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
-
-        let subpass = vk::SubpassDescription::default()
-            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-            .color_attachments(std::slice::from_ref(&color_attachment_ref));
-
-        let render_pass_info = vk::RenderPassCreateInfo::default()
-            .attachments(std::slice::from_ref(&color_attachment))
-            .subpasses(std::slice::from_ref(&subpass));
-
-        let render_pass = unsafe { context.device.create_render_pass(&render_pass_info, None)? };
-
-        let framebuffers: Vec<vk::Framebuffer> = image_views.iter().map(|&view| {
-            let attachments = [view];
-            let create_info = vk::FramebufferCreateInfo::default()
-                .render_pass(render_pass)
-                .attachments(&attachments)
-                .width(extent.width)
-                .height(extent.height)
-                .layers(1);
-            unsafe { context.device.create_framebuffer(&create_info, None).unwrap() }
-        }).collect();
-
-        // Graphics Pipeline
-        let vert_source = include_str!("shaders/particle.vert");
-        let frag_source = include_str!("shaders/particle.frag");
-        let vert_spirv = crate::pipeline_utils::compile_shader(vert_source, "particle.vert", shaderc::ShaderKind::Vertex)?;
-        let frag_spirv = crate::pipeline_utils::compile_shader(frag_source, "particle.frag", shaderc::ShaderKind::Fragment)?;
-        
-        let vert_module = crate::pipeline_utils::create_shader_module(&context.device, &vert_spirv)?;
-        let frag_module = crate::pipeline_utils::create_shader_module(&context.device, &frag_spirv)?;
-
-        let entry_name = unsafe { std::ffi::CStr::from_bytes_with_nul_unchecked(b"main\0") };
-        
-        let shader_stages = [
-            vk::PipelineShaderStageCreateInfo::default()
-                .stage(vk::ShaderStageFlags::VERTEX)
-                .module(vert_module)
-                .name(entry_name),
-            vk::PipelineShaderStageCreateInfo::default()
-                .stage(vk::ShaderStageFlags::FRAGMENT)
-                .module(frag_module)
-                .name(entry_name),
-        ];
+                })
+        };
 
-        let vertex_binding_description = vk::VertexInputBindingDescription::default()
-            .binding(0)
-            .stride(std::mem::size_of::<crate::particles::Particle>() as u32)
-            .input_rate(vk::VertexInputRate::VERTEX);
-
-        let vertex_attribute_descriptions = [
-            vk::VertexInputAttributeDescription::default()
-                .binding(0)
-                .location(0)
-                .format(vk::Format::R32G32_SFLOAT)
-                .offset(0),
-        ];
+        unsafe {
+            let to_transfer = transition(
+                layout,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                vk::AccessFlags::TRANSFER_READ,
+            );
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_transfer],
+            );
 
-        let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::default()
-            .vertex_binding_descriptions(std::slice::from_ref(&vertex_binding_description))
-            .vertex_attribute_descriptions(&vertex_attribute_descriptions);
-
-        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::default()
-            .topology(vk::PrimitiveTopology::POINT_LIST)
-            .primitive_restart_enable(false);
-
-        let viewport = vk::Viewport::default()
-            .x(0.0)
-            .y(0.0)
-            .width(extent.width as f32)
-            .height(extent.height as f32)
-            .min_depth(0.0)
-            .max_depth(1.0);
-
-        let scissor = vk::Rect2D::default()
-            .offset(vk::Offset2D { x: 0, y: 0 })
-            .extent(extent);
-
-        let viewport_state = vk::PipelineViewportStateCreateInfo::default()
-            .viewports(std::slice::from_ref(&viewport))
-            .scissors(std::slice::from_ref(&scissor));
-
-        let rasterizer = vk::PipelineRasterizationStateCreateInfo::default()
-            .depth_clamp_enable(false)
-            .rasterizer_discard_enable(false)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .line_width(1.0)
-            .cull_mode(vk::CullModeFlags::BACK)
-            .front_face(vk::FrontFace::CLOCKWISE)
-            .depth_bias_enable(false);
-
-        let multisampling = vk::PipelineMultisampleStateCreateInfo::default()
-            .sample_shading_enable(false)
-            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
-
-        let color_blend_attachment = vk::PipelineColorBlendAttachmentState::default()
-            .color_write_mask(vk::ColorComponentFlags::RGBA)
-            .blend_enable(false);
-
-        let color_blending = vk::PipelineColorBlendStateCreateInfo::default()
-        .logic_op_enable(false)
-        .attachments(std::slice::from_ref(&color_blend_attachment));
-
-        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default();
-        let pipeline_layout = unsafe { context.device.create_pipeline_layout(&pipeline_layout_info, None)? };
-
-        let pipeline_info = vk::GraphicsPipelineCreateInfo::default()
-            .stages(&shader_stages)
-            .vertex_input_state(&vertex_input_info)
-            .input_assembly_state(&input_assembly)
-            .viewport_state(&viewport_state)
-            .rasterization_state(&rasterizer)
-            .multisample_state(&multisampling)
-            .color_blend_state(&color_blending)
-            .layout(pipeline_layout)
-            .render_pass(render_pass)
-            .subpass(0);
+            let region = vk::BufferImageCopy::default()
+                .image_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .image_extent(vk::Extent3D { width: self.extent.width, height: self.extent.height, depth: 1 });
+            device.cmd_copy_image_to_buffer(cmd, image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, buffer, &[region]);
+
+            // Hands the image back in the layout the presentation engine expects.
+            let to_original = transition(
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                layout,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::empty(),
+            );
+            let to_host = vk::BufferMemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::HOST_READ)
+                .buffer(buffer)
+                .offset(0)
+                .size(vk::WHOLE_SIZE);
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::HOST | vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[to_host],
+                &[to_original],
+            );
+        }
+    }
+
+    /// Copies a rendered image into host memory as tightly packed RGBA8 rows:
+    /// the scene image of a headless renderer, or swapchain image
+    /// `image_index` between submitting a frame and presenting it.
+    ///
+    /// The copy is submitted on its own and waited on, after everything already
+    /// on the queue, so it costs the frame loop about one frame.
+    pub fn read_pixels(&self, context: &VulkanContext, image_index: u32) -> Result<Vec<u8>, VulkanDemoError> {
+        let swizzle = self.readback_swizzle(context)?;
+        let size = self.readback_size();
+        let readback = create_buffer(
+            context,
+            size,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            MemoryLocation::GpuToCpu,
+        )?;
+        context.one_time_submit(|cmd| self.record_readback(&context.device, cmd, image_index, readback.handle()))?;
+
+        let mut pixels = read_from_buffer(&readback, size as usize)?;
+        if swizzle {
+            bgra_to_rgba(&mut pixels);
+        }
+        Ok(pixels)
+    }
+}
+
+fn set_viewport_and_scissor(device: &Device, cmd: vk::CommandBuffer, extent: vk::Extent2D) {
+    let viewport = vk::Viewport::default()
+        .width(extent.width as f32)
+        .height(extent.height as f32)
+        .min_depth(0.0)
+        .max_depth(1.0);
+    let scissor = vk::Rect2D::default().extent(extent);
+    unsafe {
+        device.cmd_set_viewport(cmd, 0, std::slice::from_ref(&viewport));
+        device.cmd_set_scissor(cmd, 0, std::slice::from_ref(&scissor));
+    }
+}
+
+/// How the scene and overlay passes are begun: with render pass objects and
+/// framebuffers, or with dynamic rendering and barriers making the
+/// layout transitions the render passes would.
+enum RenderPath {
+    RenderPasses(RenderPasses),
+    Dynamic(DynamicRendering),
+}
+
+struct RenderPasses {
+    /// Draws the particles into the scene image, clearing it first.
+    scene: OwnedRenderPass,
+    /// Like `scene` but keeps the previous frame to fade it for trails. Only
+    /// the load op differs, so the two share pipelines and framebuffers.
+    trail: OwnedRenderPass,
+    /// Draws the overlay into a swapchain image holding a copy of the scene.
+    present: OwnedRenderPass,
+    /// Like `present` but without the copy, for the bloom composite.
+    composite: OwnedRenderPass,
+}
 
-        let graphics_pipeline = unsafe {
-            context.device.create_graphics_pipelines(vk::PipelineCache::null(), std::slice::from_ref(&pipeline_info), None)
-                .map_err(|(_, e)| e)?[0]
+impl RenderPath {
+    /// Uses dynamic rendering when the device supports it.
+    fn new(context: &VulkanContext, format: vk::Format) -> Result<Self, vk::Result> {
+        if let Some(loader) = &context.dynamic_rendering {
+            return Ok(Self::Dynamic(loader.clone()));
+        }
+        let device = &context.device;
+        let passes = RenderPasses {
+            scene: create_scene_render_pass(device, format, vk::AttachmentLoadOp::CLEAR)?,
+            trail: create_scene_render_pass(device, format, vk::AttachmentLoadOp::LOAD)?,
+            present: create_present_render_pass(device, format, vk::AttachmentLoadOp::LOAD)?,
+            composite: create_present_render_pass(device, format, vk::AttachmentLoadOp::DONT_CARE)?,
         };
+        context.debug.set_object_name(passes.scene.handle(), "scene render pass");
+        context.debug.set_object_name(passes.trail.handle(), "trail render pass");
+        context.debug.set_object_name(passes.present.handle(), "present render pass");
+        context.debug.set_object_name(passes.composite.handle(), "bloom composite render pass");
+        Ok(Self::RenderPasses(passes))
+    }
 
-        unsafe {
-            context.device.destroy_shader_module(vert_module, None);
-            context.device.destroy_shader_module(frag_module, None);
+    fn scene_render_pass(&self) -> Option<vk::RenderPass> {
+        match self {
+            Self::RenderPasses(passes) => Some(passes.scene.handle()),
+            Self::Dynamic(_) => None,
         }
+    }
 
-        Ok(Self {
-            swapchain_loader,
-            swapchain,
-            images,
-            image_views,
-            render_pass,
-            framebuffers,
-            extent,
-            pipeline_layout,
-            graphics_pipeline,
-        })
+    fn scene_target(&self, format: vk::Format) -> RenderTarget {
+        match self {
+            Self::RenderPasses(passes) => RenderTarget::Subpass { render_pass: passes.scene.handle(), subpass: 0 },
+            Self::Dynamic(_) => RenderTarget::Dynamic { color_format: format, depth_format: Some(DEPTH_FORMAT) },
+        }
     }
 
-    pub fn clean(&mut self, device: &Device) {
-        unsafe {
-            device.destroy_pipeline(self.graphics_pipeline, None);
-            device.destroy_pipeline_layout(self.pipeline_layout, None);
-            for &framebuffer in &self.framebuffers {
-                device.destroy_framebuffer(framebuffer, None);
-            }
-            device.destroy_render_pass(self.render_pass, None);
-            for &view in &self.image_views {
-                device.destroy_image_view(view, None);
+    fn overlay_target(&self, format: vk::Format) -> RenderTarget {
+        match self {
+            Self::RenderPasses(passes) => RenderTarget::Subpass { render_pass: passes.present.handle(), subpass: OVERLAY_SUBPASS },
+            Self::Dynamic(_) => RenderTarget::Dynamic { color_format: format, depth_format: None },
+        }
+    }
+
+    /// Present render pass framebuffers over `image_views`, or none with
+    /// dynamic rendering.
+    fn create_framebuffers(
+        &self,
+        device: &Arc<Device>,
+        image_views: &[OwnedImageView],
+        extent: vk::Extent2D,
+    ) -> Result<Vec<OwnedFramebuffer>, vk::Result> {
+        match self {
+            Self::RenderPasses(passes) => create_framebuffers(device, passes.present.handle(), image_views, extent),
+            Self::Dynamic(_) => Ok(Vec::new()),
+        }
+    }
+}
+
+/// The persistent image particles are drawn into, with its depth buffer and,
+/// without dynamic rendering, the framebuffer combining them. Recreated
+/// whenever the extent changes.
+struct SceneTarget {
+    framebuffer: Option<OwnedFramebuffer>,
+    view: OwnedImageView,
+    /// Frames in flight draw one after another on the same queue, so a single depth buffer does.
+    depth_view: OwnedImageView,
+    depth_image: OwnedImage,
+    image: OwnedImage,
+}
+
+impl SceneTarget {
+    /// Creates a framebuffer for `render_pass` if given one.
+    fn new(
+        context: &VulkanContext,
+        render_pass: Option<vk::RenderPass>,
+        format: vk::Format,
+        extent: vk::Extent2D,
+    ) -> Result<Self, VulkanDemoError> {
+        let image = create_image(
+            context,
+            extent,
+            format,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::SAMPLED,
+        )?;
+        let view = create_image_views(&context.device, &[image.handle()], format)?.remove(0);
+        let (depth_image, depth_view) = create_depth_buffer(context, extent)?;
+
+        let framebuffer = match render_pass {
+            Some(render_pass) => {
+                let attachments = [view.handle(), depth_view.handle()];
+                let create_info = vk::FramebufferCreateInfo::default()
+                    .render_pass(render_pass)
+                    .attachments(&attachments)
+                    .width(extent.width)
+                    .height(extent.height)
+                    .layers(1);
+                Some(OwnedFramebuffer::new(&context.device, unsafe { context.device.create_framebuffer(&create_info, None)? }))
             }
-            self.swapchain_loader.destroy_swapchain(self.swapchain, None);
+            None => None,
+        };
+        Ok(Self { framebuffer, view, depth_view, depth_image, image })
+    }
+}
+
+/// The uniform buffers holding `CameraUniforms` and the descriptor sets binding
+/// them to `particle.vert`, along with the sprite `particle.frag` samples. Each
+/// frame in flight has its own copy, so writing one never races a draw.
+struct CameraBinding {
+    _descriptor_pool: OwnedDescriptorPool,
+    descriptor_set_layout: OwnedDescriptorSetLayout,
+    frames: Vec<CameraFrame>,
+    /// Index into `frames` of the frame being recorded.
+    current: usize,
+}
+
+struct CameraFrame {
+    buffer: OwnedBuffer,
+    descriptor_set: vk::DescriptorSet,
+}
+
+impl CameraBinding {
+    /// Starts out with the identity matrix, which draws the z = 0 plane as the flat 2D view.
+    fn new(context: &VulkanContext, interface: &ShaderInterface, sprite: &Texture) -> Result<Self, VulkanDemoError> {
+        let device = &context.device;
+        let descriptor_set_layout = interface.create_set_layout(device, CAMERA_SET)?;
+        let pool_sizes = interface.pool_sizes(CAMERA_SET, FRAMES_IN_FLIGHT as u32);
+        let pool_info = vk::DescriptorPoolCreateInfo::default()
+            .pool_sizes(&pool_sizes)
+            .max_sets(FRAMES_IN_FLIGHT as u32);
+        let descriptor_pool = OwnedDescriptorPool::new(device, unsafe { device.create_descriptor_pool(&pool_info, None)? });
+
+        let set_layouts = [descriptor_set_layout.handle(); FRAMES_IN_FLIGHT];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool.handle())
+            .set_layouts(&set_layouts);
+        let descriptor_sets = unsafe { device.allocate_descriptor_sets(&alloc_info)? };
+
+        let mut frames = Vec::with_capacity(FRAMES_IN_FLIGHT);
+        for (frame, descriptor_set) in descriptor_sets.into_iter().enumerate() {
+            context.debug.set_object_name(descriptor_set, &format!("camera descriptor set {frame}"));
+            let buffer = create_buffer(
+                context,
+                std::mem::size_of::<CameraUniforms>() as vk::DeviceSize,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+                MemoryLocation::CpuToGpu,
+            )?;
+            let buffer_info = vk::DescriptorBufferInfo::default()
+                .buffer(buffer.handle())
+                .offset(0)
+                .range(vk::WHOLE_SIZE);
+            let write = vk::WriteDescriptorSet::default()
+                .dst_set(descriptor_set)
+                .dst_binding(CAMERA_BINDING)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(std::slice::from_ref(&buffer_info));
+            unsafe { device.update_descriptor_sets(&[write], &[]) };
+            buffer.write_pod(0, &CameraUniforms::new(glam::Mat4::IDENTITY))?;
+            frames.push(CameraFrame { buffer, descriptor_set });
         }
+
+        let camera = Self { _descriptor_pool: descriptor_pool, descriptor_set_layout, frames, current: 0 };
+        camera.set_sprite(device, sprite);
+        Ok(camera)
+    }
+
+    /// Must not be called while a frame using the descriptor sets is in flight.
+    fn set_sprite(&self, device: &Device, sprite: &Texture) {
+        let image_info = sprite.descriptor_info();
+        let writes: Vec<_> = self
+            .frames
+            .iter()
+            .map(|frame| {
+                vk::WriteDescriptorSet::default()
+                    .dst_set(frame.descriptor_set)
+                    .dst_binding(SPRITE_BINDING)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(std::slice::from_ref(&image_info))
+            })
+            .collect();
+        unsafe { device.update_descriptor_sets(&writes, &[]) };
+    }
+
+    /// Writes the current frame's copy.
+    fn update(&self, uniforms: &CameraUniforms) -> Result<(), vk::Result> {
+        self.frames[self.current].buffer.write_pod(0, uniforms)
+    }
+}
+
+/// Swaps the red and blue channels of packed 8-bit pixels in place.
+pub fn bgra_to_rgba(pixels: &mut [u8]) {
+    for pixel in pixels.chunks_exact_mut(4) {
+        pixel.swap(0, 2);
+    }
+}
+
+/// The swapchain extent for a `width` x `height` window: the surface's own
+/// extent where it dictates one, otherwise the window size clamped to the
+/// range the surface supports. Zero-sized while the window is minimized.
+fn choose_extent(capabilities: &vk::SurfaceCapabilitiesKHR, width: u32, height: u32) -> vk::Extent2D {
+    if capabilities.current_extent.width != u32::MAX {
+        return capabilities.current_extent;
+    }
+    vk::Extent2D {
+        width: width.clamp(capabilities.min_image_extent.width, capabilities.max_image_extent.width),
+        height: height.clamp(capabilities.min_image_extent.height, capabilities.max_image_extent.height),
     }
 }
+
+/// `desired` images, within the surface's bounds; a `max_image_count` of 0
+/// means it has no upper bound.
+fn choose_image_count(capabilities: &vk::SurfaceCapabilitiesKHR, desired: u32) -> u32 {
+    let max = match capabilities.max_image_count {
+        0 => u32::MAX,
+        max => max,
+    };
+    desired.max(capabilities.min_image_count).min(max)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_swapchain(
+    context: &VulkanContext,
+    swapchain_loader: &SwapchainLoader,
+    surface: vk::SurfaceKHR,
+    format: vk::SurfaceFormatKHR,
+    present_mode: PresentMode,
+    image_count: u32,
+    width: u32,
+    height: u32,
+    old_swapchain: vk::SwapchainKHR,
+) -> Result<(OwnedSwapchain, vk::Extent2D, vk::PresentModeKHR), VulkanDemoError> {
+    let surface_capabilities = unsafe {
+        context.surface_loader.get_physical_device_surface_capabilities(context.physical_device, surface)?
+    };
+
+    let extent = choose_extent(&surface_capabilities, width, height);
+
+    let supported_present_modes = unsafe {
+        context.surface_loader.get_physical_device_surface_present_modes(context.physical_device, surface)?
+    };
+    let active_present_mode = present_mode
+        .preference()
+        .iter()
+        .copied()
+        .find(|mode| supported_present_modes.contains(mode))
+        .unwrap_or(vk::PresentModeKHR::FIFO);
+
+    // The scene is copied in, while copying out is only needed for screenshots.
+    if !surface_capabilities.supported_usage_flags.contains(vk::ImageUsageFlags::TRANSFER_DST) {
+        return Err(VulkanDemoError::UnsupportedSurface("its images can't be copied into".into()));
+    }
+    let image_usage = vk::ImageUsageFlags::COLOR_ATTACHMENT
+        | vk::ImageUsageFlags::TRANSFER_DST
+        | (surface_capabilities.supported_usage_flags & vk::ImageUsageFlags::TRANSFER_SRC);
+
+    let queue_family_indices = [context.queue_family_index, context.present_queue_family_index];
+    let swapchain_create_info = vk::SwapchainCreateInfoKHR::default()
+        .surface(surface)
+        .min_image_count(choose_image_count(&surface_capabilities, image_count))
+        .image_format(format.format)
+        .image_color_space(format.color_space)
+        .image_extent(extent)
+        .image_array_layers(1)
+        .image_usage(image_usage)
+        .pre_transform(surface_capabilities.current_transform)
+        .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
+        .present_mode(active_present_mode)
+        .clipped(true)
+        .old_swapchain(old_swapchain);
+    // Rendered on one family and presented from another, the images are shared
+    // rather than handed over with a pair of ownership transfers every frame.
+    let swapchain_create_info = if context.has_separate_present_queue() {
+        swapchain_create_info.image_sharing_mode(vk::SharingMode::CONCURRENT).queue_family_indices(&queue_family_indices)
+    } else {
+        swapchain_create_info.image_sharing_mode(vk::SharingMode::EXCLUSIVE)
+    };
+
+    let swapchain = unsafe { swapchain_loader.create_swapchain(&swapchain_create_info, None)? };
+    Ok((OwnedSwapchain::new(&context.device, swapchain_loader, swapchain), extent, active_present_mode))
+}
+
+fn name_swapchain_images(debug: &DebugUtils, images: &[vk::Image], views: &[OwnedImageView]) {
+    for (index, (&image, view)) in images.iter().zip(views).enumerate() {
+        debug.set_object_name(image, &format!("swapchain image {index}"));
+        debug.set_object_name(view.handle(), &format!("swapchain image view {index}"));
+    }
+}
+
+pub(crate) fn create_image_views(device: &Arc<Device>, images: &[vk::Image], format: vk::Format) -> Result<Vec<OwnedImageView>, vk::Result> {
+    images.iter().map(|&image| {
+        let create_info = vk::ImageViewCreateInfo::default()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            });
+        unsafe { device.create_image_view(&create_info, None) }.map(|view| OwnedImageView::new(device, view))
+    }).collect()
+}
+
+/// Reflects the particle shaders, and checks they declare the camera uniform
+/// and the push constants the renderer provides.
+fn particle_shader_interface(vertex_spirv: &[u32], fragment_spirv: &[u32]) -> Result<ShaderInterface, VulkanDemoError> {
+    let interface = ShaderInterface::reflect(&[
+        (vk::ShaderStageFlags::VERTEX, vertex_spirv),
+        (vk::ShaderStageFlags::FRAGMENT, fragment_spirv),
+    ])?;
+    interface.expect_binding(CAMERA_SET, CAMERA_BINDING, vk::DescriptorType::UNIFORM_BUFFER)?;
+    interface.expect_binding(CAMERA_SET, SPRITE_BINDING, vk::DescriptorType::COMBINED_IMAGE_SAMPLER)?;
+    interface.expect_push_constants::<RenderPushConstants>()?;
+    Ok(interface)
+}
+
+fn compile_particle_shaders() -> Result<(Vec<u32>, Vec<u32>), VulkanDemoError> {
+    Ok((
+        compile_shader(include_str!("shaders/particle.vert"), "particle.vert", shaderc::ShaderKind::Vertex, &ShaderCompileOptions::default())?,
+        compile_shader(include_str!("shaders/particle.frag"), "particle.frag", shaderc::ShaderKind::Fragment, &ShaderCompileOptions::default())?,
+    ))
+}
+
+/// Creates a depth image matching `extent` and a view of it.
+fn create_depth_buffer(context: &VulkanContext, extent: vk::Extent2D) -> Result<(OwnedImage, OwnedImageView), VulkanDemoError> {
+    let image = create_image(context, extent, DEPTH_FORMAT, vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)?;
+    let create_info = vk::ImageViewCreateInfo::default()
+        .image(image.handle())
+        .view_type(vk::ImageViewType::TYPE_2D)
+        .format(DEPTH_FORMAT)
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::DEPTH,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        });
+    let view = unsafe { context.device.create_image_view(&create_info, None)? };
+    Ok((image, OwnedImageView::new(&context.device, view)))
+}
+
+/// The pass drawing the particles into the scene image. With a `LOAD` op it
+/// keeps the previous frame, which the last pass left in `TRANSFER_SRC_OPTIMAL`
+/// for copying out, where this one leaves it too.
+fn create_scene_render_pass(
+    device: &Arc<Device>,
+    format: vk::Format,
+    load_op: vk::AttachmentLoadOp,
+) -> Result<OwnedRenderPass, vk::Result> {
+    let initial_layout = if load_op == vk::AttachmentLoadOp::LOAD {
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL
+    } else {
+        vk::ImageLayout::UNDEFINED
+    };
+    let color_attachment = vk::AttachmentDescription::default()
+        .format(format)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .load_op(load_op)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(initial_layout)
+        .final_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL);
+
+    // Cleared every frame and never read back.
+    let depth_attachment = vk::AttachmentDescription::default()
+        .format(DEPTH_FORMAT)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+    let color_attachment_ref = vk::AttachmentReference::default()
+        .attachment(0)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+    let depth_attachment_ref = vk::AttachmentReference::default()
+        .attachment(1)
+        .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+    let subpass = vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(std::slice::from_ref(&color_attachment_ref))
+        .depth_stencil_attachment(&depth_attachment_ref);
+
+    // The shared depth image is cleared while the previous frame's depth writes may
+    // still be in flight, and the scene image may still be being copied out of or
+    // sampled by bloom. The finished scene is then copied into the swapchain image or read back.
+    let dependencies = [
+        vk::SubpassDependency::default()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(
+                vk::PipelineStageFlags::TRANSFER
+                    | vk::PipelineStageFlags::COMPUTE_SHADER
+                    | vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            )
+            .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_READ
+                    | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            ),
+        vk::SubpassDependency::default()
+            .src_subpass(0)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .dst_stage_mask(vk::PipelineStageFlags::TRANSFER)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ),
+    ];
+
+    let attachments = [color_attachment, depth_attachment];
+    let render_pass_info = vk::RenderPassCreateInfo::default()
+        .attachments(&attachments)
+        .subpasses(std::slice::from_ref(&subpass))
+        .dependencies(&dependencies);
+
+    let render_pass = unsafe { device.create_render_pass(&render_pass_info, None)? };
+    Ok(OwnedRenderPass::new(device, render_pass))
+}
+
+/// The pass drawing overlays into a swapchain image, handing it over for
+/// presentation. With a `LOAD` op the scene has just been copied into the
+/// image; otherwise the pass starts by drawing over all of it, as the bloom
+/// composite does.
+fn create_present_render_pass(
+    device: &Arc<Device>,
+    format: vk::Format,
+    load_op: vk::AttachmentLoadOp,
+) -> Result<OwnedRenderPass, vk::Result> {
+    let initial_layout = if load_op == vk::AttachmentLoadOp::LOAD {
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL
+    } else {
+        vk::ImageLayout::UNDEFINED
+    };
+    let color_attachment = vk::AttachmentDescription::default()
+        .format(format)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .load_op(load_op)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(initial_layout)
+        .final_layout(vk::ImageLayout::PRESENT_SRC_KHR);
+    let color_attachment_ref = vk::AttachmentReference::default()
+        .attachment(0)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
+    // `OVERLAY_SUBPASS`.
+    let subpass = vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(std::slice::from_ref(&color_attachment_ref));
+
+    // The overlay blends over the copied scene. Without the copy, the image is
+    // first written here, after the acquire semaphore waited on at this stage.
+    let dependency = vk::SubpassDependency::default()
+        .src_subpass(vk::SUBPASS_EXTERNAL)
+        .dst_subpass(OVERLAY_SUBPASS)
+        .src_stage_mask(vk::PipelineStageFlags::TRANSFER | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+
+    let render_pass_info = vk::RenderPassCreateInfo::default()
+        .attachments(std::slice::from_ref(&color_attachment))
+        .subpasses(std::slice::from_ref(&subpass))
+        .dependencies(std::slice::from_ref(&dependency));
+
+    let render_pass = unsafe { device.create_render_pass(&render_pass_info, None)? };
+    Ok(OwnedRenderPass::new(device, render_pass))
+}
+
+fn create_framebuffers(
+    device: &Arc<Device>,
+    render_pass: vk::RenderPass,
+    image_views: &[OwnedImageView],
+    extent: vk::Extent2D,
+) -> Result<Vec<OwnedFramebuffer>, vk::Result> {
+    image_views.iter().map(|view| {
+        let attachments = [view.handle()];
+        let create_info = vk::FramebufferCreateInfo::default()
+            .render_pass(render_pass)
+            .attachments(&attachments)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        unsafe { device.create_framebuffer(&create_info, None) }.map(|framebuffer| OwnedFramebuffer::new(device, framebuffer))
+    }).collect()
+}
+
+fn create_pipeline_layout(
+    device: &Arc<Device>,
+    interface: &ShaderInterface,
+    camera_set_layout: vk::DescriptorSetLayout,
+) -> Result<OwnedPipelineLayout, vk::Result> {
+    let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default()
+        .set_layouts(std::slice::from_ref(&camera_set_layout))
+        .push_constant_ranges(interface.push_constant_ranges());
+    let pipeline_layout = unsafe { device.create_pipeline_layout(&pipeline_layout_info, None)? };
+    Ok(OwnedPipelineLayout::new(device, pipeline_layout))
+}
+
+/// How to blit a scene image of `format` into a swapchain image of the same
+/// format, or `None` if it can't be.
+fn choose_scene_filter(context: &VulkanContext, format: vk::Format) -> Option<vk::Filter> {
+    let features = unsafe {
+        context.instance.get_physical_device_format_properties(context.physical_device, format).optimal_tiling_features
+    };
+    if !features.contains(vk::FormatFeatureFlags::BLIT_SRC | vk::FormatFeatureFlags::BLIT_DST) {
+        None
+    } else if features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR) {
+        Some(vk::Filter::LINEAR)
+    } else {
+        Some(vk::Filter::NEAREST)
+    }
+}
+
+/// Whether `format` stores sRGB-encoded values, so that shaders writing to it
+/// must output linear colors and leave the encoding to the hardware.
+pub fn is_srgb_format(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::B8G8R8A8_SRGB | vk::Format::R8G8B8A8_SRGB | vk::Format::A8B8G8R8_SRGB_PACK32
+    )
+}
+
+/// Picks the swapchain format: 8-bit sRGB in the sRGB color space if the
+/// surface offers it, otherwise the first sRGB color space format (likely
+/// UNORM, with gamma then applied in the shader), otherwise the first at all.
+fn choose_surface_format(formats: &[vk::SurfaceFormatKHR]) -> vk::SurfaceFormatKHR {
+    let preferred = vk::SurfaceFormatKHR {
+        format: vk::Format::B8G8R8A8_SRGB,
+        color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+    };
+    // A lone UNDEFINED entry means the surface takes any format.
+    if formats.is_empty() || (formats.len() == 1 && formats[0].format == vk::Format::UNDEFINED) {
+        return preferred;
+    }
+    let srgb_space = |format: &&vk::SurfaceFormatKHR| format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR;
+    formats
+        .iter()
+        .filter(srgb_space)
+        .find(|format| matches!(format.format, vk::Format::B8G8R8A8_SRGB | vk::Format::R8G8B8A8_SRGB))
+        .or_else(|| formats.iter().find(srgb_space))
+        .copied()
+        .unwrap_or(formats[0])
+}
+
+/// Specialization constants of `particle.frag`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct FragmentSpecialization {
+    /// `SRGB_TARGET`: the attachment encodes to sRGB itself.
+    srgb_target: vk::Bool32,
+}
+
+impl SpecializationConstants for FragmentSpecialization {
+    const ENTRIES: &'static [vk::SpecializationMapEntry] =
+        &[specialization_entry(0, std::mem::offset_of!(Self, srgb_target), std::mem::size_of::<vk::Bool32>())];
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_graphics_pipeline(
+    device: &Arc<Device>,
+    pipeline_cache: vk::PipelineCache,
+    target: RenderTarget,
+    pipeline_layout: vk::PipelineLayout,
+    blend_mode: BlendMode,
+    particle_format: ParticleFormat,
+    target_format: vk::Format,
+    vert_spirv: &[u32],
+    frag_spirv: &[u32],
+) -> Result<OwnedPipeline, VulkanDemoError> {
+    let fragment_constants = FragmentSpecialization { srgb_target: vk::Bool32::from(is_srgb_format(target_format)) };
+
+    // Binding 0 is the particles' position-and-size vec4 and binding 1 their
+    // color, bound at `ParticleFormat::vertex_offsets` into the same buffer.
+    let stride = particle_format.vertex_stride();
+    let vertex_layout = VertexLayout::new()
+        .binding(stride)
+        .attribute(0, vk::Format::R32G32B32_SFLOAT, std::mem::offset_of!(crate::particles::Particle, pos) as u32)
+        .attribute(2, vk::Format::R32_SFLOAT, std::mem::offset_of!(crate::particles::Particle, size) as u32)
+        .binding(stride)
+        .attribute(1, particle_format.color_format(), 0);
+
+    // Less-or-equal keeps the draw-order layering of the flat view, where every depth is 0.
+    GraphicsPipelineBuilder::new(target, pipeline_layout)
+        .shader(vk::ShaderStageFlags::VERTEX, vert_spirv)
+        .shader(vk::ShaderStageFlags::FRAGMENT, frag_spirv)
+        .specialization(&fragment_constants)
+        .vertex_layout(vertex_layout)
+        .topology(vk::PrimitiveTopology::POINT_LIST)
+        .cull_mode(vk::CullModeFlags::BACK, vk::FrontFace::CLOCKWISE)
+        .depth_test(true, vk::CompareOp::LESS_OR_EQUAL)
+        .blend_mode(blend_mode)
+        .build(device, pipeline_cache)
+}
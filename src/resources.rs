@@ -0,0 +1,323 @@
+//! Owning wrappers around Vulkan handles that destroy them on drop.
+//!
+//! Each wrapper keeps the `Arc<Device>` it was created with, so objects can be
+//! dropped in any order relative to each other, as long as they all go before
+//! the `VulkanContext` destroys the device itself.
+
+use ash::{vk, Device};
+use ash::khr::{surface, swapchain};
+use bytemuck::Pod;
+use std::cell::Cell;
+use std::sync::Arc;
+use crate::allocator::{Allocation, Allocator};
+
+macro_rules! owned_handle {
+    ($(#[$meta:meta])* $name:ident, $handle:ty, $destroy:ident) => {
+        $(#[$meta])*
+        pub struct $name {
+            device: Arc<Device>,
+            handle: $handle,
+        }
+
+        impl $name {
+            /// Takes ownership of `handle`, which must have been created from `device`.
+            pub fn new(device: &Arc<Device>, handle: $handle) -> Self {
+                Self { device: Arc::clone(device), handle }
+            }
+
+            pub fn handle(&self) -> $handle {
+                self.handle
+            }
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                unsafe { self.device.$destroy(self.handle, None) };
+            }
+        }
+    };
+}
+
+owned_handle!(OwnedImageView, vk::ImageView, destroy_image_view);
+owned_handle!(OwnedFramebuffer, vk::Framebuffer, destroy_framebuffer);
+owned_handle!(OwnedRenderPass, vk::RenderPass, destroy_render_pass);
+owned_handle!(OwnedPipeline, vk::Pipeline, destroy_pipeline);
+owned_handle!(OwnedPipelineLayout, vk::PipelineLayout, destroy_pipeline_layout);
+owned_handle!(OwnedDescriptorSetLayout, vk::DescriptorSetLayout, destroy_descriptor_set_layout);
+owned_handle!(
+    /// Descriptor sets allocated from the pool are freed along with it.
+    OwnedDescriptorPool,
+    vk::DescriptorPool,
+    destroy_descriptor_pool
+);
+owned_handle!(OwnedShaderModule, vk::ShaderModule, destroy_shader_module);
+owned_handle!(
+    /// Command buffers allocated from the pool are freed along with it.
+    OwnedCommandPool,
+    vk::CommandPool,
+    destroy_command_pool
+);
+owned_handle!(OwnedSemaphore, vk::Semaphore, destroy_semaphore);
+owned_handle!(OwnedFence, vk::Fence, destroy_fence);
+owned_handle!(OwnedQueryPool, vk::QueryPool, destroy_query_pool);
+owned_handle!(OwnedSampler, vk::Sampler, destroy_sampler);
+
+/// Which queue family may use a buffer, as tracked by `OwnedBuffer::release`
+/// and `acquire`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QueueOwnership {
+    /// `CONCURRENT` sharing: every listed family may use the buffer, and no
+    /// transfers are needed.
+    Concurrent,
+    /// `EXCLUSIVE`, and not used by any family yet; the first to use it owns it.
+    Unowned,
+    Owned(u32),
+    /// Released by `from`; `to` must acquire it before anyone uses it again.
+    Released { from: u32, to: u32 },
+}
+
+/// A buffer together with the memory bound to it, which goes back to the
+/// allocator on drop.
+pub struct OwnedBuffer {
+    device: Arc<Device>,
+    allocator: Arc<Allocator>,
+    buffer: vk::Buffer,
+    allocation: Allocation,
+    ownership: Cell<QueueOwnership>,
+}
+
+impl OwnedBuffer {
+    /// Takes ownership of `buffer` and `allocation`, which must have been created
+    /// from `device` and `allocator`. `concurrent` is whether the buffer was
+    /// created with `CONCURRENT` sharing.
+    pub fn new(
+        device: &Arc<Device>,
+        allocator: &Arc<Allocator>,
+        buffer: vk::Buffer,
+        allocation: Allocation,
+        concurrent: bool,
+    ) -> Self {
+        let ownership = if concurrent { QueueOwnership::Concurrent } else { QueueOwnership::Unowned };
+        Self { device: Arc::clone(device), allocator: Arc::clone(allocator), buffer, allocation, ownership: Cell::new(ownership) }
+    }
+
+    pub fn handle(&self) -> vk::Buffer {
+        self.buffer
+    }
+
+    pub fn ownership(&self) -> QueueOwnership {
+        self.ownership.get()
+    }
+
+    /// Records the releasing half of a transfer of the buffer from queue family
+    /// `from` to `to`, into a command buffer for `from` after its last use of
+    /// the buffer, described by `src`. Nothing is recorded for a
+    /// `CONCURRENT` buffer or when the families are the same.
+    pub fn release(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        (from, to): (u32, u32),
+        src: (vk::PipelineStageFlags, vk::AccessFlags),
+    ) {
+        let ownership = self.ownership.get();
+        if ownership == QueueOwnership::Concurrent || from == to {
+            return;
+        }
+        debug_assert!(
+            matches!(ownership, QueueOwnership::Unowned) || ownership == QueueOwnership::Owned(from),
+            "queue family {from} releasing a buffer it doesn't own: {ownership:?}",
+        );
+        self.ownership_barrier(device, cmd, (from, to), src, (vk::PipelineStageFlags::BOTTOM_OF_PIPE, vk::AccessFlags::empty()));
+        self.ownership.set(QueueOwnership::Released { from, to });
+    }
+
+    /// Records whatever queue family `family` needs before its next use of the
+    /// buffer, described by `dst`, into a command buffer for `family`: the
+    /// acquiring half of a `release` to it, or nothing when it already owns
+    /// the buffer, nobody does yet or the buffer is `CONCURRENT`.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, if another family owns the buffer or it was released to one.
+    pub fn acquire(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        family: u32,
+        dst: (vk::PipelineStageFlags, vk::AccessFlags),
+    ) {
+        match self.ownership.get() {
+            QueueOwnership::Concurrent => return,
+            QueueOwnership::Released { from, to } => {
+                debug_assert_eq!(to, family, "queue family {family} acquiring a buffer released to {to}");
+                self.ownership_barrier(device, cmd, (from, to), (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()), dst);
+            }
+            QueueOwnership::Owned(owner) => {
+                debug_assert_eq!(owner, family, "queue family {family} acquiring a buffer {owner} never released");
+            }
+            QueueOwnership::Unowned => (),
+        }
+        self.ownership.set(QueueOwnership::Owned(family));
+    }
+
+    fn ownership_barrier(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        (from, to): (u32, u32),
+        (src_stage, src_access): (vk::PipelineStageFlags, vk::AccessFlags),
+        (dst_stage, dst_access): (vk::PipelineStageFlags, vk::AccessFlags),
+    ) {
+        let barrier = vk::BufferMemoryBarrier::default()
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access)
+            .src_queue_family_index(from)
+            .dst_queue_family_index(to)
+            .buffer(self.buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE);
+        unsafe {
+            device.cmd_pipeline_barrier(cmd, src_stage, dst_stage, vk::DependencyFlags::empty(), &[], &[barrier], &[]);
+        }
+    }
+
+    /// Start of the buffer's contents, mapped for as long as the buffer lives.
+    ///
+    /// # Panics
+    ///
+    /// If the buffer is not in host-visible memory.
+    pub fn mapped_ptr(&self) -> *mut u8 {
+        self.allocation.mapped_ptr().expect("buffer memory is not host-visible")
+    }
+
+    /// Copies `items` into the buffer `offset` bytes in, through its mapping,
+    /// and flushes them to the device if the memory is not coherent. Buffers
+    /// that aren't host-visible fail with `ERROR_MEMORY_MAP_FAILED`.
+    ///
+    /// # Panics
+    ///
+    /// If the items don't fit in the buffer at `offset`.
+    pub fn write_slice<T: Pod>(&self, offset: vk::DeviceSize, items: &[T]) -> Result<(), vk::Result> {
+        let bytes: &[u8] = bytemuck::cast_slice(items);
+        let size = bytes.len() as vk::DeviceSize;
+        let mapped = self.allocation.mapped_ptr().ok_or(vk::Result::ERROR_MEMORY_MAP_FAILED)?;
+        assert!(
+            offset.checked_add(size).is_some_and(|end| end <= self.allocation.size()),
+            "writing {size} bytes at {offset} overruns a {}-byte buffer",
+            self.allocation.size(),
+        );
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), mapped.add(offset as usize), bytes.len()) };
+        self.flush(offset, size)
+    }
+
+    /// `write_slice` of a single value.
+    pub fn write_pod<T: Pod>(&self, offset: vk::DeviceSize, value: &T) -> Result<(), vk::Result> {
+        self.write_slice(offset, std::slice::from_ref(value))
+    }
+
+    /// Makes host writes to `size` bytes at `offset` visible to the device. Only
+    /// does anything for non-coherent memory.
+    pub fn flush(&self, offset: vk::DeviceSize, size: vk::DeviceSize) -> Result<(), vk::Result> {
+        match self.allocation.mapped_range(offset, size) {
+            Some(range) => unsafe { self.device.flush_mapped_memory_ranges(&[range]) },
+            None => Ok(()),
+        }
+    }
+
+    /// Makes device writes to `size` bytes at `offset` visible to the host. Only
+    /// does anything for non-coherent memory.
+    pub fn invalidate(&self, offset: vk::DeviceSize, size: vk::DeviceSize) -> Result<(), vk::Result> {
+        match self.allocation.mapped_range(offset, size) {
+            Some(range) => unsafe { self.device.invalidate_mapped_memory_ranges(&[range]) },
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for OwnedBuffer {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_buffer(self.buffer, None) };
+        self.allocator.free(&self.device, &self.allocation);
+    }
+}
+
+/// An image together with the memory bound to it, which goes back to the
+/// allocator on drop.
+pub struct OwnedImage {
+    device: Arc<Device>,
+    allocator: Arc<Allocator>,
+    image: vk::Image,
+    allocation: Allocation,
+}
+
+impl OwnedImage {
+    /// Takes ownership of `image` and `allocation`, which must have been created
+    /// from `device` and `allocator`.
+    pub fn new(device: &Arc<Device>, allocator: &Arc<Allocator>, image: vk::Image, allocation: Allocation) -> Self {
+        Self { device: Arc::clone(device), allocator: Arc::clone(allocator), image, allocation }
+    }
+
+    pub fn handle(&self) -> vk::Image {
+        self.image
+    }
+}
+
+impl Drop for OwnedImage {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_image(self.image, None) };
+        self.allocator.free(&self.device, &self.allocation);
+    }
+}
+
+/// A swapchain and the loader needed to destroy it.
+pub struct OwnedSwapchain {
+    // Holds the device alive for the loader's function pointers.
+    _device: Arc<Device>,
+    loader: swapchain::Device,
+    handle: vk::SwapchainKHR,
+}
+
+impl OwnedSwapchain {
+    pub fn new(device: &Arc<Device>, loader: &swapchain::Device, handle: vk::SwapchainKHR) -> Self {
+        Self { _device: Arc::clone(device), loader: loader.clone(), handle }
+    }
+
+    pub fn handle(&self) -> vk::SwapchainKHR {
+        self.handle
+    }
+}
+
+impl Drop for OwnedSwapchain {
+    fn drop(&mut self) {
+        unsafe { self.loader.destroy_swapchain(self.handle, None) };
+    }
+}
+
+/// A window surface besides the context's own, which must go before the
+/// context destroys the instance, and after every swapchain made for it.
+pub struct OwnedSurface {
+    loader: surface::Instance,
+    handle: vk::SurfaceKHR,
+}
+
+impl OwnedSurface {
+    pub fn new(loader: &surface::Instance, handle: vk::SurfaceKHR) -> Self {
+        Self { loader: loader.clone(), handle }
+    }
+
+    pub fn handle(&self) -> vk::SurfaceKHR {
+        self.handle
+    }
+
+    /// Gives up ownership of the surface, leaving destroying it to the caller.
+    pub fn into_handle(self) -> vk::SurfaceKHR {
+        std::mem::ManuallyDrop::new(self).handle
+    }
+}
+
+impl Drop for OwnedSurface {
+    fn drop(&mut self) {
+        unsafe { self.loader.destroy_surface(self.handle, None) };
+    }
+}
@@ -0,0 +1,404 @@
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::attractors::Attractor;
+use crate::background::Background;
+use crate::bloom::{DEFAULT_BLOOM_INTENSITY, DEFAULT_BLOOM_THRESHOLD};
+use crate::config::{AppConfig, DEFAULT_PARTICLES, MAX_PARTICLES};
+use crate::cpu_sim::SimBackend;
+use crate::emitter::EmitterConfig;
+use crate::error::VulkanDemoError;
+use crate::obstacles::{default_obstacles, Obstacle};
+use crate::particles::{time_seed, BoundaryMode, ColorMode, KindParams, ParticleSystem, SimParams, SimPipelines, SimulationMode, MAX_PARTICLE_KINDS};
+use crate::renderer::{BlendMode, PresentMode, Renderer, DEFAULT_EDGE_SOFTNESS, DEFAULT_TRAIL_STRENGTH};
+use crate::spatial_grid::MAX_GRID_SIZE;
+use crate::vulkan_context::VulkanContext;
+
+/// Everything that makes up a look: the particle systems with their emitters,
+/// forces and obstacles, and how they are drawn. Read from TOML with `--scene`
+/// and written back out with `--dump-scene`.
+///
+/// Every field may be left out for its default; unknown ones are warned about
+/// and skipped. The systems are `[[system]]` tables.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScenePreset {
+    pub renderer: RendererPreset,
+    #[serde(rename = "system")]
+    pub systems: Vec<SystemPreset>,
+}
+
+/// What `Renderer` takes from a scene.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RendererPreset {
+    pub blend_mode: BlendMode,
+    pub present_mode: PresentMode,
+    /// sRGB, from 0 to 1.
+    pub clear_color: [f32; 3],
+    pub background: Background,
+    pub point_size: f32,
+    pub edge_softness: f32,
+    pub trails: bool,
+    pub trail_strength: f32,
+    pub bloom: bool,
+    pub bloom_threshold: f32,
+    pub bloom_intensity: f32,
+}
+
+/// One particle system of a scene.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SystemPreset {
+    pub particles: u32,
+    /// Left out for a different layout every run.
+    pub seed: Option<u64>,
+    pub three_d: bool,
+    pub mode: SimulationMode,
+    pub depth_sort: bool,
+    pub emitter: EmitterConfig,
+    pub sim: SimSettings,
+    #[serde(rename = "attractor")]
+    pub attractors: Vec<Attractor>,
+    #[serde(rename = "obstacle")]
+    pub obstacles: Vec<Obstacle>,
+}
+
+/// The `SimParams` a scene sets. The rest follow from the system: its emitter,
+/// whether it's 3D, and its seed.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimSettings {
+    pub gravity: [f32; 2],
+    pub drag: f32,
+    pub max_speed: f32,
+    pub boundary: BoundaryMode,
+    pub color_mode: ColorMode,
+    pub color_speed_scale: f32,
+    pub base_color: [f32; 4],
+    pub lifetime: f32,
+    pub softening: f32,
+    pub nbody_strength: f32,
+    pub grid_size: u32,
+    pub repulsion: f32,
+    /// Boids separation, alignment and cohesion.
+    pub flocking_weights: [f32; 3],
+    pub perception_radius: f32,
+    pub min_speed: f32,
+    pub noise_scale: f32,
+    pub noise_strength: f32,
+    pub noise_speed: f32,
+    /// Fluid smoothing radius, rest density, stiffness and viscosity.
+    pub smoothing_radius: f32,
+    pub rest_density: f32,
+    pub stiffness: f32,
+    pub viscosity: f32,
+    /// How each kind of particle departs from the settings above.
+    #[serde(rename = "kind")]
+    pub kinds: [KindParams; MAX_PARTICLE_KINDS],
+}
+
+impl ScenePreset {
+    /// Reads a scene from a TOML file.
+    pub fn load(path: &Path) -> Result<Self, VulkanDemoError> {
+        let invalid = |reason: String| VulkanDemoError::InvalidScene { path: path.to_path_buf(), reason };
+        let text = std::fs::read_to_string(path).map_err(|error| VulkanDemoError::SceneIo { path: path.to_path_buf(), error })?;
+        Self::parse(&text, |field| log::warn!("{}: ignoring unknown field {field}", path.display())).map_err(invalid)
+    }
+
+    /// Reads a scene from TOML `text`, calling `unknown_field` with the path
+    /// of every field it skips.
+    fn parse(text: &str, mut unknown_field: impl FnMut(&str)) -> Result<Self, String> {
+        let deserializer = toml::Deserializer::parse(text).map_err(|e| e.to_string())?;
+        let scene: Self = serde_ignored::deserialize(deserializer, |field| unknown_field(&field.to_string())).map_err(|e| e.to_string())?;
+        if scene.systems.is_empty() {
+            return Err("no particle systems".to_string());
+        }
+        if let Some(system) = scene.systems.iter().find(|system| !(1..=MAX_PARTICLES).contains(&system.particles)) {
+            return Err(format!("{} particles, expected 1 to {MAX_PARTICLES}", system.particles));
+        }
+        Ok(scene)
+    }
+
+    /// Writes the scene to `path` as TOML.
+    pub fn save(&self, path: &Path) -> Result<(), VulkanDemoError> {
+        let text = toml::to_string_pretty(self)
+            .map_err(|e| VulkanDemoError::InvalidScene { path: path.to_path_buf(), reason: e.to_string() })?;
+        std::fs::write(path, text).map_err(|error| VulkanDemoError::SceneIo { path: path.to_path_buf(), error })
+    }
+
+    /// The single-system scene the command-line flags describe.
+    pub fn from_config(config: &AppConfig) -> Self {
+        let sim = SimSettings {
+            grid_size: config.grid_size,
+            repulsion: config.repulsion,
+            flocking_weights: [config.separation, config.alignment, config.cohesion],
+            color_mode: if config.emitter_config().mixes_kinds() {
+                ColorMode::Kind
+            } else {
+                SimSettings::default().color_mode
+            },
+            ..SimSettings::default()
+        };
+        Self {
+            renderer: RendererPreset {
+                blend_mode: BlendMode::default(),
+                present_mode: config.present_mode,
+                clear_color: config.clear_color,
+                background: config.background,
+                point_size: config.point_size,
+                edge_softness: config.edge_softness,
+                trails: config.trails,
+                trail_strength: config.trail_strength,
+                bloom: config.bloom,
+                bloom_threshold: config.bloom_threshold,
+                bloom_intensity: config.bloom_intensity,
+            },
+            systems: vec![SystemPreset {
+                particles: config.particles,
+                seed: config.seed,
+                three_d: config.three_d,
+                mode: config.mode,
+                depth_sort: config.depth_sort,
+                emitter: config.emitter_config(),
+                sim,
+                attractors: Vec::new(),
+                obstacles: if config.obstacles { default_obstacles() } else { Vec::new() },
+            }],
+        }
+    }
+
+    /// The scene as it is now, to save with `save`.
+    pub fn capture(particle_systems: &[ParticleSystem], renderer: &Renderer) -> Self {
+        Self {
+            renderer: RendererPreset::capture(renderer),
+            systems: particle_systems.iter().map(SystemPreset::capture).collect(),
+        }
+    }
+
+    /// Creates the scene's systems with `pipelines`, all stepped on `backend`.
+    pub fn build_systems(
+        &self,
+        context: &VulkanContext,
+        pipelines: &SimPipelines,
+        backend: SimBackend,
+    ) -> Result<Vec<ParticleSystem>, VulkanDemoError> {
+        self.systems.iter().map(|system| system.build(context, pipelines, backend)).collect()
+    }
+}
+
+impl Default for RendererPreset {
+    /// Matches the command-line defaults.
+    fn default() -> Self {
+        Self {
+            blend_mode: BlendMode::default(),
+            present_mode: PresentMode::default(),
+            clear_color: [0.0; 3],
+            background: Background::default(),
+            point_size: 1.0,
+            edge_softness: DEFAULT_EDGE_SOFTNESS,
+            trails: false,
+            trail_strength: DEFAULT_TRAIL_STRENGTH,
+            bloom: false,
+            bloom_threshold: DEFAULT_BLOOM_THRESHOLD,
+            bloom_intensity: DEFAULT_BLOOM_INTENSITY,
+        }
+    }
+}
+
+impl RendererPreset {
+    fn capture(renderer: &Renderer) -> Self {
+        Self {
+            blend_mode: renderer.blend_mode,
+            present_mode: renderer.present_mode,
+            clear_color: renderer.background.clear_color,
+            background: renderer.background.mode,
+            point_size: renderer.point_size_scale,
+            edge_softness: renderer.edge_softness,
+            trails: renderer.trails,
+            trail_strength: renderer.trail_strength,
+            bloom: renderer.bloom.enabled,
+            bloom_threshold: renderer.bloom.threshold,
+            bloom_intensity: renderer.bloom.intensity,
+        }
+    }
+
+    /// Sets everything but the blend and present modes, which `Renderer::new`
+    /// takes. Bloom stays off on a headless renderer.
+    pub fn apply(&self, renderer: &mut Renderer) {
+        renderer.background.clear_color = self.clear_color;
+        renderer.background.mode = self.background;
+        renderer.set_point_size_scale(self.point_size);
+        renderer.edge_softness = self.edge_softness;
+        renderer.trails = self.trails;
+        renderer.set_trail_strength(self.trail_strength);
+        renderer.bloom.enabled = self.bloom && !renderer.is_headless();
+        renderer.bloom.threshold = self.bloom_threshold;
+        renderer.bloom.intensity = self.bloom_intensity;
+    }
+}
+
+impl Default for SystemPreset {
+    fn default() -> Self {
+        Self {
+            particles: DEFAULT_PARTICLES,
+            seed: None,
+            three_d: false,
+            mode: SimulationMode::default(),
+            depth_sort: false,
+            emitter: EmitterConfig::default(),
+            sim: SimSettings::default(),
+            attractors: Vec::new(),
+            obstacles: Vec::new(),
+        }
+    }
+}
+
+impl SystemPreset {
+    fn capture(particle_system: &ParticleSystem) -> Self {
+        Self {
+            particles: particle_system.count,
+            seed: Some(particle_system.seed),
+            three_d: particle_system.is_3d(),
+            mode: particle_system.mode(),
+            depth_sort: particle_system.is_depth_sorted(),
+            emitter: particle_system.emitter,
+            sim: SimSettings::from_params(&particle_system.params),
+            attractors: particle_system.attractors.clone(),
+            obstacles: particle_system.obstacles.clone(),
+        }
+    }
+
+    fn build(&self, context: &VulkanContext, pipelines: &SimPipelines, backend: SimBackend) -> Result<ParticleSystem, VulkanDemoError> {
+        let seed = self.seed.unwrap_or_else(time_seed);
+        let mut particle_system =
+            ParticleSystem::new(context, pipelines, self.particles, seed, &self.emitter, self.three_d, backend)?;
+        particle_system.update_params(&self.sim.apply(&particle_system.params))?;
+        particle_system.set_mode(self.mode);
+        particle_system.set_depth_sort(context, self.depth_sort)?;
+        particle_system.set_attractors(&self.attractors)?;
+        particle_system.set_obstacles(context, &self.obstacles)?;
+        Ok(particle_system)
+    }
+}
+
+impl Default for SimSettings {
+    fn default() -> Self {
+        Self::from_params(&SimParams::default())
+    }
+}
+
+impl SimSettings {
+    pub fn from_params(params: &SimParams) -> Self {
+        Self {
+            gravity: params.gravity,
+            drag: params.drag,
+            max_speed: params.max_speed,
+            boundary: params.boundary_mode(),
+            color_mode: params.color_mode(),
+            color_speed_scale: params.color_speed_scale,
+            base_color: params.base_color,
+            lifetime: params.lifetime,
+            softening: params.softening,
+            nbody_strength: params.nbody_strength,
+            grid_size: params.grid_size,
+            repulsion: params.repulsion_strength,
+            flocking_weights: [params.separation_weight, params.alignment_weight, params.cohesion_weight],
+            perception_radius: params.perception_radius,
+            min_speed: params.min_speed,
+            noise_scale: params.noise_scale,
+            noise_strength: params.noise_strength,
+            noise_speed: params.noise_speed,
+            smoothing_radius: params.smoothing_radius,
+            rest_density: params.rest_density,
+            stiffness: params.stiffness,
+            viscosity: params.viscosity,
+            kinds: params.kinds,
+        }
+    }
+
+    /// `params` with these settings in place of their own.
+    pub fn apply(&self, params: &SimParams) -> SimParams {
+        let restitution = match self.boundary {
+            BoundaryMode::Bounce { restitution } => restitution,
+            _ => params.restitution,
+        };
+        let [separation_weight, alignment_weight, cohesion_weight] = self.flocking_weights;
+        SimParams {
+            gravity: self.gravity,
+            drag: self.drag,
+            max_speed: self.max_speed,
+            restitution,
+            boundary_mode: self.boundary.id(),
+            color_mode: self.color_mode as u32,
+            color_speed_scale: self.color_speed_scale,
+            base_color: self.base_color,
+            lifetime: self.lifetime,
+            softening: self.softening,
+            nbody_strength: self.nbody_strength,
+            grid_size: self.grid_size.clamp(1, MAX_GRID_SIZE),
+            repulsion_strength: self.repulsion,
+            separation_weight,
+            alignment_weight,
+            cohesion_weight,
+            perception_radius: self.perception_radius,
+            min_speed: self.min_speed,
+            noise_scale: self.noise_scale,
+            noise_strength: self.noise_strength,
+            noise_speed: self.noise_speed,
+            smoothing_radius: self.smoothing_radius,
+            rest_density: self.rest_density,
+            stiffness: self.stiffness,
+            viscosity: self.viscosity,
+            kinds: self.kinds,
+            ..*params
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_scenes_parse() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/scenes");
+        let mut parsed = 0;
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|extension| extension != "toml") {
+                continue;
+            }
+            let text = std::fs::read_to_string(&path).unwrap();
+            let mut unknown = Vec::new();
+            ScenePreset::parse(&text, |field| unknown.push(field.to_string()))
+                .unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+            assert!(unknown.is_empty(), "{} has unknown fields {unknown:?}", path.display());
+            parsed += 1;
+        }
+        assert!(parsed > 0, "no scenes in {}", dir.display());
+    }
+
+    #[test]
+    fn missing_fields_default() {
+        let scene = ScenePreset::parse("[[system]]\nparticles = 100\n", |field| panic!("unknown field {field}")).unwrap();
+        assert_eq!(scene.renderer, RendererPreset::default());
+        assert_eq!(scene.systems, vec![SystemPreset { particles: 100, ..SystemPreset::default() }]);
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let text = "[renderer]\nglow = 1.0\n\n[[system]]\nparticles = 100\nsparkle = true\n";
+        let mut unknown = Vec::new();
+        let scene = ScenePreset::parse(text, |field| unknown.push(field.to_string())).unwrap();
+        assert_eq!(scene.systems[0].particles, 100);
+        assert_eq!(unknown.len(), 2, "{unknown:?}");
+        assert!(unknown.iter().any(|field| field.ends_with("glow")), "{unknown:?}");
+        assert!(unknown.iter().any(|field| field.ends_with("sparkle")), "{unknown:?}");
+    }
+
+    #[test]
+    fn rejects_scenes_without_systems() {
+        assert!(ScenePreset::parse("[renderer]\ntrails = true\n", |_| ()).is_err());
+        assert!(ScenePreset::parse("[[system]]\nparticles = 0\n", |_| ()).is_err());
+    }
+}
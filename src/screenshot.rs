@@ -0,0 +1,69 @@
+use ash::vk;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::error::VulkanDemoError;
+
+/// Writes tightly packed RGBA8 rows as a PNG.
+pub fn write_png(path: &Path, extent: vk::Extent2D, rgba: &[u8]) -> Result<(), VulkanDemoError> {
+    let image_write_error = |error| VulkanDemoError::ImageWrite { path: path.to_path_buf(), error };
+    let file = File::create(path).map_err(|e| image_write_error(e.into()))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), extent.width, extent.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(image_write_error)?;
+    writer.write_image_data(rgba).map_err(image_write_error)?;
+    writer.finish().map_err(image_write_error)
+}
+
+/// Reads a PNG of any color type, converted to tightly packed RGBA8 rows.
+pub fn read_png(path: &Path) -> Result<(vk::Extent2D, Vec<u8>), VulkanDemoError> {
+    let read_error = |error| VulkanDemoError::ImageRead { path: path.to_path_buf(), error };
+    let file = File::open(path).map_err(|e| read_error(e.into()))?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(read_error)?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).map_err(read_error)?;
+    pixels.truncate(info.buffer_size());
+
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => pixels,
+        png::ColorType::Rgb => pixels.chunks_exact(3).flat_map(|rgb| [rgb[0], rgb[1], rgb[2], u8::MAX]).collect(),
+        png::ColorType::GrayscaleAlpha => pixels.chunks_exact(2).flat_map(|la| [la[0], la[0], la[0], la[1]]).collect(),
+        // Indexed images are expanded to RGB(A) by the transformations.
+        png::ColorType::Grayscale | png::ColorType::Indexed => pixels.iter().flat_map(|&l| [l, l, l, u8::MAX]).collect(),
+    };
+    Ok((vk::Extent2D { width: info.width, height: info.height }, rgba))
+}
+
+/// `screenshot-YYYYMMDD-HHMMSS-mmm.png` in the working directory, in UTC.
+pub fn screenshot_path() -> PathBuf {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    PathBuf::from(format!(
+        "screenshot-{year:04}{month:02}{day:02}-{:02}{:02}{:02}-{:03}.png",
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        now.subsec_millis(),
+    ))
+}
+
+/// Converts days since 1970-01-01 to a proleptic Gregorian (year, month, day),
+/// after Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
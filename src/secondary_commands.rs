@@ -0,0 +1,141 @@
+use ash::{vk, Device};
+use rayon::prelude::*;
+use std::sync::{Arc, Mutex};
+use crate::error::VulkanDemoError;
+use crate::pipeline_utils::RenderTarget;
+use crate::renderer::PassInheritance;
+use crate::resources::OwnedCommandPool;
+use crate::sync::FRAMES_IN_FLIGHT;
+use crate::vulkan_context::VulkanContext;
+
+/// Threads recording by default: one per core, up to this many.
+pub const DEFAULT_MAX_THREADS: usize = 8;
+
+/// Records the passes drawn in a render pass into secondary command buffers on
+/// a thread pool, for the primary command buffer to execute in order.
+///
+/// Command pools can only be used by one thread at a time, so every thread
+/// has its own for each frame in flight. The buffers from one are reset all at
+/// once in `begin_frame` and reused, so steady-state frames allocate nothing.
+pub struct SecondaryCommands {
+    device: Arc<Device>,
+    threads: rayon::ThreadPool,
+    /// `pools[frame][thread]`. Only ever locked by that thread, or by
+    /// `begin_frame` while no thread records.
+    pools: Vec<Vec<Mutex<ThreadCommandPool>>>,
+    frame: usize,
+}
+
+struct ThreadCommandPool {
+    pool: OwnedCommandPool,
+    buffers: Vec<vk::CommandBuffer>,
+    /// How many of `buffers` this frame has recorded into.
+    used: usize,
+}
+
+impl ThreadCommandPool {
+    /// The next buffer not recorded into this frame, allocating one if all are.
+    fn next(&mut self, device: &Device) -> Result<vk::CommandBuffer, vk::Result> {
+        if self.used == self.buffers.len() {
+            let alloc_info = vk::CommandBufferAllocateInfo::default()
+                .command_pool(self.pool.handle())
+                .level(vk::CommandBufferLevel::SECONDARY)
+                .command_buffer_count(1);
+            self.buffers.extend(unsafe { device.allocate_command_buffers(&alloc_info)? });
+        }
+        self.used += 1;
+        Ok(self.buffers[self.used - 1])
+    }
+}
+
+impl SecondaryCommands {
+    /// A pool of `thread_count` threads, each with a command pool per frame in flight.
+    pub fn new(context: &VulkanContext, thread_count: usize) -> Result<Self, VulkanDemoError> {
+        let device = &context.device;
+        let threads = rayon::ThreadPoolBuilder::new()
+            .num_threads(thread_count)
+            .thread_name(|index| format!("command recording {index}"))
+            .build()
+            .map_err(VulkanDemoError::ThreadPool)?;
+        let pool_info = vk::CommandPoolCreateInfo::default()
+            .queue_family_index(context.queue_family_index)
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT);
+        let pools = (0..FRAMES_IN_FLIGHT)
+            .map(|_| {
+                (0..thread_count)
+                    .map(|_| {
+                        let pool = OwnedCommandPool::new(device, unsafe { device.create_command_pool(&pool_info, None)? });
+                        Ok(Mutex::new(ThreadCommandPool { pool, buffers: Vec::new(), used: 0 }))
+                    })
+                    .collect::<Result<Vec<_>, vk::Result>>()
+            })
+            .collect::<Result<Vec<_>, vk::Result>>()?;
+        Ok(Self { device: Arc::clone(device), threads, pools, frame: 0 })
+    }
+
+    pub fn thread_count(&self) -> usize {
+        self.threads.current_num_threads()
+    }
+
+    /// Resets the command pools of frame in flight `frame`, which the GPU must
+    /// be done with, and records into them from now on.
+    pub fn begin_frame(&mut self, frame: usize) -> Result<(), vk::Result> {
+        self.frame = frame;
+        for pool in &mut self.pools[frame] {
+            let pool = pool.get_mut().unwrap_or_else(|e| e.into_inner());
+            unsafe { self.device.reset_command_pool(pool.pool.handle(), vk::CommandPoolResetFlags::empty())? };
+            pool.used = 0;
+        }
+        Ok(())
+    }
+
+    /// Records each of `passes` into its own secondary command buffer
+    /// continuing the render pass `inheritance` describes, spread over the
+    /// threads. Returns the buffers in the order of `passes`, for
+    /// `cmd_execute_commands`.
+    pub fn record<F>(&self, inheritance: PassInheritance, passes: Vec<F>) -> Result<Vec<vk::CommandBuffer>, vk::Result>
+    where
+        F: FnOnce(vk::CommandBuffer) + Send,
+    {
+        let pools = &self.pools[self.frame];
+        let device = &*self.device;
+        self.threads.install(|| {
+            passes
+                .into_par_iter()
+                .map(|pass| {
+                    let thread = rayon::current_thread_index().expect("recording outside the pool");
+                    let cmd = pools[thread].lock().unwrap_or_else(|e| e.into_inner()).next(device)?;
+                    begin_secondary(device, cmd, inheritance)?;
+                    pass(cmd);
+                    unsafe { device.end_command_buffer(cmd)? };
+                    Ok(cmd)
+                })
+                .collect()
+        })
+    }
+}
+
+/// Begins `cmd` for one submission of recording into the render pass
+/// `inheritance` describes.
+fn begin_secondary(device: &Device, cmd: vk::CommandBuffer, inheritance: PassInheritance) -> Result<(), vk::Result> {
+    let color_formats;
+    let mut rendering_info;
+    let mut inheritance_info = vk::CommandBufferInheritanceInfo::default();
+    match inheritance.target {
+        RenderTarget::Subpass { render_pass, subpass } => {
+            inheritance_info = inheritance_info.render_pass(render_pass).subpass(subpass).framebuffer(inheritance.framebuffer);
+        }
+        RenderTarget::Dynamic { color_format, depth_format } => {
+            color_formats = [color_format];
+            rendering_info = vk::CommandBufferInheritanceRenderingInfo::default()
+                .color_attachment_formats(&color_formats)
+                .depth_attachment_format(depth_format.unwrap_or(vk::Format::UNDEFINED))
+                .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+            inheritance_info = inheritance_info.push_next(&mut rendering_info);
+        }
+    }
+    let begin_info = vk::CommandBufferBeginInfo::default()
+        .flags(vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE | vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+        .inheritance_info(&inheritance_info);
+    unsafe { device.begin_command_buffer(cmd, &begin_info) }
+}
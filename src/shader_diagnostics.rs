@@ -0,0 +1,262 @@
+use std::fmt;
+
+// Lines of source shown before and after the one a diagnostic is about.
+const CONTEXT_LINES: u32 = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+        })
+    }
+}
+
+/// One error or warning from shaderc, located in the shader or include it is
+/// about. Displays like rustc's diagnostics, with the source around it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShaderDiagnostic {
+    /// The shader or include, as named to shaderc.
+    pub file: String,
+    /// 1-based; `None` for messages about the file as a whole.
+    pub line: Option<u32>,
+    /// 1-based, in characters. glslang reports lines only, so this is where
+    /// the token the message quotes first appears on the line, if it does.
+    pub column: Option<usize>,
+    pub severity: Severity,
+    pub message: String,
+    /// The source lines around `line` with a caret under `column`, ready to
+    /// print; empty when the source isn't at hand.
+    pub context: String,
+}
+
+impl ShaderDiagnostic {
+    /// Parses shaderc's `log` of "file:line: severity: message" lines. Lines
+    /// in no such form continue the message before them, or are about `file`
+    /// when first. `source_of` looks up the source of a shader or include, for
+    /// the context.
+    pub fn parse_log(log: &str, file: &str, source_of: impl Fn(&str) -> Option<String>) -> Vec<Self> {
+        let mut diagnostics: Vec<Self> = Vec::new();
+        for text in log.lines().map(str::trim_end).filter(|text| !text.is_empty()) {
+            if is_summary(text) {
+                continue;
+            }
+            match parse_line(text) {
+                Some((file, line, severity, message)) => diagnostics.push(Self {
+                    file: file.to_string(),
+                    line,
+                    column: None,
+                    severity,
+                    message: message.to_string(),
+                    context: String::new(),
+                }),
+                None => match diagnostics.last_mut() {
+                    Some(last) => {
+                        last.message.push('\n');
+                        last.message.push_str(text);
+                    }
+                    None => diagnostics.push(Self {
+                        file: file.to_string(),
+                        line: None,
+                        column: None,
+                        severity: Severity::Error,
+                        message: text.to_string(),
+                        context: String::new(),
+                    }),
+                },
+            }
+        }
+
+        for diagnostic in &mut diagnostics {
+            let Some(line) = diagnostic.line else {
+                continue;
+            };
+            let Some(source) = source_of(&diagnostic.file) else {
+                continue;
+            };
+            let Some(text) = (line as usize).checked_sub(1).and_then(|index| source.lines().nth(index)) else {
+                continue;
+            };
+            let caret = quoted_token(&diagnostic.message)
+                .and_then(|token| text.find(token).map(|start| (text[..start].chars().count() + 1, token.chars().count())));
+            diagnostic.column = caret.map(|(column, _)| column);
+            diagnostic.context = source_context(&source, line, caret);
+        }
+        diagnostics
+    }
+}
+
+impl fmt::Display for ShaderDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {}", self.severity, self.message)?;
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, " --> {}:{line}:{column}", self.file)?,
+            (Some(line), None) => write!(f, " --> {}:{line}", self.file)?,
+            (None, _) => write!(f, " --> {}", self.file)?,
+        }
+        if !self.context.is_empty() {
+            write!(f, "\n{}", self.context)?;
+        }
+        Ok(())
+    }
+}
+
+/// `diagnostics` one after the other, separated by blank lines.
+pub fn format_diagnostics(diagnostics: &[ShaderDiagnostic]) -> String {
+    diagnostics.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n\n")
+}
+
+/// Splits "file:line: severity: message", or "file: severity: message".
+fn parse_line(text: &str) -> Option<(&str, Option<u32>, Severity, &str)> {
+    let (location, rest) = text.split_once(": ")?;
+    let (severity, message) = match rest.split_once(": ") {
+        Some(("error", message)) => (Severity::Error, message),
+        Some(("warning", message)) => (Severity::Warning, message),
+        _ => return None,
+    };
+    let (file, line) = match location.rsplit_once(':') {
+        Some((file, line)) => (file, Some(line.parse().ok()?)),
+        None => (location, None),
+    };
+    Some((file, line, severity, message))
+}
+
+/// Whether `text` is shaderc's closing count, like "2 errors generated.".
+fn is_summary(text: &str) -> bool {
+    text.ends_with(" generated.") && text.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// The first non-empty token glslang quotes in `message`, as in
+/// "'foo' : undeclared identifier".
+fn quoted_token(message: &str) -> Option<&str> {
+    message.split('\'').skip(1).step_by(2).map(str::trim).find(|token| !token.is_empty())
+}
+
+/// Up to `CONTEXT_LINES` lines either side of `line` in `source`, numbered in
+/// a gutter, with `caret`'s width of carets under its column, or under the
+/// whole line's code when the column isn't known.
+fn source_context(source: &str, line: u32, caret: Option<(usize, usize)>) -> String {
+    let lines: Vec<&str> = source.lines().collect();
+    let first = line.saturating_sub(CONTEXT_LINES).max(1);
+    let last = (line + CONTEXT_LINES).min(lines.len() as u32);
+    let gutter = last.to_string().len();
+
+    let mut context = format!("{:gutter$} |", "");
+    for number in first..=last {
+        let text = lines[number as usize - 1];
+        context.push_str(&format!("\n{number:>gutter$} | {text}"));
+        if number != line {
+            continue;
+        }
+        // Tabs stay tabs, so the carets line up however they are shown.
+        let (indent, width) = match caret {
+            Some((column, width)) => (text.chars().take(column - 1).collect::<String>(), width),
+            None => {
+                let code = text.trim_start();
+                (text[..text.len() - code.len()].to_string(), code.chars().count().max(1))
+            }
+        };
+        let indent: String = indent.chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        context.push_str(&format!("\n{:gutter$} | {indent}{}", "", "^".repeat(width)));
+    }
+    context
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "#version 450\nvoid main() {\n    float x = y;\n    gl_Position = vec4(x);\n}";
+
+    fn parse(log: &str) -> Vec<ShaderDiagnostic> {
+        ShaderDiagnostic::parse_log(log, "particle.vert", |file| (file == "particle.vert").then(|| SOURCE.to_string()))
+    }
+
+    fn locations(diagnostics: &[ShaderDiagnostic]) -> Vec<(&str, Option<u32>, Severity, &str)> {
+        diagnostics.iter().map(|d| (d.file.as_str(), d.line, d.severity, d.message.as_str())).collect()
+    }
+
+    #[test]
+    fn parses_errors_and_warnings() {
+        let log = "particle.vert:3: error: 'y' : undeclared identifier\n\
+                   particle.glsl:7: warning: 'w' : unused\n\
+                   particle.vert: error: linking failed\n\
+                   \x20   in stage vertex\n\
+                   2 errors generated.\n";
+        assert_eq!(
+            locations(&parse(log)),
+            [
+                ("particle.vert", Some(3), Severity::Error, "'y' : undeclared identifier"),
+                ("particle.glsl", Some(7), Severity::Warning, "'w' : unused"),
+                ("particle.vert", None, Severity::Error, "linking failed\n    in stage vertex"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unparsed_first_lines_are_about_the_file() {
+        assert_eq!(
+            locations(&parse("internal compiler error\n")),
+            [("particle.vert", None, Severity::Error, "internal compiler error")]
+        );
+        assert!(parse("1 error generated.\n").is_empty());
+    }
+
+    #[test]
+    fn context_points_at_the_quoted_token() {
+        let diagnostics = parse("particle.vert:3: error: 'y' : undeclared identifier");
+        assert_eq!(diagnostics[0].column, Some(15));
+        assert_eq!(
+            diagnostics[0].context,
+            "  |\n\
+             1 | #version 450\n\
+             2 | void main() {\n\
+             3 |     float x = y;\n  \
+             |               ^\n\
+             4 |     gl_Position = vec4(x);\n\
+             5 | }"
+        );
+    }
+
+    #[test]
+    fn context_on_the_first_line() {
+        let diagnostics = parse("particle.vert:1: warning: version is old");
+        assert_eq!(diagnostics[0].column, None);
+        assert_eq!(
+            diagnostics[0].context,
+            "  |\n\
+             1 | #version 450\n  \
+             | ^^^^^^^^^^^^\n\
+             2 | void main() {\n\
+             3 |     float x = y;"
+        );
+    }
+
+    #[test]
+    fn context_on_the_last_line() {
+        let diagnostics = parse("particle.vert:5: error: '}' : unexpected");
+        assert_eq!(diagnostics[0].column, Some(1));
+        assert_eq!(
+            diagnostics[0].context,
+            "  |\n\
+             3 |     float x = y;\n\
+             4 |     gl_Position = vec4(x);\n\
+             5 | }\n  \
+             | ^"
+        );
+    }
+
+    #[test]
+    fn no_context_past_the_end_or_without_source() {
+        for log in ["particle.vert:9: error: 'y' : past the end", "particle.glsl:3: error: 'y' : no source"] {
+            let diagnostics = parse(log);
+            assert_eq!((diagnostics[0].column, diagnostics[0].context.as_str()), (None, ""));
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+
+/// Watches a shader directory and reports which files changed since the last poll.
+pub struct ShaderWatcher {
+    dir: PathBuf,
+    // Kept alive so events keep arriving on `events`.
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<notify::Event>>,
+}
+
+impl ShaderWatcher {
+    pub fn new(dir: impl Into<PathBuf>) -> notify::Result<Self> {
+        let dir = dir.into();
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        Ok(Self { dir, _watcher: watcher, events })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the file names touched since the previous call, without blocking.
+    /// Editors often emit several events per save; they are collapsed here.
+    pub fn changed_files(&self) -> BTreeSet<String> {
+        let mut changed = BTreeSet::new();
+        for event in self.events.try_iter() {
+            match event {
+                Ok(event) if event.kind.is_create() || event.kind.is_modify() => {
+                    changed.extend(
+                        event.paths.iter()
+                            .filter_map(|path| path.file_name())
+                            .map(|name| name.to_string_lossy().into_owned()),
+                    );
+                }
+                Ok(_) => (),
+                Err(e) => log::warn!("Shader watcher error: {e}"),
+            }
+        }
+        changed
+    }
+}
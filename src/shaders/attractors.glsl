@@ -0,0 +1,33 @@
+// Persistent attractors shared by the particle compute shaders; must match
+// `Attractor` in attractors.rs.
+#ifndef ATTRACTORS_GLSL
+#define ATTRACTORS_GLSL
+
+struct Attractor {
+    vec2 pos;
+    float strength;
+    float radius;
+};
+
+layout(std430, binding = 7) readonly buffer Attractors {
+    uint attractorCount;
+    Attractor attractors[];
+};
+
+// Acceleration at `pos` from every attractor, falling off with the square of
+// the distance outside its radius and constant within it.
+vec3 attractorForce(vec3 pos) {
+    vec3 force = vec3(0.0);
+    for (uint i = 0u; i < attractorCount; i++) {
+        Attractor attractor = attractors[i];
+        vec3 toAttractor = vec3(attractor.pos, 0.0) - pos;
+        float dist = length(toAttractor);
+        if (dist > 0.0) {
+            float clamped = max(dist, attractor.radius);
+            force += toAttractor / dist * attractor.strength / (clamped * clamped);
+        }
+    }
+    return force;
+}
+
+#endif
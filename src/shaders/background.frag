@@ -0,0 +1,14 @@
+#version 450
+
+layout(location = 0) in vec2 inUv;
+layout(location = 0) out vec4 outFragColor;
+
+// Already in the color attachment's encoding, so they are written as they are.
+layout(push_constant) uniform PushConstants {
+    vec4 top;
+    vec4 bottom;
+} pc;
+
+void main() {
+    outFragColor = mix(pc.top, pc.bottom, inUv.y);
+}
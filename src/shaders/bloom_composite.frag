@@ -0,0 +1,18 @@
+#version 450
+
+layout(location = 0) in vec2 inUv;
+layout(location = 0) out vec4 outFragColor;
+
+layout(set = 0, binding = 0) uniform sampler2D scene;
+layout(set = 0, binding = 1) uniform sampler2D bloom;
+
+layout(push_constant) uniform PushConstants {
+    float intensity;
+} pc;
+
+// Adds the blurred highlights back onto the scene. Both are sampled with
+// linear filtering, which also scales a scene of another size to the window.
+void main() {
+    vec3 color = texture(scene, inUv).rgb + texture(bloom, inUv).rgb * pc.intensity;
+    outFragColor = vec4(color, 1.0);
+}
@@ -0,0 +1,26 @@
+#version 450
+
+layout(location = 0) in vec2 inUv;
+layout(location = 1) in vec4 inColor;
+layout(location = 0) out vec4 outFragColor;
+
+layout(set = 0, binding = 0) uniform sampler2D tex;
+
+layout(push_constant) uniform PushConstants {
+    // Set when the framebuffer is sRGB and so expects linear output.
+    layout(offset = 8) uint linearOutput;
+} pc;
+
+vec3 srgbToLinear(vec3 srgb) {
+    return mix(srgb / 12.92, pow((srgb + 0.055) / 1.055, vec3(2.4)), greaterThan(srgb, vec3(0.04045)));
+}
+
+// egui colors and textures are gamma-encoded with premultiplied alpha, and
+// egui expects them to be blended as they are.
+void main() {
+    vec4 color = inColor * texture(tex, inUv);
+    if (pc.linearOutput != 0u) {
+        color.rgb = srgbToLinear(color.rgb);
+    }
+    outFragColor = color;
+}
@@ -0,0 +1,19 @@
+#version 450
+
+layout(location = 0) in vec2 inPos;
+layout(location = 1) in vec2 inUv;
+layout(location = 2) in vec4 inColor;
+layout(location = 0) out vec2 outUv;
+layout(location = 1) out vec4 outColor;
+
+layout(push_constant) uniform PushConstants {
+    // Render target size in egui points.
+    vec2 screenSize;
+} pc;
+
+// egui positions are in points from the top-left corner, y down like Vulkan's NDC.
+void main() {
+    gl_Position = vec4(2.0 * inPos / pc.screenSize - 1.0, 0.0, 1.0);
+    outUv = inUv;
+    outColor = inColor;
+}
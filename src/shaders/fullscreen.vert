@@ -0,0 +1,10 @@
+#version 450
+
+layout(location = 0) out vec2 outUv;
+
+// A single triangle covering the whole screen, built from gl_VertexIndex alone
+// (draw 3 vertices, no vertex buffer). uv (0, 0) is the top-left corner.
+void main() {
+    outUv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    gl_Position = vec4(outUv * 2.0 - 1.0, 0.0, 1.0);
+}
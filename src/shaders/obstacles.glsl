@@ -0,0 +1,57 @@
+// Static obstacles shared by the particle compute shaders; must match
+// `Obstacle` in obstacles.rs. Obstacles are 2D shapes, extruded along z in 3D.
+#ifndef OBSTACLES_GLSL
+#define OBSTACLES_GLSL
+
+const uint OBSTACLE_CIRCLE = 0u;
+const uint OBSTACLE_BOX = 1u;
+
+struct Obstacle {
+    vec2 center;
+    // Radius in x for circles, half width and height for boxes.
+    vec2 extent;
+    uint shape;
+    // Fraction of the speed into the surface kept when bouncing off it.
+    float restitution;
+};
+
+layout(std430, binding = 6) readonly buffer Obstacles {
+    uint obstacleCount;
+    Obstacle obstacles[];
+};
+
+// Pushes `pos` out of every obstacle it has entered, through the nearest
+// surface, and reflects the velocity into it. A particle exactly at a circle's
+// center, e.g. spawned there, leaves upward instead of dividing by zero.
+void collideObstacles(inout vec3 pos, inout vec3 vel) {
+    for (uint i = 0u; i < obstacleCount; i++) {
+        Obstacle obstacle = obstacles[i];
+        vec2 offset = pos.xy - obstacle.center;
+        float depth;
+        vec2 normal;
+        if (obstacle.shape == OBSTACLE_CIRCLE) {
+            float dist = length(offset);
+            depth = obstacle.extent.x - dist;
+            normal = dist > 0.0 ? offset / dist : vec2(0.0, 1.0);
+        } else {
+            vec2 penetration = obstacle.extent - abs(offset);
+            if (penetration.x < penetration.y) {
+                depth = penetration.x;
+                normal = vec2(offset.x < 0.0 ? -1.0 : 1.0, 0.0);
+            } else {
+                depth = penetration.y;
+                normal = vec2(0.0, offset.y < 0.0 ? -1.0 : 1.0);
+            }
+        }
+        if (depth <= 0.0) {
+            continue;
+        }
+        pos.xy += normal * depth;
+        float into = dot(vel.xy, normal);
+        if (into < 0.0) {
+            vel.xy -= (1.0 + obstacle.restitution) * into * normal;
+        }
+    }
+}
+
+#endif
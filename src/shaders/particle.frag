@@ -1,8 +1,50 @@
 #version 450
 
-layout(location = 0) in vec3 inColor;
+#define SHAPE_SQUARE 0u
+#define SHAPE_DISC 1u
+#define SHAPE_SPRITE 2u
+
+layout(location = 0) in vec4 inColor;
 layout(location = 0) out vec4 outFragColor;
 
+// Set when the color attachment is an sRGB format that encodes on write.
+layout(constant_id = 0) const bool SRGB_TARGET = false;
+
+// Only sampled with SHAPE_SPRITE; a white texel otherwise.
+layout(set = 0, binding = 1) uniform sampler2D sprite;
+
+layout(push_constant) uniform PushConstants {
+    layout(offset = 12) float edgeSoftness;
+    uint pointShape;
+} pc;
+
+vec3 srgbToLinear(vec3 c) {
+    return mix(c / 12.92, pow((c + 0.055) / 1.055, vec3(2.4)), greaterThan(c, vec3(0.04045)));
+}
+
 void main() {
-    outFragColor = vec4(inColor, 1.0);
+    float coverage = 1.0;
+    vec4 texel = vec4(1.0);
+    if (pc.pointShape == SHAPE_SPRITE) {
+        texel = texture(sprite, gl_PointCoord);
+    } else if (pc.pointShape == SHAPE_DISC) {
+        // Signed distance to the disc edge, in units of the point radius.
+        float dist = length(gl_PointCoord * 2.0 - 1.0) - 1.0;
+        if (dist > 0.0) {
+            discard;
+        }
+        coverage = 1.0 - smoothstep(-max(pc.edgeSoftness, 1e-3), 0.0, dist);
+    }
+    float alpha = inColor.a * coverage * texel.a;
+    // Keep nearly invisible fragments out of the depth buffer, where they would
+    // hide the particles behind them.
+    if (alpha < 0.01) {
+        discard;
+    }
+    // Particle colors are authored in sRGB. An sRGB target expects linear
+    // values and encodes them itself; a UNORM one stores them as they are.
+    // Sprite texels are sRGB values too and tint the color.
+    vec3 tinted = inColor.rgb * texel.rgb;
+    vec3 color = SRGB_TARGET ? srgbToLinear(tinted) : tinted;
+    outFragColor = vec4(color, alpha);
 }
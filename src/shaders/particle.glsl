@@ -0,0 +1,184 @@
+// Shared by every shader that reads the particle buffers; must match
+// `Particle` in particles.rs (64 bytes under std430), and `HalfParticle` there
+// with PARTICLE_HALF.
+#ifndef PARTICLE_GLSL
+#define PARTICLE_GLSL
+
+#ifdef PARTICLE_BDA
+#extension GL_EXT_buffer_reference : require
+#endif
+#ifdef PARTICLE_F16_STORAGE
+#extension GL_EXT_shader_16bit_storage : require
+#extension GL_EXT_shader_explicit_arithmetic_types_float16 : require
+#endif
+
+struct Particle {
+    vec3 pos;
+    float size;
+    vec3 vel;
+    float mass;
+    vec4 color;
+    float life;
+    float maxLife;
+    // Index in the initial layout; stays with the particle as steps reorder them.
+    uint id;
+    // Index into the SimParams kinds array.
+    uint kind;
+};
+
+// How one kind of particle departs from the SimParams it shares; must match
+// `KindParams` in particles.rs.
+const uint MAX_PARTICLE_KINDS = 4u;
+
+struct KindParams {
+    float gravityScale;
+    float noiseWeight;
+    float dragScale;
+    float _padding;
+    vec4 color;
+};
+
+// Position and velocity per particle, grouped by cell by the spatial grid,
+// with the particle's index in the buffer the grid was built from.
+struct Neighbor {
+    vec3 pos;
+    uint index;
+    vec3 vel;
+};
+
+// A particle's distance from the depth sort's focal point, and where it is.
+struct SortKey {
+    float key;
+    uint index;
+};
+
+// Particle buffers are declared as arrays of the vec4s particles are made of,
+// and read and written through the macros below, which follow ParticleFormat
+// in particles.rs: interleaved by default, or with PARTICLE_SOA all particles'
+// first vec4s, then all their second ones and so on. With PARTICLE_HALF the
+// velocity, mass and color are half floats packed into a single vec4, which
+// shaders built with PARTICLE_F16_STORAGE read and write as the halves they
+// are, and the rest through packHalf2x16.
+layout(constant_id = 2) const bool PARTICLE_SOA = false;
+layout(constant_id = 3) const bool PARTICLE_HALF = false;
+
+// The vec4s of a particle.
+const uint FIELD_POSITION_SIZE = 0u;
+const uint FIELD_VELOCITY_MASS = 1u;
+const uint FIELD_COLOR = PARTICLE_HALF ? 1u : 2u;
+const uint FIELD_LIFE = PARTICLE_HALF ? 2u : 3u;
+const uint PARTICLE_VEC4S = PARTICLE_HALF ? 3u : 4u;
+
+uint particleSlot(uint index, uint field, uint count) {
+    return PARTICLE_SOA ? field * count + index : index * PARTICLE_VEC4S + field;
+}
+
+// Four half floats in the bits of two floats, and back.
+vec4 unpackHalf4(vec2 packed) {
+    return vec4(unpackHalf2x16(floatBitsToUint(packed.x)), unpackHalf2x16(floatBitsToUint(packed.y)));
+}
+
+vec2 packHalf4(vec4 value) {
+    return vec2(uintBitsToFloat(packHalf2x16(value.xy)), uintBitsToFloat(packHalf2x16(value.zw)));
+}
+
+// Velocity and mass, and color, from the stored FIELD_VELOCITY_MASS and FIELD_COLOR.
+vec4 unpackVelocityMass(vec4 field) {
+    return PARTICLE_HALF ? unpackHalf4(field.xy) : field;
+}
+
+vec4 unpackColor(vec4 field) {
+    return PARTICLE_HALF ? unpackHalf4(field.zw) : field;
+}
+
+// With PARTICLE_F16_STORAGE, defined by SimPipelines for F16 formats on
+// devices with shaderFloat16 and storageBuffer16BitAccess, the FIELD_VELOCITY_MASS
+// vec4 of a particle buffer `data` is also reached as one of these, through
+// data##Half: a view declared with PARTICLE_HALF_VIEW, aliasing the binding.
+#ifdef PARTICLE_F16_STORAGE
+struct HalfFields {
+    f16vec4 velocityMass;
+    f16vec4 color;
+};
+
+#define PARTICLE_HALF_VIEW(binding_, access, data) \
+    layout(std430, binding = binding_) access buffer data##HalfView { HalfFields data##Half[]; };
+#else
+#define PARTICLE_HALF_VIEW(binding_, access, data)
+#endif
+
+Particle unpackParticle(vec4 positionSize, vec4 velocityMass, vec4 color, vec4 life) {
+    velocityMass = unpackVelocityMass(velocityMass);
+    return Particle(
+        positionSize.xyz, positionSize.w,
+        velocityMass.xyz, velocityMass.w,
+        unpackColor(color),
+        life.x, life.y, floatBitsToUint(life.z), floatBitsToUint(life.w)
+    );
+}
+
+#ifdef PARTICLE_F16_STORAGE
+Particle halfFieldsParticle(vec4 positionSize, HalfFields fields, vec4 life) {
+    return Particle(
+        positionSize.xyz, positionSize.w,
+        vec3(fields.velocityMass.xyz), float(fields.velocityMass.w),
+        vec4(fields.color),
+        life.x, life.y, floatBitsToUint(life.z), floatBitsToUint(life.w)
+    );
+}
+#endif
+
+// With PARTICLE_BDA, defined by SimPipelines for behaviors that support it,
+// the particle buffers are reached through device addresses in the push
+// constants instead of bindings, as PARTICLE_ADDRESS members. Without it the
+// members are still declared, for the push constants to keep their size.
+#ifdef PARTICLE_BDA
+layout(std430, buffer_reference, buffer_reference_align = 16) buffer ParticleData {
+    vec4 data[];
+};
+#define PARTICLE_ADDRESS ParticleData
+#else
+#define PARTICLE_ADDRESS uvec2
+#endif
+
+// `data` is the vec4 array of a particle buffer. Those reached by address have
+// no length, so the push constants carry it.
+#ifdef PARTICLE_BDA
+#define PARTICLE_COUNT(data) pc.particleCapacity
+#else
+#define PARTICLE_COUNT(data) (uint(data.length()) / PARTICLE_VEC4S)
+#endif
+#define PARTICLE_FIELD(data, index, field) data[particleSlot(index, field, PARTICLE_COUNT(data))]
+#ifdef PARTICLE_F16_STORAGE
+#define PARTICLE_HALF_FIELDS(data, index) data##Half[particleSlot(index, FIELD_VELOCITY_MASS, PARTICLE_COUNT(data))]
+#define PARTICLE_VELOCITY_MASS(data, index) vec4(PARTICLE_HALF_FIELDS(data, index).velocityMass)
+#define LOAD_PARTICLE(data, index) halfFieldsParticle( \
+    PARTICLE_FIELD(data, index, FIELD_POSITION_SIZE), \
+    PARTICLE_HALF_FIELDS(data, index), \
+    PARTICLE_FIELD(data, index, FIELD_LIFE))
+#define STORE_HALF_FIELDS(data, index, particle) \
+    PARTICLE_HALF_FIELDS(data, index) = HalfFields(f16vec4(vec4(particle.vel, particle.mass)), f16vec4(particle.color));
+#else
+#define PARTICLE_VELOCITY_MASS(data, index) unpackVelocityMass(PARTICLE_FIELD(data, index, FIELD_VELOCITY_MASS))
+#define LOAD_PARTICLE(data, index) unpackParticle( \
+    PARTICLE_FIELD(data, index, FIELD_POSITION_SIZE), \
+    PARTICLE_FIELD(data, index, FIELD_VELOCITY_MASS), \
+    PARTICLE_FIELD(data, index, FIELD_COLOR), \
+    PARTICLE_FIELD(data, index, FIELD_LIFE))
+#define STORE_HALF_FIELDS(data, index, particle) \
+    PARTICLE_FIELD(data, index, FIELD_VELOCITY_MASS) = \
+        vec4(packHalf4(vec4(particle.vel, particle.mass)), packHalf4(particle.color));
+#endif
+#define STORE_PARTICLE(data, index, particle) { \
+    Particle stored_ = particle; \
+    PARTICLE_FIELD(data, index, FIELD_POSITION_SIZE) = vec4(stored_.pos, stored_.size); \
+    if (PARTICLE_HALF) { \
+        STORE_HALF_FIELDS(data, index, stored_) \
+    } else { \
+        PARTICLE_FIELD(data, index, FIELD_VELOCITY_MASS) = vec4(stored_.vel, stored_.mass); \
+        PARTICLE_FIELD(data, index, FIELD_COLOR) = stored_.color; \
+    } \
+    PARTICLE_FIELD(data, index, FIELD_LIFE) = vec4(stored_.life, stored_.maxLife, uintBitsToFloat(stored_.id), uintBitsToFloat(stored_.kind)); \
+}
+
+#endif
@@ -1,10 +1,23 @@
 #version 450
 
-layout(location = 0) in vec2 inPos;
-layout(location = 0) out vec3 outColor;
+layout(location = 0) in vec3 inPos;
+layout(location = 1) in vec4 inColor;
+layout(location = 2) in float inSize;
+layout(location = 0) out vec4 outColor;
+
+layout(push_constant) uniform PushConstants {
+    float sizeScale;
+    // The device's supported point size range.
+    float minPointSize;
+    float maxPointSize;
+} pc;
+
+layout(set = 0, binding = 0) uniform Camera {
+    mat4 viewProjection;
+} camera;
 
 void main() {
-    gl_Position = vec4(inPos, 0.0, 1.0);
-    gl_PointSize = 2.0;
-    outColor = vec3(1.0, 1.0, 1.0);
+    gl_Position = camera.viewProjection * vec4(inPos, 1.0);
+    gl_PointSize = clamp(inSize * pc.sizeScale, pc.minPointSize, pc.maxPointSize);
+    outColor = inColor;
 }
@@ -0,0 +1,47 @@
+// Smoothing kernels of the fluid mode: the 2D forms of those in "Particle-Based
+// Fluid Simulation for Interactive Applications" (Müller et al. 2003), for
+// neighbors `r` apart with smoothing radius `h`.
+#ifndef SPH_GLSL
+#define SPH_GLSL
+
+const float SPH_PI = 3.14159265;
+
+// Caps the work per neighboring cell so a particle crushed into a corner
+// can't stall the step. Both passes stop at the same neighbors.
+const uint SPH_MAX_NEIGHBORS_PER_CELL = 48u;
+
+// Poly6, for density.
+float poly6(float r, float h) {
+    float d = h * h - r * r;
+    return d > 0.0 ? 4.0 / (SPH_PI * pow(h, 8.0)) * d * d * d : 0.0;
+}
+
+// Length of the spiky kernel's gradient, for pressure; it points away from the neighbor.
+float spikyGradient(float r, float h) {
+    float d = h - r;
+    return d > 0.0 ? 30.0 / (SPH_PI * pow(h, 5.0)) * d * d : 0.0;
+}
+
+// Laplacian of the viscosity kernel.
+float viscosityLaplacian(float r, float h) {
+    return r < h ? 40.0 / (SPH_PI * pow(h, 5.0)) * (h - r) : 0.0;
+}
+
+// Mass of every particle, making the density of particles half a smoothing
+// radius apart about 1, whatever the radius.
+float particleMass(float h) {
+    return 0.25 * h * h;
+}
+
+// The first and last cells, in x and y, of the `gridSize` square grid over
+// `halfSide` that can hold neighbors within `h` of `pos`; see grid_count.comp.
+void neighborCells(vec2 pos, float h, float halfSide, uint gridSize, out ivec2 lo, out ivec2 hi) {
+    float cellWidth = 2.0 * halfSide / float(gridSize);
+    int reach = int(ceil(h / cellWidth));
+    vec2 scaled = (pos / halfSide * 0.5 + 0.5) * float(gridSize);
+    ivec2 cell = ivec2(clamp(scaled, vec2(0.0), vec2(gridSize - 1u)));
+    lo = max(cell - reach, ivec2(0));
+    hi = min(cell + reach, ivec2(gridSize - 1u));
+}
+
+#endif
@@ -0,0 +1,62 @@
+use std::path::Path;
+use bytemuck::{Pod, Zeroable};
+use crate::error::VulkanDemoError;
+use crate::particles::Particle;
+
+const MAGIC: [u8; 8] = *b"VKPSNAP\0";
+/// Bumped whenever `Particle` changes in a way its size doesn't reveal, such
+/// as reordered fields.
+const VERSION: u32 = 1;
+
+/// Leads a snapshot file, followed by `count` particles of `particle_size`
+/// bytes each. Everything is in the byte order of the machine that wrote it.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct SnapshotHeader {
+    magic: [u8; 8],
+    version: u32,
+    count: u32,
+    particle_size: u32,
+    _padding: u32,
+}
+
+/// Writes `particles`, as returned by `ParticleSystem::snapshot`, to `path`.
+pub fn write_snapshot(path: &Path, particles: &[Particle]) -> Result<(), VulkanDemoError> {
+    let header = SnapshotHeader {
+        magic: MAGIC,
+        version: VERSION,
+        count: particles.len() as u32,
+        particle_size: size_of::<Particle>() as u32,
+        _padding: 0,
+    };
+    let mut bytes = bytemuck::bytes_of(&header).to_vec();
+    bytes.extend_from_slice(bytemuck::cast_slice(particles));
+    std::fs::write(path, bytes).map_err(|error| VulkanDemoError::SnapshotIo { path: path.to_path_buf(), error })
+}
+
+/// Reads a snapshot written by `write_snapshot`. Files from another version of
+/// the demo, whose particles may be laid out differently, are rejected.
+pub fn read_snapshot(path: &Path) -> Result<Vec<Particle>, VulkanDemoError> {
+    let invalid = |reason: String| VulkanDemoError::InvalidSnapshot { path: path.to_path_buf(), reason };
+    let bytes = std::fs::read(path).map_err(|error| VulkanDemoError::SnapshotIo { path: path.to_path_buf(), error })?;
+    if bytes.len() < size_of::<SnapshotHeader>() {
+        return Err(invalid("too short for a header".to_string()));
+    }
+    let (header, body) = bytes.split_at(size_of::<SnapshotHeader>());
+    let header: SnapshotHeader = bytemuck::pod_read_unaligned(header);
+    if header.magic != MAGIC {
+        return Err(invalid("not a particle snapshot".to_string()));
+    }
+    if header.version != VERSION || header.particle_size as usize != size_of::<Particle>() {
+        return Err(invalid(format!(
+            "version {} with {}-byte particles, expected version {VERSION} with {}-byte ones",
+            header.version,
+            header.particle_size,
+            size_of::<Particle>(),
+        )));
+    }
+    if header.count == 0 || body.len() != header.count as usize * size_of::<Particle>() {
+        return Err(invalid(format!("{} bytes of particle data for {} particles", body.len(), header.count)));
+    }
+    Ok(body.chunks_exact(size_of::<Particle>()).map(bytemuck::pod_read_unaligned).collect())
+}
@@ -0,0 +1,306 @@
+use ash::vk;
+use std::mem::size_of;
+use crate::allocator::MemoryLocation;
+use crate::error::VulkanDemoError;
+use crate::gpu_scan::{GpuScan, ScanBinding};
+use crate::memory::create_buffer;
+use crate::particles::{create_compute_pipeline, ComputeSpecialization, ParticleFormat};
+use crate::pipeline_utils::{compile_shader, specialization_info, ShaderCompileOptions};
+use crate::resources::{OwnedBuffer, OwnedDescriptorPool, OwnedDescriptorSetLayout, OwnedPipeline, OwnedPipelineLayout};
+use crate::vulkan_context::VulkanContext;
+
+/// Largest number of cells along each axis; the cell tables are sized for it.
+pub const MAX_GRID_SIZE: u32 = 256;
+pub const DEFAULT_GRID_SIZE: u32 = 128;
+const MAX_CELLS: u32 = MAX_GRID_SIZE * MAX_GRID_SIZE;
+
+// Binding numbers shared by the grid shaders.
+const BINDING_PARTICLES: u32 = 0;
+const BINDING_PARAMS: u32 = 1;
+const BINDING_CELL_COUNTS: u32 = 2;
+const BINDING_CELL_RANGES: u32 = 3;
+const BINDING_PARTICLE_CELLS: u32 = 4;
+const BINDING_PARTICLE_OFFSETS: u32 = 5;
+const BINDING_SORTED_PARTICLES: u32 = 6;
+const BINDING_CELL_STARTS: u32 = 7;
+
+/// How many workgroups a pass is dispatched with.
+#[derive(Copy, Clone, Debug)]
+enum Workgroups {
+    /// One invocation per particle, in workgroups of the particle system's size.
+    PerParticle,
+    /// One invocation per cell of the current grid size.
+    PerCell,
+}
+
+/// One dispatch in the grid build, followed by a barrier so the next pass sees its writes.
+struct GridPass {
+    name: &'static str,
+    pipeline: OwnedPipeline,
+    workgroups: Workgroups,
+}
+
+/// Bins particles into a uniform grid over the square around the world
+/// rectangle each step, so neighbor forces only have to look at the 3x3 cells
+/// around a particle. In 3D the grid still only splits x and y; neighbor tests
+/// use the full distance.
+///
+/// A counting sort: `grid_count.comp` finds each particle's cell and its slot
+/// within it, a `GpuScan` of the per-cell counts gives each cell's start,
+/// `grid_ranges.comp` turns those into `[start, end)` ranges, and
+/// `grid_scatter.comp` writes positions and velocities into `sorted_particles`
+/// grouped by cell. All passes read the same input buffer and parameters as
+/// the simulation step, so the descriptor sets follow its ping-pong index and
+/// frame in flight.
+pub struct SpatialGrid {
+    /// Counting, then after the scan the rest of the passes in order.
+    passes: Vec<GridPass>,
+    /// Scans `cell_counts` into `cell_starts`.
+    scan_binding: ScanBinding,
+    pipeline_layout: OwnedPipelineLayout,
+    /// Set `2 * frame + i` reads particle buffer `i` with the parameters of frame in flight `frame`.
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    _descriptor_pool: OwnedDescriptorPool,
+    _descriptor_set_layout: OwnedDescriptorSetLayout,
+    cell_counts: OwnedBuffer,
+    cell_ranges: OwnedBuffer,
+    _cell_starts: OwnedBuffer,
+    _particle_cells: OwnedBuffer,
+    _particle_offsets: OwnedBuffer,
+    sorted_particles: OwnedBuffer,
+    count: u32,
+    workgroup_size: u32,
+}
+
+impl SpatialGrid {
+    /// Bytes of device-local memory the per-particle buffers of a grid over
+    /// `count` particles take.
+    pub fn memory_size(count: u32) -> vk::DeviceSize {
+        count as vk::DeviceSize * (2 * size_of::<u32>() + size_of::<[[f32; 4]; 2]>()) as vk::DeviceSize
+    }
+
+    /// `particle_buffers` are the ping-pong pair and `params_buffers` the
+    /// parameters of each frame in flight; set `2 * frame + i` reads
+    /// `particle_buffers[i]` and `params_buffers[frame]`. `scan` is what
+    /// `record` is later given.
+    pub fn new(
+        context: &VulkanContext,
+        scan: &GpuScan,
+        particle_buffers: &[OwnedBuffer; 2],
+        params_buffers: &[&OwnedBuffer],
+        count: u32,
+        workgroup_size: u32,
+        format: ParticleFormat,
+    ) -> Result<Self, VulkanDemoError> {
+        let device = &context.device;
+        let storage = |size: vk::DeviceSize, usage: vk::BufferUsageFlags| {
+            create_buffer(context, size, vk::BufferUsageFlags::STORAGE_BUFFER | usage, MemoryLocation::GpuOnly)
+        };
+        let per_particle = count as vk::DeviceSize * size_of::<u32>() as vk::DeviceSize;
+        let max_cells = MAX_CELLS as vk::DeviceSize;
+        let cell_counts = storage(max_cells * size_of::<u32>() as vk::DeviceSize, vk::BufferUsageFlags::TRANSFER_DST)?;
+        let cell_ranges = storage(max_cells * size_of::<[u32; 2]>() as vk::DeviceSize, vk::BufferUsageFlags::empty())?;
+        let cell_starts = storage(max_cells * size_of::<u32>() as vk::DeviceSize, vk::BufferUsageFlags::empty())?;
+        let particle_cells = storage(per_particle, vk::BufferUsageFlags::empty())?;
+        let particle_offsets = storage(per_particle, vk::BufferUsageFlags::empty())?;
+        let sorted_particles = storage(count as vk::DeviceSize * size_of::<[[f32; 4]; 2]>() as vk::DeviceSize, vk::BufferUsageFlags::empty())?;
+
+        let binding = |binding: u32, descriptor_type: vk::DescriptorType| {
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(binding)
+                .descriptor_type(descriptor_type)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        };
+        let layout_bindings = [
+            binding(BINDING_PARTICLES, vk::DescriptorType::STORAGE_BUFFER),
+            binding(BINDING_PARAMS, vk::DescriptorType::UNIFORM_BUFFER),
+            binding(BINDING_CELL_COUNTS, vk::DescriptorType::STORAGE_BUFFER),
+            binding(BINDING_CELL_RANGES, vk::DescriptorType::STORAGE_BUFFER),
+            binding(BINDING_PARTICLE_CELLS, vk::DescriptorType::STORAGE_BUFFER),
+            binding(BINDING_PARTICLE_OFFSETS, vk::DescriptorType::STORAGE_BUFFER),
+            binding(BINDING_SORTED_PARTICLES, vk::DescriptorType::STORAGE_BUFFER),
+            binding(BINDING_CELL_STARTS, vk::DescriptorType::STORAGE_BUFFER),
+        ];
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(&layout_bindings);
+        let descriptor_set_layout = OwnedDescriptorSetLayout::new(
+            device,
+            unsafe { device.create_descriptor_set_layout(&layout_info, None)? },
+        );
+
+        let set_count = 2 * params_buffers.len() as u32;
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(7 * set_count),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(set_count),
+        ];
+        let pool_info = vk::DescriptorPoolCreateInfo::default()
+            .pool_sizes(&pool_sizes)
+            .max_sets(set_count);
+        let descriptor_pool = OwnedDescriptorPool::new(
+            device,
+            unsafe { device.create_descriptor_pool(&pool_info, None)? },
+        );
+
+        let set_layouts = vec![descriptor_set_layout.handle(); set_count as usize];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool.handle())
+            .set_layouts(&set_layouts);
+        let descriptor_sets = unsafe { device.allocate_descriptor_sets(&alloc_info)? };
+
+        let whole = |buffer: &OwnedBuffer| {
+            vk::DescriptorBufferInfo::default()
+                .buffer(buffer.handle())
+                .offset(0)
+                .range(vk::WHOLE_SIZE)
+        };
+        for (index, &descriptor_set) in descriptor_sets.iter().enumerate() {
+            let (particles, params_buffer) = (&particle_buffers[index % 2], params_buffers[index / 2]);
+            let infos = [
+                (BINDING_PARTICLES, vk::DescriptorType::STORAGE_BUFFER, whole(particles)),
+                (BINDING_PARAMS, vk::DescriptorType::UNIFORM_BUFFER, whole(params_buffer)),
+                (BINDING_CELL_COUNTS, vk::DescriptorType::STORAGE_BUFFER, whole(&cell_counts)),
+                (BINDING_CELL_RANGES, vk::DescriptorType::STORAGE_BUFFER, whole(&cell_ranges)),
+                (BINDING_PARTICLE_CELLS, vk::DescriptorType::STORAGE_BUFFER, whole(&particle_cells)),
+                (BINDING_PARTICLE_OFFSETS, vk::DescriptorType::STORAGE_BUFFER, whole(&particle_offsets)),
+                (BINDING_SORTED_PARTICLES, vk::DescriptorType::STORAGE_BUFFER, whole(&sorted_particles)),
+                (BINDING_CELL_STARTS, vk::DescriptorType::STORAGE_BUFFER, whole(&cell_starts)),
+            ];
+            let writes: Vec<_> = infos.iter().map(|(binding, descriptor_type, info)| {
+                vk::WriteDescriptorSet::default()
+                    .dst_set(descriptor_set)
+                    .dst_binding(*binding)
+                    .descriptor_type(*descriptor_type)
+                    .buffer_info(std::slice::from_ref(info))
+            }).collect();
+            unsafe { device.update_descriptor_sets(&writes, &[]) };
+        }
+
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default().set_layouts(&set_layouts[..1]);
+        let pipeline_layout = OwnedPipelineLayout::new(
+            device,
+            unsafe { device.create_pipeline_layout(&pipeline_layout_info, None)? },
+        );
+
+        let sources = [
+            ("grid_count.comp", include_str!("shaders/grid_count.comp"), Workgroups::PerParticle),
+            ("grid_ranges.comp", include_str!("shaders/grid_ranges.comp"), Workgroups::PerCell),
+            ("grid_scatter.comp", include_str!("shaders/grid_scatter.comp"), Workgroups::PerParticle),
+        ];
+        let constants = ComputeSpecialization::new(workgroup_size, format);
+        let specialization = specialization_info(&constants);
+        let passes = sources.into_iter().map(|(name, source, workgroups)| {
+            let spirv = compile_shader(source, name, shaderc::ShaderKind::Compute, &ShaderCompileOptions::default())?;
+            let pipeline = create_compute_pipeline(device, context.pipeline_cache, pipeline_layout.handle(), &spirv, Some(&specialization))?;
+            Ok(GridPass { name, pipeline, workgroups })
+        }).collect::<Result<Vec<_>, VulkanDemoError>>()?;
+        log::debug!("Spatial grid passes: {:?}", passes.iter().map(|pass| pass.name).collect::<Vec<_>>());
+        let scan_binding = scan.bind(context, &cell_counts, &cell_starts, MAX_CELLS)?;
+
+        Ok(Self {
+            passes,
+            scan_binding,
+            pipeline_layout,
+            descriptor_sets,
+            _descriptor_pool: descriptor_pool,
+            _descriptor_set_layout: descriptor_set_layout,
+            cell_counts,
+            cell_ranges,
+            _cell_starts: cell_starts,
+            _particle_cells: particle_cells,
+            _particle_offsets: particle_offsets,
+            sorted_particles,
+            count,
+            workgroup_size,
+        })
+    }
+
+    /// `[start, end)` indices into `sorted_particles` for each cell, row by row.
+    pub fn cell_ranges(&self) -> &OwnedBuffer {
+        &self.cell_ranges
+    }
+
+    /// Position and velocity of every particle, grouped by cell.
+    pub fn sorted_particles(&self) -> &OwnedBuffer {
+        &self.sorted_particles
+    }
+
+    /// Records the grid build for a step with descriptor set `set`, as numbered
+    /// in `new`, with `grid_size` cells along each axis, using the `scan` the
+    /// grid was made with. Ends with a barrier making the tables visible to the
+    /// following simulation dispatch.
+    pub fn record(&self, device: &ash::Device, cmd: vk::CommandBuffer, scan: &GpuScan, set: usize, grid_size: u32) {
+        let cell_count = grid_size * grid_size;
+        unsafe {
+            // The previous build's atomics must finish before the counts are cleared.
+            memory_barrier(
+                device,
+                cmd,
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+                (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+            );
+            device.cmd_fill_buffer(cmd, self.cell_counts.handle(), 0, cell_count as vk::DeviceSize * size_of::<u32>() as vk::DeviceSize, 0);
+            memory_barrier(
+                device,
+                cmd,
+                (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE),
+            );
+
+            let bind_descriptor_set = || {
+                device.cmd_bind_descriptor_sets(
+                    cmd,
+                    vk::PipelineBindPoint::COMPUTE,
+                    self.pipeline_layout.handle(),
+                    0,
+                    &[self.descriptor_sets[set]],
+                    &[],
+                );
+            };
+            bind_descriptor_set();
+            for (index, pass) in self.passes.iter().enumerate() {
+                if index == 1 {
+                    // The scan ends with its own barrier and binds its own set.
+                    scan.record(device, cmd, &self.scan_binding, cell_count);
+                    bind_descriptor_set();
+                }
+                let groups = match pass.workgroups {
+                    Workgroups::PerParticle => self.count.div_ceil(self.workgroup_size),
+                    Workgroups::PerCell => cell_count.div_ceil(self.workgroup_size),
+                };
+                device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, pass.pipeline.handle());
+                device.cmd_dispatch(cmd, groups, 1, 1);
+                memory_barrier(
+                    device,
+                    cmd,
+                    (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+                    (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE),
+                );
+            }
+        }
+    }
+}
+
+/// A global memory barrier from one `(stage, access)` pair to another.
+pub(crate) unsafe fn memory_barrier(
+    device: &ash::Device,
+    cmd: vk::CommandBuffer,
+    (src_stage, src_access): (vk::PipelineStageFlags, vk::AccessFlags),
+    (dst_stage, dst_access): (vk::PipelineStageFlags, vk::AccessFlags),
+) {
+    let barrier = vk::MemoryBarrier::default()
+        .src_access_mask(src_access)
+        .dst_access_mask(dst_access);
+    device.cmd_pipeline_barrier(
+        cmd,
+        src_stage,
+        dst_stage,
+        vk::DependencyFlags::empty(),
+        &[barrier],
+        &[],
+        &[],
+    );
+}
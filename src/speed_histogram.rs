@@ -0,0 +1,242 @@
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use std::mem::size_of;
+use crate::allocator::MemoryLocation;
+use crate::compute_pass::{BufferBinding, ComputePass, ComputeShader};
+use crate::error::VulkanDemoError;
+use crate::frame_graph::{BufferUsage, RecordPass, RenderFrame};
+use crate::memory::{create_buffer, read_from_buffer};
+use crate::particles::{ComputeSpecialization, ParticleFormat, ParticleSystem};
+use crate::pipeline_utils::ShaderCompileOptions;
+use crate::resources::OwnedBuffer;
+use crate::spatial_grid::memory_barrier;
+use crate::sync::FRAMES_IN_FLIGHT;
+use crate::vulkan_context::VulkanContext;
+
+/// Buckets between zero and the system's max speed.
+pub const BIN_COUNT: usize = 64;
+
+// Binding numbers of `speed_histogram.comp`.
+const BINDING_PARTICLES: u32 = 0;
+const BINDING_DRAW_COUNTS: u32 = 1;
+const BINDING_HISTOGRAM: u32 = 2;
+
+/// The `Histogram` block of `speed_histogram.comp`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct HistogramCounts {
+    max_speed_bits: u32,
+    bins: [u32; BIN_COUNT],
+}
+
+/// How fast the live particles of a system were going in one frame.
+#[derive(Copy, Clone, Debug)]
+pub struct SpeedDistribution {
+    /// Live particles per bucket of `range / BIN_COUNT`; the last also holds
+    /// any faster ones.
+    pub bins: [u32; BIN_COUNT],
+    /// The speed the last bucket ends at.
+    pub range: f32,
+    pub max: f32,
+}
+
+impl SpeedDistribution {
+    pub fn count(&self) -> u32 {
+        self.bins.iter().sum()
+    }
+
+    /// Estimated from the middle of each bucket, so within half a bucket's width.
+    pub fn mean(&self) -> f32 {
+        let count = self.count();
+        if count == 0 {
+            return 0.0;
+        }
+        let width = self.range / BIN_COUNT as f32;
+        let sum: f32 = self.bins.iter().enumerate().map(|(bin, &n)| (bin as f32 + 0.5) * width * n as f32).sum();
+        sum / count as f32
+    }
+}
+
+/// What one frame in flight bins into and reads back.
+struct HistogramFrame {
+    /// Device-local `HistogramCounts`, cleared and accumulated into with atomics.
+    counts: OwnedBuffer,
+    /// Host-visible copy of `counts`.
+    readback: OwnedBuffer,
+    /// The range of the copy waiting in `readback`, if any.
+    pending_range: Option<f32>,
+}
+
+/// Bins the speeds of one particle system's live particles each frame, for the
+/// overlay and the stats.
+///
+/// Like `GpuTimer`, every frame in flight bins into and copies out of its own
+/// buffers, read back once `begin_frame` is called for it again after the
+/// frame has been waited for, so the distribution is a couple of frames old
+/// but reading it never stalls the GPU.
+pub struct SpeedHistogram {
+    /// Its descriptor set `i` is frame in flight `i`'s.
+    pass: ComputePass,
+    frames: Vec<HistogramFrame>,
+    /// The frame in flight being recorded.
+    frame: usize,
+    latest: Option<SpeedDistribution>,
+    workgroup_size: u32,
+}
+
+impl SpeedHistogram {
+    /// Specialized for particle buffers in `format`.
+    pub fn new(context: &VulkanContext, workgroup_size: u32, format: ParticleFormat) -> Result<Self, VulkanDemoError> {
+        let constants = ComputeSpecialization::new(workgroup_size, format);
+        let pass = ComputePass::new(
+            context,
+            ComputeShader::Glsl {
+                source: include_str!("shaders/speed_histogram.comp"),
+                filename: "speed_histogram.comp",
+                options: &ShaderCompileOptions::default(),
+            },
+            &[
+                (BINDING_PARTICLES, BufferBinding::Storage),
+                (BINDING_DRAW_COUNTS, BufferBinding::Storage),
+                (BINDING_HISTOGRAM, BufferBinding::Storage),
+            ],
+            size_of::<f32>() as u32,
+            Some(&constants),
+            FRAMES_IN_FLIGHT,
+        )?;
+
+        let counts_size = size_of::<HistogramCounts>() as vk::DeviceSize;
+        let frames = (0..FRAMES_IN_FLIGHT).map(|frame| {
+            let counts = create_buffer(
+                context,
+                counts_size,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::TRANSFER_SRC,
+                MemoryLocation::GpuOnly,
+            )?;
+            let readback = create_buffer(context, counts_size, vk::BufferUsageFlags::TRANSFER_DST, MemoryLocation::GpuToCpu)?;
+            context.debug.set_object_name(counts.handle(), "speed histogram");
+            pass.bind_buffers(&context.device, frame, &[(BINDING_HISTOGRAM, counts.handle())]);
+            Ok(HistogramFrame { counts, readback, pending_range: None })
+        }).collect::<Result<Vec<_>, VulkanDemoError>>()?;
+
+        Ok(Self {
+            pass,
+            frames,
+            frame: 0,
+            latest: None,
+            workgroup_size,
+        })
+    }
+
+    /// Switches to the buffers of frame in flight `frame`, first reading the
+    /// histogram it copied out last time. Must be called after that frame has
+    /// been waited for.
+    pub fn begin_frame(&mut self, frame: usize) -> Result<(), vk::Result> {
+        let histogram_frame = &mut self.frames[frame];
+        if let Some(range) = histogram_frame.pending_range.take() {
+            let bytes = read_from_buffer(&histogram_frame.readback, size_of::<HistogramCounts>())?;
+            let counts: HistogramCounts = bytemuck::pod_read_unaligned(&bytes);
+            self.latest = Some(SpeedDistribution { bins: counts.bins, range, max: f32::from_bits(counts.max_speed_bits) });
+        }
+        self.frame = frame;
+        Ok(())
+    }
+
+    /// The most recent distribution read back, if any has been yet.
+    pub fn latest(&self) -> Option<&SpeedDistribution> {
+        self.latest.as_ref()
+    }
+
+    /// Records binning the speeds of the live particles among the `count` in
+    /// `particles`, whose live count is in `draw_buffer`, into buckets up to
+    /// `range`, and copying the result out for a later `begin_frame`. Starts
+    /// with a barrier after the steps' writes on this queue.
+    pub fn record(
+        &mut self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        particles: vk::Buffer,
+        draw_buffer: vk::Buffer,
+        count: u32,
+        range: f32,
+    ) {
+        let histogram_frame = &mut self.frames[self.frame];
+        // The frame's previous use of the set has finished, and this one isn't recorded yet.
+        self.pass.bind_buffers(device, self.frame, &[(BINDING_PARTICLES, particles), (BINDING_DRAW_COUNTS, draw_buffer)]);
+        let counts = histogram_frame.counts.handle();
+        let counts_size = size_of::<HistogramCounts>() as vk::DeviceSize;
+        unsafe {
+            // Clear, then accumulate, then copy out: each waits on the last.
+            memory_barrier(
+                device,
+                cmd,
+                (
+                    vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::TRANSFER,
+                    vk::AccessFlags::SHADER_WRITE | vk::AccessFlags::TRANSFER_WRITE,
+                ),
+                (
+                    vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::TRANSFER,
+                    vk::AccessFlags::SHADER_READ | vk::AccessFlags::TRANSFER_WRITE,
+                ),
+            );
+            device.cmd_fill_buffer(cmd, counts, 0, counts_size, 0);
+            memory_barrier(
+                device,
+                cmd,
+                (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE),
+            );
+
+            self.pass.record(device, cmd, self.frame, [count.div_ceil(self.workgroup_size), 1, 1], bytemuck::bytes_of(&range));
+            memory_barrier(
+                device,
+                cmd,
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+                (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ),
+            );
+
+            let region = vk::BufferCopy::default().size(counts_size);
+            device.cmd_copy_buffer(cmd, counts, histogram_frame.readback.handle(), &[region]);
+            memory_barrier(
+                device,
+                cmd,
+                (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+                (vk::PipelineStageFlags::HOST, vk::AccessFlags::HOST_READ),
+            );
+        }
+        histogram_frame.pending_range = Some(range);
+    }
+
+    /// `record` for what `particle_system` draws after `steps` more steps,
+    /// binned up to its maximum speed, as a `FrameGraph` pass.
+    pub fn pass(&mut self, particle_system: &ParticleSystem, steps: usize) -> SpeedHistogramPass<'_> {
+        SpeedHistogramPass {
+            histogram: self,
+            particles: particle_system.buffer_after(steps),
+            draw_buffer: particle_system.indirect_buffer(),
+            count: particle_system.count,
+            range: particle_system.params.max_speed,
+        }
+    }
+}
+
+/// Made by `SpeedHistogram::pass`.
+pub struct SpeedHistogramPass<'a> {
+    histogram: &'a mut SpeedHistogram,
+    particles: vk::Buffer,
+    draw_buffer: vk::Buffer,
+    count: u32,
+    range: f32,
+}
+
+impl RecordPass for SpeedHistogramPass<'_> {
+    fn buffer_usages(&self) -> Vec<BufferUsage> {
+        let read = |buffer| BufferUsage::new(buffer, vk::PipelineStageFlags2::COMPUTE_SHADER, vk::AccessFlags2::SHADER_STORAGE_READ);
+        vec![read(self.particles), read(self.draw_buffer)]
+    }
+
+    fn record(&mut self, frame: &mut RenderFrame) -> Result<(), vk::Result> {
+        self.histogram.record(frame.device, frame.cmd, self.particles, self.draw_buffer, self.count, self.range);
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+use ash::{vk, Device};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use crate::allocator::{Allocator, MemoryLocation};
+use crate::error::VulkanDemoError;
+use crate::memory::allocate_buffer;
+use crate::resources::{OwnedBuffer, OwnedFence};
+
+/// Bytes in the ring every upload and readback is staged through.
+pub const STAGING_RING_SIZE: vk::DeviceSize = 32 << 20;
+
+/// Host-visible space for copies between the host and device-local buffers,
+/// handed out from one persistently mapped buffer instead of creating a
+/// staging buffer per copy.
+///
+/// Allocations advance a head through the buffer, skipping back to its start
+/// when one does not fit before the end. `fence` ties everything allocated
+/// since its last call to the submission using it; once that fence has
+/// signaled, the space is reused. Allocations that cannot fit, because they
+/// are bigger than the ring or the unsubmitted ones already fill it, get a
+/// dedicated buffer that is freed the same way.
+pub struct StagingRing {
+    device: Arc<Device>,
+    allocator: Arc<Allocator>,
+    buffer: OwnedBuffer,
+    capacity: vk::DeviceSize,
+    /// Every offset is a multiple of `optimalBufferCopyOffsetAlignment`, of a
+    /// texel for copies into images, and of `nonCoherentAtomSize` so flushing
+    /// or invalidating one allocation never touches another's bytes.
+    alignment: vk::DeviceSize,
+    /// Total bytes ever allocated and released; offsets into the buffer are
+    /// these modulo `capacity`.
+    head: vk::DeviceSize,
+    tail: vk::DeviceSize,
+    /// Dedicated buffers allocated since the last `fence`.
+    dedicated: Vec<OwnedBuffer>,
+    /// Submissions that may still be using the ring, oldest first.
+    in_flight: VecDeque<Submission>,
+    /// Fences that have signaled and been reset, ready for the next `fence`.
+    spare_fences: Vec<OwnedFence>,
+}
+
+struct Submission {
+    fence: OwnedFence,
+    /// `StagingRing::head` when the submission was made; everything before is
+    /// free once `fence` signals.
+    head: vk::DeviceSize,
+    dedicated: Vec<OwnedBuffer>,
+}
+
+/// Space handed out by `StagingRing::allocate`, mapped at `ptr`. Only valid
+/// until the fence it is submitted with has signaled and another allocation
+/// is made.
+#[derive(Copy, Clone, Debug)]
+pub struct StagingAllocation {
+    pub buffer: vk::Buffer,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+    pub ptr: *mut u8,
+}
+
+impl StagingRing {
+    pub fn new(device: &Arc<Device>, allocator: &Arc<Allocator>, limits: &vk::PhysicalDeviceLimits) -> Result<Self, VulkanDemoError> {
+        let alignment = limits.optimal_buffer_copy_offset_alignment.max(limits.non_coherent_atom_size).max(4);
+        let capacity = STAGING_RING_SIZE.next_multiple_of(alignment);
+        let buffer = create_staging_buffer(device, allocator, capacity)?;
+        Ok(Self {
+            device: device.clone(),
+            allocator: allocator.clone(),
+            buffer,
+            capacity,
+            alignment,
+            head: 0,
+            tail: 0,
+            dedicated: Vec::new(),
+            in_flight: VecDeque::new(),
+            spare_fences: Vec::new(),
+        })
+    }
+
+    /// Hands out `size` bytes aligned to `alignment`, a power of two, and to
+    /// the ring's own alignment. Waits for the oldest submission still using
+    /// the ring when there is no room, and falls back to a dedicated buffer
+    /// when waiting would not make any.
+    pub fn allocate(&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize) -> Result<StagingAllocation, VulkanDemoError> {
+        self.reclaim()?;
+        if size > self.capacity {
+            return self.allocate_dedicated(size);
+        }
+        let alignment = alignment.max(self.alignment);
+        loop {
+            let mut start = self.head.next_multiple_of(alignment);
+            if start % self.capacity + size > self.capacity {
+                start = start.next_multiple_of(self.capacity);
+            }
+            if start + size - self.tail <= self.capacity {
+                self.head = start + size;
+                let offset = start % self.capacity;
+                let ptr = unsafe { self.buffer.mapped_ptr().add(offset as usize) };
+                return Ok(StagingAllocation { buffer: self.buffer.handle(), offset, size, ptr });
+            }
+            if self.in_flight.is_empty() {
+                return self.allocate_dedicated(size);
+            }
+            let oldest = self.in_flight[0].fence.handle();
+            unsafe { self.device.wait_for_fences(&[oldest], true, u64::MAX)? };
+            self.retire_oldest()?;
+        }
+    }
+
+    /// Allocates space for `bytes` and writes them there, ready to be copied
+    /// from.
+    pub fn stage(&mut self, bytes: &[u8]) -> Result<StagingAllocation, VulkanDemoError> {
+        let allocation = self.allocate(bytes.len() as vk::DeviceSize, 1)?;
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), allocation.ptr, bytes.len()) };
+        self.owner(&allocation).flush(allocation.offset, allocation.size)?;
+        Ok(allocation)
+    }
+
+    /// The bytes a finished copy wrote into `allocation`, invalidating them
+    /// first if the memory is not coherent.
+    pub fn read(&self, allocation: &StagingAllocation) -> Result<Vec<u8>, vk::Result> {
+        self.owner(allocation).invalidate(allocation.offset, allocation.size)?;
+        Ok(unsafe { std::slice::from_raw_parts(allocation.ptr, allocation.size as usize).to_vec() })
+    }
+
+    /// A fence for the next submission, which everything allocated since the
+    /// previous call is then released with. It must be submitted.
+    pub fn fence(&mut self) -> Result<vk::Fence, vk::Result> {
+        let fence = match self.spare_fences.pop() {
+            Some(fence) => fence,
+            None => OwnedFence::new(&self.device, unsafe { self.device.create_fence(&vk::FenceCreateInfo::default(), None)? }),
+        };
+        let handle = fence.handle();
+        self.in_flight.push_back(Submission { fence, head: self.head, dedicated: std::mem::take(&mut self.dedicated) });
+        Ok(handle)
+    }
+
+    /// Releases the space of every submission that has finished.
+    fn reclaim(&mut self) -> Result<(), vk::Result> {
+        while let Some(submission) = self.in_flight.front() {
+            if !unsafe { self.device.get_fence_status(submission.fence.handle())? } {
+                break;
+            }
+            self.retire_oldest()?;
+        }
+        Ok(())
+    }
+
+    fn retire_oldest(&mut self) -> Result<(), vk::Result> {
+        let submission = self.in_flight.pop_front().expect("no submission in flight");
+        unsafe { self.device.reset_fences(&[submission.fence.handle()])? };
+        self.tail = submission.head;
+        self.spare_fences.push(submission.fence);
+        Ok(())
+    }
+
+    fn allocate_dedicated(&mut self, size: vk::DeviceSize) -> Result<StagingAllocation, VulkanDemoError> {
+        let buffer = create_staging_buffer(&self.device, &self.allocator, size)?;
+        let allocation = StagingAllocation { buffer: buffer.handle(), offset: 0, size, ptr: buffer.mapped_ptr() };
+        self.dedicated.push(buffer);
+        Ok(allocation)
+    }
+
+    fn owner(&self, allocation: &StagingAllocation) -> &OwnedBuffer {
+        std::iter::once(&self.buffer)
+            .chain(&self.dedicated)
+            .chain(self.in_flight.iter().flat_map(|submission| &submission.dedicated))
+            .find(|buffer| buffer.handle() == allocation.buffer)
+            .expect("staging allocation from another ring, or already released")
+    }
+}
+
+impl StagingAllocation {
+    /// Records copying the whole allocation into `dst` at `dst_offset`.
+    pub fn record_copy_to(&self, device: &Device, cmd: vk::CommandBuffer, dst: vk::Buffer, dst_offset: vk::DeviceSize) {
+        let region = vk::BufferCopy { src_offset: self.offset, dst_offset, size: self.size };
+        unsafe { device.cmd_copy_buffer(cmd, self.buffer, dst, &[region]) };
+    }
+
+    /// Records filling the whole allocation from `src` at `src_offset`.
+    pub fn record_copy_from(&self, device: &Device, cmd: vk::CommandBuffer, src: vk::Buffer, src_offset: vk::DeviceSize) {
+        let region = vk::BufferCopy { src_offset, dst_offset: self.offset, size: self.size };
+        unsafe { device.cmd_copy_buffer(cmd, src, self.buffer, &[region]) };
+    }
+}
+
+fn create_staging_buffer(
+    device: &Arc<Device>,
+    allocator: &Arc<Allocator>,
+    size: vk::DeviceSize,
+) -> Result<OwnedBuffer, VulkanDemoError> {
+    let buffer_info = vk::BufferCreateInfo::default()
+        .size(size)
+        .usage(vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+    allocate_buffer(device, allocator, &buffer_info, MemoryLocation::CpuToGpu)
+}
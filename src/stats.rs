@@ -0,0 +1,185 @@
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::error::VulkanDemoError;
+use crate::gpu_timer::GpuTimings;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+const CSV_HEADER: &str =
+    "elapsed_s,fps,avg_ms,min_ms,max_ms,compute_ms,graphics_ms,bloom_ms,sort_ms,mean_speed,max_speed,record_ms";
+
+/// Frame statistics over one reporting interval.
+#[derive(Copy, Clone, Debug)]
+pub struct StatsReport {
+    pub fps: f64,
+    pub avg_ms: f64,
+    pub min_ms: f64,
+    pub max_ms: f64,
+    /// Average CPU time spent recording a frame's commands.
+    pub record_ms: f64,
+    /// Latest GPU pass times, when the device supports timestamps.
+    pub gpu: Option<GpuTimings>,
+    /// Latest speeds of the selected system's live particles.
+    pub speed: Option<SpeedStats>,
+}
+
+/// Mean and fastest speed of a system's live particles.
+#[derive(Copy, Clone, Debug)]
+pub struct SpeedStats {
+    pub mean: f32,
+    pub max: f32,
+}
+
+/// Accumulates CPU frame times and reports them once a second, optionally
+/// appending each report to a CSV file.
+///
+/// Recording a frame only updates a few counters, so it never allocates.
+pub struct FrameStats {
+    started: Instant,
+    interval_start: Instant,
+    frames: u32,
+    sum_ms: f64,
+    min_ms: f64,
+    max_ms: f64,
+    record_sum_ms: f64,
+    gpu: Option<GpuTimings>,
+    speed: Option<SpeedStats>,
+    csv: Option<(PathBuf, BufWriter<File>)>,
+    /// Reused between reports to build the window title.
+    title: String,
+}
+
+impl FrameStats {
+    /// Opens `csv_path` for appending, writing the header first if the file is new or empty.
+    pub fn new(csv_path: Option<&Path>) -> Result<Self, VulkanDemoError> {
+        let csv = csv_path.map(open_csv).transpose()?;
+        let now = Instant::now();
+        Ok(Self {
+            started: now,
+            interval_start: now,
+            frames: 0,
+            sum_ms: 0.0,
+            min_ms: f64::INFINITY,
+            max_ms: 0.0,
+            record_sum_ms: 0.0,
+            gpu: None,
+            speed: None,
+            csv,
+            title: String::new(),
+        })
+    }
+
+    /// Adds one frame that took `frame_time` from the previous one.
+    pub fn record_frame(&mut self, frame_time: Duration) {
+        let ms = frame_time.as_secs_f64() * 1000.0;
+        self.frames += 1;
+        self.sum_ms += ms;
+        self.min_ms = self.min_ms.min(ms);
+        self.max_ms = self.max_ms.max(ms);
+    }
+
+    /// Adds the time one frame took to record its commands.
+    pub fn add_record_time(&mut self, record_time: Duration) {
+        self.record_sum_ms += record_time.as_secs_f64() * 1000.0;
+    }
+
+    pub fn set_gpu_timings(&mut self, timings: GpuTimings) {
+        self.gpu = Some(timings);
+    }
+
+    pub fn set_speeds(&mut self, mean: f32, max: f32) {
+        self.speed = Some(SpeedStats { mean, max });
+    }
+
+    /// Returns the statistics of the interval once it has lasted a second, and
+    /// starts the next one. A CSV write failure is logged and stops further rows.
+    pub fn report(&mut self) -> Option<StatsReport> {
+        let elapsed = self.interval_start.elapsed();
+        if self.frames == 0 || elapsed < REPORT_INTERVAL {
+            return None;
+        }
+        let report = StatsReport {
+            fps: self.frames as f64 / elapsed.as_secs_f64(),
+            avg_ms: self.sum_ms / self.frames as f64,
+            min_ms: self.min_ms,
+            max_ms: self.max_ms,
+            record_ms: self.record_sum_ms / self.frames as f64,
+            gpu: self.gpu,
+            speed: self.speed,
+        };
+        self.interval_start = Instant::now();
+        self.frames = 0;
+        self.sum_ms = 0.0;
+        self.min_ms = f64::INFINITY;
+        self.max_ms = 0.0;
+        self.record_sum_ms = 0.0;
+
+        if let Some((path, writer)) = &mut self.csv {
+            if let Err(e) = write_row(writer, self.started.elapsed(), &report) {
+                log::error!("Failed to write {}: {e}", path.display());
+                self.csv = None;
+            }
+        }
+        Some(report)
+    }
+
+    /// Formats the window title for `report`, reusing the same buffer every time.
+    pub fn title(&mut self, base: &str, particles: u32, report: &StatsReport) -> &str {
+        self.title.clear();
+        let _ = write!(
+            self.title,
+            "{base} — {particles} particles — {:.0} FPS ({:.2} ms, recorded in {:.2} ms)",
+            report.fps, report.avg_ms, report.record_ms,
+        );
+        if let Some(gpu) = report.gpu {
+            let _ = write!(self.title, " — GPU {:.2} + {:.2} ms", gpu.compute_ms, gpu.graphics_ms);
+            if gpu.bloom_ms > 0.0 {
+                let _ = write!(self.title, " (bloom {:.2} ms)", gpu.bloom_ms);
+            }
+            if gpu.sort_ms > 0.0 {
+                let _ = write!(self.title, " + sort {:.2} ms", gpu.sort_ms);
+            }
+        }
+        if let Some(speed) = report.speed {
+            let _ = write!(self.title, " — speed {:.2} mean, {:.2} max", speed.mean, speed.max);
+        }
+        &self.title
+    }
+}
+
+fn open_csv(path: &Path) -> Result<(PathBuf, BufWriter<File>), VulkanDemoError> {
+    let stats_write_error = |error| VulkanDemoError::StatsWrite { path: path.to_path_buf(), error };
+    let file = OpenOptions::new().create(true).append(true).open(path).map_err(stats_write_error)?;
+    let is_empty = file.metadata().map_err(stats_write_error)?.len() == 0;
+    let mut writer = BufWriter::new(file);
+    if is_empty {
+        writeln!(writer, "{CSV_HEADER}").and_then(|()| writer.flush()).map_err(stats_write_error)?;
+    }
+    Ok((path.to_path_buf(), writer))
+}
+
+fn write_row(writer: &mut BufWriter<File>, elapsed: Duration, report: &StatsReport) -> std::io::Result<()> {
+    write!(
+        writer,
+        "{:.3},{:.1},{:.3},{:.3},{:.3}",
+        elapsed.as_secs_f64(),
+        report.fps,
+        report.avg_ms,
+        report.min_ms,
+        report.max_ms,
+    )?;
+    // GPU columns stay empty without timestamp support.
+    match report.gpu {
+        Some(gpu) => write!(writer, ",{:.3},{:.3},{:.3},{:.3}", gpu.compute_ms, gpu.graphics_ms, gpu.bloom_ms, gpu.sort_ms)?,
+        None => write!(writer, ",,,,")?,
+    }
+    match report.speed {
+        Some(speed) => write!(writer, ",{:.4},{:.4}", speed.mean, speed.max)?,
+        None => write!(writer, ",,")?,
+    }
+    writeln!(writer, ",{:.3}", report.record_ms)?;
+    // Flushed every row so the file is usable while the demo is still running.
+    writer.flush()
+}
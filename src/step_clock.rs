@@ -0,0 +1,75 @@
+use crate::particles::SimPushConstants;
+
+/// Default length of one simulation step, in seconds.
+pub const DEFAULT_STEP_DT: f32 = 1.0 / 120.0;
+/// Default cap on steps per frame.
+pub const DEFAULT_MAX_STEPS: u32 = 8;
+
+/// Splits frame times into simulation steps of a fixed length, so the
+/// simulation evolves the same at any frame rate and a long frame can't move
+/// particles through an obstacle in one step.
+///
+/// Drawn positions aren't interpolated between steps: compaction reorders the
+/// particles every step, so the previous state has nothing to blend with at
+/// the same index.
+#[derive(Clone, Debug)]
+pub struct StepClock {
+    step_dt: f32,
+    /// Caps `step_dt` while set; see `limit_step_dt`.
+    step_limit: Option<f32>,
+    max_steps: u32,
+    /// Frame time not yet covered by a step.
+    accumulator: f32,
+    /// Steps taken so far; reseeds the respawn hash.
+    steps: u32,
+    /// Simulated seconds so far.
+    pub sim_time: f32,
+}
+
+impl StepClock {
+    pub fn new(step_dt: f32, max_steps: u32) -> Self {
+        Self { step_dt, step_limit: None, max_steps, accumulator: 0.0, steps: 0, sim_time: 0.0 }
+    }
+
+    /// Shortens steps to at most `max_step_dt` while it's set, for behaviors
+    /// only stable with shorter steps than configured. Call every frame with
+    /// the limits of the systems being stepped.
+    pub fn limit_step_dt(&mut self, max_step_dt: Option<f32>) {
+        self.step_limit = max_step_dt;
+    }
+
+    fn step_dt(&self) -> f32 {
+        self.step_limit.map_or(self.step_dt, |limit| self.step_dt.min(limit))
+    }
+
+    /// The steps covering `frame_dt` more seconds, each `template` with its own
+    /// `dt`, `elapsed` and `frame`. Beyond `max_steps` the rest of the time is
+    /// dropped, so the simulation slows down after a stall instead of falling
+    /// ever further behind. The steps count once passed to `advance`.
+    pub fn substeps(&mut self, frame_dt: f32, template: SimPushConstants) -> Vec<SimPushConstants> {
+        let step_dt = self.step_dt();
+        self.accumulator += frame_dt;
+        // The tolerance keeps rounding from leaving a step a hair short.
+        let due = (self.accumulator / step_dt + 1e-3).floor() as u32;
+        let count = due.min(self.max_steps);
+        self.accumulator = if due > count {
+            0.0
+        } else {
+            (self.accumulator - count as f32 * step_dt).max(0.0)
+        };
+        (0..count)
+            .map(|index| SimPushConstants {
+                dt: step_dt,
+                elapsed: self.sim_time + index as f32 * step_dt,
+                frame: self.steps.wrapping_add(index),
+                ..template
+            })
+            .collect()
+    }
+
+    /// Moves the clock past `substeps`, once they have been submitted.
+    pub fn advance(&mut self, substeps: &[SimPushConstants]) {
+        self.steps = self.steps.wrapping_add(substeps.len() as u32);
+        self.sim_time += substeps.iter().map(|substep| substep.dt).sum::<f32>();
+    }
+}
@@ -0,0 +1,511 @@
+use ash::{vk, Device};
+use std::sync::Arc;
+use crate::promoted::TimelineSemaphore;
+use crate::resources::{OwnedCommandPool, OwnedFence, OwnedSemaphore};
+use crate::sync2::Sync2;
+use crate::upload::UploadDependency;
+use crate::vulkan_context::VulkanContext;
+
+/// Frames the CPU may record while the GPU is still working on earlier ones.
+pub const FRAMES_IN_FLIGHT: usize = 2;
+
+/// What one frame in flight records into and waits on. `FrameSync::begin_frame`
+/// hands it out again only once the GPU has finished the frame that last used it.
+pub struct FrameResources {
+    pub command_buffer: vk::CommandBuffer,
+    /// For the async compute queue, when there is one.
+    pub compute_command_buffer: Option<vk::CommandBuffer>,
+    pub image_available: OwnedSemaphore,
+    /// Signaled by the frame's graphics submission when there is no timeline.
+    in_flight: OwnedFence,
+    /// The timeline value signaled by the frame's latest submission.
+    timeline_value: u64,
+}
+
+/// Command buffers and synchronization primitives used to drive frames.
+///
+/// Up to `FRAMES_IN_FLIGHT` frames are recorded ahead of the GPU, each with
+/// its own `FrameResources`. Render-finished semaphores are indexed by
+/// swapchain image instead, because presentation may still be waiting on one
+/// when a later frame is submitted.
+///
+/// The CPU waits for frames on a timeline semaphore that every submission
+/// signals with the next value, so the async compute step and the frame that
+/// draws its results are ordered by values on the same timeline. Without
+/// timeline semaphore support, per-frame fences and a binary compute semaphore
+/// do the same job.
+pub struct FrameSync {
+    pub command_pool: OwnedCommandPool,
+    frames: Vec<FrameResources>,
+    /// Index into `frames` of the frame being recorded.
+    current: usize,
+    pub render_finished: Vec<OwnedSemaphore>,
+    timeline: Option<Timeline>,
+    sync2: Sync2,
+    /// Present only when the simulation runs on a separate compute queue.
+    pub compute: Option<ComputeSync>,
+    pending_present: PendingPresents,
+    /// The upload timeline value the next submission waits for, from `wait_for_upload`.
+    upload_wait: Option<(vk::Semaphore, u64)>,
+}
+
+/// Which render-finished semaphores, one per swapchain image, are signaled
+/// but not yet waited on by their image's present.
+///
+/// Each image has a semaphore of its own, so pending ones never hold up
+/// signaling another image's; the only misuse left is signaling an image's
+/// semaphore a second time before its present, which `signal` checks for in
+/// debug builds.
+#[derive(Debug, Default)]
+struct PendingPresents(Vec<bool>);
+
+impl PendingPresents {
+    /// Forgets every pending present, for `image_count` images.
+    fn reset(&mut self, image_count: usize) {
+        self.0.clear();
+        self.0.resize(image_count, false);
+    }
+
+    /// Records that the semaphore of `image_index` of `swapchain` is about to be signaled.
+    fn signal(&mut self, image_index: u32, swapchain: &str) {
+        let index = image_index as usize;
+        debug_assert!(!self.0[index], "render-finished semaphore for {swapchain} image {index} re-signaled before its present consumed it");
+        self.0[index] = true;
+    }
+
+    /// Records that the present of `image_index` has waited on its semaphore.
+    fn presented(&mut self, image_index: u32) {
+        self.0[image_index as usize] = false;
+    }
+}
+
+struct Timeline {
+    loader: TimelineSemaphore,
+    semaphore: OwnedSemaphore,
+    /// The value signaled by the latest submission.
+    value: u64,
+    /// The value signaled by the latest compute submission.
+    compute_value: u64,
+}
+
+/// A graphics queue submission made through `FrameSync::submit_graphics`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GraphicsSubmit {
+    pub command_buffer: Option<vk::CommandBuffer>,
+    /// The acquired swapchain image: waits for `image_available` and signals
+    /// the image's render-finished semaphore for the present.
+    pub image_index: Option<u32>,
+    /// Waits for the latest `submit_compute` before drawing its results.
+    pub after_compute: bool,
+    /// A second window's image drawn by the same command buffer, from `ViewSync::signal`.
+    pub view_image: Option<ViewImage>,
+}
+
+/// The semaphores of a second swapchain's image drawn by a frame: the one its
+/// acquire signals, and the one the frame signals for its present.
+#[derive(Copy, Clone, Debug)]
+pub struct ViewImage {
+    pub image_available: vk::Semaphore,
+    pub render_finished: vk::Semaphore,
+}
+
+/// Acquire and render-finished semaphores for a second window drawn by the
+/// frames of a `FrameSync`, which also orders reusing them: the per-frame ones
+/// are free again once `FrameSync::begin_frame` has waited for that frame.
+pub struct ViewSync {
+    /// One per frame in flight.
+    image_available: Vec<OwnedSemaphore>,
+    /// One per swapchain image, as in `FrameSync`.
+    render_finished: Vec<OwnedSemaphore>,
+    pending_present: PendingPresents,
+}
+
+impl ViewSync {
+    pub fn new(device: &Arc<Device>, image_count: usize) -> Result<Self, vk::Result> {
+        let semaphore_info = vk::SemaphoreCreateInfo::default();
+        let image_available = (0..FRAMES_IN_FLIGHT)
+            .map(|_| Ok(OwnedSemaphore::new(device, unsafe { device.create_semaphore(&semaphore_info, None)? })))
+            .collect::<Result<_, vk::Result>>()?;
+        let mut sync = Self { image_available, render_finished: Vec::new(), pending_present: PendingPresents::default() };
+        sync.resize(device, image_count)?;
+        Ok(sync)
+    }
+
+    /// Recreates the per-image semaphores after the swapchain was recreated.
+    /// The caller must make sure the device is idle.
+    pub fn resize(&mut self, device: &Arc<Device>, image_count: usize) -> Result<(), vk::Result> {
+        let semaphore_info = vk::SemaphoreCreateInfo::default();
+        self.render_finished = (0..image_count)
+            .map(|_| Ok(OwnedSemaphore::new(device, unsafe { device.create_semaphore(&semaphore_info, None)? })))
+            .collect::<Result<_, vk::Result>>()?;
+        self.pending_present.reset(image_count);
+        Ok(())
+    }
+
+    /// What acquiring for frame in flight `frame` signals.
+    pub fn image_available(&self, frame: usize) -> vk::Semaphore {
+        self.image_available[frame].handle()
+    }
+
+    /// The semaphores of `image_index`, acquired for frame `frame`, for the
+    /// frame's submission; records that its render-finished one is about to be
+    /// signaled, checked as in `FrameSync::signal_render_finished`.
+    pub fn signal(&mut self, frame: usize, image_index: u32) -> ViewImage {
+        self.pending_present.signal(image_index, "second window");
+        ViewImage {
+            image_available: self.image_available(frame),
+            render_finished: self.render_finished[image_index as usize].handle(),
+        }
+    }
+
+    /// Records that the present of `image_index` has waited on its semaphore.
+    pub fn presented(&mut self, image_index: u32) {
+        self.pending_present.presented(image_index);
+    }
+}
+
+/// The command pool for the async compute queue, and the semaphore the
+/// graphics submission waits on before drawing its results when there is no
+/// timeline semaphore.
+///
+/// Waiting for a frame also covers the compute work, since the graphics
+/// submission cannot start before the compute submission finished.
+pub struct ComputeSync {
+    pub command_pool: OwnedCommandPool,
+    finished: OwnedSemaphore,
+}
+
+impl ComputeSync {
+    fn new(device: &Arc<Device>, queue_family_index: u32) -> Result<Self, vk::Result> {
+        let pool_info = vk::CommandPoolCreateInfo::default()
+            .queue_family_index(queue_family_index)
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
+        let command_pool = OwnedCommandPool::new(device, unsafe { device.create_command_pool(&pool_info, None)? });
+
+        let semaphore_info = vk::SemaphoreCreateInfo::default();
+        let finished = OwnedSemaphore::new(device, unsafe { device.create_semaphore(&semaphore_info, None)? });
+        Ok(Self { command_pool, finished })
+    }
+}
+
+/// `FRAMES_IN_FLIGHT` primary command buffers from `pool`.
+fn allocate_command_buffers(device: &Device, pool: &OwnedCommandPool) -> Result<Vec<vk::CommandBuffer>, vk::Result> {
+    let alloc_info = vk::CommandBufferAllocateInfo::default()
+        .command_pool(pool.handle())
+        .level(vk::CommandBufferLevel::PRIMARY)
+        .command_buffer_count(FRAMES_IN_FLIGHT as u32);
+    unsafe { device.allocate_command_buffers(&alloc_info) }
+}
+
+impl FrameSync {
+    /// With `compute_queue_family_index` set, also creates the objects for
+    /// submitting the simulation on that family's queue.
+    pub fn new(context: &VulkanContext, compute_queue_family_index: Option<u32>, image_count: usize) -> Result<Self, vk::Result> {
+        let device = &context.device;
+        let pool_info = vk::CommandPoolCreateInfo::default()
+            .queue_family_index(context.queue_family_index)
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
+        let command_pool = OwnedCommandPool::new(device, unsafe { device.create_command_pool(&pool_info, None)? });
+
+        let compute = compute_queue_family_index.map(|family| ComputeSync::new(device, family)).transpose()?;
+
+        let semaphore_info = vk::SemaphoreCreateInfo::default();
+        let fence_info = vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED);
+        let command_buffers = allocate_command_buffers(device, &command_pool)?;
+        let compute_command_buffers = match &compute {
+            Some(compute) => allocate_command_buffers(device, &compute.command_pool)?.into_iter().map(Some).collect(),
+            None => vec![None; FRAMES_IN_FLIGHT],
+        };
+        let frames = command_buffers
+            .into_iter()
+            .zip(compute_command_buffers)
+            .map(|(command_buffer, compute_command_buffer)| {
+                Ok(FrameResources {
+                    command_buffer,
+                    compute_command_buffer,
+                    image_available: OwnedSemaphore::new(device, unsafe { device.create_semaphore(&semaphore_info, None)? }),
+                    in_flight: OwnedFence::new(device, unsafe { device.create_fence(&fence_info, None)? }),
+                    timeline_value: 0,
+                })
+            })
+            .collect::<Result<Vec<_>, vk::Result>>()?;
+        let timeline = match &context.timeline_semaphore {
+            Some(loader) => {
+                let mut type_info = vk::SemaphoreTypeCreateInfo::default()
+                    .semaphore_type(vk::SemaphoreType::TIMELINE)
+                    .initial_value(0);
+                let timeline_info = vk::SemaphoreCreateInfo::default().push_next(&mut type_info);
+                let semaphore = OwnedSemaphore::new(device, unsafe { device.create_semaphore(&timeline_info, None)? });
+                Some(Timeline { loader: loader.clone(), semaphore, value: 0, compute_value: 0 })
+            }
+            None => None,
+        };
+
+        let mut sync = Self {
+            command_pool,
+            frames,
+            current: 0,
+            render_finished: Vec::new(),
+            timeline,
+            sync2: Sync2::new(context),
+            compute,
+            pending_present: PendingPresents::default(),
+            upload_wait: None,
+        };
+        sync.resize(device, image_count)?;
+        Ok(sync)
+    }
+
+    /// Recreates the per-image semaphores after the swapchain was recreated.
+    /// The caller must make sure the device is idle.
+    pub fn resize(&mut self, device: &Arc<Device>, image_count: usize) -> Result<(), vk::Result> {
+        self.pending_present.reset(image_count);
+        if self.render_finished.len() == image_count {
+            return Ok(());
+        }
+
+        self.render_finished.clear();
+
+        let semaphore_info = vk::SemaphoreCreateInfo::default();
+        for _ in 0..image_count {
+            self.render_finished.push(OwnedSemaphore::new(device, unsafe { device.create_semaphore(&semaphore_info, None)? }));
+        }
+
+        Ok(())
+    }
+
+    /// Blocks until the GPU has finished the frame that last used the current
+    /// `FrameResources`, so they can be recorded into again. Returns their index,
+    /// for per-frame copies kept elsewhere.
+    pub fn begin_frame(&self, device: &Device) -> Result<usize, vk::Result> {
+        let frame = &self.frames[self.current];
+        match &self.timeline {
+            Some(timeline) => wait_timeline(timeline, frame.timeline_value)?,
+            None => unsafe { device.wait_for_fences(&[frame.in_flight.handle()], true, u64::MAX)? },
+        }
+        Ok(self.current)
+    }
+
+    /// Moves on to the next `FrameResources` once this frame is submitted.
+    pub fn end_frame(&mut self) {
+        self.current = (self.current + 1) % FRAMES_IN_FLIGHT;
+    }
+
+    /// Index of the frame submitted before the current one, the last that
+    /// resources dropped now may still be in use by.
+    pub fn previous_frame(&self) -> usize {
+        (self.current + FRAMES_IN_FLIGHT - 1) % FRAMES_IN_FLIGHT
+    }
+
+    /// The resources of the frame being recorded.
+    pub fn frame(&self) -> &FrameResources {
+        &self.frames[self.current]
+    }
+
+    /// Blocks until the GPU has finished every submission, and with them all
+    /// frames in flight.
+    pub fn wait(&self, device: &Device) -> Result<(), vk::Result> {
+        match &self.timeline {
+            Some(timeline) => wait_timeline(timeline, timeline.value),
+            None => {
+                let fences: Vec<_> = self.frames.iter().map(|frame| frame.in_flight.handle()).collect();
+                unsafe { device.wait_for_fences(&fences, true, u64::MAX) }
+            }
+        }
+    }
+
+    /// Makes the next submission wait for a finished upload before touching
+    /// its buffers. Dependencies without a wait, or ones the pending wait
+    /// already covers, change nothing.
+    pub fn wait_for_upload(&mut self, dependency: &UploadDependency) {
+        if let Some(wait) = dependency.wait {
+            let value = self.upload_wait.map_or(wait.value, |(_, pending)| pending.max(wait.value));
+            self.upload_wait = Some((wait.semaphore, value));
+        }
+    }
+
+    /// Submits the current frame's recorded compute command buffer on `queue`.
+    /// Does nothing without a compute queue.
+    ///
+    /// The steps overwrite what the frames still in flight draw, so they wait
+    /// for every earlier submission: on the timeline, or on the CPU without one.
+    pub fn submit_compute(&mut self, device: &Device, queue: vk::Queue) -> Result<(), vk::Result> {
+        let (Some(compute), Some(command_buffer)) = (&self.compute, self.frames[self.current].compute_command_buffer) else {
+            return Ok(());
+        };
+        let mut waits = Vec::with_capacity(2);
+        waits.extend(self.upload_wait.take().map(upload_wait));
+        let (semaphore, value) = match &mut self.timeline {
+            Some(timeline) => {
+                waits.push(
+                    vk::SemaphoreSubmitInfo::default()
+                        .semaphore(timeline.semaphore.handle())
+                        .value(timeline.value)
+                        .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS),
+                );
+                timeline.value += 1;
+                timeline.compute_value = timeline.value;
+                self.frames[self.current].timeline_value = timeline.value;
+                (timeline.semaphore.handle(), timeline.value)
+            }
+            None => {
+                let fences: Vec<_> = self.frames.iter().map(|frame| frame.in_flight.handle()).collect();
+                unsafe { device.wait_for_fences(&fences, true, u64::MAX)? };
+                (compute.finished.handle(), 0)
+            }
+        };
+        let signal = vk::SemaphoreSubmitInfo::default()
+            .semaphore(semaphore)
+            .value(value)
+            .stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER);
+        self.sync2.queue_submit(device, queue, &[command_buffer], &waits, &[signal], vk::Fence::null())
+    }
+
+    /// Submits `submit` on the graphics `queue`, signaling the end of the current frame.
+    pub fn submit_graphics(&mut self, device: &Device, queue: vk::Queue, submit: GraphicsSubmit) -> Result<(), vk::Result> {
+        let mut waits = Vec::with_capacity(4);
+        waits.extend(self.upload_wait.take().map(upload_wait));
+        let mut signals = Vec::with_capacity(3);
+        if let Some(image_index) = submit.image_index {
+            waits.push(
+                vk::SemaphoreSubmitInfo::default()
+                    .semaphore(self.frames[self.current].image_available.handle())
+                    .stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT),
+            );
+            signals.push(
+                vk::SemaphoreSubmitInfo::default()
+                    .semaphore(self.signal_render_finished(image_index))
+                    .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS),
+            );
+        }
+        if let Some(view_image) = submit.view_image {
+            waits.push(
+                vk::SemaphoreSubmitInfo::default()
+                    .semaphore(view_image.image_available)
+                    .stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT),
+            );
+            signals.push(
+                vk::SemaphoreSubmitInfo::default()
+                    .semaphore(view_image.render_finished)
+                    .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS),
+            );
+        }
+        if submit.after_compute {
+            let (semaphore, value) = match (&self.timeline, &self.compute) {
+                (Some(timeline), _) => (timeline.semaphore.handle(), timeline.compute_value),
+                (None, Some(compute)) => (compute.finished.handle(), 0),
+                (None, None) => unreachable!("waiting for compute without a compute queue"),
+            };
+            waits.push(
+                vk::SemaphoreSubmitInfo::default()
+                    .semaphore(semaphore)
+                    .value(value)
+                    .stage_mask(
+                        vk::PipelineStageFlags2::DRAW_INDIRECT
+                            | vk::PipelineStageFlags2::VERTEX_ATTRIBUTE_INPUT
+                            | vk::PipelineStageFlags2::ALL_TRANSFER
+                            // The depth sort reads the particles in a compute shader.
+                            | vk::PipelineStageFlags2::COMPUTE_SHADER,
+                    ),
+            );
+        }
+        let frame = &mut self.frames[self.current];
+        let fence = match &mut self.timeline {
+            Some(timeline) => {
+                timeline.value += 1;
+                frame.timeline_value = timeline.value;
+                signals.push(
+                    vk::SemaphoreSubmitInfo::default()
+                        .semaphore(timeline.semaphore.handle())
+                        .value(timeline.value)
+                        .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS),
+                );
+                vk::Fence::null()
+            }
+            None => {
+                unsafe { device.reset_fences(&[frame.in_flight.handle()])? };
+                frame.in_flight.handle()
+            }
+        };
+
+        let command_buffers: Vec<_> = submit.command_buffer.into_iter().collect();
+        self.sync2.queue_submit(device, queue, &command_buffers, &waits, &signals, fence)
+    }
+
+    /// Whether a compute step that no frame draws has to be consumed with an
+    /// empty submission. Timeline values need no consuming, unlike the binary
+    /// semaphore of the fallback.
+    pub fn needs_compute_drain(&self) -> bool {
+        self.timeline.is_none()
+    }
+
+    /// Returns the render-finished semaphore for `image_index` and records that
+    /// it is about to be signaled by a submit.
+    ///
+    /// Debug builds panic if the image's semaphore is still waiting for its
+    /// present, from `presented`. Other images' pending semaphores don't
+    /// matter: no two images share one.
+    fn signal_render_finished(&mut self, image_index: u32) -> vk::Semaphore {
+        self.pending_present.signal(image_index, "swapchain");
+        self.render_finished[image_index as usize].handle()
+    }
+
+    /// Records that the present of `image_index` has waited on its semaphore.
+    pub fn presented(&mut self, image_index: u32) {
+        self.pending_present.presented(image_index);
+    }
+}
+
+/// Waiting for the upload timeline to reach a value before anything in the submission.
+fn upload_wait((semaphore, value): (vk::Semaphore, u64)) -> vk::SemaphoreSubmitInfo<'static> {
+    vk::SemaphoreSubmitInfo::default()
+        .semaphore(semaphore)
+        .value(value)
+        .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+}
+
+fn wait_timeline(timeline: &Timeline, value: u64) -> Result<(), vk::Result> {
+    let semaphores = [timeline.semaphore.handle()];
+    let values = [value];
+    let wait_info = vk::SemaphoreWaitInfo::default().semaphores(&semaphores).values(&values);
+    unsafe { timeline.loader.wait_semaphores(&wait_info, u64::MAX) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_are_pending_independently() {
+        let mut pending = PendingPresents::default();
+        pending.reset(3);
+        pending.signal(0, "swapchain");
+        // Image 0's present still pending doesn't hold up the others.
+        pending.signal(1, "swapchain");
+        pending.signal(2, "swapchain");
+        pending.presented(0);
+        pending.signal(0, "swapchain");
+        assert_eq!(pending.0, [true, true, true]);
+    }
+
+    #[test]
+    fn reset_forgets_pending_presents() {
+        let mut pending = PendingPresents::default();
+        pending.reset(2);
+        pending.signal(1, "swapchain");
+        pending.reset(2);
+        pending.signal(1, "swapchain");
+        pending.reset(4);
+        assert_eq!(pending.0, [false; 4]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "render-finished semaphore for swapchain image 1 re-signaled before its present consumed it")]
+    fn resignaling_before_the_present_panics() {
+        let mut pending = PendingPresents::default();
+        pending.reset(2);
+        pending.signal(1, "swapchain");
+        pending.signal(1, "swapchain");
+    }
+}
@@ -0,0 +1,267 @@
+use ash::{vk, Device};
+use crate::promoted::Synchronization2;
+use crate::vulkan_context::VulkanContext;
+
+/// A buffer memory barrier in synchronization2 terms.
+#[derive(Copy, Clone, Debug)]
+pub struct BufferBarrier {
+    pub buffer: vk::Buffer,
+    pub src_stage: vk::PipelineStageFlags2,
+    pub src_access: vk::AccessFlags2,
+    pub dst_stage: vk::PipelineStageFlags2,
+    pub dst_access: vk::AccessFlags2,
+    /// `QUEUE_FAMILY_IGNORED` unless the barrier transfers ownership.
+    pub src_queue_family: u32,
+    pub dst_queue_family: u32,
+}
+
+impl BufferBarrier {
+    /// A barrier over the whole of `buffer`, without an ownership transfer.
+    pub fn new(
+        buffer: vk::Buffer,
+        (src_stage, src_access): (vk::PipelineStageFlags2, vk::AccessFlags2),
+        (dst_stage, dst_access): (vk::PipelineStageFlags2, vk::AccessFlags2),
+    ) -> Self {
+        Self {
+            buffer,
+            src_stage,
+            src_access,
+            dst_stage,
+            dst_access,
+            src_queue_family: vk::QUEUE_FAMILY_IGNORED,
+            dst_queue_family: vk::QUEUE_FAMILY_IGNORED,
+        }
+    }
+}
+
+/// An image layout transition in synchronization2 terms, over the first mip
+/// level and array layer.
+#[derive(Copy, Clone, Debug)]
+pub struct ImageBarrier {
+    pub image: vk::Image,
+    pub aspect_mask: vk::ImageAspectFlags,
+    pub src_stage: vk::PipelineStageFlags2,
+    pub src_access: vk::AccessFlags2,
+    pub dst_stage: vk::PipelineStageFlags2,
+    pub dst_access: vk::AccessFlags2,
+    pub old_layout: vk::ImageLayout,
+    pub new_layout: vk::ImageLayout,
+}
+
+impl ImageBarrier {
+    /// A transition of the color aspect of `image` from `old_layout` to `new_layout`.
+    pub fn color(
+        image: vk::Image,
+        (src_stage, src_access): (vk::PipelineStageFlags2, vk::AccessFlags2),
+        (dst_stage, dst_access): (vk::PipelineStageFlags2, vk::AccessFlags2),
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+    ) -> Self {
+        Self {
+            image,
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            src_stage,
+            src_access,
+            dst_stage,
+            dst_access,
+            old_layout,
+            new_layout,
+        }
+    }
+}
+
+/// Records barriers and submits work through synchronization2 when the device
+/// supports it, core or `VK_KHR_synchronization2`, and through the legacy
+/// entry points otherwise. Callers
+/// describe everything in synchronization2 terms either way.
+#[derive(Clone)]
+pub struct Sync2 {
+    loader: Option<Synchronization2>,
+}
+
+impl Sync2 {
+    pub fn new(context: &VulkanContext) -> Self {
+        Self::with_loader(context.synchronization2.clone())
+    }
+
+    /// For use before there is a `VulkanContext`, while creating one.
+    pub(crate) fn with_loader(loader: Option<Synchronization2>) -> Self {
+        Self { loader }
+    }
+
+    pub fn pipeline_barrier(&self, device: &Device, cmd: vk::CommandBuffer, buffers: &[BufferBarrier], images: &[ImageBarrier]) {
+        if let Some(loader) = &self.loader {
+            let buffer_barriers: Vec<_> = buffers
+                .iter()
+                .map(|barrier| {
+                    vk::BufferMemoryBarrier2::default()
+                        .src_stage_mask(barrier.src_stage)
+                        .src_access_mask(barrier.src_access)
+                        .dst_stage_mask(barrier.dst_stage)
+                        .dst_access_mask(barrier.dst_access)
+                        .src_queue_family_index(barrier.src_queue_family)
+                        .dst_queue_family_index(barrier.dst_queue_family)
+                        .buffer(barrier.buffer)
+                        .offset(0)
+                        .size(vk::WHOLE_SIZE)
+                })
+                .collect();
+            let image_barriers: Vec<_> = images
+                .iter()
+                .map(|barrier| {
+                    vk::ImageMemoryBarrier2::default()
+                        .src_stage_mask(barrier.src_stage)
+                        .src_access_mask(barrier.src_access)
+                        .dst_stage_mask(barrier.dst_stage)
+                        .dst_access_mask(barrier.dst_access)
+                        .old_layout(barrier.old_layout)
+                        .new_layout(barrier.new_layout)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .image(barrier.image)
+                        .subresource_range(subresource_range(barrier.aspect_mask))
+                })
+                .collect();
+            let dependency_info = vk::DependencyInfo::default()
+                .buffer_memory_barriers(&buffer_barriers)
+                .image_memory_barriers(&image_barriers);
+            unsafe { loader.cmd_pipeline_barrier2(cmd, &dependency_info) };
+            return;
+        }
+
+        // The legacy call takes a single pair of stage masks for all its barriers.
+        let stages = buffers
+            .iter()
+            .map(|barrier| (barrier.src_stage, barrier.dst_stage))
+            .chain(images.iter().map(|barrier| (barrier.src_stage, barrier.dst_stage)));
+        let (src_stages, dst_stages) = stages.fold(
+            (vk::PipelineStageFlags2::NONE, vk::PipelineStageFlags2::NONE),
+            |(src, dst), (barrier_src, barrier_dst)| (src | barrier_src, dst | barrier_dst),
+        );
+        let buffer_barriers: Vec<_> = buffers
+            .iter()
+            .map(|barrier| {
+                vk::BufferMemoryBarrier::default()
+                    .src_access_mask(legacy_access(barrier.src_access))
+                    .dst_access_mask(legacy_access(barrier.dst_access))
+                    .src_queue_family_index(barrier.src_queue_family)
+                    .dst_queue_family_index(barrier.dst_queue_family)
+                    .buffer(barrier.buffer)
+                    .offset(0)
+                    .size(vk::WHOLE_SIZE)
+            })
+            .collect();
+        let image_barriers: Vec<_> = images
+            .iter()
+            .map(|barrier| {
+                vk::ImageMemoryBarrier::default()
+                    .src_access_mask(legacy_access(barrier.src_access))
+                    .dst_access_mask(legacy_access(barrier.dst_access))
+                    .old_layout(barrier.old_layout)
+                    .new_layout(barrier.new_layout)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .image(barrier.image)
+                    .subresource_range(subresource_range(barrier.aspect_mask))
+            })
+            .collect();
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                legacy_stages(src_stages, vk::PipelineStageFlags::TOP_OF_PIPE),
+                legacy_stages(dst_stages, vk::PipelineStageFlags::BOTTOM_OF_PIPE),
+                vk::DependencyFlags::empty(),
+                &[],
+                &buffer_barriers,
+                &image_barriers,
+            );
+        }
+    }
+
+    /// Submits `command_buffers` on `queue`. Binary semaphores take a `value` of
+    /// 0; on the legacy path, non-zero values are chained in a
+    /// `TimelineSemaphoreSubmitInfo` for the timeline semaphores.
+    pub fn queue_submit(
+        &self,
+        device: &Device,
+        queue: vk::Queue,
+        command_buffers: &[vk::CommandBuffer],
+        waits: &[vk::SemaphoreSubmitInfo],
+        signals: &[vk::SemaphoreSubmitInfo],
+        fence: vk::Fence,
+    ) -> Result<(), vk::Result> {
+        if let Some(loader) = &self.loader {
+            let command_buffer_infos: Vec<_> = command_buffers
+                .iter()
+                .map(|&cmd| vk::CommandBufferSubmitInfo::default().command_buffer(cmd))
+                .collect();
+            let submit_info = vk::SubmitInfo2::default()
+                .wait_semaphore_infos(waits)
+                .command_buffer_infos(&command_buffer_infos)
+                .signal_semaphore_infos(signals);
+            return unsafe { loader.queue_submit2(queue, &[submit_info], fence) };
+        }
+
+        let wait_semaphores: Vec<_> = waits.iter().map(|wait| wait.semaphore).collect();
+        let wait_values: Vec<_> = waits.iter().map(|wait| wait.value).collect();
+        let wait_stages: Vec<_> = waits.iter().map(|wait| legacy_stages(wait.stage_mask, vk::PipelineStageFlags::TOP_OF_PIPE)).collect();
+        let signal_semaphores: Vec<_> = signals.iter().map(|signal| signal.semaphore).collect();
+        let signal_values: Vec<_> = signals.iter().map(|signal| signal.value).collect();
+        let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::default()
+            .wait_semaphore_values(&wait_values)
+            .signal_semaphore_values(&signal_values);
+        let mut submit_info = vk::SubmitInfo::default()
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&wait_stages)
+            .command_buffers(command_buffers)
+            .signal_semaphores(&signal_semaphores);
+        if waits.iter().chain(signals).any(|info| info.value != 0) {
+            submit_info = submit_info.push_next(&mut timeline_info);
+        }
+        unsafe { device.queue_submit(queue, &[submit_info], fence) }
+    }
+}
+
+fn subresource_range(aspect_mask: vk::ImageAspectFlags) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
+
+/// The legacy stages covering `stages`, or `empty` for none. The stages that
+/// synchronization2 splits out of legacy ones map back to those; the others
+/// share their bit values.
+fn legacy_stages(stages: vk::PipelineStageFlags2, empty: vk::PipelineStageFlags) -> vk::PipelineStageFlags {
+    if stages == vk::PipelineStageFlags2::NONE {
+        return empty;
+    }
+    let mut legacy = vk::PipelineStageFlags::from_raw(stages.as_raw() as u32);
+    if stages.intersects(vk::PipelineStageFlags2::VERTEX_ATTRIBUTE_INPUT | vk::PipelineStageFlags2::INDEX_INPUT) {
+        legacy |= vk::PipelineStageFlags::VERTEX_INPUT;
+    }
+    if stages.intersects(
+        vk::PipelineStageFlags2::COPY
+            | vk::PipelineStageFlags2::BLIT
+            | vk::PipelineStageFlags2::RESOLVE
+            | vk::PipelineStageFlags2::CLEAR,
+    ) {
+        legacy |= vk::PipelineStageFlags::TRANSFER;
+    }
+    legacy
+}
+
+/// Like `legacy_stages`, for access flags.
+fn legacy_access(access: vk::AccessFlags2) -> vk::AccessFlags {
+    let mut legacy = vk::AccessFlags::from_raw(access.as_raw() as u32);
+    if access.intersects(vk::AccessFlags2::SHADER_SAMPLED_READ | vk::AccessFlags2::SHADER_STORAGE_READ) {
+        legacy |= vk::AccessFlags::SHADER_READ;
+    }
+    if access.intersects(vk::AccessFlags2::SHADER_STORAGE_WRITE) {
+        legacy |= vk::AccessFlags::SHADER_WRITE;
+    }
+    legacy
+}
@@ -0,0 +1,152 @@
+use ash::vk;
+use std::path::Path;
+use crate::error::VulkanDemoError;
+use crate::memory::create_mipmapped_image;
+use crate::resources::{OwnedImage, OwnedImageView, OwnedSampler};
+use crate::screenshot::read_png;
+use crate::vulkan_context::VulkanContext;
+
+// Texels are sampled as stored, like particle colors they're treated as sRGB values.
+const TEXTURE_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
+/// A sampled RGBA8 image with a full mip chain, its view and a trilinear sampler.
+/// Left in `SHADER_READ_ONLY_OPTIMAL`.
+pub struct Texture {
+    pub sampler: OwnedSampler,
+    pub view: OwnedImageView,
+    pub image: OwnedImage,
+    pub extent: vk::Extent2D,
+    pub mip_levels: u32,
+}
+
+impl Texture {
+    /// Loads a PNG of any color type, converted to 8-bit RGBA.
+    pub fn load_png(context: &VulkanContext, path: &Path) -> Result<Self, VulkanDemoError> {
+        let (extent, rgba) = read_png(path)?;
+        let texture = Self::from_rgba(context, extent, &rgba)?;
+        context.debug.set_object_name(texture.image.handle(), &path.display().to_string());
+        Ok(texture)
+    }
+
+    /// A single opaque white texel, for a binding that has to hold some texture.
+    pub fn white(context: &VulkanContext) -> Result<Self, VulkanDemoError> {
+        Self::from_rgba(context, vk::Extent2D { width: 1, height: 1 }, &[u8::MAX; 4])
+    }
+
+    /// Uploads tightly packed RGBA8 rows and blits the rest of the mip chain
+    /// from them. Formats that can't be filtered when blitted get no mips.
+    pub fn from_rgba(context: &VulkanContext, extent: vk::Extent2D, rgba: &[u8]) -> Result<Self, VulkanDemoError> {
+        let device = &context.device;
+        let features = unsafe {
+            context.instance.get_physical_device_format_properties(context.physical_device, TEXTURE_FORMAT).optimal_tiling_features
+        };
+        let blittable = features.contains(
+            vk::FormatFeatureFlags::BLIT_SRC | vk::FormatFeatureFlags::BLIT_DST | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
+        );
+        let mip_levels = if blittable { u32::BITS - extent.width.max(extent.height).leading_zeros() } else { 1 };
+
+        let image = create_mipmapped_image(
+            context,
+            extent,
+            TEXTURE_FORMAT,
+            vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST,
+            mip_levels,
+        )?;
+        let staged = context.staging().stage(rgba)?;
+
+        context.one_time_submit(|cmd| unsafe {
+            let image = image.handle();
+            let transition = |levels: std::ops::Range<u32>, (old_layout, src_access, src_stage), (new_layout, dst_access, dst_stage)| {
+                let barrier = vk::ImageMemoryBarrier::default()
+                    .old_layout(old_layout)
+                    .new_layout(new_layout)
+                    .src_access_mask(src_access)
+                    .dst_access_mask(dst_access)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .image(image)
+                    .subresource_range(mip_range(levels));
+                device.cmd_pipeline_barrier(cmd, src_stage, dst_stage, vk::DependencyFlags::empty(), &[], &[], &[barrier]);
+            };
+            let undefined = (vk::ImageLayout::UNDEFINED, vk::AccessFlags::empty(), vk::PipelineStageFlags::TOP_OF_PIPE);
+            let transfer_dst = (vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::AccessFlags::TRANSFER_WRITE, vk::PipelineStageFlags::TRANSFER);
+            let transfer_src = (vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::AccessFlags::TRANSFER_READ, vk::PipelineStageFlags::TRANSFER);
+            let shader_read =
+                (vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::AccessFlags::SHADER_READ, vk::PipelineStageFlags::FRAGMENT_SHADER);
+
+            transition(0..mip_levels, undefined, transfer_dst);
+            let region = vk::BufferImageCopy::default()
+                .buffer_offset(staged.offset)
+                .image_subresource(mip_layers(0))
+                .image_extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 });
+            device.cmd_copy_buffer_to_image(cmd, staged.buffer, image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &[region]);
+
+            // Each level is blitted from the one above it, which is then done with.
+            for level in 1..mip_levels {
+                transition(level - 1..level, transfer_dst, transfer_src);
+                let blit = vk::ImageBlit::default()
+                    .src_subresource(mip_layers(level - 1))
+                    .src_offsets([vk::Offset3D::default(), mip_corner(extent, level - 1)])
+                    .dst_subresource(mip_layers(level))
+                    .dst_offsets([vk::Offset3D::default(), mip_corner(extent, level)]);
+                device.cmd_blit_image(
+                    cmd,
+                    image,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[blit],
+                    vk::Filter::LINEAR,
+                );
+                transition(level - 1..level, transfer_src, shader_read);
+            }
+            transition(mip_levels - 1..mip_levels, transfer_dst, shader_read);
+        })?;
+
+        let view_info = vk::ImageViewCreateInfo::default()
+            .image(image.handle())
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(TEXTURE_FORMAT)
+            .subresource_range(mip_range(0..mip_levels));
+        let view = OwnedImageView::new(device, unsafe { device.create_image_view(&view_info, None)? });
+
+        let sampler_info = vk::SamplerCreateInfo::default()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .max_lod(vk::LOD_CLAMP_NONE);
+        let sampler = OwnedSampler::new(device, unsafe { device.create_sampler(&sampler_info, None)? });
+
+        Ok(Self { sampler, view, image, extent, mip_levels })
+    }
+
+    /// For a `COMBINED_IMAGE_SAMPLER` descriptor.
+    pub fn descriptor_info(&self) -> vk::DescriptorImageInfo {
+        vk::DescriptorImageInfo::default()
+            .sampler(self.sampler.handle())
+            .image_view(self.view.handle())
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+    }
+}
+
+fn mip_range(levels: std::ops::Range<u32>) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: levels.start,
+        level_count: levels.len() as u32,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
+
+fn mip_layers(level: u32) -> vk::ImageSubresourceLayers {
+    vk::ImageSubresourceLayers { aspect_mask: vk::ImageAspectFlags::COLOR, mip_level: level, base_array_layer: 0, layer_count: 1 }
+}
+
+/// The far corner of mip `level`, for blit offsets.
+fn mip_corner(extent: vk::Extent2D, level: u32) -> vk::Offset3D {
+    vk::Offset3D { x: (extent.width >> level).max(1) as i32, y: (extent.height >> level).max(1) as i32, z: 1 }
+}
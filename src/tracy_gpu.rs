@@ -0,0 +1,121 @@
+use ash::ext::calibrated_timestamps;
+use ash::vk;
+use tracy_client::{Client, GpuContext, GpuContextType, GpuSpan};
+use crate::error::VulkanDemoError;
+use crate::resources::OwnedQueryPool;
+use crate::sync::FRAMES_IN_FLIGHT;
+use crate::vulkan_context::VulkanContext;
+
+/// Zone names of the passes `GpuTimer` measures, in its query order.
+pub const PASS_NAMES: [&str; 4] = ["compute", "render", "bloom", "sort"];
+
+/// Tracy GPU zones for the passes `GpuTimer` measures, filled in with the
+/// timestamps it reads back.
+///
+/// A zone opens and closes on the CPU timeline where its pass is recorded,
+/// and its GPU times follow once the frame has been waited for. The GPU clock
+/// is aligned with Tracy's through `VK_EXT_calibrated_timestamps`, resynced
+/// every frame; without it, from a timestamp written at startup, which drifts.
+pub struct TracyGpu {
+    context: GpuContext,
+    calibration: Option<calibrated_timestamps::Device>,
+    spans: [[Option<GpuSpan>; PASS_NAMES.len()]; FRAMES_IN_FLIGHT],
+}
+
+impl TracyGpu {
+    /// `None`, with a warning, once Tracy has no GPU contexts left.
+    pub fn new(context: &VulkanContext, timestamp_period_ns: f32) -> Result<Option<Self>, VulkanDemoError> {
+        let calibration = context.calibrated_timestamps.clone();
+        let gpu_timestamp = match &calibration {
+            Some(calibration) => device_timestamp(calibration)?,
+            None => {
+                log::info!("VK_EXT_calibrated_timestamps unavailable, GPU zones may drift from CPU ones");
+                write_timestamp(context)?
+            }
+        };
+        let client = Client::start();
+        match client.new_gpu_context(Some("Vulkan"), GpuContextType::Vulkan, gpu_timestamp, timestamp_period_ns) {
+            Ok(gpu_context) => Ok(Some(Self { context: gpu_context, calibration, spans: Default::default() })),
+            Err(e) => {
+                log::warn!("No Tracy GPU zones: {e}");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Realigns the GPU clock with Tracy's, when calibrated timestamps allow it.
+    pub fn sync(&self) -> Result<(), vk::Result> {
+        if let Some(calibration) = &self.calibration {
+            self.context.sync_gpu_time(device_timestamp(calibration)?);
+        }
+        Ok(())
+    }
+
+    /// Opens the zone of `pass` in frame in flight `frame`, as it is recorded.
+    pub fn begin(&mut self, frame: usize, pass: usize) {
+        let span = self.context.span_alloc(PASS_NAMES[pass], "", file!(), line!());
+        match span {
+            Ok(span) => self.spans[frame][pass] = Some(span),
+            Err(e) => log::warn!("Dropped a GPU zone: {e}"),
+        }
+    }
+
+    pub fn end(&mut self, frame: usize, pass: usize) {
+        if let Some(span) = &mut self.spans[frame][pass] {
+            span.end_zone();
+        }
+    }
+
+    /// Hands Tracy the begin and end timestamps of `frame`'s passes, by pass,
+    /// and closes their zones.
+    pub fn upload(&mut self, frame: usize, timestamps: &[Option<[i64; 2]>; PASS_NAMES.len()]) {
+        // Tracy wants them in increasing order, so nested zones like bloom
+        // come between the ends of the one around them.
+        let mut events = Vec::new();
+        for (pass, ticks) in timestamps.iter().enumerate() {
+            if let (Some([begin, end]), Some(_)) = (ticks, &self.spans[frame][pass]) {
+                events.push((*begin, pass, true));
+                events.push((*end, pass, false));
+            }
+        }
+        events.sort_by_key(|&(timestamp, _, _)| timestamp);
+        for (timestamp, pass, start) in events {
+            let span = self.spans[frame][pass].as_ref().expect("zone without a span");
+            if start {
+                span.upload_timestamp_start(timestamp);
+            } else {
+                span.upload_timestamp_end(timestamp);
+            }
+        }
+        // Dropping the spans frees their query ids for new zones.
+        self.spans[frame] = Default::default();
+    }
+}
+
+fn device_timestamp(calibration: &calibrated_timestamps::Device) -> Result<i64, vk::Result> {
+    let info = vk::CalibratedTimestampInfoEXT::default().time_domain(vk::TimeDomainEXT::DEVICE);
+    let (timestamps, _) = unsafe { calibration.get_calibrated_timestamps(&[info])? };
+    Ok(timestamps[0] as i64)
+}
+
+/// A timestamp written by the GPU just now, as near as it can be told
+/// without calibrated timestamps.
+fn write_timestamp(context: &VulkanContext) -> Result<i64, VulkanDemoError> {
+    let device = &context.device;
+    let pool_info = vk::QueryPoolCreateInfo::default().query_type(vk::QueryType::TIMESTAMP).query_count(1);
+    let query_pool = OwnedQueryPool::new(device, unsafe { device.create_query_pool(&pool_info, None)? });
+    context.one_time_submit(|cmd| unsafe {
+        device.cmd_reset_query_pool(cmd, query_pool.handle(), 0, 1);
+        device.cmd_write_timestamp(cmd, vk::PipelineStageFlags::BOTTOM_OF_PIPE, query_pool.handle(), 0);
+    })?;
+    let mut timestamp = [0u64; 1];
+    unsafe {
+        device.get_query_pool_results(
+            query_pool.handle(),
+            0,
+            &mut timestamp,
+            vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+        )?;
+    }
+    Ok(timestamp[0] as i64)
+}
@@ -0,0 +1,304 @@
+use ash::vk;
+use egui::{CollapsingHeader, Slider};
+use std::collections::BTreeMap;
+use winit::event::WindowEvent;
+use winit::window::Window;
+use crate::allocator::HeapUsage;
+use crate::background::Background;
+use crate::config::MAX_PARTICLES;
+use crate::egui_renderer::EguiRenderer;
+use crate::error::VulkanDemoError;
+use crate::particles::SimParams;
+use crate::renderer::{PresentMode, Renderer};
+use crate::shader_diagnostics::{Severity, ShaderDiagnostic};
+use crate::spatial_grid::MAX_GRID_SIZE;
+use crate::speed_histogram::SpeedDistribution;
+use crate::vulkan_context::VulkanContext;
+
+/// What the settings window edits. The app fills it in before each frame and
+/// applies whatever came back changed.
+#[derive(Copy, Clone, Debug)]
+pub struct Settings {
+    /// `params` and `particle_count` are those of system `system` of `system_count`.
+    pub params: SimParams,
+    pub particle_count: u32,
+    pub system: usize,
+    pub system_count: usize,
+    pub present_mode: PresentMode,
+    pub clear_color: [f32; 3],
+    pub background: Background,
+    pub trails: bool,
+    pub trail_strength: f32,
+    pub bloom: bool,
+    pub bloom_threshold: f32,
+    pub bloom_intensity: f32,
+}
+
+/// The egui settings window drawn over the particles, in the renderer's
+/// overlay pass.
+pub struct Overlay {
+    renderer: EguiRenderer,
+    state: egui_winit::State,
+    context: egui::Context,
+    /// Toggled with F1. A hidden overlay draws nothing and takes no input.
+    pub visible: bool,
+    /// Particle count being edited and the system it is for, applied only with
+    /// the Apply button as it reallocates the particle buffers.
+    particle_count_edit: Option<(usize, u32)>,
+    /// What the last hot reload of each shader reported, shown in a window of
+    /// their own while there are any.
+    shader_diagnostics: BTreeMap<String, Vec<ShaderDiagnostic>>,
+}
+
+impl Overlay {
+    pub fn new(context: &VulkanContext, window: &Window, renderer: &Renderer) -> Result<Self, VulkanDemoError> {
+        let egui_context = egui::Context::default();
+        let state = egui_winit::State::new(
+            egui_context.clone(),
+            egui::ViewportId::ROOT,
+            window,
+            Some(window.scale_factor() as f32),
+            None,
+        );
+        let egui_renderer = EguiRenderer::new(context, renderer.overlay_target(), renderer.format.format)?;
+        Ok(Self {
+            renderer: egui_renderer,
+            state,
+            context: egui_context,
+            visible: true,
+            particle_count_edit: None,
+            shader_diagnostics: BTreeMap::new(),
+        })
+    }
+
+    /// Passes a window event to egui first. Returns whether egui consumed it,
+    /// in which case the app should ignore it.
+    pub fn on_window_event(&mut self, window: &Window, event: &WindowEvent) -> bool {
+        self.visible && self.state.on_window_event(window, event).consumed
+    }
+
+    /// Shows `diagnostics` for the reload of `shader` in place of what its last
+    /// reload reported; none clears them.
+    pub fn set_shader_diagnostics(&mut self, shader: &str, diagnostics: Vec<ShaderDiagnostic>) {
+        if diagnostics.is_empty() {
+            self.shader_diagnostics.remove(shader);
+        } else {
+            self.shader_diagnostics.insert(shader.to_string(), diagnostics);
+        }
+    }
+
+    /// Switches to the draw buffers of frame in flight `frame`, which the GPU
+    /// must be done with. Called even while hidden, to release retired textures.
+    pub fn begin_frame(&mut self, device: &ash::Device, frame: usize) {
+        self.renderer.begin_frame(device, frame);
+    }
+
+    /// Runs the settings window for one frame, editing `settings`, and uploads
+    /// what it draws into the current frame's buffers. `speeds` are those of
+    /// the selected system, when read back yet.
+    pub fn update(
+        &mut self,
+        context: &VulkanContext,
+        window: &Window,
+        extent: vk::Extent2D,
+        settings: &mut Settings,
+        speeds: Option<&SpeedDistribution>,
+    ) -> Result<(), VulkanDemoError> {
+        if !self.visible {
+            return Ok(());
+        }
+        let raw_input = self.state.take_egui_input(window);
+        let egui_context = self.context.clone();
+        let memory = context.memory_report();
+        let output = egui_context.run(raw_input, |ctx| {
+            self.settings_window(ctx, settings, &memory, speeds);
+            self.shader_diagnostics_window(ctx);
+        });
+        self.state.handle_platform_output(window, output.platform_output);
+
+        let primitives = egui_context.tessellate(output.shapes, output.pixels_per_point);
+        self.renderer.update_textures(context, &output.textures_delta)?;
+        self.renderer.upload(context, &primitives, output.pixels_per_point, extent)
+    }
+
+    /// Records the overlay's draws. Must be called inside the overlay pass.
+    pub fn record(&self, device: &ash::Device, cmd: vk::CommandBuffer) {
+        if self.visible {
+            self.renderer.record(device, cmd);
+        }
+    }
+
+    fn settings_window(
+        &mut self,
+        ctx: &egui::Context,
+        settings: &mut Settings,
+        memory: &[HeapUsage],
+        speeds: Option<&SpeedDistribution>,
+    ) {
+        egui::Window::new("Settings").default_width(280.0).show(ctx, |ui| {
+            let params = &mut settings.params;
+            if settings.system_count > 1 {
+                ui.label(format!("System {} of {} (Tab to switch)", settings.system + 1, settings.system_count));
+            }
+
+            CollapsingHeader::new("Simulation").default_open(true).show(ui, |ui| {
+                ui.add(Slider::new(&mut params.gravity[0], -2.0..=2.0).text("gravity x"));
+                ui.add(Slider::new(&mut params.gravity[1], -2.0..=2.0).text("gravity y"));
+                ui.add(Slider::new(&mut params.drag, 0.0..=5.0).text("drag"));
+                ui.add(Slider::new(&mut params.max_speed, 0.1..=10.0).text("max speed"));
+                ui.add(Slider::new(&mut params.restitution, 0.0..=1.0).text("restitution"));
+                ui.add(Slider::new(&mut params.color_speed_scale, 0.0..=10.0).text("color speed scale"));
+            });
+
+            CollapsingHeader::new("Speeds").show(ui, |ui| match speeds {
+                Some(speeds) => speed_bars(ui, speeds),
+                None => {
+                    ui.label("Not read back yet");
+                }
+            });
+
+            CollapsingHeader::new("Emitter").show(ui, |ui| {
+                ui.add(Slider::new(&mut params.emit_speed_min, 0.0..=2.0).text("min speed"));
+                ui.add(Slider::new(&mut params.emit_speed_max, 0.0..=2.0).text("max speed"));
+                ui.add(Slider::new(&mut params.lifetime, 0.1..=20.0).logarithmic(true).text("lifetime"));
+                params.emit_speed_max = params.emit_speed_max.max(params.emit_speed_min);
+            });
+
+            CollapsingHeader::new("N-body").show(ui, |ui| {
+                ui.add(Slider::new(&mut params.softening, 0.001..=0.2).logarithmic(true).text("softening"));
+                ui.add(Slider::new(&mut params.nbody_strength, 0.0..=5.0).text("strength"));
+            });
+
+            CollapsingHeader::new("Neighbors").show(ui, |ui| {
+                ui.add(Slider::new(&mut params.grid_size, 1..=MAX_GRID_SIZE).text("grid size"));
+                ui.add(Slider::new(&mut params.repulsion_strength, 0.0..=2.0).text("repulsion"));
+                ui.add(Slider::new(&mut params.separation_weight, 0.0..=3.0).text("separation"));
+                ui.add(Slider::new(&mut params.alignment_weight, 0.0..=3.0).text("alignment"));
+                ui.add(Slider::new(&mut params.cohesion_weight, 0.0..=3.0).text("cohesion"));
+                ui.add(Slider::new(&mut params.perception_radius, 0.01..=0.3).text("perception radius"));
+                ui.add(Slider::new(&mut params.min_speed, 0.0..=1.0).text("boid min speed"));
+            });
+
+            CollapsingHeader::new("Curl noise").show(ui, |ui| {
+                ui.add(Slider::new(&mut params.noise_scale, 0.1..=10.0).logarithmic(true).text("scale"));
+                ui.add(Slider::new(&mut params.noise_strength, 0.0..=2.0).text("strength"));
+                ui.add(Slider::new(&mut params.noise_speed, 0.0..=2.0).text("speed"));
+            });
+
+            CollapsingHeader::new("Fluid").show(ui, |ui| {
+                ui.add(Slider::new(&mut params.smoothing_radius, 0.005..=0.05).logarithmic(true).text("smoothing radius"));
+                ui.add(Slider::new(&mut params.rest_density, 0.2..=4.0).text("rest density"));
+                ui.add(Slider::new(&mut params.stiffness, 0.0..=20.0).text("stiffness"));
+                ui.add(Slider::new(&mut params.viscosity, 0.0..=0.01).text("viscosity"));
+            });
+
+            CollapsingHeader::new("Background").show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Clear color");
+                    // The picker's float variant is linear, so edit the sRGB color as bytes, and only
+                    // write back a change so the rounding doesn't drift the configured color.
+                    let mut srgb = settings.clear_color.map(|c| (c * 255.0).round() as u8);
+                    if ui.color_edit_button_srgb(&mut srgb).changed() {
+                        settings.clear_color = srgb.map(|c| c as f32 / 255.0);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.selectable_value(&mut settings.background, Background::Solid, "Solid");
+                    ui.selectable_value(&mut settings.background, Background::Gradient, "Gradient");
+                    ui.selectable_value(&mut settings.background, Background::Hue, "Hue");
+                });
+                ui.checkbox(&mut settings.trails, "Trails");
+                ui.add_enabled(settings.trails, Slider::new(&mut settings.trail_strength, 0.0..=0.99).text("trail strength"));
+            });
+
+            CollapsingHeader::new("Bloom").show(ui, |ui| {
+                ui.checkbox(&mut settings.bloom, "Enabled");
+                ui.add_enabled_ui(settings.bloom, |ui| {
+                    ui.add(Slider::new(&mut settings.bloom_threshold, 0.0..=1.0).text("threshold"));
+                    ui.add(Slider::new(&mut settings.bloom_intensity, 0.0..=4.0).text("intensity"));
+                });
+            });
+
+            CollapsingHeader::new("Memory").show(ui, |ui| {
+                for (index, heap) in memory.iter().enumerate() {
+                    let kind = if heap.device_local { "device" } else { "host" };
+                    ui.label(format!(
+                        "Heap {index} ({kind}): {:.1} / {:.1} MiB in {} allocations, {} blocks",
+                        mib(heap.used),
+                        mib(heap.reserved),
+                        heap.allocations,
+                        heap.blocks,
+                    ));
+                    ui.label(format!("    process usage {:.1} / {:.1} MiB budget", mib(heap.usage), mib(heap.budget)));
+                }
+            });
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                if self.particle_count_edit.is_some_and(|(system, _)| system != settings.system) {
+                    self.particle_count_edit = None;
+                }
+                let (_, count) = self.particle_count_edit.get_or_insert((settings.system, settings.particle_count));
+                ui.add(Slider::new(count, 1..=MAX_PARTICLES).logarithmic(true).text("particles"));
+                if ui.add_enabled(*count != settings.particle_count, egui::Button::new("Apply")).clicked() {
+                    settings.particle_count = *count;
+                }
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Present mode");
+                ui.selectable_value(&mut settings.present_mode, PresentMode::Fifo, "FIFO");
+                ui.selectable_value(&mut settings.present_mode, PresentMode::Mailbox, "Mailbox");
+                ui.selectable_value(&mut settings.present_mode, PresentMode::Immediate, "Immediate");
+            });
+        });
+    }
+
+    /// Lists what failed hot reloads reported, each diagnostic with its source context.
+    fn shader_diagnostics_window(&self, ctx: &egui::Context) {
+        if self.shader_diagnostics.is_empty() {
+            return;
+        }
+        egui::Window::new("Shader errors").default_width(520.0).show(ctx, |ui| {
+            egui::ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                for (shader, diagnostics) in &self.shader_diagnostics {
+                    ui.strong(format!("Reloading {shader}"));
+                    for diagnostic in diagnostics {
+                        let color = match diagnostic.severity {
+                            Severity::Error => ui.visuals().error_fg_color,
+                            Severity::Warning => ui.visuals().warn_fg_color,
+                        };
+                        ui.label(egui::RichText::new(diagnostic.to_string()).monospace().color(color));
+                    }
+                    ui.separator();
+                }
+            });
+        });
+    }
+}
+
+/// Draws `speeds` as a bar per bucket, scaled to the fullest one, over its mean and max.
+fn speed_bars(ui: &mut egui::Ui, speeds: &SpeedDistribution) {
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(ui.available_width(), 64.0), egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, ui.visuals().extreme_bg_color);
+    let fullest = speeds.bins.iter().copied().max().unwrap_or(0).max(1) as f32;
+    let bar_width = rect.width() / speeds.bins.len() as f32;
+    for (bin, &count) in speeds.bins.iter().enumerate() {
+        let left = rect.left() + bin as f32 * bar_width;
+        let top = rect.bottom() - rect.height() * count as f32 / fullest;
+        let bar = egui::Rect::from_min_max(egui::pos2(left, top), egui::pos2(left + bar_width, rect.bottom()));
+        painter.rect_filled(bar.shrink2(egui::vec2(0.5, 0.0)), 0.0, ui.visuals().selection.bg_fill);
+    }
+    ui.label(format!(
+        "0 to {:.2}: mean {:.3}, max {:.3} over {} particles",
+        speeds.range,
+        speeds.mean(),
+        speeds.max,
+        speeds.count(),
+    ));
+}
+
+fn mib(bytes: vk::DeviceSize) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
@@ -0,0 +1,307 @@
+use ash::{vk, Device};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use crate::allocator::Allocator;
+use crate::error::VulkanDemoError;
+use crate::promoted::Synchronization2;
+use crate::resources::{OwnedBuffer, OwnedCommandPool, OwnedFence, OwnedSemaphore, QueueOwnership};
+use crate::staging::StagingRing;
+use crate::sync2::Sync2;
+
+/// Bytes of queued uploads `UploadContext::poll` copies per call, half its
+/// staging ring so the next call's chunk fits while this one is in flight.
+/// Uploads bigger than this are worth streaming over several frames.
+pub const UPLOAD_BYTES_PER_POLL: vk::DeviceSize = 16 << 20;
+
+/// Copies into device-local buffers on the transfer queue, so large uploads
+/// don't hold up the graphics queue. Falls back to the graphics queue on
+/// devices without a transfer-only family.
+///
+/// `upload_async` queues the bytes and returns straight away; each `poll`, once
+/// a frame, then copies the next `UPLOAD_BYTES_PER_POLL` of them through the
+/// context's own staging ring, so a big upload streams in over several frames.
+/// Uploads finish in the order they were made. `upload_now` does the same but
+/// blocks until its copies are done.
+///
+/// Every submission signals the next value of a timeline semaphore, when the
+/// device has them, which the graphics side waits on through the finished
+/// upload's `UploadDependency`. Exclusive buffers are released to the graphics
+/// family after their last copy, for it to acquire with `OwnedBuffer::acquire`.
+pub struct UploadContext {
+    device: Arc<Device>,
+    sync2: Sync2,
+    queue: vk::Queue,
+    family: u32,
+    graphics_family: u32,
+    command_pool: OwnedCommandPool,
+    staging: StagingRing,
+    timeline: Option<UploadTimeline>,
+    /// Uploads with bytes left to copy, oldest first.
+    queued: VecDeque<QueuedUpload>,
+    /// Submissions not yet known to have finished, oldest first.
+    in_flight: VecDeque<Submission>,
+    /// Signaled and reset, ready for the next submission.
+    spare_fences: Vec<OwnedFence>,
+    next_id: u64,
+    /// Uploads before this id have finished.
+    finished: u64,
+    /// The timeline value signaled by the latest finished submission.
+    finished_value: u64,
+}
+
+struct UploadTimeline {
+    semaphore: OwnedSemaphore,
+    /// The value signaled by the latest submission.
+    value: u64,
+}
+
+struct QueuedUpload {
+    id: u64,
+    bytes: Vec<u8>,
+    /// Buffers the bytes are copied into, each at its offset.
+    targets: Vec<(vk::Buffer, vk::DeviceSize)>,
+    /// Bytes copied so far.
+    copied: usize,
+    /// Releases exclusive targets to the graphics family, submitted after the last copy.
+    release: Option<vk::CommandBuffer>,
+}
+
+struct Submission {
+    command_buffers: Vec<vk::CommandBuffer>,
+    /// Signaled by a fence-only `vkQueueSubmit` right after the copies; the
+    /// staging ring has a fence of its own on them.
+    fence: OwnedFence,
+    /// Uploads before this id have finished once the fence signals.
+    finishes: u64,
+    timeline_value: u64,
+}
+
+/// An upload made by `UploadContext::upload_async`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct UploadHandle(u64);
+
+/// What the graphics side needs before using the buffers of a finished upload.
+#[derive(Copy, Clone, Debug)]
+pub struct UploadDependency {
+    /// For the first submission using them to wait on; `None` without
+    /// timeline semaphores, when seeing the upload finish on the host is all
+    /// there is to wait for.
+    pub wait: Option<vk::SemaphoreSubmitInfo<'static>>,
+    /// The family exclusive targets were released to, which must acquire
+    /// them with `OwnedBuffer::acquire` before anything else uses them.
+    pub family: u32,
+}
+
+impl UploadContext {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        device: &Arc<Device>,
+        allocator: &Arc<Allocator>,
+        limits: &vk::PhysicalDeviceLimits,
+        synchronization2: Option<Synchronization2>,
+        timeline_semaphores: bool,
+        queue: vk::Queue,
+        family: u32,
+        graphics_family: u32,
+    ) -> Result<Self, VulkanDemoError> {
+        let pool_info = vk::CommandPoolCreateInfo::default()
+            .queue_family_index(family)
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT);
+        let command_pool = OwnedCommandPool::new(device, unsafe { device.create_command_pool(&pool_info, None)? });
+        let timeline = match timeline_semaphores {
+            true => {
+                let mut type_info = vk::SemaphoreTypeCreateInfo::default()
+                    .semaphore_type(vk::SemaphoreType::TIMELINE)
+                    .initial_value(0);
+                let semaphore_info = vk::SemaphoreCreateInfo::default().push_next(&mut type_info);
+                let semaphore = OwnedSemaphore::new(device, unsafe { device.create_semaphore(&semaphore_info, None)? });
+                Some(UploadTimeline { semaphore, value: 0 })
+            }
+            false => None,
+        };
+        Ok(Self {
+            device: device.clone(),
+            sync2: Sync2::with_loader(synchronization2),
+            queue,
+            family,
+            graphics_family,
+            command_pool,
+            staging: StagingRing::new(device, allocator, limits)?,
+            timeline,
+            queued: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            spare_fences: Vec::new(),
+            next_id: 0,
+            finished: 0,
+            finished_value: 0,
+        })
+    }
+
+    /// Queues copying `bytes` into each of `targets` at its offset, for `poll`
+    /// to carry out. The targets must stay alive, and unused by anything else,
+    /// until the upload has finished.
+    pub fn upload_async(&mut self, bytes: Vec<u8>, targets: &[(&OwnedBuffer, vk::DeviceSize)]) -> Result<UploadHandle, vk::Result> {
+        let exclusive: Vec<_> = targets
+            .iter()
+            .map(|(buffer, _)| *buffer)
+            .filter(|buffer| buffer.ownership() != QueueOwnership::Concurrent && self.family != self.graphics_family)
+            .collect();
+        let release = if exclusive.is_empty() {
+            None
+        } else {
+            let cmd = self.begin_command_buffer()?;
+            for buffer in exclusive {
+                buffer.release(
+                    &self.device,
+                    cmd,
+                    (self.family, self.graphics_family),
+                    (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+                );
+            }
+            unsafe { self.device.end_command_buffer(cmd)? };
+            Some(cmd)
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queued.push_back(QueuedUpload {
+            id,
+            bytes,
+            targets: targets.iter().map(|(buffer, offset)| (buffer.handle(), *offset)).collect(),
+            copied: 0,
+            release,
+        });
+        Ok(UploadHandle(id))
+    }
+
+    /// Copies `bytes` into each of `targets` like `upload_async`, and blocks
+    /// until the copies, and every upload queued before them, have finished.
+    pub fn upload_now(
+        &mut self,
+        bytes: &[u8],
+        targets: &[(&OwnedBuffer, vk::DeviceSize)],
+    ) -> Result<UploadDependency, VulkanDemoError> {
+        let handle = self.upload_async(bytes.to_vec(), targets)?;
+        self.wait(handle)
+    }
+
+    /// Blocks until `handle` has finished, submitting whatever it still has
+    /// to copy.
+    pub fn wait(&mut self, handle: UploadHandle) -> Result<UploadDependency, VulkanDemoError> {
+        loop {
+            if let Some(dependency) = self.finished(handle) {
+                return Ok(dependency);
+            }
+            if let Some(oldest) = self.in_flight.front() {
+                unsafe { self.device.wait_for_fences(&[oldest.fence.handle()], true, u64::MAX)? };
+            }
+            self.poll()?;
+        }
+    }
+
+    /// Retires finished submissions, then copies up to `UPLOAD_BYTES_PER_POLL`
+    /// more bytes of the queued uploads. Called once a frame.
+    pub fn poll(&mut self) -> Result<(), VulkanDemoError> {
+        self.retire()?;
+        if self.queued.is_empty() {
+            return Ok(());
+        }
+
+        let cmd = self.begin_command_buffer()?;
+        let mut command_buffers = vec![cmd];
+        let mut budget = UPLOAD_BYTES_PER_POLL as usize;
+        let mut finishes = self.finished;
+        while let Some(upload) = self.queued.front_mut() {
+            let len = budget.min(upload.bytes.len() - upload.copied);
+            if len > 0 {
+                let staged = self.staging.stage(&upload.bytes[upload.copied..upload.copied + len])?;
+                for &(buffer, offset) in &upload.targets {
+                    staged.record_copy_to(&self.device, cmd, buffer, offset + upload.copied as vk::DeviceSize);
+                }
+                upload.copied += len;
+                budget -= len;
+            }
+            if upload.copied < upload.bytes.len() {
+                break;
+            }
+            let upload = self.queued.pop_front().expect("no upload queued");
+            command_buffers.extend(upload.release);
+            finishes = upload.id + 1;
+        }
+        unsafe { self.device.end_command_buffer(cmd)? };
+
+        let mut signals = Vec::with_capacity(1);
+        let timeline_value = match &mut self.timeline {
+            Some(timeline) => {
+                timeline.value += 1;
+                signals.push(
+                    vk::SemaphoreSubmitInfo::default()
+                        .semaphore(timeline.semaphore.handle())
+                        .value(timeline.value)
+                        .stage_mask(vk::PipelineStageFlags2::ALL_TRANSFER),
+                );
+                timeline.value
+            }
+            None => 0,
+        };
+        let staging_fence = self.staging.fence()?;
+        self.sync2.queue_submit(&self.device, self.queue, &command_buffers, &[], &signals, staging_fence)?;
+        // A submit with no batches only signals its fence, once everything
+        // submitted to the queue before it, the copies included, has finished.
+        let fence = match self.spare_fences.pop() {
+            Some(fence) => fence,
+            None => OwnedFence::new(&self.device, unsafe { self.device.create_fence(&vk::FenceCreateInfo::default(), None)? }),
+        };
+        unsafe { self.device.queue_submit(self.queue, &[], fence.handle())? };
+        self.in_flight.push_back(Submission { command_buffers, fence, finishes, timeline_value });
+        Ok(())
+    }
+
+    /// What using the buffers of `handle` has to wait for, once it has finished.
+    pub fn finished(&self, handle: UploadHandle) -> Option<UploadDependency> {
+        (handle.0 < self.finished).then(|| UploadDependency {
+            wait: self.timeline.as_ref().map(|timeline| {
+                vk::SemaphoreSubmitInfo::default()
+                    .semaphore(timeline.semaphore.handle())
+                    .value(self.finished_value)
+                    .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+            }),
+            family: self.graphics_family,
+        })
+    }
+
+    /// Whether uploads go through a queue of their own rather than the graphics queue.
+    pub fn is_dedicated(&self) -> bool {
+        self.family != self.graphics_family
+    }
+
+    /// Frees the command buffers of every submission that has finished.
+    fn retire(&mut self) -> Result<(), vk::Result> {
+        while let Some(submission) = self.in_flight.front() {
+            if !unsafe { self.device.get_fence_status(submission.fence.handle())? } {
+                break;
+            }
+            let submission = self.in_flight.pop_front().expect("no submission in flight");
+            unsafe {
+                self.device.reset_fences(&[submission.fence.handle()])?;
+                self.device.free_command_buffers(self.command_pool.handle(), &submission.command_buffers);
+            }
+            // A submission that finished no upload leaves `finished` as it was.
+            self.finished = self.finished.max(submission.finishes);
+            self.finished_value = submission.timeline_value;
+            self.spare_fences.push(submission.fence);
+        }
+        Ok(())
+    }
+
+    fn begin_command_buffer(&self) -> Result<vk::CommandBuffer, vk::Result> {
+        let alloc_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(self.command_pool.handle())
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        let cmd = unsafe { self.device.allocate_command_buffers(&alloc_info)? }[0];
+        let begin_info = vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe { self.device.begin_command_buffer(cmd, &begin_info)? };
+        Ok(cmd)
+    }
+}
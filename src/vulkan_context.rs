@@ -1,110 +1,798 @@
 use ash::{vk, Entry, Instance, Device};
-use ash::khr::{surface, swapchain};
-use std::ffi::CStr;
+use ash::ext::{calibrated_timestamps, debug_utils, memory_budget, validation_features};
+use ash::khr::{buffer_device_address, dynamic_rendering, surface, swapchain, synchronization2, timeline_semaphore};
+use std::ffi::{c_void, CStr};
+use std::mem::ManuallyDrop;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use winit::window::Window;
-use winit::raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
+use crate::allocator::{Allocator, HeapUsage, MemoryLocation};
+use crate::compute_pass::SharedDescriptorPool;
+use crate::debug::DebugUtils;
+use crate::device_features::{supports_device_extensions, DeviceFeatures, FeatureRequest};
+use crate::error::VulkanDemoError;
+use crate::pipeline_cache::{default_cache_path, load_pipeline_cache, save_pipeline_cache};
+use crate::promoted::{BufferDeviceAddress, DynamicRendering, Promoted, Synchronization2, TimelineSemaphore};
+use crate::resources::{OwnedCommandPool, OwnedSurface};
+use crate::staging::StagingRing;
+use crate::upload::UploadContext;
+use winit::raw_window_handle::{HasDisplayHandle, HasWindowHandle};
 
+const VALIDATION_LAYER: &CStr = c"VK_LAYER_KHRONOS_validation";
+const SWAPCHAIN_DEVICE_EXTENSIONS: [&CStr; 1] = [swapchain::NAME];
+/// The newest Vulkan version the demo uses the core features of; older
+/// instances and devices down to `MIN_API_VERSION` get their extensions.
+const TARGET_API_VERSION: u32 = vk::API_VERSION_1_3;
+const MIN_API_VERSION: u32 = vk::API_VERSION_1_1;
+
+/// Instance, window surface, logical device and queues shared by the rest of the demo.
+///
+/// A headless context has a null `surface` and no swapchain support.
 pub struct VulkanContext {
+    /// Keeps the Vulkan loader alive for the lifetime of the instance.
     pub entry: Entry,
     pub instance: Instance,
+    pub debug_utils_loader: Option<debug_utils::Instance>,
+    pub debug_messenger: vk::DebugUtilsMessengerEXT,
+    /// Object names and command buffer labels, when `VK_EXT_debug_utils` is available.
+    pub debug: DebugUtils,
     pub surface_loader: surface::Instance,
     pub surface: vk::SurfaceKHR,
     pub physical_device: vk::PhysicalDevice,
-    pub device: Device,
+    /// The Vulkan version the device is used at, without the patch number:
+    /// the lowest of the instance's, the device's and `TARGET_API_VERSION`.
+    pub api_version: u32,
+    /// Shared with the owning wrappers in `resources`, which need it to destroy themselves.
+    pub device: Arc<Device>,
+    /// Memory for every buffer and image; destroyed after them, right before the device.
+    pub allocator: Arc<Allocator>,
+    /// Staging space for uploads and readbacks, through `staging`.
+    staging: ManuallyDrop<Mutex<StagingRing>>,
+    /// Uploads through the transfer queue, through `uploads`.
+    uploads: ManuallyDrop<Mutex<UploadContext>>,
+    /// Where every `DescriptorSets` of a compute pass comes from.
+    pub descriptor_pool: ManuallyDrop<Arc<SharedDescriptorPool>>,
     pub graphics_queue: vk::Queue,
+    /// A queue of a dedicated compute family when the device has one, otherwise
+    /// the graphics queue itself.
     pub compute_queue: vk::Queue,
+    /// Swapchain images are presented here: the graphics queue itself, unless
+    /// the device can only present from another family. Also the graphics
+    /// queue when headless.
+    pub present_queue: vk::Queue,
+    /// A queue of a transfer-only family when the device has one, otherwise
+    /// the graphics queue itself.
+    pub transfer_queue: vk::Queue,
     pub queue_family_index: u32,
+    pub compute_queue_family_index: u32,
+    pub present_queue_family_index: u32,
+    pub transfer_queue_family_index: u32,
+    /// Smallest and largest `gl_PointSize` the device can rasterize; `[1, 1]`
+    /// without the `largePoints` feature.
+    pub point_size_range: [f32; 2],
+    /// The features enabled on the device: those requested that it supports.
+    pub features: DeviceFeatures,
+    /// Whether `VK_EXT_memory_budget` is enabled, so `memory_report` has the
+    /// driver's budgets instead of plain heap sizes.
+    pub memory_budget: bool,
+    /// Loaded when `VK_EXT_calibrated_timestamps` can read the device's
+    /// timestamp clock from the host, which profiler GPU zones are aligned by.
+    pub calibrated_timestamps: Option<calibrated_timestamps::Device>,
+    /// Loaded with the `timeline_semaphore` feature, which frame
+    /// synchronization then uses instead of fences.
+    pub timeline_semaphore: Option<TimelineSemaphore>,
+    /// Loaded with the `synchronization2` feature, used for barriers and
+    /// submits through `Sync2`.
+    pub synchronization2: Option<Synchronization2>,
+    /// Loaded with the `dynamic_rendering` feature, which the renderer then
+    /// uses instead of render pass objects.
+    pub dynamic_rendering: Option<DynamicRendering>,
+    /// Loaded with the `buffer_device_address` feature, for `--bda` to look up
+    /// the particle buffers' addresses.
+    pub buffer_device_address: Option<BufferDeviceAddress>,
+    /// Shared by every pipeline; written to `pipeline_cache_path` when the context is dropped.
+    pub pipeline_cache: vk::PipelineCache,
+    pipeline_cache_path: Option<PathBuf>,
 }
 
 impl VulkanContext {
-    pub fn new(window: &Window) -> Result<Self, Box<dyn std::error::Error>> {
+    /// Creates the instance, surface and device for `window`.
+    ///
+    /// `device_index` forces a specific adapter from `enumerate_physical_devices`;
+    /// when `None`, the `VK_DEVICE_INDEX` environment variable is consulted before
+    /// falling back to picking the best-ranked GPU. `validation` likewise takes
+    /// precedence over the `VALIDATION` environment variable.
+    ///
+    /// The device is created with the `features` it supports, and fails with
+    /// `MissingDeviceFeatures` if it lacks required ones.
+    pub fn new(
+        window: &Window,
+        device_index: Option<usize>,
+        validation: Option<bool>,
+        features: &FeatureRequest,
+    ) -> Result<Self, VulkanDemoError> {
+        Self::create(Some(window), device_index, validation, features)
+    }
+
+    /// Creates an instance and device for off-screen rendering, without a surface.
+    /// Any GPU with a graphics and compute queue qualifies.
+    pub fn new_headless(
+        device_index: Option<usize>,
+        validation: Option<bool>,
+        features: &FeatureRequest,
+    ) -> Result<Self, VulkanDemoError> {
+        Self::create(None, device_index, validation, features)
+    }
+
+    fn create(
+        window: Option<&Window>,
+        device_index: Option<usize>,
+        validation: Option<bool>,
+        requested: &FeatureRequest,
+    ) -> Result<Self, VulkanDemoError> {
         let entry = unsafe { Entry::load()? };
-        
+        // Vulkan 1.0 loaders can't report their version, and can't create 1.1 instances either.
+        let instance_version = unsafe { entry.try_enumerate_instance_version()? }.unwrap_or(vk::API_VERSION_1_0);
+        if instance_version < MIN_API_VERSION {
+            return Err(VulkanDemoError::UnsupportedApiVersion(format_api_version(instance_version)));
+        }
+        let requested_version = without_patch(instance_version).min(TARGET_API_VERSION);
+
         let app_info = vk::ApplicationInfo::default()
-            .application_name(unsafe { CStr::from_bytes_with_nul_unchecked(b"Vulkan Particle Demo\0") })
+            .application_name(c"Vulkan Particle Demo")
             .application_version(vk::make_api_version(0, 1, 0, 0))
-            .engine_name(unsafe { CStr::from_bytes_with_nul_unchecked(b"No Engine\0") })
+            .engine_name(c"No Engine")
             .engine_version(vk::make_api_version(0, 1, 0, 0))
-            .api_version(vk::API_VERSION_1_1);
+            .api_version(requested_version);
 
-        let extension_names = ash_window::enumerate_required_extensions(window.raw_display_handle()?)?;
-        
-        let create_info = vk::InstanceCreateInfo::default()
+        let mut extension_names = match window {
+            Some(window) => ash_window::enumerate_required_extensions(window.display_handle()?.as_raw())?.to_vec(),
+            None => Vec::new(),
+        };
+
+        let validation = validation.unwrap_or_else(validation_requested) && validation_available(&entry)?;
+        let mut layer_names = Vec::new();
+        if validation {
+            layer_names.push(VALIDATION_LAYER.as_ptr());
+            log::info!("Enabling {}", VALIDATION_LAYER.to_string_lossy());
+        }
+        // The validation layer provides debug utils itself; otherwise a capture
+        // tool or the driver may.
+        let debug_utils_enabled = validation || debug_utils_available(&entry)?;
+        if debug_utils_enabled {
+            extension_names.push(debug_utils::NAME.as_ptr());
+        }
+        let sync_validation = validation && sync_validation_requested();
+        if sync_validation {
+            extension_names.push(validation_features::NAME.as_ptr());
+            log::info!("Enabling synchronization validation");
+        }
+
+        let mut messenger_info = debug_messenger_create_info();
+        let mut create_info = vk::InstanceCreateInfo::default()
             .application_info(&app_info)
+            .enabled_layer_names(&layer_names)
             .enabled_extension_names(&extension_names);
+        if validation {
+            // Also report problems during instance creation and destruction.
+            create_info = create_info.push_next(&mut messenger_info);
+        }
+        let enabled_features = [vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION];
+        let mut features_info = vk::ValidationFeaturesEXT::default().enabled_validation_features(&enabled_features);
+        if sync_validation {
+            create_info = create_info.push_next(&mut features_info);
+        }
 
         let instance = unsafe { entry.create_instance(&create_info, None)? };
-        
-        let surface = unsafe {
-            ash_window::create_surface(
-                &entry,
-                &instance,
-                window.raw_display_handle()?,
-                window.raw_window_handle()?,
-                None,
-            )?
+
+        let (debug_utils_loader, debug_messenger) = if validation {
+            let loader = debug_utils::Instance::new(&entry, &instance);
+            let messenger = unsafe { loader.create_debug_utils_messenger(&debug_messenger_create_info(), None)? };
+            (Some(loader), messenger)
+        } else {
+            (None, vk::DebugUtilsMessengerEXT::null())
+        };
+
+        let surface = match window {
+            Some(window) => create_surface(&entry, &instance, window)?,
+            None => vk::SurfaceKHR::null(),
         };
         
         let surface_loader = surface::Instance::new(&entry, &instance);
+        let required_extensions: &[&CStr] = if window.is_some() { &SWAPCHAIN_DEVICE_EXTENSIONS } else { &[] };
 
-        let (physical_device, queue_family_index) = unsafe {
-            instance.enumerate_physical_devices()?
-                .into_iter()
-                .filter_map(|pdevice| {
-                    instance.get_physical_device_queue_family_properties(pdevice)
-                        .into_iter()
-                        .enumerate()
-                        .filter_map(|(index, info)| {
-                            let supports_graphic_and_compute = info.queue_flags.contains(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE);
-                            let supports_surface = surface_loader.get_physical_device_surface_support(pdevice, index as u32, surface).unwrap_or(false);
-                            
-                            if supports_graphic_and_compute && supports_surface {
-                                Some((pdevice, index as u32))
-                            } else {
-                                None
-                            }
-                        })
-                        .next()
+        let physical_devices = unsafe { instance.enumerate_physical_devices()? };
+        let device_index = device_index.or_else(|| {
+            std::env::var("VK_DEVICE_INDEX").ok().and_then(|value| value.parse().ok())
+        });
+
+        let (physical_device, (queue_family_index, present_queue_family_index)) = match device_index {
+            Some(index) => {
+                let &pdevice = physical_devices.get(index).ok_or(VulkanDemoError::InvalidDeviceIndex {
+                    index,
+                    count: physical_devices.len(),
+                })?;
+                let queue_families = find_queue_families(&instance, &surface_loader, surface, pdevice)
+                    .filter(|_| supports_device_extensions(&instance, pdevice, required_extensions))
+                    .ok_or(VulkanDemoError::NoSuitableGpu)?;
+                (pdevice, queue_families)
+            }
+            None => physical_devices
+                .iter()
+                .filter(|&&pdevice| supports_device_extensions(&instance, pdevice, required_extensions))
+                .filter_map(|&pdevice| {
+                    find_queue_families(&instance, &surface_loader, surface, pdevice).map(|families| (pdevice, families))
+                })
+                // max_by_key keeps the last maximum, so reverse to prefer enumeration order on ties.
+                .rev()
+                .max_by_key(|&(pdevice, _)| {
+                    let properties = unsafe { instance.get_physical_device_properties(pdevice) };
+                    device_type_score(properties.device_type)
                 })
-                .next()
-                .ok_or("No suitable GPU found")?
+                .ok_or(VulkanDemoError::NoSuitableGpu)?,
         };
 
+        log::info!("Selected: {}", describe_device(&instance, physical_device));
+        let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+        let api_version = without_patch(properties.api_version).min(requested_version);
+        if api_version >= vk::API_VERSION_1_3 {
+            log::info!("Device supports Vulkan {}, using Vulkan 1.3 core features", format_api_version(properties.api_version));
+        } else {
+            log::info!(
+                "Device supports Vulkan {}, using the Vulkan {}.{} compatibility path",
+                format_api_version(properties.api_version),
+                vk::api_version_major(api_version),
+                vk::api_version_minor(api_version),
+            );
+        }
+
+        let compute_queue_family_index = find_compute_queue_family(&instance, physical_device).unwrap_or(queue_family_index);
+        let transfer_queue_family_index = find_transfer_queue_family(&instance, physical_device).unwrap_or(queue_family_index);
+
         let priorities = [1.0];
-        let queue_info = vk::DeviceQueueCreateInfo::default()
-            .queue_family_index(queue_family_index)
-            .queue_priorities(&priorities);
+        let mut queue_families = vec![queue_family_index];
+        if compute_queue_family_index != queue_family_index {
+            log::info!("Using queue family {compute_queue_family_index} for async compute");
+            queue_families.push(compute_queue_family_index);
+        }
+        if present_queue_family_index != queue_family_index {
+            log::info!("Presenting from queue family {present_queue_family_index}");
+            if !queue_families.contains(&present_queue_family_index) {
+                queue_families.push(present_queue_family_index);
+            }
+        }
+        if transfer_queue_family_index != queue_family_index {
+            log::info!("Using queue family {transfer_queue_family_index} for uploads");
+            if !queue_families.contains(&transfer_queue_family_index) {
+                queue_families.push(transfer_queue_family_index);
+            }
+        }
+        let queue_infos: Vec<_> = queue_families.iter().map(|&family| {
+            vk::DeviceQueueCreateInfo::default()
+                .queue_family_index(family)
+                .queue_priorities(&priorities)
+        }).collect();
 
-        let device_extensions = [swapchain::NAME.as_ptr()];
-        
-        let device_create_info = vk::DeviceCreateInfo::default()
-            .queue_create_infos(std::slice::from_ref(&queue_info))
-            .enabled_extension_names(&device_extensions);
+        let supported = DeviceFeatures::supported(&instance, physical_device, api_version);
+        let missing = requested.required.difference(supported);
+        if missing != DeviceFeatures::default() {
+            return Err(VulkanDemoError::MissingDeviceFeatures(missing.names()));
+        }
+        let features = requested.required.union(requested.preferred).intersection(supported);
+        let unavailable = requested.preferred.difference(supported).names();
+        if !unavailable.is_empty() {
+            log::info!("Unavailable device features, using fallbacks: {}", unavailable.join(", "));
+        }
+        log::debug!("Enabled device features: {}", features.names().join(", "));
+
+        let mut device_extensions: Vec<_> = required_extensions.iter().map(|name| name.as_ptr()).collect();
+        device_extensions.extend(features.extensions(api_version).iter().map(|name| name.as_ptr()));
+        // Queried through vkGetPhysicalDeviceMemoryProperties2, which is Vulkan 1.1.
+        let memory_budget = api_version >= vk::API_VERSION_1_1
+            && supports_device_extensions(&instance, physical_device, &[memory_budget::NAME]);
+        if memory_budget {
+            device_extensions.push(memory_budget::NAME.as_ptr());
+        } else {
+            log::info!("VK_EXT_memory_budget unavailable, memory reports show heap sizes only");
+        }
+        let calibrated_timestamps = supports_device_extensions(&instance, physical_device, &[calibrated_timestamps::NAME])
+            && unsafe {
+                calibrated_timestamps::Instance::new(&entry, &instance)
+                    .get_physical_device_calibrateable_time_domains(physical_device)
+                    .is_ok_and(|domains| domains.contains(&vk::TimeDomainEXT::DEVICE))
+            };
+        if calibrated_timestamps {
+            device_extensions.push(calibrated_timestamps::NAME.as_ptr());
+        }
+
+        let point_size_range = if features.large_points { properties.limits.point_size_range } else { [1.0, 1.0] };
+        log::info!("Point size range: {:?}", point_size_range);
+        let enabled_features = features.core();
 
-        let device = unsafe { instance.create_device(physical_device, &device_create_info, None)? };
+        let mut timeline_features = vk::PhysicalDeviceTimelineSemaphoreFeatures::default().timeline_semaphore(true);
+        let mut synchronization2_features = vk::PhysicalDeviceSynchronization2Features::default().synchronization2(true);
+        let mut dynamic_rendering_features = vk::PhysicalDeviceDynamicRenderingFeatures::default().dynamic_rendering(true);
+        let mut buffer_device_address_features =
+            vk::PhysicalDeviceBufferDeviceAddressFeatures::default().buffer_device_address(true);
+        let mut float16_features = vk::PhysicalDeviceShaderFloat16Int8Features::default().shader_float16(true);
+        let mut storage16_features = vk::PhysicalDevice16BitStorageFeatures::default().storage_buffer16_bit_access(true);
+        // Core features are enabled through the version's struct, which can't
+        // be chained alongside the extension structs it covers.
+        let mut vulkan11_features =
+            vk::PhysicalDeviceVulkan11Features::default().storage_buffer16_bit_access(features.storage_buffer_16bit_access);
+        let mut vulkan12_features = vk::PhysicalDeviceVulkan12Features::default()
+            .timeline_semaphore(features.timeline_semaphore)
+            .buffer_device_address(features.buffer_device_address)
+            .shader_float16(features.shader_float16);
+        let mut vulkan13_features = vk::PhysicalDeviceVulkan13Features::default()
+            .synchronization2(features.synchronization2)
+            .dynamic_rendering(features.dynamic_rendering)
+            .maintenance4(features.maintenance4);
+        let mut device_create_info = vk::DeviceCreateInfo::default()
+            .queue_create_infos(&queue_infos)
+            .enabled_extension_names(&device_extensions)
+            .enabled_features(&enabled_features);
+        if api_version >= vk::API_VERSION_1_2 {
+            device_create_info = device_create_info.push_next(&mut vulkan11_features).push_next(&mut vulkan12_features);
+        } else {
+            if features.timeline_semaphore {
+                device_create_info = device_create_info.push_next(&mut timeline_features);
+            }
+            if features.buffer_device_address {
+                device_create_info = device_create_info.push_next(&mut buffer_device_address_features);
+            }
+            if features.shader_float16 {
+                device_create_info = device_create_info.push_next(&mut float16_features);
+            }
+            if features.storage_buffer_16bit_access {
+                device_create_info = device_create_info.push_next(&mut storage16_features);
+            }
+        }
+        if api_version >= vk::API_VERSION_1_3 {
+            device_create_info = device_create_info.push_next(&mut vulkan13_features);
+        } else {
+            if features.synchronization2 {
+                device_create_info = device_create_info.push_next(&mut synchronization2_features);
+            }
+            if features.dynamic_rendering {
+                device_create_info = device_create_info.push_next(&mut dynamic_rendering_features);
+            }
+        }
+
+        let device = Arc::new(unsafe { instance.create_device(physical_device, &device_create_info, None)? });
         let graphics_queue = unsafe { device.get_device_queue(queue_family_index, 0) };
-        let compute_queue = graphics_queue; // Using same queue for simplicity in this demo
+        let compute_queue = unsafe { device.get_device_queue(compute_queue_family_index, 0) };
+        let present_queue = unsafe { device.get_device_queue(present_queue_family_index, 0) };
+        let transfer_queue = unsafe { device.get_device_queue(transfer_queue_family_index, 0) };
+        let allocator = Arc::new(Allocator::new(&instance, physical_device));
+        let staging = ManuallyDrop::new(Mutex::new(StagingRing::new(&device, &allocator, &properties.limits)?));
+        let timeline_semaphore = features.timeline_semaphore
+            .then(|| Promoted::load(&instance, &device, api_version, vk::API_VERSION_1_2, timeline_semaphore::Device::new));
+        let synchronization2 = features.synchronization2
+            .then(|| Promoted::load(&instance, &device, api_version, vk::API_VERSION_1_3, synchronization2::Device::new));
+        let dynamic_rendering = features.dynamic_rendering
+            .then(|| Promoted::load(&instance, &device, api_version, vk::API_VERSION_1_3, dynamic_rendering::Device::new));
+        let buffer_device_address = features.buffer_device_address
+            .then(|| Promoted::load(&instance, &device, api_version, vk::API_VERSION_1_2, buffer_device_address::Device::new));
+        let calibrated_timestamps = calibrated_timestamps.then(|| calibrated_timestamps::Device::new(&instance, &device));
+        let debug = DebugUtils::new(&instance, &device, debug_utils_enabled);
+        let uploads = ManuallyDrop::new(Mutex::new(UploadContext::new(
+            &device,
+            &allocator,
+            &properties.limits,
+            synchronization2.clone(),
+            features.timeline_semaphore,
+            transfer_queue,
+            transfer_queue_family_index,
+            queue_family_index,
+        )?));
+
+        let descriptor_pool = ManuallyDrop::new(Arc::new(SharedDescriptorPool::new(&device)?));
+
+        let pipeline_cache_path = default_cache_path();
+        let pipeline_cache = load_pipeline_cache(&device, &properties, pipeline_cache_path.as_deref())?;
 
         Ok(Self {
             entry,
             instance,
+            debug_utils_loader,
+            debug_messenger,
+            debug,
             surface_loader,
             surface,
             physical_device,
+            api_version,
             device,
+            allocator,
+            staging,
+            uploads,
+            descriptor_pool,
             graphics_queue,
             compute_queue,
+            present_queue,
+            transfer_queue,
             queue_family_index,
+            compute_queue_family_index,
+            present_queue_family_index,
+            transfer_queue_family_index,
+            point_size_range,
+            features,
+            memory_budget,
+            calibrated_timestamps,
+            timeline_semaphore,
+            synchronization2,
+            dynamic_rendering,
+            buffer_device_address,
+            pipeline_cache,
+            pipeline_cache_path,
         })
     }
 }
 
+impl VulkanContext {
+    /// Whether the simulation runs on its own queue family, overlapping with rendering.
+    pub fn has_async_compute(&self) -> bool {
+        self.compute_queue_family_index != self.queue_family_index
+    }
+
+    /// Whether presenting happens on a different queue family than rendering,
+    /// so swapchain images are shared between the two.
+    pub fn has_separate_present_queue(&self) -> bool {
+        self.present_queue_family_index != self.queue_family_index
+    }
+
+    /// Whether uploads go through a transfer-only queue family, alongside rendering.
+    pub fn has_transfer_queue(&self) -> bool {
+        self.transfer_queue_family_index != self.queue_family_index
+    }
+
+    /// The distinct queue families that access shared resources such as the particle buffers.
+    pub fn queue_family_indices(&self) -> Vec<u32> {
+        let mut families = vec![self.queue_family_index];
+        for family in [self.compute_queue_family_index, self.transfer_queue_family_index] {
+            if !families.contains(&family) {
+                families.push(family);
+            }
+        }
+        families
+    }
+
+    /// The allocator's report per memory heap, with budgets and usage fresh from
+    /// the driver when `VK_EXT_memory_budget` is enabled.
+    pub fn memory_report(&self) -> Vec<HeapUsage> {
+        let mut report = self.allocator.report();
+        if self.memory_budget {
+            let mut budget = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+            let mut properties = vk::PhysicalDeviceMemoryProperties2::default().push_next(&mut budget);
+            unsafe { self.instance.get_physical_device_memory_properties2(self.physical_device, &mut properties) };
+            for (index, heap) in report.iter_mut().enumerate() {
+                heap.budget = budget.heap_budget[index];
+                heap.usage = budget.heap_usage[index];
+            }
+        }
+        report
+    }
+
+    /// Fails with `InsufficientMemory` if `size` bytes in `location` would not
+    /// fit in what is left of the heap's budget, instead of leaving it to the
+    /// driver's bare out-of-memory error.
+    pub fn check_memory_budget(&self, location: MemoryLocation, size: vk::DeviceSize) -> Result<(), VulkanDemoError> {
+        let Some(index) = self.allocator.heap_index(location) else {
+            return Err(VulkanDemoError::MissingMemoryType);
+        };
+        let heap = self.memory_report()[index as usize];
+        if size > heap.available() {
+            return Err(VulkanDemoError::InsufficientMemory {
+                requested: size,
+                available: heap.available(),
+                device_local: heap.device_local,
+            });
+        }
+        Ok(())
+    }
+
+    /// The GPU's name, type and driver version, for logs and error reports.
+    pub fn device_description(&self) -> String {
+        describe_device(&self.instance, self.physical_device)
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.surface == vk::SurfaceKHR::null()
+    }
+
+    /// Replaces the window surface, after it was lost or taken down by
+    /// `destroy_surface`, with a new one for `window`. Every swapchain of the
+    /// old surface must already be destroyed. Fails with `UnsupportedSurface`
+    /// if the present queue family can't present to the new one.
+    pub fn recreate_surface(&mut self, window: &Window) -> Result<(), VulkanDemoError> {
+        self.destroy_surface();
+        self.surface = create_surface(&self.entry, &self.instance, window)?;
+        self.check_present_support(self.surface)
+    }
+
+    /// Creates a surface for a window besides the one the context was made
+    /// for. The device was picked for presenting to that one, so this fails
+    /// with `UnsupportedSurface` if its present queue family can't present here too.
+    pub fn window_surface(&self, window: &Window) -> Result<OwnedSurface, VulkanDemoError> {
+        let surface = OwnedSurface::new(&self.surface_loader, create_surface(&self.entry, &self.instance, window)?);
+        self.check_present_support(surface.handle())?;
+        Ok(surface)
+    }
+
+    /// Makes `surface`, from `window_surface`, the context's own in place of
+    /// the current one, as when the window that one belongs to closes. Every
+    /// swapchain of the current surface must already be destroyed.
+    pub fn adopt_surface(&mut self, surface: OwnedSurface) {
+        self.destroy_surface();
+        self.surface = surface.into_handle();
+    }
+
+    fn check_present_support(&self, surface: vk::SurfaceKHR) -> Result<(), VulkanDemoError> {
+        let supported = unsafe {
+            self.surface_loader.get_physical_device_surface_support(self.physical_device, self.present_queue_family_index, surface)?
+        };
+        if !supported {
+            return Err(VulkanDemoError::UnsupportedSurface(format!(
+                "queue family {} can't present to it",
+                self.present_queue_family_index
+            )));
+        }
+        Ok(())
+    }
+
+    /// Destroys the window surface, leaving a null handle until
+    /// `recreate_surface`. Every swapchain of it must already be destroyed.
+    pub fn destroy_surface(&mut self) {
+        if self.surface != vk::SurfaceKHR::null() {
+            unsafe { self.surface_loader.destroy_surface(self.surface, None) };
+            self.surface = vk::SurfaceKHR::null();
+        }
+    }
+
+    /// The ring that uploads to and readbacks from device-local buffers are
+    /// staged through. Allocations from it are released with the next
+    /// `one_time_submit`.
+    pub fn staging(&self) -> MutexGuard<'_, StagingRing> {
+        self.staging.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Uploads to device-local buffers through the transfer queue, which the
+    /// frame loop polls once a frame.
+    pub fn uploads(&self) -> MutexGuard<'_, UploadContext> {
+        self.uploads.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Records commands into a transient command buffer, submits them on the
+    /// graphics queue and blocks until they have finished executing.
+    pub fn one_time_submit(&self, record: impl FnOnce(vk::CommandBuffer)) -> Result<(), vk::Result> {
+        let pool_info = vk::CommandPoolCreateInfo::default()
+            .queue_family_index(self.queue_family_index)
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT);
+        let command_pool = OwnedCommandPool::new(&self.device, unsafe { self.device.create_command_pool(&pool_info, None)? });
+
+        unsafe {
+            let alloc_info = vk::CommandBufferAllocateInfo::default()
+                .command_pool(command_pool.handle())
+                .level(vk::CommandBufferLevel::PRIMARY)
+                .command_buffer_count(1);
+            let cmd = self.device.allocate_command_buffers(&alloc_info)?[0];
+
+            let begin_info = vk::CommandBufferBeginInfo::default()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            self.device.begin_command_buffer(cmd, &begin_info)?;
+            record(cmd);
+            self.device.end_command_buffer(cmd)?;
+
+            let fence = self.staging().fence()?;
+            let submit_info = vk::SubmitInfo::default().command_buffers(std::slice::from_ref(&cmd));
+            self.device.queue_submit(self.graphics_queue, &[submit_info], fence)?;
+            self.device.wait_for_fences(&[fence], true, u64::MAX)
+        }
+    }
+}
+
 impl Drop for VulkanContext {
     fn drop(&mut self) {
         unsafe {
+            ManuallyDrop::drop(&mut self.descriptor_pool);
+            ManuallyDrop::drop(&mut self.uploads);
+            ManuallyDrop::drop(&mut self.staging);
+        }
+        debug_assert_eq!(Arc::strong_count(&self.device), 1, "Vulkan objects outlived the context");
+        self.allocator.destroy(&self.device);
+        unsafe {
+            if let Some(path) = &self.pipeline_cache_path {
+                save_pipeline_cache(&self.device, self.pipeline_cache, path);
+            }
+            self.device.destroy_pipeline_cache(self.pipeline_cache, None);
             self.device.destroy_device(None);
-            self.surface_loader.destroy_surface(self.surface, None);
+            if self.surface != vk::SurfaceKHR::null() {
+                self.surface_loader.destroy_surface(self.surface, None);
+            }
+            if let Some(loader) = &self.debug_utils_loader {
+                loader.destroy_debug_utils_messenger(self.debug_messenger, None);
+            }
             self.instance.destroy_instance(None);
         }
     }
 }
+
+fn create_surface(entry: &Entry, instance: &Instance, window: &Window) -> Result<vk::SurfaceKHR, VulkanDemoError> {
+    unsafe {
+        ash_window::create_surface(entry, instance, window.display_handle()?.as_raw(), window.window_handle()?.as_raw(), None)
+            .map_err(VulkanDemoError::SurfaceCreation)
+    }
+}
+
+/// Finds a queue family with graphics and compute, and one that can present to
+/// `surface`. A family that does both is preferred for both; with a null
+/// `surface` the graphics family stands in for presenting.
+fn find_queue_families(
+    instance: &Instance,
+    surface_loader: &surface::Instance,
+    surface: vk::SurfaceKHR,
+    pdevice: vk::PhysicalDevice,
+) -> Option<(u32, u32)> {
+    let families = unsafe { instance.get_physical_device_queue_family_properties(pdevice) };
+    let graphics: Vec<u32> = (0..families.len() as u32)
+        .filter(|&index| families[index as usize].queue_flags.contains(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE))
+        .collect();
+    let supports_surface = |index: u32| {
+        surface == vk::SurfaceKHR::null()
+            || unsafe { surface_loader.get_physical_device_surface_support(pdevice, index, surface).unwrap_or(false) }
+    };
+    if let Some(&index) = graphics.iter().find(|&&index| supports_surface(index)) {
+        return Some((index, index));
+    }
+    let present = (0..families.len() as u32).find(|&index| supports_surface(index))?;
+    Some((*graphics.first()?, present))
+}
+
+/// Finds a compute family without graphics support, which on most discrete GPUs
+/// runs alongside the graphics queue.
+fn find_compute_queue_family(instance: &Instance, pdevice: vk::PhysicalDevice) -> Option<u32> {
+    let families = unsafe { instance.get_physical_device_queue_family_properties(pdevice) };
+    families.iter().position(|info| {
+        info.queue_flags.contains(vk::QueueFlags::COMPUTE) && !info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+    }).map(|index| index as u32)
+}
+
+/// Finds a transfer family without graphics or compute support, which on most
+/// discrete GPUs copies through DMA engines alongside the other queues.
+fn find_transfer_queue_family(instance: &Instance, pdevice: vk::PhysicalDevice) -> Option<u32> {
+    let families = unsafe { instance.get_physical_device_queue_family_properties(pdevice) };
+    families.iter().position(|info| {
+        info.queue_flags.contains(vk::QueueFlags::TRANSFER)
+            && !info.queue_flags.intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+    }).map(|index| index as u32)
+}
+
+fn device_type_score(device_type: vk::PhysicalDeviceType) -> u32 {
+    match device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+        vk::PhysicalDeviceType::CPU => 1,
+        _ => 0,
+    }
+}
+
+fn device_type_name(device_type: vk::PhysicalDeviceType) -> &'static str {
+    match device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => "discrete",
+        vk::PhysicalDeviceType::INTEGRATED_GPU => "integrated",
+        vk::PhysicalDeviceType::VIRTUAL_GPU => "virtual",
+        vk::PhysicalDeviceType::CPU => "cpu",
+        _ => "other",
+    }
+}
+
+/// Driver versions are vendor-encoded; NVIDIA uses a 10.8.8.6 bit split, everyone
+/// else roughly follows the Vulkan version encoding.
+fn describe_device(instance: &Instance, physical_device: vk::PhysicalDevice) -> String {
+    let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+    format!(
+        "{} ({}), driver {}",
+        properties.device_name_as_c_str().unwrap_or(c"<unknown>").to_string_lossy(),
+        device_type_name(properties.device_type),
+        format_driver_version(properties.vendor_id, properties.driver_version),
+    )
+}
+
+/// `version` as "major.minor.patch".
+fn format_api_version(version: u32) -> String {
+    format!("{}.{}.{}", vk::api_version_major(version), vk::api_version_minor(version), vk::api_version_patch(version))
+}
+
+/// `version` with a patch number of 0, for comparing against the
+/// `API_VERSION_*` constants.
+fn without_patch(version: u32) -> u32 {
+    vk::make_api_version(vk::api_version_variant(version), vk::api_version_major(version), vk::api_version_minor(version), 0)
+}
+
+fn format_driver_version(vendor_id: u32, version: u32) -> String {
+    const NVIDIA: u32 = 0x10DE;
+    if vendor_id == NVIDIA {
+        format!("{}.{}.{}.{}", version >> 22, (version >> 14) & 0xFF, (version >> 6) & 0xFF, version & 0x3F)
+    } else {
+        format!(
+            "{}.{}.{}",
+            vk::api_version_major(version),
+            vk::api_version_minor(version),
+            vk::api_version_patch(version)
+        )
+    }
+}
+
+/// Validation defaults to on in debug builds; `VALIDATION=0` or `VALIDATION=1` overrides it.
+fn validation_requested() -> bool {
+    match std::env::var("VALIDATION") {
+        Ok(value) => !matches!(value.as_str(), "0" | "off" | "false"),
+        Err(_) => cfg!(debug_assertions),
+    }
+}
+
+/// Synchronization validation checks for hazards between frames in flight, at
+/// a large cost, so it stays off unless `VALIDATION_SYNC=1` asks for it.
+fn sync_validation_requested() -> bool {
+    std::env::var("VALIDATION_SYNC").is_ok_and(|value| matches!(value.as_str(), "1" | "on" | "true"))
+}
+
+fn validation_available(entry: &Entry) -> Result<bool, vk::Result> {
+    let layers = unsafe { entry.enumerate_instance_layer_properties()? };
+    let found = layers
+        .iter()
+        .any(|layer| layer.layer_name_as_c_str().is_ok_and(|name| name == VALIDATION_LAYER));
+    if !found {
+        log::warn!("{} is not installed, continuing without validation", VALIDATION_LAYER.to_string_lossy());
+    }
+    Ok(found)
+}
+
+fn debug_utils_available(entry: &Entry) -> Result<bool, vk::Result> {
+    let extensions = unsafe { entry.enumerate_instance_extension_properties(None)? };
+    Ok(extensions
+        .iter()
+        .any(|extension| extension.extension_name_as_c_str().is_ok_and(|name| name == debug_utils::NAME)))
+}
+
+fn debug_messenger_create_info() -> vk::DebugUtilsMessengerCreateInfoEXT<'static> {
+    vk::DebugUtilsMessengerCreateInfoEXT::default()
+        .message_severity(
+            vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
+                | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                | vk::DebugUtilsMessageSeverityFlagsEXT::INFO
+                | vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE,
+        )
+        .message_type(
+            vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+                | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+        )
+        .pfn_user_callback(Some(vulkan_debug_callback))
+}
+
+unsafe extern "system" fn vulkan_debug_callback(
+    severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT<'_>,
+    _user_data: *mut c_void,
+) -> vk::Bool32 {
+    let message = if callback_data.is_null() || (*callback_data).p_message.is_null() {
+        std::borrow::Cow::Borrowed("<no message>")
+    } else {
+        CStr::from_ptr((*callback_data).p_message).to_string_lossy()
+    };
+
+    if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        log::error!("[{message_type:?}] {message}");
+    } else if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+        log::warn!("[{message_type:?}] {message}");
+    } else if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+        log::info!("[{message_type:?}] {message}");
+    } else {
+        log::debug!("[{message_type:?}] {message}");
+    }
+
+    vk::FALSE
+}
@@ -0,0 +1,42 @@
+//! Renders a fixed-seed, fixed-step scene headlessly and compares the last
+//! frame with `tests/golden/simple.png`. References only hold on the software
+//! implementation they were rendered with, so this is built with
+//! `--features swiftshader-tests` alone. Run it with `BLESS_GOLDEN=1` to write
+//! the reference instead.
+#![cfg(feature = "swiftshader-tests")]
+
+use clap::Parser;
+use std::path::Path;
+use vulkan_particle_demo::config::AppConfig;
+use vulkan_particle_demo::golden::BLESS_VAR;
+use vulkan_particle_demo::headless::run_benchmark;
+
+#[test]
+fn simple_scene_matches_reference() {
+    let reference = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/simple.png");
+    assert!(
+        reference.exists() || std::env::var_os(BLESS_VAR).is_some(),
+        "no reference at {}; render one with {BLESS_VAR}=1",
+        reference.display(),
+    );
+    let config = AppConfig::try_parse_from([
+        "vulkan-particle-demo",
+        "--headless",
+        "--frames",
+        "60",
+        "--particles",
+        "4096",
+        "--width",
+        "256",
+        "--height",
+        "256",
+        "--seed",
+        "1",
+        "--fixed-dt",
+        "0.016666668",
+        "--golden",
+        reference.to_str().expect("a UTF-8 manifest path"),
+    ])
+    .unwrap();
+    run_benchmark(&config).unwrap();
+}
@@ -0,0 +1,59 @@
+//! Library entry points that need neither a window nor a GPU.
+
+use vulkan_particle_demo::behaviors::SimulationMode;
+use vulkan_particle_demo::emitter::{EmitterConfig, EmitterShape};
+use vulkan_particle_demo::particles::initial_particles;
+use vulkan_particle_demo::pipeline_utils::{compile_shader, set_shader_cache_enabled, ShaderCompileOptions};
+
+#[test]
+fn embedded_shaders_compile() {
+    // Compile every time rather than trusting SPIR-V from earlier runs.
+    set_shader_cache_enabled(false);
+    let options = ShaderCompileOptions::default();
+    let mut compute = Vec::new();
+    for &mode in SimulationMode::all() {
+        let behavior = mode.behavior();
+        compute.push((behavior.shader_file(), behavior.shader_source()));
+        compute.extend_from_slice(behavior.prepasses());
+    }
+    for (file, source) in compute {
+        let spirv = compile_shader(source, file, shaderc::ShaderKind::Compute, &options).unwrap_or_else(|e| panic!("{e}"));
+        assert!(!spirv.is_empty(), "{file} compiled to nothing");
+    }
+    // As `SimPipelines` build them for F16 formats on devices with 16-bit storage.
+    let f16_options = ShaderCompileOptions { defines: vec!["PARTICLE_F16_STORAGE"], ..ShaderCompileOptions::default() };
+    for &mode in SimulationMode::all() {
+        let behavior = mode.behavior();
+        compile_shader(behavior.shader_source(), behavior.shader_file(), shaderc::ShaderKind::Compute, &f16_options)
+            .unwrap_or_else(|e| panic!("{e}"));
+    }
+    let graphics = [
+        ("particle.vert", include_str!("../src/shaders/particle.vert"), shaderc::ShaderKind::Vertex),
+        ("particle.frag", include_str!("../src/shaders/particle.frag"), shaderc::ShaderKind::Fragment),
+    ];
+    for (file, source, kind) in graphics {
+        compile_shader(source, file, kind, &options).unwrap_or_else(|e| panic!("{e}"));
+    }
+}
+
+#[test]
+fn initial_particles_stay_within_the_emitter() {
+    let radius = 0.3;
+    let emitter = EmitterConfig { shape: EmitterShape::Disc { radius }, ..EmitterConfig::default() };
+    let particles = initial_particles(10_000, 7, &emitter, false);
+    assert_eq!(particles.len(), 10_000);
+    for (index, particle) in particles.iter().enumerate() {
+        let offset = [particle.pos[0] - emitter.position[0], particle.pos[1] - emitter.position[1]];
+        assert!(offset[0].hypot(offset[1]) <= radius + 1e-5, "particle {index} at {:?}", particle.pos);
+        assert_eq!(particle.pos[2], 0.0, "particle {index} left the plane");
+        let speed = particle.vel.iter().map(|v| v * v).sum::<f32>().sqrt();
+        assert!(speed <= emitter.max_speed + 1e-5, "particle {index} at speed {speed}");
+        assert!((0.0..=particle.max_life).contains(&particle.life), "particle {index} life {}", particle.life);
+        assert_eq!(particle.id, index as u32);
+    }
+}
+
+#[test]
+fn no_initial_particles() {
+    assert!(initial_particles(0, 7, &EmitterConfig::default(), false).is_empty());
+}